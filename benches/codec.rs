@@ -0,0 +1,57 @@
+//! Decoding and encoding server output shaped like real BatMUD traffic: a
+//! realm map of colored cells, long room descriptions, channel messages and
+//! prompts.
+
+mod common;
+
+use batproxy_rs::bc::Decoder;
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use self::common::{decode, traffic, READ_SIZE};
+
+/// Decode and encode again read by read, dropping the frames of each read
+/// as the proxy does.
+fn pass_through(input: &[u8], out: &mut BytesMut) {
+    let mut decoder = Decoder::new();
+    let mut frames = Vec::new();
+    for chunk in input.chunks(READ_SIZE) {
+        decoder.decode(chunk, &mut frames);
+        out.clear();
+        for frame in frames.drain(..) {
+            frame.encode(out);
+        }
+    }
+}
+
+fn codec(c: &mut Criterion) {
+    let input = traffic();
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("decode", |b| b.iter(|| decode(&input)));
+    group.bench_function("encode", |b| {
+        b.iter_batched(
+            || decode(&input),
+            |frames| {
+                let mut out = BytesMut::with_capacity(input.len());
+                for frame in &frames {
+                    frame.encode(&mut out);
+                }
+                out
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("pass_through", |b| {
+        let mut out = BytesMut::with_capacity(READ_SIZE * 2);
+        b.iter(|| pass_through(&input, &mut out))
+    });
+    group.bench_function("clone", |b| {
+        let frames = decode(&input);
+        b.iter(|| frames.clone())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, codec);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+//! Server output shaped like real BatMUD traffic, shared by the benchmarks.
+
+use batproxy_rs::bc::{Decoder, Frame, ESC};
+
+/// Bytes per read, as the proxy reads from the server.
+pub const READ_SIZE: usize = 8 * 1024;
+
+fn code(id: u8, attr: Option<&str>, body: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&[ESC, b'<']);
+    out.extend_from_slice(format!("{:02}", id).as_bytes());
+    if let Some(attr) = attr {
+        out.extend_from_slice(attr.as_bytes());
+        out.extend_from_slice(&[ESC, b'|']);
+    }
+    out.extend_from_slice(body);
+    out.extend_from_slice(&[ESC, b'>']);
+    out.extend_from_slice(format!("{:02}", id).as_bytes());
+}
+
+/// A realm map of `rows` lines, each cell in a color code of its own.
+fn map(rows: usize, out: &mut Vec<u8>) {
+    let colors = ["00cc00", "0000cc", "cccc00", "cc0000", "888888"];
+    let mut body = Vec::new();
+    for row in 0..rows {
+        for col in 0..60 {
+            let color = colors[(row * 7 + col * 3) % colors.len()];
+            code(20, Some(color), b"~", &mut body);
+        }
+        body.extend_from_slice(b"\r\n");
+    }
+    code(11, None, b"", out);
+    code(10, Some("spec_map"), &body, out);
+}
+
+/// Output of a session moving about, fighting and chatting.
+pub fn traffic() -> Vec<u8> {
+    let desc = "You are standing on a wide road leading through an ancient forest. \
+                Tall oaks line both sides and the canopy above lets through only \
+                a little light. "
+        .repeat(4);
+    let mut out = Vec::new();
+    for i in 0..40 {
+        map(20, &mut out);
+        out.extend_from_slice(desc.as_bytes());
+        out.extend_from_slice(b"\r\n");
+        code(
+            99,
+            None,
+            format!(
+                "BAT_MAPPER;;forest;;r{};;n;;0;;A road;;{};;n,s;;BAT_MAPPER",
+                i, desc
+            )
+            .as_bytes(),
+            &mut out,
+        );
+        let mut said = Vec::new();
+        said.extend_from_slice(b"Bob [sales]: ");
+        code(21, Some("000080"), b"wtb sword", &mut said);
+        said.extend_from_slice(b"\r\n");
+        code(10, Some("chan_sales"), &said, &mut out);
+        out.extend_from_slice(b"You hit the orc hard.\r\nThe orc misses you.\r\n");
+        code(
+            10,
+            Some("spec_prompt"),
+            b"Hp:320/320 Sp:200/200 Ep:150/150 > ",
+            &mut out,
+        );
+    }
+    out
+}
+
+/// Decode `input` as the proxy reads it.
+pub fn decode(input: &[u8]) -> Vec<Frame> {
+    let mut decoder = Decoder::new();
+    let mut frames = Vec::new();
+    for chunk in input.chunks(READ_SIZE) {
+        decoder.decode(chunk, &mut frames);
+    }
+    decoder.finish(&mut frames);
+    frames
+}
@@ -0,0 +1,74 @@
+//! The work done on decoded frames before they reach the client: picking
+//! palette colors, rendering the BC color codes and the middleware chain.
+
+mod common;
+
+use std::hint::black_box;
+
+use batproxy_rs::{
+    color::{render_codes, rgb_to_256, ColorMode},
+    middleware::Chain,
+    session::Session,
+    Config,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use self::common::{decode, traffic};
+
+fn colors(c: &mut Criterion) {
+    // Every 16th level of each channel.
+    let rgb: Vec<(u8, u8, u8)> = (0..16u8)
+        .flat_map(|r| (0..16u8).flat_map(move |g| (0..16u8).map(move |b| (r, g, b))))
+        .map(|(r, g, b)| (r * 17, g * 17, b * 17))
+        .collect();
+    let mut group = c.benchmark_group("color");
+    group.throughput(Throughput::Elements(rgb.len() as u64));
+    group.bench_function("rgb_to_256", |b| {
+        b.iter(|| {
+            for &(r, g, b) in &rgb {
+                black_box(rgb_to_256(r, g, b));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn transform(c: &mut Criterion) {
+    let input = traffic();
+    let frames = decode(&input);
+    let mut group = c.benchmark_group("transform");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    for (name, mode) in [
+        ("render_256", ColorMode::Xterm256),
+        ("render_truecolor", ColorMode::TrueColor),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || frames.clone(),
+                |frames| {
+                    let mut out = Vec::with_capacity(frames.len());
+                    for frame in frames {
+                        render_codes(frame, mode, &mut out);
+                    }
+                    out
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.bench_function("chain", |b| {
+        let config = Config::default();
+        b.iter_batched(
+            || {
+                let chain = Chain::new(&config, Vec::new(), &[]);
+                (chain, Session::new(&config, None), frames.clone())
+            },
+            |(mut chain, mut session, frames)| chain.run(frames, &mut session),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, colors, transform);
+criterion_main!(benches);
@@ -0,0 +1,293 @@
+use bytes::{Bytes, BytesMut};
+
+use super::{push_id, ControlCode, Frame, ESC, LOGIN_FAILURE, LOGIN_SUCCESS, PROMPT_ATTR};
+
+struct OpenCode {
+    id: u8,
+    attr: Option<Bytes>,
+    body: Vec<Frame>,
+    text: Vec<u8>,
+}
+
+/// The text collected in `buf` as a frame's own copy. `buf` is kept for
+/// the next text, so frames do not hold on to its spare capacity.
+fn take_text(buf: &mut Vec<u8>) -> Bytes {
+    let text = Bytes::copy_from_slice(buf);
+    buf.clear();
+    text
+}
+
+/// Bounds on what the decoder buffers for unterminated control codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// Bytes a top level code may span, nested codes included.
+    pub max_code_bytes: usize,
+    /// How deep codes may nest.
+    pub max_depth: usize,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        Self {
+            max_code_bytes: 64 * 1024,
+            max_depth: 16,
+        }
+    }
+}
+
+/// Streaming decoder turning raw server bytes into frames.
+///
+/// Top level text is emitted as soon as it is seen, control codes only once
+/// their outermost closing tag has arrived. Malformed codes and codes going
+/// over the limits are passed on as text, with a warning explaining why.
+#[derive(Default)]
+pub struct Decoder {
+    limits: DecoderLimits,
+    stack: Vec<OpenCode>,
+    // Bytes seen since the outermost open code started.
+    open_bytes: usize,
+    text: Vec<u8>,
+    // Bytes of an escape sequence split across reads.
+    partial: Vec<u8>,
+    warnings: Vec<String>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: DecoderLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Decode `input`. The text of the frames is copied out of it, so a
+    /// frame kept around holds only its own bytes.
+    pub fn decode(&mut self, input: &[u8], frames: &mut Vec<Frame>) {
+        let data;
+        let bytes = if self.partial.is_empty() {
+            input
+        } else {
+            self.partial.extend_from_slice(input);
+            data = std::mem::take(&mut self.partial);
+            &data[..]
+        };
+
+        let mut i = 0;
+        while i < bytes.len() {
+            let next = match bytes[i..].iter().position(|&b| b == ESC) {
+                Some(n) => i + n,
+                None => {
+                    self.push_text(&bytes[i..]);
+                    self.check_size(frames);
+                    break;
+                }
+            };
+            self.push_text(&bytes[i..next]);
+            self.check_size(frames);
+            i = next;
+
+            match escape(&bytes[i..]) {
+                Escape::Incomplete => {
+                    self.partial.extend_from_slice(&bytes[i..]);
+                    break;
+                }
+                Escape::Open(_) if self.stack.len() >= self.limits.max_depth => {
+                    self.warn(format!(
+                        "control codes nested deeper than {}, passing them on as text",
+                        self.limits.max_depth
+                    ));
+                    self.give_up(frames);
+                    self.push_text(&bytes[i..i + 4]);
+                    i += 4;
+                }
+                Escape::Open(id) => {
+                    self.open_bytes += 4;
+                    self.flush_text(frames);
+                    self.stack.push(OpenCode {
+                        id,
+                        attr: None,
+                        body: Vec::new(),
+                        text: Vec::new(),
+                    });
+                    i += 4;
+                }
+                Escape::Close(id) if self.stack.last().map(|open| open.id) == Some(id) => {
+                    self.open_bytes += 4;
+                    self.close(frames);
+                    i += 4;
+                }
+                Escape::Close(id) => {
+                    self.warn(format!(
+                        "closing tag for control code {:02} that is not open, passing it on as text",
+                        id
+                    ));
+                    self.push_text(&bytes[i..i + 4]);
+                    self.check_size(frames);
+                    i += 4;
+                }
+                Escape::Separator if self.is_attr_pending() => {
+                    self.open_bytes += 2;
+                    self.end_attr();
+                    i += 2;
+                }
+                Escape::Separator | Escape::Other => {
+                    self.push_text(&bytes[i..i + 1]);
+                    i += 1;
+                }
+            }
+        }
+
+        // Text outside codes goes out with the read. Open codes keep theirs,
+        // so how the output is split into reads does not change their body.
+        if self.stack.is_empty() {
+            self.flush_text(frames);
+        }
+    }
+
+    /// Emit whatever is still buffered once the server has closed the
+    /// connection. Unterminated codes are passed through as text.
+    pub fn finish(&mut self, frames: &mut Vec<Frame>) {
+        self.give_up(frames);
+        self.text.append(&mut self.partial);
+        self.flush_text(frames);
+    }
+
+    /// Why input was passed on as text since the last call.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn warn(&mut self, warning: String) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    fn check_size(&mut self, frames: &mut Vec<Frame>) {
+        if self.open_bytes > self.limits.max_code_bytes {
+            self.warn(format!(
+                "control code longer than {} bytes, passing it on as text",
+                self.limits.max_code_bytes
+            ));
+            self.give_up(frames);
+        }
+    }
+
+    /// Pass on the open codes as the text they were sent as.
+    fn give_up(&mut self, frames: &mut Vec<Frame>) {
+        self.open_bytes = 0;
+        let mut raw = Vec::new();
+        for mut open in std::mem::take(&mut self.stack) {
+            raw.extend_from_slice(&[ESC, b'<']);
+            push_id(open.id, &mut raw);
+            if let Some(attr) = open.attr {
+                raw.extend_from_slice(&attr);
+                raw.extend_from_slice(&[ESC, b'|']);
+            }
+            for frame in &open.body {
+                frame.encode(&mut raw);
+            }
+            raw.append(&mut open.text);
+        }
+        self.text.append(&mut raw);
+        self.flush_text(frames);
+    }
+
+    fn push_text(&mut self, text: &[u8]) {
+        match self.stack.last_mut() {
+            Some(open) => {
+                self.open_bytes += text.len();
+                open.text.extend_from_slice(text);
+            }
+            None => self.text.extend_from_slice(text),
+        }
+    }
+
+    fn flush_text(&mut self, frames: &mut Vec<Frame>) {
+        match self.stack.last_mut() {
+            Some(open) => {
+                if !open.text.is_empty() {
+                    open.body.push(Frame::Text(take_text(&mut open.text)));
+                }
+            }
+            None => {
+                if !self.text.is_empty() {
+                    frames.push(Frame::Text(take_text(&mut self.text)));
+                }
+            }
+        }
+    }
+
+    fn is_attr_pending(&self) -> bool {
+        matches!(self.stack.last(), Some(open) if open.attr.is_none())
+    }
+
+    fn end_attr(&mut self) {
+        if let Some(open) = self.stack.last_mut() {
+            open.attr = Some(if open.body.is_empty() {
+                take_text(&mut open.text)
+            } else {
+                let mut attr = BytesMut::new();
+                for frame in open.body.drain(..) {
+                    frame.encode(&mut attr);
+                }
+                attr.extend_from_slice(&open.text);
+                open.text.clear();
+                attr.freeze()
+            });
+        }
+    }
+
+    fn close(&mut self, frames: &mut Vec<Frame>) {
+        self.flush_text(frames);
+        if let Some(open) = self.stack.pop() {
+            if self.stack.is_empty() {
+                self.open_bytes = 0;
+            }
+            let code = ControlCode::new(open.id, open.attr, open.body);
+            match self.stack.last_mut() {
+                Some(parent) => parent.body.push(Frame::Code(code)),
+                None if code.id == 10 && code.attr_is(PROMPT_ATTR) => {
+                    frames.push(Frame::Prompt(code))
+                }
+                None if code.id == LOGIN_SUCCESS || code.id == LOGIN_FAILURE => {
+                    frames.push(Frame::LoginResult(code))
+                }
+                None => frames.push(Frame::Code(code)),
+            }
+        }
+    }
+}
+
+enum Escape {
+    Incomplete,
+    Open(u8),
+    Close(u8),
+    Separator,
+    Other,
+}
+
+fn escape(bytes: &[u8]) -> Escape {
+    match bytes.get(1) {
+        None => Escape::Incomplete,
+        Some(b'|') => Escape::Separator,
+        Some(&tag @ (b'<' | b'>')) => match (bytes.get(2), bytes.get(3)) {
+            (Some(d1), Some(d2)) if d1.is_ascii_digit() && d2.is_ascii_digit() => {
+                let id = (d1 - b'0') * 10 + (d2 - b'0');
+                if tag == b'<' {
+                    Escape::Open(id)
+                } else {
+                    Escape::Close(id)
+                }
+            }
+            (Some(d1), None) if d1.is_ascii_digit() => Escape::Incomplete,
+            (None, _) => Escape::Incomplete,
+            _ => Escape::Other,
+        },
+        Some(_) => Escape::Other,
+    }
+}
@@ -0,0 +1,196 @@
+//! BatMUD's BC protocol: the control codes the server wraps its output in,
+//! decoded into [`Frame`]s and encoded back. Plain parsing without I/O, so
+//! it can be used outside the proxy.
+
+mod decoder;
+mod repr;
+
+use bytes::{BufMut, Bytes};
+use serde::{Deserialize, Serialize};
+
+pub use self::{
+    decoder::{Decoder, DecoderLimits},
+    repr::{UnsupportedVersion, Versioned, FORMAT_VERSION},
+};
+
+pub const ESC: u8 = 0x1b;
+
+/// The code the server accepts a login with.
+pub const LOGIN_SUCCESS: u8 = 5;
+/// The code the server refuses a login with.
+pub const LOGIN_FAILURE: u8 = 6;
+
+/// Attribute of the message code the server sends prompts in.
+pub const PROMPT_ATTR: &[u8] = b"spec_prompt";
+
+/// Prefix of the attribute of channel messages, as in `chan_sales`.
+pub const CHANNEL_PREFIX: &[u8] = b"chan_";
+
+/// A unit of server output: either plain bytes or a complete BC control code.
+///
+/// Text and attributes are [`Bytes`], so frames are cheap to clone. The
+/// decoder gives each frame its own copy of the text it read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "frame", rename_all = "snake_case")]
+pub enum Frame {
+    #[serde(with = "repr::bytes_text")]
+    Text(Bytes),
+    Code(ControlCode),
+    /// A top level `spec_prompt` message.
+    Prompt(ControlCode),
+    /// A top level code 05 or 06, the server accepting or refusing a login.
+    LoginResult(ControlCode),
+    /// A `spec_map` message gathered by the map layer.
+    Map(MapFrame),
+}
+
+/// The map drawn around the player: a `spec_map` message and the clear
+/// screen code sent right before it, if there was one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapFrame {
+    pub clear: Option<ControlCode>,
+    pub code: ControlCode,
+    /// Lines of the map as the server sent it.
+    pub rows: usize,
+    /// Width of the widest line, in columns.
+    pub cols: usize,
+}
+
+/// A line said on a channel: a message code with a `chan_*` attribute.
+#[derive(Debug, Clone, Copy)]
+pub struct Channel<'a> {
+    /// The channel without the `chan_` prefix, e.g. `sales`.
+    pub name: &'a str,
+    pub code: &'a ControlCode,
+}
+
+/// A BC control code `ESC<NN[attr ESC|]body ESC>NN`.
+///
+/// `attr` is only present if the code carried the `ESC|` separator, so that
+/// encoding a decoded code gives back the exact bytes the server sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlCode {
+    pub id: u8,
+    #[serde(default, with = "repr::option_text")]
+    pub attr: Option<Bytes>,
+    pub body: Vec<Frame>,
+}
+
+impl Frame {
+    /// A text frame holding `text`.
+    pub fn text(text: impl Into<Bytes>) -> Self {
+        Frame::Text(text.into())
+    }
+
+    /// Write the frame as the server sent it, to a `Vec<u8>` or a
+    /// `BytesMut` alike.
+    pub fn encode<B: BufMut>(&self, out: &mut B) {
+        match self {
+            Frame::Text(text) => out.put_slice(text),
+            Frame::Code(code) | Frame::Prompt(code) | Frame::LoginResult(code) => code.encode(out),
+            Frame::Map(map) => {
+                if let Some(clear) = &map.clear {
+                    clear.encode(out);
+                }
+                map.code.encode(out);
+            }
+        }
+    }
+
+    /// The length of the frame as [`encode`](Self::encode) writes it.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Code(code) | Frame::Prompt(code) | Frame::LoginResult(code) => {
+                code.encoded_len()
+            }
+            Frame::Map(map) => {
+                map.clear.as_ref().map_or(0, ControlCode::encoded_len) + map.code.encoded_len()
+            }
+        }
+    }
+
+    /// The control code of this frame, prompts included.
+    pub fn code(&self) -> Option<&ControlCode> {
+        match self {
+            Frame::Text(_) => None,
+            Frame::Code(code) | Frame::Prompt(code) | Frame::LoginResult(code) => Some(code),
+            Frame::Map(map) => Some(&map.code),
+        }
+    }
+
+    /// The channel message this frame is, if it is one.
+    pub fn channel(&self) -> Option<Channel<'_>> {
+        let code = match self {
+            Frame::Code(code) if code.id == 10 => code,
+            _ => return None,
+        };
+        let name = code.attr.as_deref()?.strip_prefix(CHANNEL_PREFIX)?;
+        Some(Channel {
+            name: std::str::from_utf8(name).ok()?,
+            code,
+        })
+    }
+
+    /// Append the text of this frame to `out`, leaving out control codes and
+    /// their attributes.
+    pub fn push_text(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Text(text) => out.extend_from_slice(text),
+            Frame::Code(code) | Frame::Prompt(code) | Frame::LoginResult(code) => {
+                for frame in &code.body {
+                    frame.push_text(out);
+                }
+            }
+            Frame::Map(map) => out.extend_from_slice(&map.code.text()),
+        }
+    }
+}
+
+impl ControlCode {
+    pub fn new(id: u8, attr: Option<impl Into<Bytes>>, body: Vec<Frame>) -> Self {
+        Self {
+            id,
+            attr: attr.map(Into::into),
+            body,
+        }
+    }
+
+    pub fn attr_is(&self, attr: &[u8]) -> bool {
+        self.attr.as_deref() == Some(attr)
+    }
+
+    /// The text of the body, without nested control codes.
+    pub fn text(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        for frame in &self.body {
+            frame.push_text(&mut text);
+        }
+        text
+    }
+
+    /// The length of the code as [`encode`](Self::encode) writes it.
+    pub fn encoded_len(&self) -> usize {
+        // `ESC<NN` and `ESC>NN`, and `ESC|` after the attribute.
+        let attr = self.attr.as_ref().map_or(0, |attr| attr.len() + 2);
+        8 + attr + self.body.iter().map(Frame::encoded_len).sum::<usize>()
+    }
+
+    pub fn encode<B: BufMut>(&self, out: &mut B) {
+        out.put_slice(&[ESC, b'<']);
+        push_id(self.id, out);
+        if let Some(attr) = &self.attr {
+            out.put_slice(attr);
+            out.put_slice(&[ESC, b'|']);
+        }
+        for frame in &self.body {
+            frame.encode(out);
+        }
+        out.put_slice(&[ESC, b'>']);
+        push_id(self.id, out);
+    }
+}
+
+fn push_id<B: BufMut>(id: u8, out: &mut B) {
+    out.put_slice(&[b'0' + id / 10, b'0' + id % 10]);
+}
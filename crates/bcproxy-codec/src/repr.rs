@@ -0,0 +1,131 @@
+//! How frames are written with serde, for tools that read decoded server
+//! output as JSON or another format serde knows.
+//!
+//! Frames are tagged with their `type` as in `{"type":"text","frame":"You
+//! are hungry.\r\n"}`, and codes are objects with their `id`, `attr` and
+//! `body`. Text is a string when it is valid UTF-8 and an array of bytes
+//! otherwise, so nothing the server sent is lost. The representation has a
+//! version, see [`Versioned`].
+
+use std::fmt;
+
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The version of the representation. Changes older readers cannot read
+/// bump it.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// A value along with the version of the representation it was written
+/// in, as in `{"version":1,"value":{...}}`. Reading a version other than
+/// [`FORMAT_VERSION`] fails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Unchecked<T>")]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub value: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            value,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Unchecked<T> {
+    version: u32,
+    value: T,
+}
+
+/// A [`Versioned`] value of a version this build cannot read.
+#[derive(Debug)]
+pub struct UnsupportedVersion(pub u32);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unsupported format version {}, expected {}",
+            self.0, FORMAT_VERSION
+        )
+    }
+}
+
+impl<T> TryFrom<Unchecked<T>> for Versioned<T> {
+    type Error = UnsupportedVersion;
+
+    fn try_from(unchecked: Unchecked<T>) -> Result<Self, Self::Error> {
+        if unchecked.version != FORMAT_VERSION {
+            return Err(UnsupportedVersion(unchecked.version));
+        }
+        Ok(Self {
+            version: unchecked.version,
+            value: unchecked.value,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Text<'a> {
+    Utf8(&'a str),
+    Bytes(&'a [u8]),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OwnedText {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<OwnedText> for Bytes {
+    fn from(text: OwnedText) -> Self {
+        match text {
+            OwnedText::Utf8(text) => text.into(),
+            OwnedText::Bytes(bytes) => bytes.into(),
+        }
+    }
+}
+
+fn text(bytes: &[u8]) -> Text<'_> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Text::Utf8(text),
+        Err(_) => Text::Bytes(bytes),
+    }
+}
+
+/// [`Bytes`] as text.
+pub(super) mod bytes_text {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        text(bytes).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        OwnedText::deserialize(deserializer).map(Bytes::from)
+    }
+}
+
+/// An optional [`Bytes`] as text.
+pub(super) mod option_text {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Bytes>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(text).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Bytes>, D::Error> {
+        Ok(Option::<OwnedText>::deserialize(deserializer)?.map(Bytes::from))
+    }
+}
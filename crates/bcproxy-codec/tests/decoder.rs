@@ -0,0 +1,254 @@
+//! Round-trip properties of the BC decoder: whatever the server sends, the
+//! decoded frames encode back to the same bytes, however it is split into
+//! reads. Decoded frames also read back the same from their JSON.
+
+use bcproxy_codec::{
+    ControlCode, Decoder, DecoderLimits, Frame, Versioned, ESC, FORMAT_VERSION, PROMPT_ATTR,
+};
+use proptest::prelude::*;
+
+fn decode(input: &[u8]) -> Vec<Frame> {
+    decode_with(Decoder::new(), input)
+}
+
+fn decode_with(decoder: Decoder, input: &[u8]) -> Vec<Frame> {
+    decode_reads(decoder, &[input])
+}
+
+fn decode_reads(mut decoder: Decoder, reads: &[&[u8]]) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    for read in reads {
+        decoder.decode(read, &mut frames);
+    }
+    decoder.finish(&mut frames);
+    frames
+}
+
+/// Split `input` before each of `at`.
+fn split<'a>(input: &'a [u8], at: &[prop::sample::Index]) -> Vec<&'a [u8]> {
+    let mut at: Vec<usize> = at.iter().map(|i| i.index(input.len() + 1)).collect();
+    at.sort_unstable();
+    let mut reads = Vec::new();
+    let mut start = 0;
+    for end in at {
+        reads.push(&input[start..end]);
+        start = end;
+    }
+    reads.push(&input[start..]);
+    reads
+}
+
+fn small_limits() -> Decoder {
+    Decoder::with_limits(DecoderLimits {
+        max_code_bytes: 16,
+        max_depth: 2,
+    })
+}
+
+fn encode(frames: &[Frame]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for frame in frames {
+        frame.encode(&mut out);
+    }
+    out
+}
+
+/// Bytes that are mostly escapes, tags and digits, so that well formed,
+/// truncated and mismatched codes all come up.
+fn noisy_bytes() -> impl Strategy<Value = Vec<u8>> {
+    let byte = prop_oneof![
+        4 => Just(ESC),
+        2 => Just(b'<'),
+        2 => Just(b'>'),
+        2 => Just(b'|'),
+        4 => b'0'..=b'9',
+        1 => any::<u8>(),
+    ];
+    proptest::collection::vec(byte, 0..256)
+}
+
+fn text() -> impl Strategy<Value = Vec<u8>> {
+    proptest::collection::vec(any::<u8>().prop_filter("no escapes", |&b| b != ESC), 1..16)
+}
+
+fn code(body: impl Strategy<Value = Vec<Frame>>) -> impl Strategy<Value = ControlCode> {
+    (
+        0u8..100,
+        proptest::option::of(proptest::collection::vec(
+            any::<u8>().prop_filter("no escapes", |&b| b != ESC),
+            0..12,
+        )),
+        body,
+    )
+        .prop_map(|(id, attr, body)| ControlCode::new(id, attr, body))
+}
+
+/// Frames as the decoder produces them: no empty or adjacent text frames.
+fn frames() -> impl Strategy<Value = Vec<Frame>> {
+    let leaf = text().prop_map(Frame::text);
+    let frame = leaf.prop_recursive(4, 64, 6, |inner| {
+        prop_oneof![
+            text().prop_map(Frame::text),
+            code(proptest::collection::vec(inner, 0..6).prop_map(merge_text)).prop_map(Frame::Code),
+        ]
+    });
+    proptest::collection::vec(frame, 0..8).prop_map(merge_text)
+}
+
+/// Join adjacent text frames. Text outside codes is passed on with each
+/// read, so the frames of different splits differ only in where it is cut.
+fn merge_text(frames: Vec<Frame>) -> Vec<Frame> {
+    let mut merged: Vec<Frame> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        match (merged.last_mut(), frame) {
+            (Some(Frame::Text(last)), Frame::Text(text)) => {
+                *last = [&last[..], &text[..]].concat().into()
+            }
+            (_, frame) => merged.push(frame),
+        }
+    }
+    merged
+}
+
+/// Top level prompts are decoded as [`Frame::Prompt`], login results as
+/// [`Frame::LoginResult`].
+fn mark_prompts(frames: Vec<Frame>) -> Vec<Frame> {
+    frames
+        .into_iter()
+        .map(|frame| match frame {
+            Frame::Code(code) if code.id == 10 && code.attr_is(PROMPT_ATTR) => Frame::Prompt(code),
+            Frame::Code(code) if code.id == 5 || code.id == 6 => Frame::LoginResult(code),
+            frame => frame,
+        })
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn any_input_encodes_back(input in noisy_bytes()) {
+        prop_assert_eq!(encode(&decode(&input)), input);
+    }
+
+    #[test]
+    fn codes_over_the_limits_encode_back(input in noisy_bytes()) {
+        prop_assert_eq!(encode(&decode_with(small_limits(), &input)), input);
+    }
+
+    #[test]
+    fn any_split_in_two_decodes_the_same(input in noisy_bytes()) {
+        let whole = merge_text(decode(&input));
+        let limited = merge_text(decode_with(small_limits(), &input));
+        for i in 0..=input.len() {
+            let reads = [&input[..i], &input[i..]];
+            prop_assert_eq!(&merge_text(decode_reads(Decoder::new(), &reads)), &whole);
+            prop_assert_eq!(&merge_text(decode_reads(small_limits(), &reads)), &limited);
+        }
+    }
+
+    #[test]
+    fn any_split_decodes_the_same(
+        input in noisy_bytes(),
+        at in proptest::collection::vec(any::<prop::sample::Index>(), 0..32),
+    ) {
+        let reads = split(&input, &at);
+        prop_assert_eq!(
+            merge_text(decode_reads(Decoder::new(), &reads)),
+            merge_text(decode(&input))
+        );
+        prop_assert_eq!(
+            merge_text(decode_reads(small_limits(), &reads)),
+            merge_text(decode_with(small_limits(), &input))
+        );
+    }
+
+    #[test]
+    fn codes_split_into_reads_survive_a_round_trip(
+        frames in frames(),
+        at in proptest::collection::vec(any::<prop::sample::Index>(), 0..32),
+    ) {
+        let expected = mark_prompts(frames);
+        let input = encode(&expected);
+        let reads = split(&input, &at);
+        prop_assert_eq!(merge_text(decode_reads(Decoder::new(), &reads)), expected);
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip(frames in frames()) {
+        let expected = mark_prompts(frames);
+        prop_assert_eq!(decode(&encode(&expected)), expected);
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip_through_json(frames in frames()) {
+        let frames = mark_prompts(frames);
+        let json = serde_json::to_string(&Versioned::new(frames.clone())).unwrap();
+        let read: Versioned<Vec<Frame>> = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(read.value, frames);
+    }
+}
+
+#[test]
+fn frames_are_written_as_tagged_json() {
+    let frames = decode(b"\x1b<10chan_sales\x1b|Bob: \xffsword\x1b>10hi\r\n");
+    let json = serde_json::to_value(Versioned::new(frames)).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "version": FORMAT_VERSION,
+            "value": [
+                {
+                    "type": "code",
+                    "frame": {
+                        "id": 10,
+                        "attr": "chan_sales",
+                        "body": [{ "type": "text", "frame": [66, 111, 98, 58, 32, 255, 115, 119, 111, 114, 100] }],
+                    },
+                },
+                { "type": "text", "frame": "hi\r\n" },
+            ],
+        })
+    );
+}
+
+#[test]
+fn other_format_versions_are_not_read() {
+    let json = r#"{"version":2,"value":[{"type":"text","frame":"hi"}]}"#;
+    let error = serde_json::from_str::<Versioned<Vec<Frame>>>(json).unwrap_err();
+    assert!(error.to_string().contains("unsupported format version 2"));
+}
+
+/// Decode `input` in one read, expecting nothing but text and a warning.
+fn decode_as_text(mut decoder: Decoder, input: &[u8]) -> Vec<u8> {
+    let mut frames = Vec::new();
+    decoder.decode(input, &mut frames);
+    decoder.finish(&mut frames);
+    assert!(!decoder.take_warnings().is_empty(), "no warning");
+    assert!(
+        frames.iter().all(|frame| matches!(frame, Frame::Text(_))),
+        "{:?}",
+        frames
+    );
+    encode(&frames)
+}
+
+#[test]
+fn codes_nested_too_deep_come_out_as_text() {
+    let input = b"\x1b<20\x1b<21\x1b<22deep\x1b>22\x1b>21\x1b>20";
+    assert_eq!(decode_as_text(small_limits(), input), input);
+}
+
+#[test]
+fn no_nesting_at_all_passes_every_code_as_text() {
+    let decoder = Decoder::with_limits(DecoderLimits {
+        max_code_bytes: 16,
+        max_depth: 0,
+    });
+    let input = b"\x1b<10chan_sales\x1b|hi\x1b>10";
+    assert_eq!(decode_as_text(decoder, input), input);
+}
+
+#[test]
+fn codes_too_long_come_out_as_text() {
+    let input = [&b"\x1b<20"[..], &[b'a'; 20], b"\x1b>20"].concat();
+    assert_eq!(decode_as_text(small_limits(), &input), input);
+}
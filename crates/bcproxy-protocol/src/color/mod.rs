@@ -0,0 +1,314 @@
+mod plain;
+mod render;
+mod true_color;
+mod xterm;
+
+use std::{fmt, str::FromStr};
+
+pub use self::{
+    plain::Plain,
+    render::render_codes,
+    xterm::{palette_rgb, rgb_to_16, rgb_to_256},
+};
+
+/// The colors a client can show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// 24-bit colors.
+    TrueColor,
+    /// The xterm 256 color palette.
+    #[default]
+    Xterm256,
+    /// The 16 basic ANSI colors.
+    Ansi16,
+    /// No colors at all.
+    None,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truecolor" => Ok(ColorMode::TrueColor),
+            "256" => Ok(ColorMode::Xterm256),
+            "16" => Ok(ColorMode::Ansi16),
+            "none" => Ok(ColorMode::None),
+            _ => Err(format!(
+                "invalid color mode `{}`, expected truecolor, 256, 16 or none",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorMode::TrueColor => "truecolor",
+            ColorMode::Xterm256 => "256",
+            ColorMode::Ansi16 => "16",
+            ColorMode::None => "none",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Rgb(u8, u8, u8),
+    /// An index into the xterm 256 color palette.
+    Xterm(u8),
+}
+
+/// Parses `ff8800`, `#ff8800` or an xterm palette index such as `208`.
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() == 6 {
+            if let Ok(rgb) = u32::from_str_radix(hex, 16) {
+                return Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+            }
+        }
+        s.parse()
+            .map(Color::Xterm)
+            .map_err(|_| format!("invalid color `{}`", s))
+    }
+}
+
+impl Color {
+    pub fn to_256(self) -> u8 {
+        match self {
+            Color::Rgb(r, g, b) => rgb_to_256(r, g, b),
+            Color::Xterm(n) => n,
+        }
+    }
+
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Rgb(r, g, b) => (r, g, b),
+            Color::Xterm(n) => palette_rgb(n),
+        }
+    }
+
+    /// SGR parameters selecting this color as foreground or background in
+    /// `mode`, `None` if the mode has no colors.
+    pub fn sgr(self, mode: ColorMode, background: bool) -> Option<String> {
+        match mode {
+            ColorMode::TrueColor => {
+                let (r, g, b) = self.to_rgb();
+                Some(true_color::sgr(r, g, b, background))
+            }
+            ColorMode::Xterm256 => Some(format!(
+                "{};5;{}",
+                if background { 48 } else { 38 },
+                self.to_256()
+            )),
+            ColorMode::Ansi16 => {
+                let (r, g, b) = self.to_rgb();
+                let n = rgb_to_16(r, g, b);
+                let base = match (background, n < 8) {
+                    (false, true) => 30,
+                    (false, false) => 90 - 8,
+                    (true, true) => 40,
+                    (true, false) => 100 - 8,
+                };
+                Some((base + n as u16).to_string())
+            }
+            ColorMode::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+}
+
+/// Parses space separated `fg=<color>`, `bg=<color>`, `bold` and `underline`.
+impl FromStr for Style {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut style = Style::default();
+        for word in s.split_whitespace() {
+            match word.split_once('=') {
+                Some(("fg", color)) => style.fg = Some(color.parse()?),
+                Some(("bg", color)) => style.bg = Some(color.parse()?),
+                None if word == "bold" => style.bold = true,
+                None if word == "underline" => style.underline = true,
+                _ => return Err(format!("invalid style `{}`", word)),
+            }
+        }
+        Ok(style)
+    }
+}
+
+impl Style {
+    /// SGR parameters turning this style on in `mode`, `None` if it sets
+    /// nothing there.
+    pub fn sgr(&self, mode: ColorMode) -> Option<String> {
+        let mut on = Vec::new();
+        if self.bold {
+            on.push("1".to_string());
+        }
+        if self.underline {
+            on.push("4".to_string());
+        }
+        on.extend(self.fg.and_then(|fg| fg.sgr(mode, false)));
+        on.extend(self.bg.and_then(|bg| bg.sgr(mode, true)));
+        (!on.is_empty()).then(|| on.join(";"))
+    }
+}
+
+/// Drop ANSI CSI sequences such as colors from `bytes`.
+pub fn strip_ansi(bytes: &[u8]) -> String {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0x1b && bytes.get(i + 1) == Some(&b'[') {
+            i += 2;
+            while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+                i += 1;
+            }
+            i += 1;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_hex_or_palette_indexes() {
+        assert_eq!("ff8800".parse::<Color>(), Ok(Color::Rgb(255, 136, 0)));
+        assert_eq!("#FF8800".parse::<Color>(), Ok(Color::Rgb(255, 136, 0)));
+        assert_eq!("208".parse::<Color>(), Ok(Color::Xterm(208)));
+        assert!("256".parse::<Color>().is_err());
+        assert!("#ff88".parse::<Color>().is_err());
+        assert_eq!(
+            "red".parse::<Color>(),
+            Err("invalid color `red`".to_string())
+        );
+    }
+
+    #[test]
+    fn styles_are_words() {
+        assert_eq!(
+            "bold fg=ff0000 bg=17".parse::<Style>(),
+            Ok(Style {
+                fg: Some(Color::Rgb(255, 0, 0)),
+                bg: Some(Color::Xterm(17)),
+                bold: true,
+                underline: false,
+            })
+        );
+        assert_eq!("".parse::<Style>(), Ok(Style::default()));
+        assert_eq!(
+            "fg=nope".parse::<Style>(),
+            Err("invalid color `nope`".to_string())
+        );
+        assert_eq!(
+            "italic".parse::<Style>(),
+            Err("invalid style `italic`".to_string())
+        );
+    }
+
+    #[test]
+    fn rgb_colors_take_the_nearest_palette_color() {
+        assert_eq!(rgb_to_256(0, 0, 0), 16);
+        assert_eq!(rgb_to_256(255, 0, 0), 196);
+        assert_eq!(rgb_to_256(250, 130, 10), 208);
+        // Grays are closer on the grayscale ramp than in the cube.
+        assert_eq!(rgb_to_256(128, 128, 128), 244);
+        assert_eq!(Color::Rgb(255, 255, 255).to_256(), 231);
+        assert_eq!(Color::Xterm(3).to_256(), 3);
+    }
+
+    #[test]
+    fn palette_colors_map_back_to_themselves() {
+        assert_eq!(palette_rgb(1), (205, 0, 0));
+        assert_eq!(palette_rgb(208), (255, 135, 0));
+        assert_eq!(palette_rgb(255), (238, 238, 238));
+        for n in 16..=255 {
+            let (r, g, b) = palette_rgb(n);
+            assert_eq!(rgb_to_256(r, g, b), n);
+        }
+    }
+
+    #[test]
+    fn rgb_colors_take_the_nearest_basic_color() {
+        assert_eq!(rgb_to_16(0, 0, 0), 0);
+        assert_eq!(rgb_to_16(200, 10, 0), 1);
+        assert_eq!(rgb_to_16(255, 0, 0), 9);
+        assert_eq!(rgb_to_16(250, 250, 250), 15);
+        assert_eq!(Color::Xterm(9).to_rgb(), (255, 0, 0));
+    }
+
+    #[test]
+    fn color_modes_are_read_as_shown() {
+        for mode in [
+            ColorMode::TrueColor,
+            ColorMode::Xterm256,
+            ColorMode::Ansi16,
+            ColorMode::None,
+        ] {
+            assert_eq!(mode.to_string().parse::<ColorMode>(), Ok(mode));
+        }
+        assert!("8".parse::<ColorMode>().is_err());
+    }
+
+    #[test]
+    fn colors_are_selected_for_each_mode() {
+        let orange = Color::Rgb(255, 135, 0);
+        assert_eq!(
+            orange.sgr(ColorMode::TrueColor, false).as_deref(),
+            Some("38;2;255;135;0")
+        );
+        assert_eq!(
+            orange.sgr(ColorMode::Xterm256, true).as_deref(),
+            Some("48;5;208")
+        );
+        assert_eq!(
+            Color::Xterm(208).sgr(ColorMode::TrueColor, true).as_deref(),
+            Some("48;2;255;135;0")
+        );
+        assert_eq!(orange.sgr(ColorMode::None, false), None);
+
+        // Bright basic colors start from 90 and 100.
+        let red = Color::Rgb(205, 0, 0);
+        let bright_red = Color::Rgb(255, 0, 0);
+        assert_eq!(red.sgr(ColorMode::Ansi16, false).as_deref(), Some("31"));
+        assert_eq!(red.sgr(ColorMode::Ansi16, true).as_deref(), Some("41"));
+        assert_eq!(
+            bright_red.sgr(ColorMode::Ansi16, false).as_deref(),
+            Some("91")
+        );
+        assert_eq!(
+            bright_red.sgr(ColorMode::Ansi16, true).as_deref(),
+            Some("101")
+        );
+    }
+
+    #[test]
+    fn styles_turn_on_what_the_mode_can_show() {
+        let style: Style = "underline bold fg=ff0000 bg=0".parse().unwrap();
+        assert_eq!(
+            style.sgr(ColorMode::Xterm256).as_deref(),
+            Some("1;4;38;5;196;48;5;0")
+        );
+        assert_eq!(style.sgr(ColorMode::None).as_deref(), Some("1;4"));
+
+        let colors: Style = "fg=ff0000".parse().unwrap();
+        assert_eq!(colors.sgr(ColorMode::None), None);
+    }
+}
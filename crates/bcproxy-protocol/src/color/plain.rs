@@ -0,0 +1,159 @@
+use bcproxy_codec::ESC;
+
+const BOLD_MARKER: &[u8] = b"*";
+const UNDERLINE_MARKER: &[u8] = b"_";
+
+/// Turns ANSI output into plain text for braille displays and other tools.
+///
+/// CSI sequences are dropped. Bold and reverse video become `*text*` and
+/// underline `_text_`; colors leave no trace. Sequences split between two
+/// calls are held back until the rest arrives.
+#[derive(Debug, Default)]
+pub struct Plain {
+    held: Vec<u8>,
+    bold: bool,
+    underline: bool,
+}
+
+impl Plain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn convert(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut input_buf;
+        let input = if self.held.is_empty() {
+            input
+        } else {
+            input_buf = std::mem::take(&mut self.held);
+            input_buf.extend_from_slice(input);
+            &input_buf[..]
+        };
+
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != ESC {
+                out.push(input[i]);
+                i += 1;
+                continue;
+            }
+            match input.get(i + 1) {
+                None => {
+                    self.held.push(ESC);
+                    return;
+                }
+                Some(b'[') => {}
+                Some(_) => {
+                    out.push(ESC);
+                    i += 1;
+                    continue;
+                }
+            }
+
+            let params_start = i + 2;
+            let end = match input[params_start..]
+                .iter()
+                .position(|b| (0x40..=0x7e).contains(b))
+            {
+                Some(n) => params_start + n,
+                None => {
+                    self.held.extend_from_slice(&input[i..]);
+                    return;
+                }
+            };
+            if input[end] == b'm' {
+                self.sgr(&input[params_start..end], out);
+            }
+            i = end + 1;
+        }
+    }
+
+    fn sgr(&mut self, params: &[u8], out: &mut Vec<u8>) {
+        let mut params = params
+            .split(|&b| b == b';')
+            .map(|p| std::str::from_utf8(p).ok().and_then(|p| p.parse().ok()));
+        while let Some(param) = params.next() {
+            match param.unwrap_or(0u16) {
+                0 => {
+                    self.set_underline(false, out);
+                    self.set_bold(false, out);
+                }
+                1 | 7 => self.set_bold(true, out),
+                22 | 27 => self.set_bold(false, out),
+                4 => self.set_underline(true, out),
+                24 => self.set_underline(false, out),
+                38 | 48 => {
+                    // Skip the color's own parameters.
+                    let skip = match params.next().flatten() {
+                        Some(5) => 1,
+                        Some(2) => 3,
+                        _ => 0,
+                    };
+                    for _ in 0..skip {
+                        params.next();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn set_bold(&mut self, bold: bool, out: &mut Vec<u8>) {
+        if self.bold != bold {
+            self.bold = bold;
+            out.extend_from_slice(BOLD_MARKER);
+        }
+    }
+
+    fn set_underline(&mut self, underline: bool, out: &mut Vec<u8>) {
+        if self.underline != underline {
+            self.underline = underline;
+            out.extend_from_slice(UNDERLINE_MARKER);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(reads: &[&[u8]]) -> String {
+        let mut plain = Plain::new();
+        let mut out = Vec::new();
+        for read in reads {
+            plain.convert(read, &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn colors_leave_no_trace() {
+        assert_eq!(
+            plain(&[b"\x1b[38;5;196mred\x1b[0m \x1b[38;2;1;2;3mrgb\x1b[39m"]),
+            "red rgb"
+        );
+        assert_eq!(plain(&[b"\x1b[2Jclear"]), "clear");
+    }
+
+    #[test]
+    fn bold_and_underline_are_marked() {
+        assert_eq!(
+            plain(&[b"\x1b[1mbig\x1b[0m and \x1b[4munder\x1b[24m"]),
+            "*big* and _under_"
+        );
+        assert_eq!(plain(&[b"\x1b[1;4mboth\x1b[0m"]), "*_both_*");
+        assert_eq!(plain(&[b"\x1b[7mx\x1b[27m"]), "*x*");
+        // Turning on what is already on adds no marker.
+        assert_eq!(plain(&[b"\x1b[1ma\x1b[1mb\x1b[22m"]), "*ab*");
+    }
+
+    #[test]
+    fn sequences_split_between_reads_are_held_back() {
+        assert_eq!(plain(&[b"a\x1b", b"[1", b"mb\x1b[0m"]), "a*b*");
+    }
+
+    #[test]
+    fn other_escapes_pass() {
+        assert_eq!(plain(&[b"x\x1b(By"]), "x\x1b(By");
+    }
+}
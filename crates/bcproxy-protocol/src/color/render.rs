@@ -0,0 +1,155 @@
+use bcproxy_codec::{ControlCode, Frame};
+
+use super::{Color, ColorMode};
+
+/// BC codes setting the foreground and background color of their body, with
+/// the color as `RRGGBB` in the attribute.
+const FOREGROUND: u8 = 20;
+const BACKGROUND: u8 = 21;
+
+/// Replace the BC color codes in `frame` with SGR sequences for `mode`,
+/// writing the result to `out`. Nested colors are restored when an inner
+/// code ends; in [`ColorMode::None`] the codes are dropped for their body.
+pub fn render_codes(frame: Frame, mode: ColorMode, out: &mut Vec<Frame>) {
+    render(frame, mode, &mut Vec::new(), &mut Vec::new(), out);
+}
+
+fn render(
+    frame: Frame,
+    mode: ColorMode,
+    fg: &mut Vec<String>,
+    bg: &mut Vec<String>,
+    out: &mut Vec<Frame>,
+) {
+    if let Frame::Map(mut map) = frame {
+        let mut body = Vec::with_capacity(map.code.body.len());
+        for frame in std::mem::take(&mut map.code.body) {
+            render(frame, mode, fg, bg, &mut body);
+        }
+        map.code.body = body;
+        return out.push(Frame::Map(map));
+    }
+    // Rendered codes keep the kind of frame they were.
+    let (code, kind): (_, fn(ControlCode) -> Frame) = match frame {
+        Frame::Code(code) => (code, Frame::Code),
+        Frame::Prompt(code) => (code, Frame::Prompt),
+        Frame::LoginResult(code) => (code, Frame::LoginResult),
+        text => return out.push(text),
+    };
+
+    let color = match code.id {
+        FOREGROUND | BACKGROUND => code
+            .attr
+            .as_deref()
+            .and_then(|attr| std::str::from_utf8(attr).ok())
+            .and_then(|attr| attr.parse::<Color>().ok()),
+        _ => None,
+    };
+    let color = match color {
+        Some(color) => color,
+        None => {
+            let mut body = Vec::with_capacity(code.body.len());
+            for frame in code.body {
+                render(frame, mode, fg, bg, &mut body);
+            }
+            return out.push(kind(ControlCode::new(code.id, code.attr, body)));
+        }
+    };
+
+    let background = code.id == BACKGROUND;
+    let sgr = match color.sgr(mode, background) {
+        Some(sgr) => sgr,
+        None => {
+            for frame in code.body {
+                render(frame, mode, fg, bg, out);
+            }
+            return;
+        }
+    };
+
+    out.push(Frame::text(format!("\x1b[{}m", sgr).into_bytes()));
+    let stack = if background { &mut *bg } else { &mut *fg };
+    stack.push(sgr);
+    for frame in code.body {
+        render(frame, mode, fg, bg, out);
+    }
+    let stack = if background { &mut *bg } else { &mut *fg };
+    stack.pop();
+    let restore = match stack.last() {
+        Some(outer) => outer.as_str(),
+        None if background => "49",
+        None => "39",
+    };
+    out.push(Frame::text(format!("\x1b[{}m", restore).into_bytes()));
+}
+
+#[cfg(test)]
+mod tests {
+    use bcproxy_codec::Decoder;
+
+    use super::*;
+
+    fn render(bytes: &[u8], mode: ColorMode) -> Vec<u8> {
+        let mut frames = Vec::new();
+        Decoder::new().decode(bytes, &mut frames);
+        let mut rendered = Vec::new();
+        for frame in frames {
+            render_codes(frame, mode, &mut rendered);
+        }
+        let mut out = Vec::new();
+        for frame in &rendered {
+            frame.encode(&mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn color_codes_become_sgr_sequences() {
+        assert_eq!(
+            render(b"a \x1b<20ff0000\x1b|red\x1b>20 b", ColorMode::Xterm256),
+            b"a \x1b[38;5;196mred\x1b[39m b"
+        );
+        assert_eq!(
+            render(b"\x1b<2100ff00\x1b|green\x1b>21", ColorMode::TrueColor),
+            b"\x1b[48;2;0;255;0mgreen\x1b[49m"
+        );
+    }
+
+    #[test]
+    fn inner_colors_give_way_to_the_outer_ones() {
+        assert_eq!(
+            render(
+                b"\x1b<20ff0000\x1b|a\x1b<2100ff00\x1b|b\x1b<200000ff\x1b|c\x1b>20d\x1b>21e\x1b>20",
+                ColorMode::Xterm256
+            ),
+            b"\x1b[38;5;196ma\x1b[48;5;46mb\x1b[38;5;21mc\x1b[38;5;196md\x1b[49me\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn colors_are_dropped_without_colors() {
+        assert_eq!(
+            render(
+                b"\x1b<20ff0000\x1b|a\x1b<21000000\x1b|b\x1b>21\x1b>20",
+                ColorMode::None
+            ),
+            b"ab"
+        );
+    }
+
+    #[test]
+    fn other_codes_are_kept_around_their_rendered_body() {
+        assert_eq!(
+            render(
+                b"\x1b<10chan_sales\x1b|\x1b<20ff0000\x1b|hi\x1b>20\x1b>10",
+                ColorMode::TrueColor
+            ),
+            b"\x1b<10chan_sales\x1b|\x1b[38;2;255;0;0mhi\x1b[39m\x1b>10"
+        );
+        // Colors that cannot be read are left for the client.
+        assert_eq!(
+            render(b"\x1b<20nope\x1b|x\x1b>20", ColorMode::Xterm256),
+            b"\x1b<20nope\x1b|x\x1b>20"
+        );
+    }
+}
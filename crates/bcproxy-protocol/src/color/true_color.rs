@@ -0,0 +1,4 @@
+/// SGR parameters for a 24-bit color, `38;2;r;g;b` or `48;2;r;g;b`.
+pub fn sgr(r: u8, g: u8, b: u8, background: bool) -> String {
+    format!("{};2;{};{};{}", if background { 48 } else { 38 }, r, g, b)
+}
@@ -0,0 +1,90 @@
+/// Levels of each channel in the 6x6x6 color cube at indexes 16..=231.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The 16 basic colors as xterm shows them by default.
+pub(super) const BASIC: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// The xterm 256 color palette index closest to an RGB color, taken from
+/// either the color cube or the grayscale ramp at 232..=255.
+pub fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let (ri, rl) = nearest_level(r);
+    let (gi, gl) = nearest_level(g);
+    let (bi, bl) = nearest_level(b);
+    let cube = 16 + 36 * ri + 6 * gi + bi;
+    let cube_distance = distance((r, g, b), (rl, gl, bl));
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = if average < 8 {
+        0
+    } else {
+        ((average - 8) / 10).min(23) as u8
+    };
+    let gray_level = 8 + 10 * gray_index;
+    let gray_distance = distance((r, g, b), (gray_level, gray_level, gray_level));
+
+    if gray_distance < cube_distance {
+        232 + gray_index
+    } else {
+        cube
+    }
+}
+
+/// The index of the basic color closest to an RGB color, 0..=15.
+pub fn rgb_to_16(r: u8, g: u8, b: u8) -> u8 {
+    BASIC
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, &basic)| distance((r, g, b), basic))
+        .map(|(i, _)| i as u8)
+        .unwrap()
+}
+
+/// The RGB value of an xterm 256 color palette index.
+pub fn palette_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => BASIC[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            )
+        }
+        232..=255 => {
+            let level = 8 + 10 * (n - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn nearest_level(value: u8) -> (u8, u8) {
+    let (i, level) = CUBE_LEVELS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, &level)| (level as i16 - value as i16).abs())
+        .unwrap();
+    (i as u8, *level)
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
@@ -0,0 +1,6 @@
+//! What the proxy does to BC output and the telnet stream around it that
+//! needs no I/O: colors, telnet options and wrapping.
+
+pub mod color;
+pub mod telnet;
+pub mod wrap;
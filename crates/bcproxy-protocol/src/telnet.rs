@@ -0,0 +1,511 @@
+use crate::color::ColorMode;
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const GA: u8 = 249;
+pub const SE: u8 = 240;
+
+pub const ECHO: u8 = 1;
+pub const TTYPE: u8 = 24;
+pub const NAWS: u8 = 31;
+pub const NEW_ENVIRON: u8 = 39;
+/// Not a registered option: a bcproxy agrees to it to tell a proxy
+/// connecting to it that its output is already transformed.
+pub const BCPROXY: u8 = 188;
+
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+
+// NEW-ENVIRON, see RFC 1572.
+const ENVIRON_IS: u8 = 0;
+const ENVIRON_SEND: u8 = 1;
+const ENVIRON_INFO: u8 = 2;
+const ENVIRON_VAR: u8 = 0;
+const ENVIRON_VALUE: u8 = 1;
+const ENVIRON_ESC: u8 = 2;
+const ENVIRON_USERVAR: u8 = 3;
+
+// MTTS bits sent as the third terminal type, see
+// https://tintin.mudhalla.net/protocols/mtts/
+const MTTS_ANSI: u32 = 1;
+const MTTS_UTF8: u32 = 4;
+const MTTS_256_COLORS: u32 = 8;
+const MTTS_TRUECOLOR: u32 = 256;
+
+/// A telnet command read from a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Will(u8),
+    Wont(u8),
+    Do(u8),
+    Dont(u8),
+    /// Subnegotiation of an option, with `IAC IAC` in the data unescaped.
+    Sub(u8, Vec<u8>),
+    Other(u8),
+}
+
+/// Part of a stream: data as sent, with `IAC IAC` escapes left in, or a
+/// telnet command along with its raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Data(Vec<u8>),
+    Command(Command, Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+/// Streaming parser separating telnet commands from data.
+#[derive(Debug, Default)]
+pub struct Parser {
+    state: State,
+    // Raw bytes of the command being read.
+    raw: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(&mut self, input: &[u8], segments: &mut Vec<Segment>) {
+        let mut data = Vec::new();
+        for &b in input {
+            match self.state {
+                State::Data if b == IAC => {
+                    self.state = State::Iac;
+                    self.raw.push(b);
+                }
+                State::Data => data.push(b),
+                State::Iac => {
+                    self.raw.push(b);
+                    self.state = State::Data;
+                    let command = match b {
+                        IAC => {
+                            // An escaped 255 data byte, kept escaped.
+                            data.append(&mut self.raw);
+                            continue;
+                        }
+                        WILL | WONT | DO | DONT => {
+                            self.state = State::Option(b);
+                            continue;
+                        }
+                        SB => {
+                            self.state = State::Sub;
+                            continue;
+                        }
+                        b => Command::Other(b),
+                    };
+                    self.emit(command, &mut data, segments);
+                }
+                State::Option(verb) => {
+                    self.raw.push(b);
+                    self.state = State::Data;
+                    let command = match verb {
+                        WILL => Command::Will(b),
+                        WONT => Command::Wont(b),
+                        DO => Command::Do(b),
+                        _ => Command::Dont(b),
+                    };
+                    self.emit(command, &mut data, segments);
+                }
+                State::Sub => {
+                    self.raw.push(b);
+                    if b == IAC {
+                        self.state = State::SubIac;
+                    }
+                }
+                State::SubIac if b == SE => {
+                    self.raw.push(b);
+                    self.state = State::Data;
+                    let command = sub_command(&self.raw);
+                    self.emit(command, &mut data, segments);
+                }
+                State::SubIac => {
+                    self.raw.push(b);
+                    self.state = State::Sub;
+                }
+            }
+        }
+        if !data.is_empty() {
+            segments.push(Segment::Data(data));
+        }
+    }
+
+    /// The raw bytes of a command cut off by the end of the stream, to be
+    /// passed on as received.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.state = State::Data;
+        std::mem::take(&mut self.raw)
+    }
+
+    fn emit(&mut self, command: Command, data: &mut Vec<u8>, segments: &mut Vec<Segment>) {
+        if !data.is_empty() {
+            segments.push(Segment::Data(std::mem::take(data)));
+        }
+        segments.push(Segment::Command(command, std::mem::take(&mut self.raw)));
+    }
+}
+
+/// Turn the raw bytes `IAC SB <option> <data> IAC SE` into a command.
+fn sub_command(raw: &[u8]) -> Command {
+    let option = raw.get(2).copied().unwrap_or_default();
+    let body = raw.get(3..raw.len() - 2).unwrap_or_default();
+    let mut data = Vec::with_capacity(body.len());
+    let mut escaped = false;
+    for &b in body {
+        if b == IAC && !escaped {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        data.push(b);
+    }
+    Command::Sub(option, data)
+}
+
+/// What the client told about its terminal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Terminal types the client reported, in order.
+    pub terminal_types: Vec<String>,
+    /// The colors the client reported, if it did.
+    pub color_mode: Option<ColorMode>,
+    pub utf8: bool,
+}
+
+/// The requests sent to a client when it connects, for its window size and
+/// terminal type.
+pub fn client_negotiation() -> Vec<u8> {
+    vec![IAC, DO, NAWS, IAC, DO, TTYPE]
+}
+
+/// The window size as sent with NAWS, see RFC 1073.
+pub fn naws(width: u16, height: u16) -> Vec<u8> {
+    let mut out = vec![IAC, SB, NAWS];
+    for b in [width.to_be_bytes(), height.to_be_bytes()].concat() {
+        out.push(b);
+        if b == IAC {
+            out.push(IAC);
+        }
+    }
+    out.extend_from_slice(&[IAC, SE]);
+    out
+}
+
+impl ClientInfo {
+    /// The window size the client reported, width first.
+    pub fn window(&self) -> Option<(u16, u16)> {
+        self.width.zip(self.height)
+    }
+
+    /// Record what a command from the client tells about it. Returns a reply
+    /// if more is to be asked.
+    pub fn observe(&mut self, command: &Command) -> Option<Vec<u8>> {
+        match command {
+            Command::Will(TTYPE) => Some(ttype_send()),
+            Command::Sub(NAWS, data) if data.len() == 4 => {
+                self.width = Some(u16::from_be_bytes([data[0], data[1]]));
+                self.height = Some(u16::from_be_bytes([data[2], data[3]]));
+                None
+            }
+            Command::Sub(TTYPE, data) if data.first() == Some(&TTYPE_IS) => {
+                let name = String::from_utf8_lossy(&data[1..]).into_owned();
+                // Clients cycle through their types and repeat the last one
+                // once they run out. MTTS clients send three.
+                if self.terminal_types.last() == Some(&name) {
+                    return None;
+                }
+                self.read_terminal_type(&name);
+                self.terminal_types.push(name);
+                (self.terminal_types.len() < 3).then(ttype_send)
+            }
+            _ => None,
+        }
+    }
+
+    fn read_terminal_type(&mut self, name: &str) {
+        let upper = name.to_ascii_uppercase();
+        if let Some(bits) = upper
+            .strip_prefix("MTTS ")
+            .and_then(|bits| bits.trim().parse::<u32>().ok())
+        {
+            self.utf8 = bits & MTTS_UTF8 != 0;
+            self.color_mode = Some(if bits & MTTS_TRUECOLOR != 0 {
+                ColorMode::TrueColor
+            } else if bits & MTTS_256_COLORS != 0 {
+                ColorMode::Xterm256
+            } else if bits & MTTS_ANSI != 0 {
+                ColorMode::Ansi16
+            } else {
+                ColorMode::None
+            });
+            return;
+        }
+
+        if upper.contains("UTF-8") {
+            self.utf8 = true;
+        }
+        if self.color_mode.is_none() {
+            if upper.contains("TRUECOLOR") || upper.contains("24BIT") {
+                self.color_mode = Some(ColorMode::TrueColor);
+            } else if upper.contains("256COLOR") {
+                self.color_mode = Some(ColorMode::Xterm256);
+            }
+        }
+    }
+}
+
+fn ttype_send() -> Vec<u8> {
+    vec![IAC, SB, TTYPE, TTYPE_SEND, IAC, SE]
+}
+
+/// Ask a client that agreed to NEW-ENVIRON for the user variable `name`.
+pub fn environ_send(name: &str) -> Vec<u8> {
+    let mut request = vec![IAC, SB, NEW_ENVIRON, ENVIRON_SEND, ENVIRON_USERVAR];
+    request.extend_from_slice(name.as_bytes());
+    request.extend_from_slice(&[IAC, SE]);
+    request
+}
+
+/// The value of the variable `name` in the data of a NEW-ENVIRON `IS` or
+/// `INFO` subnegotiation, `None` if it is not there or has no value.
+pub fn environ_value(data: &[u8], name: &str) -> Option<Vec<u8>> {
+    let (&kind, mut rest) = data.split_first()?;
+    if kind != ENVIRON_IS && kind != ENVIRON_INFO {
+        return None;
+    }
+    while let Some((&kind, tail)) = rest.split_first() {
+        rest = tail;
+        if kind != ENVIRON_VAR && kind != ENVIRON_USERVAR {
+            continue;
+        }
+        let var = environ_field(&mut rest);
+        let value = match rest.split_first() {
+            Some((&ENVIRON_VALUE, tail)) => {
+                rest = tail;
+                Some(environ_field(&mut rest))
+            }
+            _ => None,
+        };
+        if var == name.as_bytes() {
+            return value;
+        }
+    }
+    None
+}
+
+/// A variable name or value, up to the next unescaped type byte.
+fn environ_field(rest: &mut &[u8]) -> Vec<u8> {
+    let mut field = Vec::new();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            ENVIRON_VAR | ENVIRON_VALUE | ENVIRON_USERVAR => break,
+            ENVIRON_ESC => {
+                field.extend(tail.first());
+                *rest = tail.get(1..).unwrap_or_default();
+            }
+            b => {
+                field.push(b);
+                *rest = tail;
+            }
+        }
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(reads: &[&[u8]]) -> Vec<Segment> {
+        let mut parser = Parser::new();
+        let mut segments = Vec::new();
+        for read in reads {
+            parser.parse(read, &mut segments);
+        }
+        segments
+    }
+
+    fn ttype(name: &str) -> Command {
+        let mut data = vec![TTYPE_IS];
+        data.extend_from_slice(name.as_bytes());
+        Command::Sub(TTYPE, data)
+    }
+
+    #[test]
+    fn commands_are_split_from_data() {
+        assert_eq!(
+            parse(&[&[b'a', IAC, WILL, NAWS, b'b', IAC, GA]]),
+            [
+                Segment::Data(b"a".to_vec()),
+                Segment::Command(Command::Will(NAWS), vec![IAC, WILL, NAWS]),
+                Segment::Data(b"b".to_vec()),
+                Segment::Command(Command::Other(GA), vec![IAC, GA]),
+            ]
+        );
+        // Escaped data bytes stay escaped.
+        assert_eq!(
+            parse(&[&[b'a', IAC, IAC, b'b']]),
+            [Segment::Data(vec![b'a', IAC, IAC, b'b'])]
+        );
+    }
+
+    #[test]
+    fn subnegotiations_are_unescaped() {
+        let raw = [IAC, SB, NAWS, 0, 80, 0, IAC, IAC, IAC, SE];
+        assert_eq!(
+            parse(&[&raw]),
+            [Segment::Command(
+                Command::Sub(NAWS, vec![0, 80, 0, IAC]),
+                raw.to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn commands_may_be_split_between_reads() {
+        assert_eq!(
+            parse(&[
+                &[b'x', IAC],
+                &[SB, TTYPE, TTYPE_IS, b'a'],
+                &[IAC],
+                &[SE, b'y']
+            ]),
+            [
+                Segment::Data(b"x".to_vec()),
+                Segment::Command(
+                    Command::Sub(TTYPE, vec![TTYPE_IS, b'a']),
+                    vec![IAC, SB, TTYPE, TTYPE_IS, b'a', IAC, SE]
+                ),
+                Segment::Data(b"y".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_command_cut_off_is_given_back_raw() {
+        let mut parser = Parser::new();
+        let mut segments = Vec::new();
+        parser.parse(&[b'a', IAC, DO], &mut segments);
+        assert_eq!(segments, [Segment::Data(b"a".to_vec())]);
+        assert_eq!(parser.finish(), [IAC, DO]);
+
+        parser.parse(b"b", &mut segments);
+        assert_eq!(segments[1], Segment::Data(b"b".to_vec()));
+    }
+
+    #[test]
+    fn the_window_size_is_read_from_naws() {
+        let mut info = ClientInfo::default();
+        assert_eq!(info.window(), None);
+        assert_eq!(info.observe(&Command::Sub(NAWS, vec![0, 120, 1, 0])), None);
+        assert_eq!(info.window(), Some((120, 256)));
+
+        // Sizes of the wrong length are ignored.
+        info.observe(&Command::Sub(NAWS, vec![0, 80]));
+        assert_eq!(info.window(), Some((120, 256)));
+    }
+
+    #[test]
+    fn terminal_types_are_asked_for_until_they_repeat() {
+        let mut info = ClientInfo::default();
+        assert_eq!(info.observe(&Command::Will(TTYPE)), Some(ttype_send()));
+        assert_eq!(info.observe(&ttype("xterm-256color")), Some(ttype_send()));
+        assert_eq!(info.observe(&ttype("xterm-256color")), None);
+        assert_eq!(info.terminal_types, ["xterm-256color"]);
+        assert_eq!(info.color_mode, Some(ColorMode::Xterm256));
+        assert!(!info.utf8);
+    }
+
+    #[test]
+    fn mtts_decides_over_the_names() {
+        let mut info = ClientInfo::default();
+        info.observe(&ttype("MUDLET"));
+        info.observe(&ttype("XTERM-256COLOR"));
+        assert_eq!(info.observe(&ttype("MTTS 261")), None);
+        assert_eq!(
+            info.terminal_types,
+            ["MUDLET", "XTERM-256COLOR", "MTTS 261"]
+        );
+        assert_eq!(info.color_mode, Some(ColorMode::TrueColor));
+        assert!(info.utf8);
+
+        let mut info = ClientInfo::default();
+        info.observe(&ttype("xterm-truecolor"));
+        info.observe(&ttype("MTTS 1"));
+        assert_eq!(info.color_mode, Some(ColorMode::Ansi16));
+        assert!(!info.utf8);
+    }
+
+    #[test]
+    fn environ_values_are_read_by_name() {
+        let data = [
+            &[ENVIRON_IS, ENVIRON_VAR][..],
+            b"USER",
+            &[ENVIRON_VALUE],
+            b"bob",
+            &[ENVIRON_USERVAR],
+            b"PASS",
+            &[ENVIRON_VALUE, b'a', ENVIRON_ESC, ENVIRON_VALUE, b'b'],
+            &[ENVIRON_USERVAR],
+            b"EMPTY",
+        ]
+        .concat();
+        assert_eq!(environ_value(&data, "USER"), Some(b"bob".to_vec()));
+        assert_eq!(
+            environ_value(&data, "PASS"),
+            Some(vec![b'a', ENVIRON_VALUE, b'b'])
+        );
+        assert_eq!(environ_value(&data, "EMPTY"), None);
+        assert_eq!(environ_value(&data, "HOME"), None);
+
+        let mut info = data.clone();
+        info[0] = ENVIRON_INFO;
+        assert_eq!(environ_value(&info, "USER"), Some(b"bob".to_vec()));
+        let mut send = data;
+        send[0] = ENVIRON_SEND;
+        assert_eq!(environ_value(&send, "USER"), None);
+    }
+
+    #[test]
+    fn environ_requests_ask_for_a_user_variable() {
+        assert_eq!(
+            environ_send("PASS"),
+            [
+                &[IAC, SB, NEW_ENVIRON, ENVIRON_SEND, ENVIRON_USERVAR][..],
+                b"PASS",
+                &[IAC, SE]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn window_sizes_are_sent_escaped() {
+        let sent = naws(80, 255);
+        assert_eq!(sent, [IAC, SB, NAWS, 0, 80, 0, IAC, IAC, IAC, SE]);
+
+        let mut info = ClientInfo::default();
+        for segment in parse(&[&sent]) {
+            let Segment::Command(command, _) = segment else {
+                panic!("{:?}", segment);
+            };
+            info.observe(&command);
+        }
+        assert_eq!(info.window(), Some((80, 255)));
+    }
+}
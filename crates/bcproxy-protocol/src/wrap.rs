@@ -0,0 +1,325 @@
+use unicode_width::UnicodeWidthChar;
+
+use bcproxy_codec::{ControlCode, Frame, ESC};
+
+const TAB_STOP: usize = 8;
+
+/// Wraps server output at the client's terminal width.
+///
+/// Lines are broken between words where possible and inside a word only if
+/// it does not fit on a line by itself, never inside a UTF-8 character or an
+/// ANSI sequence. The column is kept across frames, so a line may be split
+/// over several of them, and so is an escape sequence cut off by the end of
+/// a frame.
+#[derive(Debug, Default)]
+pub struct Wrapper {
+    col: usize,
+    escape: Option<Escape>,
+}
+
+impl Wrapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wrap(&mut self, frame: &mut Frame, width: usize) {
+        match frame {
+            Frame::Text(text) => *text = self.wrap_text(text, width).into(),
+            Frame::Code(code) if is_wrapped(code) => {
+                for child in &mut code.body {
+                    self.wrap(child, width);
+                }
+            }
+            Frame::Code(_) | Frame::Prompt(_) | Frame::LoginResult(_) | Frame::Map(_) => {}
+        }
+    }
+
+    fn wrap_text(&mut self, text: &[u8], width: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(text.len() + text.len() / width.max(1) * 2);
+        let mut i = 0;
+        if let Some(escape) = self.escape.take() {
+            let (end, rest) = skip_escape(text, 0, escape);
+            out.extend_from_slice(&text[..end]);
+            self.escape = rest;
+            i = end;
+        }
+        while i < text.len() {
+            match text[i] {
+                ESC => {
+                    let (end, rest) = skip_escape(text, i + 1, Escape::Start);
+                    out.extend_from_slice(&text[i..end]);
+                    self.escape = rest;
+                    i = end;
+                }
+                b @ (b'\r' | b'\n') => {
+                    out.push(b);
+                    self.col = 0;
+                    i += 1;
+                }
+                b'\t' => {
+                    out.push(b'\t');
+                    self.col = (self.col / TAB_STOP + 1) * TAB_STOP;
+                    i += 1;
+                }
+                b' ' => {
+                    if self.col >= width {
+                        self.break_line(&mut out);
+                    } else {
+                        out.push(b' ');
+                        self.col += 1;
+                    }
+                    i += 1;
+                }
+                b if b < 0x20 || b == 0x7f => {
+                    out.push(b);
+                    i += 1;
+                }
+                b => {
+                    let end = text[i..]
+                        .iter()
+                        .position(|&b| b <= b' ' || b == 0x7f || b == ESC)
+                        .map_or(text.len(), |n| i + n);
+                    let word = &text[i..end];
+                    let word_width: usize = chars(word).map(|(_, w)| w).sum();
+                    if self.col > 0
+                        && self.col + word_width > width
+                        && word_width <= width
+                        && !is_continuation(b)
+                    {
+                        self.break_line(&mut out);
+                    }
+                    for (c, w) in chars(word) {
+                        if self.col > 0 && self.col + w > width {
+                            self.break_line(&mut out);
+                        }
+                        out.extend_from_slice(c);
+                        self.col += w;
+                    }
+                    i = end;
+                }
+            }
+        }
+        out
+    }
+
+    fn break_line(&mut self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"\r\n");
+        self.col = 0;
+    }
+}
+
+/// Codes whose body is shown as text: messages other than maps and
+/// prompts, and colors.
+fn is_wrapped(code: &ControlCode) -> bool {
+    match code.id {
+        10 => !code.attr_is(b"spec_map"),
+        20 | 21 => true,
+        _ => false,
+    }
+}
+
+/// The start of `text` that fits in `cols` columns, which are taken from
+/// it. ANSI sequences take no columns and are kept past the cut, so colors
+/// set before it are still reset.
+pub fn cut(text: &[u8], cols: &mut usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == ESC {
+            let end = escape_end(text, i);
+            out.extend_from_slice(&text[i..end]);
+            i = end;
+            continue;
+        }
+        let end = text[i..]
+            .iter()
+            .position(|&b| b == ESC)
+            .map_or(text.len(), |n| i + n);
+        for (c, width) in chars(&text[i..end]) {
+            if width <= *cols {
+                *cols -= width;
+                out.extend_from_slice(c);
+            } else {
+                *cols = 0;
+            }
+        }
+        i = end;
+    }
+    out
+}
+
+/// The characters of `word` with their display width. Bytes that are not
+/// valid UTF-8, such as a character split between two reads, are passed on
+/// one by one, counting lead bytes as one column.
+fn chars(word: &[u8]) -> impl Iterator<Item = (&[u8], usize)> {
+    word.utf8_chunks().flat_map(|chunk| {
+        let valid = chunk.valid();
+        let chars = valid.char_indices().map(move |(i, c)| {
+            let len = c.len_utf8();
+            (&valid.as_bytes()[i..i + len], c.width().unwrap_or(0))
+        });
+        let invalid = chunk
+            .invalid()
+            .chunks(1)
+            .map(|b| (b, usize::from(!is_continuation(b[0]))));
+        chars.chain(invalid)
+    })
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+/// Where an escape sequence is, up to the byte being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    /// Right after `ESC`.
+    Start,
+    /// In a CSI sequence, such as a color, up to its final byte.
+    Csi,
+    /// In an OSC sequence, such as a hyperlink, up to BEL or `ESC \`.
+    Osc,
+    /// At the `ESC` ending an OSC sequence.
+    OscEnd,
+}
+
+/// The end of the ANSI or BC escape sequence starting at `start`.
+fn escape_end(text: &[u8], start: usize) -> usize {
+    skip_escape(text, start + 1, Escape::Start).0
+}
+
+/// Read an escape sequence from `i`, where it is at `escape`. Returns where
+/// it ends, or the end of `text` and where the sequence is if it goes on
+/// past it.
+fn skip_escape(text: &[u8], mut i: usize, mut escape: Escape) -> (usize, Option<Escape>) {
+    while let Some(&b) = text.get(i) {
+        i += 1;
+        escape = match (escape, b) {
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Start, b']') => Escape::Osc,
+            (Escape::Csi, 0x40..=0x7e) | (Escape::Osc, 0x07) => return (i, None),
+            (Escape::Osc, ESC) => Escape::OscEnd,
+            (Escape::Csi | Escape::Osc, _) => escape,
+            (Escape::Start | Escape::OscEnd, _) => return (i, None),
+        };
+    }
+    (i, Some(escape))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(text: &str, width: usize) -> String {
+        let out = Wrapper::new().wrap_text(text.as_bytes(), width);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn lines_break_between_words() {
+        assert_eq!(
+            wrapped("the quick brown fox", 10),
+            "the quick \r\nbrown fox"
+        );
+        // A space at the end of a line becomes the break.
+        assert_eq!(wrapped("aaaaa bbbbb", 5), "aaaaa\r\nbbbbb");
+        assert_eq!(wrapped("one\r\ntwo three", 8), "one\r\ntwo \r\nthree");
+        assert_eq!(wrapped("\tab cd", 10), "\tab\r\ncd");
+    }
+
+    #[test]
+    fn words_longer_than_a_line_are_broken_inside() {
+        assert_eq!(wrapped("abcdefghij", 4), "abcd\r\nefgh\r\nij");
+        // Not moved to a line of their own first, as they would not fit.
+        assert_eq!(wrapped("x abcdefghij", 4), "x ab\r\ncdef\r\nghij");
+    }
+
+    #[test]
+    fn characters_are_counted_by_their_width() {
+        assert_eq!(wrapped("ääää ää", 4), "ääää\r\nää");
+        // Wide characters are not cut in half at the edge.
+        assert_eq!(wrapped("日本語", 5), "日本\r\n語");
+        assert_eq!(wrapped("a 日本", 4), "a \r\n日本");
+    }
+
+    #[test]
+    fn a_character_split_between_frames_stays_whole() {
+        let mut wrapper = Wrapper::new();
+        assert_eq!(wrapper.wrap_text(b"abcd\xc3", 5), b"abcd\xc3");
+        assert_eq!(wrapper.wrap_text(b"\xa4 x", 5), b"\xa4\r\nx");
+    }
+
+    #[test]
+    fn escape_sequences_take_no_columns_and_are_not_broken() {
+        assert_eq!(
+            wrapped("one \x1b[1;31mtwo three\x1b[0m", 8),
+            "one \x1b[1;31mtwo \r\nthree\x1b[0m"
+        );
+        let link = "\x1b]8;;https://bat.org\x1b\\bat\x1b]8;;\x1b\\";
+        assert_eq!(
+            wrapped(&format!("see {} now", link), 8),
+            format!("see {} \r\nnow", link)
+        );
+    }
+
+    #[test]
+    fn escape_sequences_split_between_frames_are_not_broken() {
+        let mut wrapper = Wrapper::new();
+        assert_eq!(wrapper.wrap_text(b"abc \x1b[1;3", 6), b"abc \x1b[1;3");
+        // The rest of the sequence is not taken for a word.
+        assert_eq!(wrapper.wrap_text(b"1mde fg", 6), b"1mde\r\nfg");
+
+        let mut wrapper = Wrapper::new();
+        wrapper.wrap_text(b"ab\x1b]8;;http://x\x1b", 4);
+        assert_eq!(wrapper.wrap_text(b"\\cd", 4), b"\\cd");
+    }
+
+    #[test]
+    fn maps_are_left_alone_and_messages_wrapped() {
+        let mut wrapper = Wrapper::new();
+        let long = "a".repeat(20);
+        let mut map = Frame::Code(ControlCode::new(
+            10,
+            Some("spec_map"),
+            vec![Frame::text(long.clone())],
+        ));
+        let before = map.clone();
+        wrapper.wrap(&mut map, 10);
+        assert_eq!(map, before);
+
+        let mut chan = Frame::Code(ControlCode::new(
+            10,
+            Some("chan_sales"),
+            vec![Frame::text(long.clone())],
+        ));
+        wrapper.wrap(&mut chan, 10);
+        let Frame::Code(code) = chan else {
+            unreachable!()
+        };
+        assert_eq!(
+            code.body,
+            [Frame::text(format!("{}\r\n{}", &long[..10], &long[10..]))]
+        );
+    }
+
+    #[test]
+    fn cut_keeps_what_fits_and_every_escape() {
+        let mut cols = 2;
+        assert_eq!(cut("äbc".as_bytes(), &mut cols), "äb".as_bytes());
+        assert_eq!(cols, 0);
+
+        let mut cols = 3;
+        assert_eq!(cut("日本".as_bytes(), &mut cols), "日".as_bytes());
+        assert_eq!(cols, 0);
+
+        let mut cols = 3;
+        assert_eq!(
+            cut(b"\x1b[31mabcdef\x1b[0m", &mut cols),
+            b"\x1b[31mabc\x1b[0m"
+        );
+
+        let mut cols = 10;
+        assert_eq!(cut(b"abc", &mut cols), b"abc");
+        assert_eq!(cols, 7);
+    }
+}
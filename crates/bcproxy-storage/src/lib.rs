@@ -0,0 +1,1475 @@
+//! What the proxy keeps in its SQLite database: the rooms and links the
+//! mapper reported, kills, deaths, offers, chat and item listings. Events
+//! are written by a task of their own, see [`Db`].
+
+#[cfg(feature = "postgres")]
+mod postgres;
+mod record;
+
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bcproxy_protocol::color;
+use rusqlite::{params, Connection};
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::Postgres;
+pub use self::record::{Kind, Location, Room, Side, Snapshot};
+
+/// Columns added to tables after they were first created, added to older
+/// databases on open.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("rooms", "realm", "TEXT"),
+    ("rooms", "x", "INTEGER"),
+    ("rooms", "y", "INTEGER"),
+    ("rooms", "z", "INTEGER"),
+    // Rooms stored before visits were counted were visited at least once.
+    ("rooms", "visited_count", "INTEGER NOT NULL DEFAULT 1"),
+    ("rooms", "last_visited_at", "INTEGER"),
+];
+
+/// Columns older versions stored text with ANSI codes in, cleaned on open.
+const STRIPPED_COLUMNS: &[(&str, &str)] = &[
+    ("rooms", "short_desc"),
+    ("rooms", "long_desc"),
+    ("monsters", "name"),
+    ("kills", "name"),
+    ("aggro", "name"),
+];
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS rooms (
+    id TEXT PRIMARY KEY,
+    area TEXT NOT NULL,
+    short_desc TEXT NOT NULL,
+    long_desc TEXT NOT NULL,
+    indoors INTEGER NOT NULL,
+    exits TEXT NOT NULL,
+    realm TEXT,
+    x INTEGER,
+    y INTEGER,
+    z INTEGER,
+    visited_count INTEGER NOT NULL DEFAULT 1,
+    last_visited_at INTEGER
+);
+CREATE TABLE IF NOT EXISTS room_links (
+    from_id TEXT NOT NULL,
+    to_id TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    PRIMARY KEY (from_id, direction)
+);
+CREATE TABLE IF NOT EXISTS realm_map (
+    realm TEXT NOT NULL,
+    x INTEGER NOT NULL,
+    y INTEGER NOT NULL,
+    tile TEXT NOT NULL,
+    PRIMARY KEY (realm, x, y)
+);
+CREATE TABLE IF NOT EXISTS monsters (
+    name TEXT NOT NULL,
+    exp INTEGER NOT NULL,
+    area TEXT,
+    room_id TEXT,
+    killed_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS kills (
+    name TEXT NOT NULL,
+    area TEXT,
+    room_id TEXT,
+    killed_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS aggro (
+    name TEXT NOT NULL,
+    area TEXT,
+    room_id TEXT,
+    marked_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE INDEX IF NOT EXISTS aggro_room ON aggro (room_id);
+CREATE TABLE IF NOT EXISTS deaths (
+    room_id TEXT NOT NULL,
+    area TEXT NOT NULL,
+    died_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS inventory (
+    kind TEXT NOT NULL,
+    items TEXT NOT NULL,
+    taken_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS offers (
+    side TEXT NOT NULL,
+    item TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    seller TEXT NOT NULL,
+    offered_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE INDEX IF NOT EXISTS offers_item ON offers (item);
+CREATE TABLE IF NOT EXISTS exp_snapshots (
+    free_exp INTEGER NOT NULL,
+    session_gain INTEGER NOT NULL,
+    recorded_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS chat (
+    channel TEXT NOT NULL,
+    speaker TEXT,
+    text TEXT NOT NULL,
+    said_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE INDEX IF NOT EXISTS chat_channel ON chat (channel, said_at);
+CREATE TABLE IF NOT EXISTS areas (
+    name TEXT PRIMARY KEY,
+    rooms INTEGER NOT NULL DEFAULT 0,
+    exits INTEGER NOT NULL DEFAULT 0,
+    explored INTEGER NOT NULL DEFAULT 0,
+    first_seen INTEGER NOT NULL DEFAULT (unixepoch()),
+    last_seen INTEGER NOT NULL DEFAULT (unixepoch())
+);
+CREATE TABLE IF NOT EXISTS unknown_codes (
+    id INTEGER NOT NULL,
+    attr TEXT NOT NULL,
+    count INTEGER NOT NULL DEFAULT 1,
+    sample TEXT NOT NULL,
+    first_seen INTEGER NOT NULL DEFAULT (unixepoch()),
+    last_seen INTEGER NOT NULL DEFAULT (unixepoch()),
+    PRIMARY KEY (id, attr)
+);
+CREATE TABLE IF NOT EXISTS session_state (
+    profile TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    saved_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+";
+
+const BACKFILL_AREAS: &str = "
+INSERT INTO areas (name, rooms, exits, explored)
+SELECT area, count(*),
+    sum(length(exits) - length(replace(exits, ',', '')) + (exits != '')),
+    sum((SELECT count(*) FROM room_links
+         WHERE from_id = rooms.id
+         AND instr(',' || rooms.exits || ',', ',' || direction || ',') > 0))
+FROM rooms GROUP BY area;
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Room(Room),
+    /// Where on the outworld map an outdoor room is.
+    RoomLocation {
+        id: String,
+        location: Location,
+    },
+    Link(Link),
+    /// Outworld map tiles as `(x, y, tile)`.
+    RealmMap {
+        realm: String,
+        tiles: Vec<(i64, i64, char)>,
+    },
+    Monster {
+        name: String,
+        exp: i64,
+        area: Option<String>,
+        room_id: Option<String>,
+    },
+    /// A target of the player reaching 0 hp.
+    Kill {
+        name: String,
+        area: Option<String>,
+        room_id: Option<String>,
+    },
+    /// A monster that attacks on sight, where the player met it.
+    Aggro {
+        name: String,
+        area: Option<String>,
+        room_id: Option<String>,
+    },
+    /// The player died in this room.
+    Death {
+        room_id: String,
+        area: String,
+    },
+    /// What the player carried or wore, one item per entry.
+    Inventory {
+        kind: Kind,
+        items: Vec<String>,
+    },
+    /// An item offered on the sales channel.
+    Offer {
+        side: Side,
+        item: String,
+        price: u64,
+        seller: String,
+    },
+    /// The player's free experience and what the session gained so far.
+    ExpSnapshot {
+        free_exp: i64,
+        session_gain: i64,
+    },
+    /// A line said on a channel.
+    Chat {
+        channel: String,
+        speaker: Option<String>,
+        text: String,
+    },
+    /// A control code the proxy does not know, with the start of its body.
+    UnknownCode {
+        id: u8,
+        attr: Option<String>,
+        sample: String,
+    },
+    /// The state of a session of `profile`, empty for the main config, as
+    /// JSON. `None` once it was restored.
+    SessionState {
+        profile: String,
+        state: Option<String>,
+    },
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    committed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Snapshot of how far the db task got.
+pub struct Status {
+    pub pending: u64,
+    pub committed: u64,
+    pub failed: u64,
+}
+
+/// How the db task gathers events into transactions: it commits once
+/// `interval` has passed since the first event of the transaction, or
+/// `max_events` are in it, whichever comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batch {
+    pub interval: Duration,
+    pub max_events: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            max_events: 500,
+        }
+    }
+}
+
+enum Message {
+    Event(u64, Event),
+    /// Commit what is gathered and say when done.
+    Flush(mpsc::Sender<()>),
+    /// Commit what is gathered and read a room from the storage.
+    Room(String, mpsc::Sender<Result<Option<Room>, StorageError>>),
+    /// Commit what is gathered and read the links from the storage.
+    Links(mpsc::Sender<Result<Vec<Link>, StorageError>>),
+}
+
+struct Sender {
+    tx: mpsc::Sender<Message>,
+    next_seq: u64,
+}
+
+/// What a [`Storage`] fails with.
+pub type StorageError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where the db task keeps events. [`Db::open`] writes them to the SQLite
+/// database it reads from, [`Db::with_storage`] to any other, such as a
+/// [`Memory`] for tests or `Postgres` with the `postgres` feature. The task calls a storage from a
+/// runtime of its own, one batch at a time: [`begin`](Storage::begin), a
+/// save per event, then [`commit`](Storage::commit).
+#[async_trait]
+pub trait Storage: Send + 'static {
+    /// Start a batch, in one transaction where the storage has them.
+    async fn begin(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn save_room(&mut self, room: &Room) -> Result<(), StorageError>;
+
+    async fn save_link(&mut self, link: &Link) -> Result<(), StorageError>;
+
+    /// Save an event other than a room or a link.
+    async fn save_event(&mut self, event: &Event) -> Result<(), StorageError>;
+
+    /// Finish the batch. If this fails, none of it counts as saved.
+    async fn commit(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// The room saved with `id`, as [`Db::room`] reads it.
+    async fn room(&mut self, id: &str) -> Result<Option<Room>, StorageError>;
+
+    /// The links saved, a link per room and direction, in the order first
+    /// saved.
+    async fn links(&mut self) -> Result<Vec<Link>, StorageError>;
+}
+
+struct Sqlite(Connection);
+
+#[async_trait]
+impl Storage for Sqlite {
+    async fn begin(&mut self) -> Result<(), StorageError> {
+        Ok(self.0.execute_batch("BEGIN")?)
+    }
+
+    async fn save_room(&mut self, room: &Room) -> Result<(), StorageError> {
+        write_room(&self.0, room)?;
+        Ok(())
+    }
+
+    async fn save_link(&mut self, link: &Link) -> Result<(), StorageError> {
+        write_link(&self.0, link)?;
+        Ok(())
+    }
+
+    async fn save_event(&mut self, event: &Event) -> Result<(), StorageError> {
+        Ok(write(&self.0, event)?)
+    }
+
+    /// An event that failed is left out, the others are committed.
+    async fn commit(&mut self) -> Result<(), StorageError> {
+        if let Err(e) = self.0.execute_batch("COMMIT") {
+            let _ = self.0.execute_batch("ROLLBACK");
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn room(&mut self, id: &str) -> Result<Option<Room>, StorageError> {
+        Ok(read_room(&self.0, id)?)
+    }
+
+    async fn links(&mut self) -> Result<Vec<Link>, StorageError> {
+        Ok(read_links(&self.0)?)
+    }
+}
+
+/// Keeps the events saved in memory, for tests to look at. Clones share
+/// the events.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events saved so far, in order.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn save(&self, event: Event) -> Result<(), StorageError> {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for Memory {
+    async fn save_room(&mut self, room: &Room) -> Result<(), StorageError> {
+        self.save(Event::Room(room.clone()))
+    }
+
+    async fn save_link(&mut self, link: &Link) -> Result<(), StorageError> {
+        self.save(Event::Link(link.clone()))
+    }
+
+    async fn save_event(&mut self, event: &Event) -> Result<(), StorageError> {
+        self.save(event.clone())
+    }
+
+    /// The room as last saved. Like the database, it does not keep the
+    /// direction it was entered from.
+    async fn room(&mut self, id: &str) -> Result<Option<Room>, StorageError> {
+        let events = self.events.lock().unwrap();
+        let room = events.iter().rev().find_map(|event| match event {
+            Event::Room(room) if room.id == id => Some(Room {
+                direction: String::new(),
+                ..room.clone()
+            }),
+            _ => None,
+        });
+        Ok(room)
+    }
+
+    async fn links(&mut self) -> Result<Vec<Link>, StorageError> {
+        let mut links: Vec<Link> = Vec::new();
+        for event in self.events.lock().unwrap().iter() {
+            let Event::Link(link) = event else {
+                continue;
+            };
+            match links
+                .iter_mut()
+                .find(|known| known.from == link.from && known.direction == link.direction)
+            {
+                Some(known) => known.to = link.to.clone(),
+                None => links.push(link.clone()),
+            }
+        }
+        Ok(links)
+    }
+}
+
+/// A link between two rooms as stored in `room_links`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub from: String,
+    pub to: String,
+    pub direction: String,
+}
+
+/// A kill recorded in `monsters`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monster {
+    pub name: String,
+    pub exp: i64,
+    pub area: Option<String>,
+    pub room_id: Option<String>,
+    /// Unix time of the kill.
+    pub killed_at: i64,
+}
+
+/// What is known of a monster in an area, from `monsters` and `aggro`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonsterInfo {
+    pub name: String,
+    pub area: Option<String>,
+    pub kills: i64,
+    /// The most experience a kill gave.
+    pub best_exp: Option<i64>,
+    pub aggro: bool,
+}
+
+/// An offer recorded in `offers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredOffer {
+    pub side: Side,
+    pub item: String,
+    pub price: i64,
+    pub seller: String,
+    /// Unix time of the offer.
+    pub offered_at: i64,
+}
+
+/// A channel message recorded in `chat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatLine {
+    pub channel: String,
+    pub speaker: Option<String>,
+    pub text: String,
+    /// Unix time the line was said.
+    pub said_at: i64,
+}
+
+/// How often the mapper reported a room, from `rooms`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomVisits {
+    pub id: String,
+    pub short_desc: String,
+    pub visits: i64,
+    /// Unix time of the last visit, unknown for rooms stored before visits
+    /// were counted.
+    pub last_visited_at: Option<i64>,
+}
+
+/// How much of an area the mapper has seen, as kept in `areas`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AreaStats {
+    pub name: String,
+    pub rooms: i64,
+    /// Exits of the rooms seen.
+    pub exits: i64,
+    /// Those of them taken, so where they lead is known.
+    pub explored: i64,
+    /// Unix times a room of the area was first and last reported.
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+/// Handle to the db task. Events are numbered in the order they are sent
+/// and written in that order, gathered into transactions as [`Batch`]
+/// says, so a speedwalk's rooms do not take a commit each. Reads from
+/// SQLite go through a connection of their own and see what the task has
+/// committed so far. Those from another storage go through the task, which
+/// commits what it has gathered first.
+#[derive(Clone)]
+pub struct Db {
+    sender: Arc<Mutex<Sender>>,
+    counters: Arc<Counters>,
+    // None if the events go to another storage, which only rooms and
+    // links are read back from.
+    reader: Option<Arc<Mutex<Connection>>>,
+}
+
+impl Db {
+    /// Open or create the SQLite database at `path` and start the db task.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::open_batched(path, Batch::default())
+    }
+
+    /// Open the database at `path`, writing events in batches as `batch`
+    /// says.
+    pub fn open_batched(path: &Path, batch: Batch) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        create_schema(&conn)?;
+        let reader = Connection::open(path)?;
+        Ok(Self::start(Sqlite(conn), Some(reader), batch))
+    }
+
+    /// Start the db task saving events to `storage`. Rooms and links are
+    /// read back from it, any other read fails with `SQLITE_MISUSE`.
+    pub fn with_storage(storage: impl Storage, batch: Batch) -> Self {
+        Self::start(storage, None, batch)
+    }
+
+    fn start(storage: impl Storage, reader: Option<Connection>, batch: Batch) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let counters = Arc::new(Counters::default());
+        let task_counters = counters.clone();
+        std::thread::spawn(move || run(storage, rx, batch, &task_counters));
+
+        Self {
+            sender: Arc::new(Mutex::new(Sender { tx, next_seq: 1 })),
+            counters,
+            reader: reader.map(|reader| Arc::new(Mutex::new(reader))),
+        }
+    }
+
+    fn reader(&self) -> rusqlite::Result<MutexGuard<'_, Connection>> {
+        match &self.reader {
+            Some(reader) => Ok(reader.lock().unwrap()),
+            None => Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+                Some("the db storage only reads rooms and links".to_string()),
+            )),
+        }
+    }
+
+    pub fn send(&self, event: Event) {
+        // Numbering and sending under the same lock keeps the channel in
+        // sequence order across sessions.
+        let mut sender = self.sender.lock().unwrap();
+        let seq = sender.next_seq;
+        if sender.tx.send(Message::Event(seq, event)).is_ok() {
+            sender.next_seq += 1;
+            self.counters.sent.store(seq, Ordering::Release);
+        }
+    }
+
+    /// Commit the events sent so far and wait for it, as before shutting
+    /// down.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        let sent = self.sender.lock().unwrap().tx.send(Message::Flush(done));
+        if sent.is_ok() {
+            let _ = wait.recv();
+        }
+    }
+
+    /// Ids of the rooms `query` names: the room with that id, else those
+    /// whose short description is `query` or, failing that, contains it.
+    pub fn find_rooms(&self, query: &str) -> rusqlite::Result<Vec<String>> {
+        let conn = self.reader()?;
+        for sql in [
+            "SELECT id FROM rooms WHERE id = ?1",
+            "SELECT id FROM rooms WHERE lower(short_desc) = lower(?1) ORDER BY id",
+            "SELECT id FROM rooms WHERE instr(lower(short_desc), lower(?1)) > 0 ORDER BY id",
+        ] {
+            let mut stmt = conn.prepare_cached(sql)?;
+            let ids = stmt
+                .query_map([query], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            if !ids.is_empty() {
+                return Ok(ids);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Ask the db task to read something from its storage.
+    fn ask<T>(
+        &self,
+        message: impl FnOnce(mpsc::Sender<Result<T, StorageError>>) -> Message,
+    ) -> rusqlite::Result<T> {
+        let (reply, answer) = mpsc::channel();
+        let sent = self.sender.lock().unwrap().tx.send(message(reply));
+        let answer = match sent {
+            Ok(()) => answer.recv().map_err(|_| "the db task has stopped".into()),
+            Err(_) => Err("the db task has stopped".into()),
+        };
+        answer.and_then(|answer| answer).map_err(|e| {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+                Some(e.to_string()),
+            )
+        })
+    }
+
+    pub fn room(&self, id: &str) -> rusqlite::Result<Option<Room>> {
+        match &self.reader {
+            Some(reader) => read_room(&reader.lock().unwrap(), id),
+            None => self.ask(|reply| Message::Room(id.to_string(), reply)),
+        }
+    }
+
+    /// Up to `limit` rooms whose short or long description contains
+    /// `text`, ignoring case, in `area` if given.
+    pub fn search_rooms(
+        &self,
+        text: &str,
+        area: Option<&str>,
+        limit: u32,
+    ) -> rusqlite::Result<Vec<Room>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, area, short_desc, long_desc, indoors, exits FROM rooms
+             WHERE (instr(lower(short_desc), lower(?1)) > 0
+                 OR instr(lower(long_desc), lower(?1)) > 0)
+             AND (?2 IS NULL OR area = ?2)
+             ORDER BY area, short_desc, id LIMIT ?3",
+        )?;
+        let rooms = stmt
+            .query_map(params![text, area, limit], |row| {
+                let exits: String = row.get(5)?;
+                Ok(Room {
+                    id: row.get(0)?,
+                    area: row.get(1)?,
+                    direction: String::new(),
+                    indoors: row.get(4)?,
+                    short_desc: row.get(2)?,
+                    long_desc: row.get(3)?,
+                    exits: split_exits(&exits),
+                })
+            })?
+            .collect();
+        rooms
+    }
+
+    /// The latest `limit` kills, in `area` if given.
+    pub fn monsters(&self, area: Option<&str>, limit: u32) -> rusqlite::Result<Vec<Monster>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT name, exp, area, room_id, killed_at FROM monsters
+             WHERE ?1 IS NULL OR area = ?1
+             ORDER BY killed_at DESC, rowid DESC LIMIT ?2",
+        )?;
+        let monsters = stmt
+            .query_map(params![area, limit], |row| {
+                Ok(Monster {
+                    name: row.get(0)?,
+                    exp: row.get(1)?,
+                    area: row.get(2)?,
+                    room_id: row.get(3)?,
+                    killed_at: row.get(4)?,
+                })
+            })?
+            .collect();
+        monsters
+    }
+
+    /// Up to `limit` monsters whose name contains `name`, ignoring case,
+    /// one per area they were met in.
+    pub fn find_monsters(&self, name: &str, limit: u32) -> rusqlite::Result<Vec<MonsterInfo>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT name, area, sum(kill), max(exp), max(aggro) FROM (
+                 SELECT name, area, 1 AS kill, exp, 0 AS aggro FROM monsters
+                 UNION ALL
+                 SELECT name, area, 0, NULL, 1 FROM aggro
+             )
+             WHERE instr(lower(name), lower(?1)) > 0
+             GROUP BY name, area ORDER BY name, area LIMIT ?2",
+        )?;
+        let monsters = stmt
+            .query_map(params![name, limit], |row| {
+                Ok(MonsterInfo {
+                    name: row.get(0)?,
+                    area: row.get(1)?,
+                    kills: row.get(2)?,
+                    best_exp: row.get(3)?,
+                    aggro: row.get(4)?,
+                })
+            })?
+            .collect();
+        monsters
+    }
+
+    /// The aggressive monsters met in room `id`, or in `area` where no
+    /// room was known.
+    pub fn aggro_in(&self, id: &str, area: &str) -> rusqlite::Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT DISTINCT name FROM aggro
+             WHERE room_id = ?1 OR (room_id IS NULL AND area = ?2)
+             ORDER BY name",
+        )?;
+        let names = stmt
+            .query_map(params![id, area], |row| row.get(0))?
+            .collect();
+        names
+    }
+
+    /// The room of the player's last death and its unix time.
+    pub fn last_death(&self) -> rusqlite::Result<Option<(String, i64)>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT room_id, died_at FROM deaths ORDER BY died_at DESC, rowid DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.next().transpose()
+    }
+
+    /// The last listing of `kind` stored.
+    pub fn last_inventory(&self, kind: Kind) -> rusqlite::Result<Option<Snapshot>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT items, taken_at FROM inventory WHERE kind = ?1
+             ORDER BY taken_at DESC, rowid DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query_map([kind.to_string()], |row| {
+            let items: String = row.get(0)?;
+            Ok(Snapshot {
+                kind,
+                items: items
+                    .split('\n')
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                taken_at: row.get(1)?,
+            })
+        })?;
+        rows.next().transpose()
+    }
+
+    /// The state last saved for a session of `profile` and its unix time.
+    pub fn session_state(&self, profile: &str) -> rusqlite::Result<Option<(String, i64)>> {
+        let conn = self.reader()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT state, saved_at FROM session_state WHERE profile = ?1")?;
+        let mut rows = stmt.query_map([profile], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.next().transpose()
+    }
+
+    /// The latest `limit` offers of items whose name contains `item`,
+    /// ignoring case, newest first.
+    pub fn offers(&self, item: &str, limit: u32) -> rusqlite::Result<Vec<StoredOffer>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT side, item, price, seller, offered_at FROM offers
+             WHERE instr(item, lower(?1)) > 0
+             ORDER BY offered_at DESC, rowid DESC LIMIT ?2",
+        )?;
+        let offers = stmt
+            .query_map(params![item, limit], |row| {
+                let side: String = row.get(0)?;
+                Ok(StoredOffer {
+                    side: if side == "buy" { Side::Buy } else { Side::Sell },
+                    item: row.get(1)?,
+                    price: row.get(2)?,
+                    seller: row.get(3)?,
+                    offered_at: row.get(4)?,
+                })
+            })?
+            .collect();
+        offers
+    }
+
+    /// The outworld map tile stored for `location`.
+    pub fn tile(&self, location: &Location) -> rusqlite::Result<Option<char>> {
+        let conn = self.reader()?;
+        let mut stmt = conn
+            .prepare_cached("SELECT tile FROM realm_map WHERE realm = ?1 AND x = ?2 AND y = ?3")?;
+        let mut rows = stmt.query_map(params![location.realm, location.x, location.y], |row| {
+            row.get::<_, String>(0)
+        })?;
+        Ok(rows
+            .next()
+            .transpose()?
+            .and_then(|tile| tile.chars().next()))
+    }
+
+    /// Ids of the outdoor rooms stored at `location`.
+    pub fn rooms_at(&self, location: &Location) -> rusqlite::Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id FROM rooms WHERE realm = ?1 AND x = ?2 AND y = ?3 ORDER BY id",
+        )?;
+        let ids = stmt
+            .query_map(params![location.realm, location.x, location.y], |row| {
+                row.get(0)
+            })?
+            .collect();
+        ids
+    }
+
+    /// The last `limit` lines said on `channel`, oldest first.
+    pub fn chat(&self, channel: &str, limit: u32) -> rusqlite::Result<Vec<ChatLine>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT speaker, text, said_at FROM chat WHERE channel = ?1
+             ORDER BY said_at DESC, rowid DESC LIMIT ?2",
+        )?;
+        let mut lines = stmt
+            .query_map(params![channel, limit], |row| {
+                Ok(ChatLine {
+                    channel: channel.to_string(),
+                    speaker: row.get(0)?,
+                    text: row.get(1)?,
+                    said_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ChatLine>>>()?;
+        lines.reverse();
+        Ok(lines)
+    }
+
+    /// The rooms of `area` and the links leading out of them.
+    pub fn area(&self, area: &str) -> rusqlite::Result<(Vec<Room>, Vec<Link>)> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, short_desc, long_desc, indoors, exits FROM rooms
+             WHERE area = ?1 ORDER BY id",
+        )?;
+        let rooms = stmt
+            .query_map([area], |row| {
+                let exits: String = row.get(4)?;
+                Ok(Room {
+                    area: area.to_string(),
+                    id: row.get(0)?,
+                    direction: String::new(),
+                    indoors: row.get(3)?,
+                    short_desc: row.get(1)?,
+                    long_desc: row.get(2)?,
+                    exits: split_exits(&exits),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Room>>>()?;
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT from_id, to_id, direction FROM room_links
+             WHERE from_id IN (SELECT id FROM rooms WHERE area = ?1)
+             ORDER BY from_id, direction",
+        )?;
+        let links = stmt
+            .query_map([area], |row| {
+                Ok(Link {
+                    from: row.get(0)?,
+                    to: row.get(1)?,
+                    direction: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Link>>>()?;
+        Ok((rooms, links))
+    }
+
+    /// The rooms of `area` by how often the mapper reported them, the most
+    /// visited first.
+    pub fn visits(&self, area: &str) -> rusqlite::Result<Vec<RoomVisits>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, short_desc, visited_count, last_visited_at FROM rooms
+             WHERE lower(area) = lower(?1)
+             ORDER BY visited_count DESC, last_visited_at DESC, id",
+        )?;
+        let visits = stmt
+            .query_map([area], |row| {
+                Ok(RoomVisits {
+                    id: row.get(0)?,
+                    short_desc: row.get(1)?,
+                    visits: row.get(2)?,
+                    last_visited_at: row.get(3)?,
+                })
+            })?
+            .collect();
+        visits
+    }
+
+    pub fn area_stats(&self, area: &str) -> rusqlite::Result<Option<AreaStats>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT name, rooms, exits, explored, first_seen, last_seen FROM areas
+             WHERE lower(name) = lower(?1)",
+        )?;
+        let mut rows = stmt.query_map([area], |row| {
+            Ok(AreaStats {
+                name: row.get(0)?,
+                rooms: row.get(1)?,
+                exits: row.get(2)?,
+                explored: row.get(3)?,
+                first_seen: row.get(4)?,
+                last_seen: row.get(5)?,
+            })
+        })?;
+        rows.next().transpose()
+    }
+
+    /// The exits of stored rooms not taken yet, as `(room id, exit)`.
+    pub fn unexplored(&self) -> rusqlite::Result<Vec<(String, String)>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached("SELECT from_id, direction FROM room_links")?;
+        let taken = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashSet<(String, String)>>>()?;
+        let mut stmt = conn.prepare_cached("SELECT id, exits FROM rooms ORDER BY id")?;
+        let rooms = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        Ok(rooms
+            .into_iter()
+            .flat_map(|(id, exits)| {
+                split_exits(&exits)
+                    .into_iter()
+                    .map(move |exit| (id.clone(), exit))
+            })
+            .filter(|exit| !taken.contains(exit))
+            .collect())
+    }
+
+    pub fn links(&self) -> rusqlite::Result<Vec<Link>> {
+        match &self.reader {
+            Some(reader) => read_links(&reader.lock().unwrap()),
+            None => self.ask(Message::Links),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let sent = self.counters.sent.load(Ordering::Acquire);
+        let committed = self.counters.committed.load(Ordering::Acquire);
+        Status {
+            pending: sent.saturating_sub(committed),
+            committed,
+            failed: self.counters.failed.load(Ordering::Acquire),
+        }
+    }
+}
+
+fn read_room(conn: &Connection, id: &str) -> rusqlite::Result<Option<Room>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT area, short_desc, long_desc, indoors, exits FROM rooms WHERE id = ?1",
+    )?;
+    let mut rows = stmt.query_map([id], |row| {
+        let exits: String = row.get(4)?;
+        Ok(Room {
+            area: row.get(0)?,
+            id: id.to_string(),
+            direction: String::new(),
+            indoors: row.get(3)?,
+            short_desc: row.get(1)?,
+            long_desc: row.get(2)?,
+            exits: split_exits(&exits),
+        })
+    })?;
+    rows.next().transpose()
+}
+
+fn read_links(conn: &Connection) -> rusqlite::Result<Vec<Link>> {
+    let mut stmt =
+        conn.prepare_cached("SELECT from_id, to_id, direction FROM room_links ORDER BY rowid")?;
+    let links = stmt
+        .query_map([], |row| {
+            Ok(Link {
+                from: row.get(0)?,
+                to: row.get(1)?,
+                direction: row.get(2)?,
+            })
+        })?
+        .collect();
+    links
+}
+
+fn split_exits(exits: &str) -> Vec<String> {
+    exits
+        .split(',')
+        .filter(|exit| !exit.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    let has_areas = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'areas'")?
+        .exists([])?;
+    conn.execute_batch(SCHEMA)?;
+    for (table, column, kind) in ADDED_COLUMNS {
+        let exists = conn
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ))?
+            .exists([column])?;
+        if !exists {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, kind
+            ))?;
+        }
+    }
+    // Databases from before `areas` get it filled in from their rooms.
+    if !has_areas {
+        conn.execute_batch(BACKFILL_AREAS)?;
+    }
+    for (table, column) in STRIPPED_COLUMNS {
+        strip_column(conn, table, column)?;
+    }
+    Ok(())
+}
+
+/// Drop the ANSI codes left in `column` of `table`.
+fn strip_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<()> {
+    let rows = conn
+        .prepare(&format!(
+            "SELECT rowid, {c} FROM {t} WHERE instr({c}, char(27)) > 0",
+            t = table,
+            c = column
+        ))?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut update = conn.prepare(&format!(
+        "UPDATE {} SET {} = ?2 WHERE rowid = ?1",
+        table, column
+    ))?;
+    for (rowid, text) in rows {
+        update.execute(params![rowid, plain(&text)])?;
+    }
+    Ok(())
+}
+
+/// `text` as stored: without ANSI codes, which are for the client's eyes.
+fn plain(text: &str) -> String {
+    color::strip_ansi(text.as_bytes())
+}
+
+fn run(mut storage: impl Storage, rx: mpsc::Receiver<Message>, batch: Batch, counters: &Counters) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => return tracing::error!("failed to start the db task: {}", e),
+    };
+    let storage = &mut storage;
+    let mut events = Vec::new();
+    // When the events gathered so far are due.
+    let mut due: Option<Instant> = None;
+    loop {
+        let message = match due {
+            Some(due) => rx.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match message {
+            Ok(Message::Event(seq, event)) => {
+                events.push((seq, event));
+                let due = *due.get_or_insert_with(|| Instant::now() + batch.interval);
+                if events.len() < batch.max_events && Instant::now() < due {
+                    continue;
+                }
+            }
+            Ok(Message::Flush(done)) => {
+                runtime.block_on(commit(storage, &mut events, counters));
+                let _ = done.send(());
+            }
+            Ok(Message::Room(id, reply)) => {
+                runtime.block_on(commit(storage, &mut events, counters));
+                let _ = reply.send(runtime.block_on(storage.room(&id)));
+            }
+            Ok(Message::Links(reply)) => {
+                runtime.block_on(commit(storage, &mut events, counters));
+                let _ = reply.send(runtime.block_on(storage.links()));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return runtime.block_on(commit(storage, &mut events, counters));
+            }
+        }
+        runtime.block_on(commit(storage, &mut events, counters));
+        due = None;
+    }
+}
+
+/// Save `events` to `storage` in one batch.
+async fn commit(storage: &mut impl Storage, events: &mut Vec<(u64, Event)>, counters: &Counters) {
+    let last = match events.last() {
+        Some(&(last, _)) => last,
+        None => return,
+    };
+    let failed = save(storage, std::mem::take(events)).await;
+    counters.failed.fetch_add(failed, Ordering::AcqRel);
+    counters.committed.store(last, Ordering::Release);
+}
+
+/// Returns how many of `events` were not saved: those that failed, or all
+/// of them if the batch did not begin or commit.
+async fn save(storage: &mut impl Storage, events: Vec<(u64, Event)>) -> u64 {
+    let count = events.len() as u64;
+    if let Err(e) = storage.begin().await {
+        tracing::error!("failed to begin a batch of {} events: {}", count, e);
+        return count;
+    }
+    let mut failed = 0;
+    for (seq, event) in events {
+        let saved = match &event {
+            Event::Room(room) => storage.save_room(room).await,
+            Event::Link(link) => storage.save_link(link).await,
+            event => storage.save_event(event).await,
+        };
+        if let Err(e) = saved {
+            tracing::error!("failed to write event {} {:?}: {}", seq, event, e);
+            failed += 1;
+        }
+    }
+    match storage.commit().await {
+        Ok(()) => failed,
+        Err(e) => {
+            tracing::error!("failed to commit {} events: {}", count, e);
+            count
+        }
+    }
+}
+
+/// Every report is a visit, the room is stored on the first.
+fn write_room(conn: &Connection, room: &Room) -> rusqlite::Result<usize> {
+    let visits: i64 = conn
+        .prepare_cached(
+            "INSERT INTO rooms
+                 (id, area, short_desc, long_desc, indoors, exits, last_visited_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, unixepoch())
+             ON CONFLICT (id) DO UPDATE SET
+                 visited_count = visited_count + 1,
+                 last_visited_at = unixepoch()
+             RETURNING visited_count",
+        )?
+        .query_row(
+            params![
+                room.id,
+                room.area,
+                plain(&room.short_desc),
+                plain(&room.long_desc),
+                room.indoors,
+                room.exits.join(","),
+            ],
+            |row| row.get(0),
+        )?;
+    let added = usize::from(visits == 1);
+    // Rooms seen again only count towards when the area was last
+    // seen.
+    let exits = if added > 0 {
+        room.exits.len() as i64
+    } else {
+        0
+    };
+    conn.execute(
+        "INSERT INTO areas (name, rooms, exits) VALUES (?1, ?2, ?3)
+         ON CONFLICT (name) DO UPDATE SET
+             rooms = rooms + excluded.rooms,
+             exits = exits + excluded.exits,
+             last_seen = unixepoch()",
+        params![room.area, added as i64, exits],
+    )
+}
+
+fn write_link(conn: &Connection, link: &Link) -> rusqlite::Result<usize> {
+    let known = conn
+        .prepare_cached("SELECT 1 FROM room_links WHERE from_id = ?1 AND direction = ?2")?
+        .exists(params![link.from, link.direction])?;
+    conn.execute(
+        "INSERT INTO room_links (from_id, to_id, direction)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT (from_id, direction) DO UPDATE SET to_id = excluded.to_id",
+        params![link.from, link.to, link.direction],
+    )?;
+    // Only exits the room lists count, not where a teleport or a
+    // special command went.
+    if !known {
+        conn.execute(
+            "UPDATE areas SET explored = explored + 1
+             WHERE name = (SELECT area FROM rooms WHERE id = ?1
+                 AND instr(',' || exits || ',', ',' || ?2 || ',') > 0)",
+            params![link.from, link.direction],
+        )?;
+    }
+    Ok(1)
+}
+
+fn write(conn: &Connection, event: &Event) -> rusqlite::Result<()> {
+    match event {
+        Event::Room(room) => write_room(conn, room)?,
+        Event::Link(link) => write_link(conn, link)?,
+        Event::RoomLocation { id, location } => conn.execute(
+            "UPDATE rooms SET realm = ?2, x = ?3, y = ?4, z = ?5 WHERE id = ?1",
+            params![id, location.realm, location.x, location.y, location.z],
+        )?,
+        // Written in the transaction of the batch, like every event.
+        Event::RealmMap { realm, tiles } => {
+            let mut insert = conn.prepare_cached(
+                "INSERT INTO realm_map (realm, x, y, tile) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (realm, x, y) DO UPDATE SET tile = excluded.tile",
+            )?;
+            for (x, y, tile) in tiles {
+                insert.execute(params![realm, x, y, tile.to_string()])?;
+            }
+            tiles.len()
+        }
+        Event::Monster {
+            name,
+            exp,
+            area,
+            room_id,
+        } => conn.execute(
+            "INSERT INTO monsters (name, exp, area, room_id) VALUES (?1, ?2, ?3, ?4)",
+            params![plain(name), exp, area, room_id],
+        )?,
+        Event::Kill {
+            name,
+            area,
+            room_id,
+        } => conn.execute(
+            "INSERT INTO kills (name, area, room_id) VALUES (?1, ?2, ?3)",
+            params![plain(name), area, room_id],
+        )?,
+        Event::Aggro {
+            name,
+            area,
+            room_id,
+        } => conn.execute(
+            "INSERT INTO aggro (name, area, room_id) SELECT ?1, ?2, ?3
+             WHERE NOT EXISTS (SELECT 1 FROM aggro
+                 WHERE name = ?1 AND area IS ?2 AND room_id IS ?3)",
+            params![plain(name), area, room_id],
+        )?,
+        Event::Death { room_id, area } => conn.execute(
+            "INSERT INTO deaths (room_id, area) VALUES (?1, ?2)",
+            params![room_id, area],
+        )?,
+        Event::Inventory { kind, items } => conn.execute(
+            "INSERT INTO inventory (kind, items) VALUES (?1, ?2)",
+            params![kind.to_string(), items.join("\n")],
+        )?,
+        Event::Offer {
+            side,
+            item,
+            price,
+            seller,
+        } => conn.execute(
+            "INSERT INTO offers (side, item, price, seller) VALUES (?1, ?2, ?3, ?4)",
+            params![side.to_string(), item, *price as i64, seller],
+        )?,
+        Event::ExpSnapshot {
+            free_exp,
+            session_gain,
+        } => conn.execute(
+            "INSERT INTO exp_snapshots (free_exp, session_gain) VALUES (?1, ?2)",
+            params![free_exp, session_gain],
+        )?,
+        Event::Chat {
+            channel,
+            speaker,
+            text,
+        } => conn.execute(
+            "INSERT INTO chat (channel, speaker, text) VALUES (?1, ?2, ?3)",
+            params![channel, speaker, text],
+        )?,
+        // Codes without an attribute are stored with an empty one, as
+        // NULLs would not collide in the primary key.
+        Event::UnknownCode { id, attr, sample } => conn.execute(
+            "INSERT INTO unknown_codes (id, attr, sample) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id, attr) DO UPDATE SET
+                 count = count + 1, last_seen = unixepoch()",
+            params![id, attr.as_deref().unwrap_or(""), sample],
+        )?,
+        Event::SessionState {
+            profile,
+            state: Some(state),
+        } => conn.execute(
+            "INSERT OR REPLACE INTO session_state (profile, state) VALUES (?1, ?2)",
+            params![profile, state],
+        )?,
+        Event::SessionState {
+            profile,
+            state: None,
+        } => conn.execute(
+            "DELETE FROM session_state WHERE profile = ?1",
+            params![profile],
+        )?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(id: &str, exits: &[&str]) -> Room {
+        Room {
+            area: "arelium".to_string(),
+            id: id.to_string(),
+            direction: "n".to_string(),
+            indoors: false,
+            short_desc: format!("Room {}", id),
+            long_desc: String::new(),
+            exits: exits.iter().map(|exit| exit.to_string()).collect(),
+        }
+    }
+
+    fn link(from: &str, direction: &str, to: &str) -> Link {
+        Link {
+            from: from.to_string(),
+            to: to.to_string(),
+            direction: direction.to_string(),
+        }
+    }
+
+    #[test]
+    fn rooms_and_links_are_read_back_from_the_storage() {
+        let memory = Memory::new();
+        let db = Db::with_storage(memory.clone(), Batch::default());
+        db.send(Event::Room(room("1", &["n"])));
+        db.send(Event::Link(link("1", "n", "2")));
+        db.send(Event::Link(link("1", "s", "3")));
+        db.send(Event::Link(link("1", "n", "4")));
+
+        // Read without a flush: the task commits what it has first.
+        let stored = db.room("1").unwrap().unwrap();
+        assert_eq!(stored.direction, "");
+        assert_eq!(stored.exits, ["n"]);
+        assert_eq!(db.room("2").unwrap(), None);
+        // A link per room and direction, the last one saved.
+        assert_eq!(
+            db.links().unwrap(),
+            [link("1", "n", "4"), link("1", "s", "3")]
+        );
+        assert_eq!(memory.events().len(), 4);
+        // Other reads are SQLite's.
+        assert!(db.find_rooms("1").is_err());
+    }
+
+    /// Saves rooms, fails every other event and, if asked to, the commit.
+    struct Failing {
+        commit: bool,
+        saved: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Storage for Failing {
+        async fn save_room(&mut self, room: &Room) -> Result<(), StorageError> {
+            self.saved.lock().unwrap().push(room.id.clone());
+            Ok(())
+        }
+
+        async fn save_link(&mut self, _link: &Link) -> Result<(), StorageError> {
+            Err("no links".into())
+        }
+
+        async fn save_event(&mut self, _event: &Event) -> Result<(), StorageError> {
+            Err("no events".into())
+        }
+
+        async fn commit(&mut self) -> Result<(), StorageError> {
+            if self.commit {
+                Ok(())
+            } else {
+                Err("no commits".into())
+            }
+        }
+
+        async fn room(&mut self, _id: &str) -> Result<Option<Room>, StorageError> {
+            Err("no reads".into())
+        }
+
+        async fn links(&mut self) -> Result<Vec<Link>, StorageError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn failed_saves_and_commits_are_counted() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let storage = Failing {
+            commit: true,
+            saved: saved.clone(),
+        };
+        let db = Db::with_storage(storage, Batch::default());
+        db.send(Event::Room(room("1", &[])));
+        db.send(Event::Link(link("1", "n", "2")));
+        db.send(Event::Room(room("2", &[])));
+        db.flush();
+        assert_eq!(*saved.lock().unwrap(), ["1", "2"]);
+        assert_eq!(db.status().failed, 1);
+        assert_eq!(db.status().pending, 0);
+
+        // The whole batch fails with its commit.
+        let storage = Failing {
+            commit: false,
+            saved,
+        };
+        let db = Db::with_storage(storage, Batch::default());
+        db.send(Event::Room(room("1", &[])));
+        db.send(Event::Room(room("2", &[])));
+        db.flush();
+        assert_eq!(db.status().failed, 2);
+
+        // Storage errors reach the reader.
+        let Err(rusqlite::Error::SqliteFailure(_, Some(message))) = db.room("1") else {
+            panic!("the read did not fail");
+        };
+        assert_eq!(message, "no reads");
+    }
+
+    #[test]
+    fn sqlite_leaves_out_the_events_that_fail() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_schema(&conn).unwrap();
+        let mut storage = Sqlite(conn);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let events = vec![
+            (1, Event::Room(room("1", &["n"]))),
+            (2, Event::Link(link("1", "n", "2"))),
+            (
+                3,
+                Event::RealmMap {
+                    realm: "outworld".to_string(),
+                    tiles: vec![(0, 0, 'x')],
+                },
+            ),
+        ];
+        assert_eq!(runtime.block_on(save(&mut storage, events)), 0);
+        let stored = runtime.block_on(storage.room("1")).unwrap().unwrap();
+        assert_eq!(stored.short_desc, "Room 1");
+        assert_eq!(
+            runtime.block_on(storage.links()).unwrap(),
+            [link("1", "n", "2")]
+        );
+
+        storage.0.execute_batch("DROP TABLE monsters").unwrap();
+        let events = vec![
+            (
+                4,
+                Event::Monster {
+                    name: "orc".to_string(),
+                    exp: 10,
+                    area: None,
+                    room_id: None,
+                },
+            ),
+            (5, Event::Room(room("2", &[]))),
+        ];
+        assert_eq!(runtime.block_on(save(&mut storage, events)), 1);
+        assert!(runtime.block_on(storage.room("2")).unwrap().is_some());
+    }
+}
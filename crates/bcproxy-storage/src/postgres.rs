@@ -0,0 +1,212 @@
+//! The map kept in PostgreSQL, so proxies on other machines can share it.
+//! Only rooms and links are kept there, other events are left out.
+
+use async_trait::async_trait;
+use tokio_postgres::{types::ToSql, Client, NoTls};
+
+use crate::{plain, split_exits, Event, Link, Room, Storage, StorageError};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS rooms (
+    id TEXT PRIMARY KEY,
+    area TEXT NOT NULL,
+    short_desc TEXT NOT NULL,
+    long_desc TEXT NOT NULL,
+    indoors BOOLEAN NOT NULL,
+    exits TEXT NOT NULL,
+    visited_count BIGINT NOT NULL DEFAULT 1,
+    last_visited_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS room_links (
+    seq BIGSERIAL,
+    from_id TEXT NOT NULL,
+    to_id TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    PRIMARY KEY (from_id, direction)
+);
+";
+
+/// A connection to the PostgreSQL database keeping the map.
+pub struct Postgres {
+    client: Client,
+}
+
+impl Postgres {
+    /// Connect to the database `config` names, as a URL or `key=value`
+    /// pairs, and create the tables it lacks. The connection is driven by
+    /// a task spawned on the runtime this is called from.
+    pub async fn connect(config: &str) -> Result<Self, StorageError> {
+        let (client, connection) = tokio_postgres::connect(config, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::error!("postgres connection failed: {}", e);
+            }
+        });
+        client.batch_execute(SCHEMA).await?;
+        Ok(Self { client })
+    }
+
+    /// Run `statement` in a savepoint of its own: an error aborts the
+    /// whole transaction in PostgreSQL, where only the event should be
+    /// left out.
+    async fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<(), StorageError> {
+        self.client.batch_execute("SAVEPOINT event").await?;
+        match self.client.execute(statement, params).await {
+            Ok(_) => Ok(self.client.batch_execute("RELEASE SAVEPOINT event").await?),
+            Err(e) => {
+                self.client
+                    .batch_execute("ROLLBACK TO SAVEPOINT event")
+                    .await?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for Postgres {
+    async fn begin(&mut self) -> Result<(), StorageError> {
+        Ok(self.client.batch_execute("BEGIN").await?)
+    }
+
+    /// Every report is a visit, the room is stored on the first.
+    async fn save_room(&mut self, room: &Room) -> Result<(), StorageError> {
+        self.execute(
+            "INSERT INTO rooms (id, area, short_desc, long_desc, indoors, exits)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (id) DO UPDATE SET
+                 visited_count = rooms.visited_count + 1,
+                 last_visited_at = now()",
+            &[
+                &room.id,
+                &room.area,
+                &plain(&room.short_desc),
+                &plain(&room.long_desc),
+                &room.indoors,
+                &room.exits.join(","),
+            ],
+        )
+        .await
+    }
+
+    async fn save_link(&mut self, link: &Link) -> Result<(), StorageError> {
+        self.execute(
+            "INSERT INTO room_links (from_id, to_id, direction) VALUES ($1, $2, $3)
+             ON CONFLICT (from_id, direction) DO UPDATE SET to_id = excluded.to_id",
+            &[&link.from, &link.to, &link.direction],
+        )
+        .await
+    }
+
+    async fn save_event(&mut self, _event: &Event) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<(), StorageError> {
+        Ok(self.client.batch_execute("COMMIT").await?)
+    }
+
+    async fn room(&mut self, id: &str) -> Result<Option<Room>, StorageError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT area, short_desc, long_desc, indoors, exits FROM rooms WHERE id = $1",
+                &[&id],
+            )
+            .await?;
+        Ok(row.map(|row| Room {
+            area: row.get(0),
+            id: id.to_string(),
+            direction: String::new(),
+            indoors: row.get(3),
+            short_desc: row.get(1),
+            long_desc: row.get(2),
+            exits: split_exits(row.get(4)),
+        }))
+    }
+
+    async fn links(&mut self) -> Result<Vec<Link>, StorageError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT from_id, to_id, direction FROM room_links ORDER BY seq",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| Link {
+                from: row.get(0),
+                to: row.get(1),
+                direction: row.get(2),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(id: &str, short_desc: &str) -> Room {
+        Room {
+            area: "arelium".to_string(),
+            id: id.to_string(),
+            direction: "n".to_string(),
+            indoors: true,
+            short_desc: short_desc.to_string(),
+            long_desc: "A room.".to_string(),
+            exits: vec!["n".to_string(), "s".to_string()],
+        }
+    }
+
+    fn link(from: &str, direction: &str, to: &str) -> Link {
+        Link {
+            from: from.to_string(),
+            to: to.to_string(),
+            direction: direction.to_string(),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a server, set BCPROXY_POSTGRES to one"]
+    fn the_map_is_kept_and_failed_events_left_out() {
+        let config = std::env::var("BCPROXY_POSTGRES").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut storage = Postgres::connect(&config).await.unwrap();
+            storage
+                .client
+                .batch_execute("TRUNCATE rooms, room_links")
+                .await
+                .unwrap();
+            let events = vec![
+                (1, Event::Room(room("1", "\x1b[1mSquare\x1b[0m"))),
+                (2, Event::Link(link("1", "n", "2"))),
+                // Text cannot hold NUL.
+                (3, Event::Room(room("2", "Ga\0te"))),
+                (4, Event::Link(link("1", "s", "3"))),
+                (5, Event::Link(link("1", "n", "4"))),
+                (6, Event::Room(room("1", "Square"))),
+            ];
+            assert_eq!(crate::save(&mut storage, events).await, 1);
+
+            let square = storage.room("1").await.unwrap().unwrap();
+            assert_eq!(square.short_desc, "Square");
+            assert_eq!(square.direction, "");
+            assert_eq!(square.exits, ["n", "s"]);
+            assert!(storage.room("2").await.unwrap().is_none());
+            assert_eq!(
+                storage.links().await.unwrap(),
+                [link("1", "n", "4"), link("1", "s", "3")]
+            );
+        });
+    }
+}
@@ -0,0 +1,210 @@
+//! What the proxy reads from the server and stores: rooms, where the
+//! player is, listings of items and offers.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use bcproxy_codec::ControlCode;
+use serde::{Deserialize, Serialize};
+
+/// Realm of locations that do not name one.
+const DEFAULT_REALM: &str = "outworld";
+
+/// A room reported by the `BAT_MAPPER` custom info message (code 99):
+///
+/// `BAT_MAPPER;;<area>;;<room id>;;<direction>;;<indoors>;;<short desc>;;<long desc>;;<exits>;;BAT_MAPPER`
+///
+/// `direction` is the exit taken from the previous room, empty after a
+/// teleport or login.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Room {
+    pub area: String,
+    pub id: String,
+    pub direction: String,
+    pub indoors: bool,
+    pub short_desc: String,
+    pub long_desc: String,
+    pub exits: Vec<String>,
+}
+
+/// Where the player is on the outworld map, from the player location code
+/// (60): `[<realm>] <x> <y> [<z>]`, the realm being the continent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub realm: String,
+    pub x: i64,
+    pub y: i64,
+    pub z: Option<i64>,
+}
+
+impl Location {
+    pub fn from_code(code: &ControlCode) -> Option<Self> {
+        if code.id != 60 {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&code.text()).into_owned();
+        let fields: Vec<&str> = text
+            .split(|c: char| c.is_whitespace() || c == ';' || c == ',')
+            .filter(|field| !field.is_empty())
+            .collect();
+        // A third number at the end is the height.
+        let (fields, z) = match fields.as_slice() {
+            [rest @ .., x, y, z] if [x, y, z].iter().all(|n| n.parse::<i64>().is_ok()) => {
+                (&fields[..rest.len() + 2], z.parse().ok())
+            }
+            _ => (fields.as_slice(), None),
+        };
+        let (realm, x, y) = match fields {
+            [realm @ .., x, y] => (realm.join(" "), x.parse().ok()?, y.parse().ok()?),
+            _ => return None,
+        };
+        Some(Self {
+            realm: if realm.is_empty() {
+                DEFAULT_REALM.to_string()
+            } else {
+                realm
+            },
+            x,
+            y,
+            z,
+        })
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {},{}", self.realm, self.x, self.y)?;
+        if let Some(z) = self.z {
+            write!(f, ",{}", z)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a listing is of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Inventory,
+    Equipment,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inv" => Ok(Kind::Inventory),
+            "eq" => Ok(Kind::Equipment),
+            _ => Err(format!(
+                "invalid inventory kind `{}`, expected inv or eq",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Inventory => "inv",
+            Kind::Equipment => "eq",
+        })
+    }
+}
+
+/// The items of a listing, as the server wrote them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub kind: Kind,
+    pub items: Vec<String>,
+    /// Unix time of the listing.
+    pub taken_at: i64,
+}
+
+impl Snapshot {
+    /// The items of `self` not in `older`, and those of `older` not in
+    /// `self`, each as many times as the difference.
+    pub fn diff(&self, older: &Snapshot) -> (Vec<String>, Vec<String>) {
+        let mut counts: BTreeMap<&str, i64> = BTreeMap::new();
+        for item in &self.items {
+            *counts.entry(item).or_default() += 1;
+        }
+        for item in &older.items {
+            *counts.entry(item).or_default() -= 1;
+        }
+        let (mut added, mut removed) = (Vec::new(), Vec::new());
+        for (item, n) in counts {
+            let list = if n > 0 { &mut added } else { &mut removed };
+            list.extend(std::iter::repeat_n(
+                item.to_string(),
+                n.unsigned_abs() as usize,
+            ));
+        }
+        (added, removed)
+    }
+}
+
+/// Whether an offer is to sell or to buy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Sell,
+    Buy,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Sell => "sell",
+            Side::Buy => "buy",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bcproxy_codec::Frame;
+
+    use super::*;
+
+    fn location(text: &'static str) -> Option<Location> {
+        Location::from_code(&ControlCode::new(
+            60,
+            None::<&[u8]>,
+            vec![Frame::text(text)],
+        ))
+    }
+
+    fn at(realm: &str, x: i64, y: i64, z: Option<i64>) -> Option<Location> {
+        Some(Location {
+            realm: realm.to_string(),
+            x,
+            y,
+            z,
+        })
+    }
+
+    #[test]
+    fn coordinates_are_read_with_or_without_a_realm() {
+        assert_eq!(location("laenor 4123 2210"), at("laenor", 4123, 2210, None));
+        assert_eq!(location("12 -7"), at(DEFAULT_REALM, 12, -7, None));
+        assert_eq!(location("lucentium;10,20"), at("lucentium", 10, 20, None));
+        assert_eq!(
+            location("new rothikgen 1 2"),
+            at("new rothikgen", 1, 2, None)
+        );
+    }
+
+    #[test]
+    fn a_third_number_is_the_height() {
+        assert_eq!(location("laenor 1 2 3"), at("laenor", 1, 2, Some(3)));
+        assert_eq!(location("1 2 3"), at(DEFAULT_REALM, 1, 2, Some(3)));
+    }
+
+    #[test]
+    fn other_codes_and_missing_numbers_are_no_location() {
+        let code = ControlCode::new(61, None::<&[u8]>, vec![Frame::text("laenor 1 2")]);
+        assert_eq!(Location::from_code(&code), None);
+        assert_eq!(location("laenor 1"), None);
+        assert_eq!(location("laenor x y"), None);
+        assert_eq!(location(""), None);
+    }
+}
@@ -0,0 +1,35 @@
+//! Feeds the decoder arbitrary server output, split into reads at arbitrary
+//! points, and checks the frames encode back to the input.
+//!
+//! Run with `cargo +nightly fuzz run decoder` from the repository root.
+#![no_main]
+
+use bcproxy_codec::{Decoder, DecoderLimits, Frame};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the read size, small limits make the give-up
+    // paths reachable.
+    let Some((&split, input)) = data.split_first() else {
+        return;
+    };
+    let mut decoder = Decoder::with_limits(DecoderLimits {
+        max_code_bytes: 64,
+        max_depth: 4,
+    });
+    let mut frames = Vec::new();
+    for chunk in input.chunks(usize::from(split).max(1)) {
+        decoder.decode(chunk, &mut frames);
+    }
+    decoder.finish(&mut frames);
+
+    let mut out = Vec::new();
+    for frame in &frames {
+        frame.encode(&mut out);
+    }
+    assert_eq!(out, input);
+    assert!(frames.iter().all(|frame| match frame {
+        Frame::Text(text) => !text.is_empty(),
+        Frame::Code(_) | Frame::Prompt(_) | Frame::LoginResult(_) | Frame::Map(_) => true,
+    }));
+});
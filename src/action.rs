@@ -0,0 +1,176 @@
+//! The spell or skill the player is busy with, from the server's action
+//! status codes:
+//!
+//! - 40 `player_spell_action_status`: `<spell> <rounds left>`
+//! - 41 `player_skill_action_status`: `<skill> <rounds left>`
+//! - 42 the action is over, finished or interrupted
+
+use std::{fmt, str::FromStr};
+
+use crate::bc::ControlCode;
+
+pub const SPELL_STATUS: u8 = 40;
+pub const SKILL_STATUS: u8 = 41;
+pub const ACTION_DONE: u8 = 42;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    Spell,
+    Skill,
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ActionKind::Spell => "spell",
+            ActionKind::Skill => "skill",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionStatus {
+    pub kind: ActionKind,
+    pub name: String,
+    pub rounds_left: u32,
+}
+
+impl fmt::Display for ActionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} round{} left",
+            self.kind,
+            self.name,
+            self.rounds_left,
+            if self.rounds_left == 1 { "" } else { "s" }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionEvent {
+    Status(ActionStatus),
+    Done,
+}
+
+impl ActionEvent {
+    pub fn from_code(code: &ControlCode) -> Option<Self> {
+        let kind = match code.id {
+            SPELL_STATUS => ActionKind::Spell,
+            SKILL_STATUS => ActionKind::Skill,
+            ACTION_DONE => return Some(ActionEvent::Done),
+            _ => return None,
+        };
+        let text = String::from_utf8_lossy(&code.text()).into_owned();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let (name, rounds_left) = match fields.as_slice() {
+            [name @ .., rounds] if !name.is_empty() => (name.join(" "), rounds.parse().ok()?),
+            _ => return None,
+        };
+        Some(ActionEvent::Status(ActionStatus {
+            kind,
+            name,
+            rounds_left,
+        }))
+    }
+}
+
+/// How the player sees the rounds left of their action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Countdown {
+    /// Added to the end of the prompt.
+    #[default]
+    Prompt,
+    /// A line from the proxy on every change.
+    Line,
+    Off,
+}
+
+impl FromStr for Countdown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prompt" => Ok(Countdown::Prompt),
+            "line" => Ok(Countdown::Line),
+            "off" => Ok(Countdown::Off),
+            _ => Err(format!(
+                "invalid countdown `{}`, expected prompt, line or off",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Countdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Countdown::Prompt => "prompt",
+            Countdown::Line => "line",
+            Countdown::Off => "off",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::Frame;
+
+    fn event(id: u8, text: &'static str) -> Option<ActionEvent> {
+        ActionEvent::from_code(&ControlCode::new(
+            id,
+            None::<&[u8]>,
+            vec![Frame::text(text)],
+        ))
+    }
+
+    fn status(kind: ActionKind, name: &str, rounds_left: u32) -> Option<ActionEvent> {
+        Some(ActionEvent::Status(ActionStatus {
+            kind,
+            name: name.to_string(),
+            rounds_left,
+        }))
+    }
+
+    #[test]
+    fn statuses_are_the_name_and_rounds_left() {
+        assert_eq!(
+            event(SPELL_STATUS, "magic missile 3"),
+            status(ActionKind::Spell, "magic missile", 3)
+        );
+        assert_eq!(
+            event(SKILL_STATUS, " bash  1 "),
+            status(ActionKind::Skill, "bash", 1)
+        );
+        assert_eq!(event(ACTION_DONE, ""), Some(ActionEvent::Done));
+    }
+
+    #[test]
+    fn statuses_without_a_name_or_count_are_ignored() {
+        assert_eq!(event(SPELL_STATUS, "3"), None);
+        assert_eq!(event(SPELL_STATUS, "magic missile"), None);
+        assert_eq!(event(SPELL_STATUS, ""), None);
+        assert_eq!(event(43, "bash 1"), None);
+    }
+
+    #[test]
+    fn statuses_read_as_a_line() {
+        let status = |rounds_left| ActionStatus {
+            kind: ActionKind::Spell,
+            name: "heal".to_string(),
+            rounds_left,
+        };
+        assert_eq!(status(1).to_string(), "spell heal: 1 round left");
+        assert_eq!(status(0).to_string(), "spell heal: 0 rounds left");
+    }
+
+    #[test]
+    fn countdowns_are_read_as_they_are_written() {
+        for countdown in [Countdown::Prompt, Countdown::Line, Countdown::Off] {
+            assert_eq!(countdown.to_string().parse(), Ok(countdown));
+        }
+        assert!("bar".parse::<Countdown>().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! The admin console, a line based prompt on a Unix socket or a loopback TCP
+//! port for looking after the proxy while players stay connected:
+//!
+//! - `sessions` lists the connected sessions, with the bytes each read
+//!   from the server and the client and its latency
+//! - `kick <id>` ends a session
+//! - `bc <id> <command>` runs a `#bc` command in a session and shows what it
+//!   answers, e.g. `bc 1 keepalive off`
+//! - `metrics` shows counts over all sessions
+//! - `log [<level>]` shows or sets the log level
+//! - `reload` reads the config file again, see [`ProxyServer::reload`]
+//! - `quit` closes the console
+//!
+//! There is no authentication: whoever can connect can do all of the above,
+//! which is why TCP addresses must be on the loopback interface and Unix
+//! sockets are only open to their owner.
+
+use std::{io, time::Instant};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    time::sleep,
+};
+use tracing::{info, warn};
+
+use crate::{
+    listener::{Listener, ACCEPT_RETRY},
+    logging,
+    server::ProxyServer,
+    session::{Sessions, Summary},
+};
+
+const GREETING: &str = "bcproxy admin console, `help` lists the commands\n";
+const PROMPT: &str = "> ";
+const HELP: &str = "\
+sessions            list the connected sessions
+kick <id>           end a session
+bc <id> <command>   run a #bc command in a session
+metrics             show counts over all sessions
+log [<level>]       show or set the log level
+reload              read the config file again
+quit                close the console";
+
+pub async fn serve(listener: Listener, server: ProxyServer) {
+    let started = Instant::now();
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("admin: accepting a console failed: {}", e);
+                sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        tokio::spawn(console(stream, server.clone(), started));
+    }
+}
+
+async fn console<S>(stream: S, server: ProxyServer, started: Instant)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = handle(stream, &server, started).await {
+        warn!("admin: {}", e);
+    }
+}
+
+async fn handle<S>(stream: S, server: &ProxyServer, started: Instant) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(GREETING.as_bytes()).await?;
+    writer.write_all(PROMPT.as_bytes()).await?;
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line == "quit" {
+            break;
+        }
+        let mut reply = String::new();
+        match run(line, server, started).await {
+            Ok(lines) => {
+                for line in lines {
+                    reply.push_str(&line);
+                    reply.push('\n');
+                }
+            }
+            Err(e) => reply.push_str(&format!("error: {}\n", e)),
+        }
+        reply.push_str(PROMPT);
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    writer.shutdown().await
+}
+
+async fn run(line: &str, server: &ProxyServer, started: Instant) -> Result<Vec<String>, String> {
+    let sessions = server.sessions();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match (name, args.trim()) {
+        ("", "") => Ok(Vec::new()),
+        ("help", "") => Ok(HELP.lines().map(String::from).collect()),
+        ("sessions", "") => Ok(sessions.summaries().iter().map(session_line).collect()),
+        ("kick", id) => {
+            let id = parse_id(id)?;
+            if !sessions.kick(id) {
+                return Err(no_session(id));
+            }
+            info!(id, "session kicked from the admin console");
+            Ok(vec![format!("session {} kicked", id)])
+        }
+        ("bc", args) => {
+            let (id, command) = args
+                .split_once(' ')
+                .ok_or_else(|| "usage: bc <id> <command>".to_string())?;
+            let id = parse_id(id)?;
+            sessions
+                .command(id, command.trim())
+                .await
+                .ok_or_else(|| no_session(id))
+        }
+        ("metrics", "") => Ok(metrics(sessions, started)),
+        ("log", "") => logging::level()
+            .map(|level| vec![format!("log level {}", level)])
+            .ok_or_else(|| "logging is not set up".to_string()),
+        ("log", level) => {
+            logging::set_level(level)?;
+            info!(level, "log level changed from the admin console");
+            Ok(vec![format!("log level {}", level)])
+        }
+        ("reload", "") => match server.reload() {
+            Ok(needs_restart) => {
+                let mut lines = vec!["config reloaded".to_string()];
+                lines.extend(
+                    needs_restart
+                        .iter()
+                        .map(|setting| format!("{} changed, takes a restart", setting)),
+                );
+                Ok(lines)
+            }
+            Err(e) => Err(e.to_string()),
+        },
+        _ => Err(format!("unknown command `{}`, try help", line)),
+    }
+}
+
+fn parse_id(id: &str) -> Result<u64, String> {
+    id.parse()
+        .map_err(|_| format!("`{}` is not a session id", id))
+}
+
+fn no_session(id: u64) -> String {
+    format!("no session {}", id)
+}
+
+fn session_line(summary: &Summary) -> String {
+    let connected = summary
+        .connected_at
+        .elapsed()
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let stats = &summary.stats;
+    let latency = stats
+        .latency
+        .map_or("-".to_string(), |latency| latency.as_millis().to_string());
+    format!(
+        "{} peer {} profile {} connected {}s room {} queue {} peak {} dropped {} \
+         server_bytes {} client_bytes {} latency_ms {} login {}",
+        summary.id,
+        summary.peer,
+        summary.profile.as_deref().unwrap_or("-"),
+        connected,
+        summary.room.as_ref().map_or("-", |room| room.id.as_str()),
+        summary.output_queue.depth,
+        summary.output_queue.peak,
+        summary.output_queue.dropped,
+        stats.server.read,
+        stats.client.read,
+        latency,
+        summary.login_state.name(),
+    )
+}
+
+fn metrics(sessions: &Sessions, started: Instant) -> Vec<String> {
+    let summaries = sessions.summaries();
+    let queues = summaries.iter().map(|summary| summary.output_queue);
+    vec![
+        format!("uptime {}s", started.elapsed().as_secs()),
+        format!("sessions {}", summaries.len()),
+        format!("sessions_served {}", sessions.served()),
+        format!(
+            "sessions_logged_in {}",
+            summaries
+                .iter()
+                .filter(|s| s.login_state.is_authenticated())
+                .count()
+        ),
+        format!(
+            "output_queued {}",
+            queues.clone().map(|q| q.depth).sum::<usize>()
+        ),
+        format!(
+            "output_queue_peak {}",
+            queues.clone().map(|q| q.peak).max().unwrap_or(0)
+        ),
+        format!("output_dropped {}", queues.map(|q| q.dropped).sum::<u64>()),
+        format!(
+            "server_bytes {}",
+            summaries.iter().map(|s| s.stats.server.read).sum::<u64>()
+        ),
+        format!(
+            "client_bytes {}",
+            summaries.iter().map(|s| s.stats.client.read).sum::<u64>()
+        ),
+        format!("log_level {}", logging::level().as_deref().unwrap_or("-")),
+    ]
+}
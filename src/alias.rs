@@ -0,0 +1,50 @@
+use std::{fmt, str::FromStr};
+
+/// A word the player types that stands for a longer command, written as
+/// `<word> => <command>`. `$*` in the command is replaced by what follows
+/// the word, which is otherwise added to the end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    pub name: String,
+    pub command: String,
+}
+
+impl FromStr for Alias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("alias `{}` has no `=>`", s))?;
+        let (name, command) = (name.trim(), command.trim());
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("alias name `{}` must be a single word", name));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} => {}", self.name, self.command)
+    }
+}
+
+/// The command `line` stands for if it starts with one of `aliases`.
+pub fn expand(aliases: &[Alias], line: &str) -> Option<String> {
+    let line = line.trim();
+    let (word, args) = line.split_once(' ').unwrap_or((line, ""));
+    // Later aliases, such as those of a profile, win.
+    let alias = aliases.iter().rev().find(|alias| alias.name == word)?;
+    let args = args.trim();
+    Some(if alias.command.contains("$*") {
+        alias.command.replace("$*", args)
+    } else if args.is_empty() {
+        alias.command.clone()
+    } else {
+        format!("{} {}", alias.command, args)
+    })
+}
@@ -0,0 +1,551 @@
+//! A read-only HTTP API for dashboards, answering `GET` requests with JSON:
+//!
+//! - `/api/rooms?area=<area>` the rooms of an area and the links out of them
+//! - `/api/room/<id>` a single room
+//! - `/api/map?area=<area>` an SVG image of the map of an area, see
+//!   [`export::to_svg`]
+//! - `/api/monsters[?area=<area>]` the latest kills
+//! - `/api/sessions` the connected clients, the room each is in and its
+//!   traffic
+//! - `/api/vitals/latest[?session=<id>]` the points last reported, in any
+//!   session or the one given
+//!
+//! and `/metrics` with the traffic of each session for Prometheus.
+//!
+//! Like the client in [`crate::http`] it only speaks enough HTTP/1.0 for
+//! that, closing the connection after each response. A request not read
+//! within [`REQUEST_TIMEOUT`] is dropped. There is no authentication, so
+//! `api_listen` must be on the loopback interface or a Unix socket.
+
+use std::{
+    fmt::Write,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    db::{Db, Link, Monster},
+    export,
+    listener::{Listener, ACCEPT_RETRY},
+    login::LoginState,
+    mapper::Room,
+    session::{Sessions, Summary, Traffic},
+};
+
+const MAX_REQUEST: usize = 8 * 1024;
+const MONSTER_LIMIT: u32 = 500;
+const JSON: &str = "application/json";
+const METRICS: &str = "text/plain; version=0.0.4";
+const SVG: &str = "image/svg+xml";
+/// How long a client has to send its request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Response {
+    Json(Value),
+    /// Prometheus text exposition format.
+    Metrics(String),
+    Svg(String),
+    Error(u16, String),
+}
+
+pub async fn serve(listener: Listener, db: Option<Db>, sessions: Sessions) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("api: accepting a request failed: {}", e);
+                sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let db = db.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, db, sessions).await {
+                tracing::warn!("api: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle<S>(mut stream: S, db: Option<Db>, sessions: Sessions) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no request in time",
+            ))
+        }
+    };
+    let request = match request {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or_default().split(' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => {
+            let target = target.to_string();
+            tokio::task::spawn_blocking(move || route(&target, db.as_ref(), &sessions))
+                .await
+                .unwrap_or_else(|e| Response::Error(500, e.to_string()))
+        }
+        _ => Response::Error(405, "only GET is supported".to_string()),
+    };
+
+    let (status, content_type, body) = match response {
+        Response::Json(value) => (200, JSON, value.to_string()),
+        Response::Metrics(text) => (200, METRICS, text),
+        Response::Svg(svg) => (200, SVG, svg),
+        Response::Error(status, message) => (status, JSON, json!({ "error": message }).to_string()),
+    };
+    let head = format!(
+        "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The head of a request, or `None` if the client closed the connection
+/// or sent too much.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(request))
+}
+
+fn route(target: &str, db: Option<&Db>, sessions: &Sessions) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| decode(value))
+    };
+
+    match path {
+        "/api/sessions" => return Response::Json(sessions_json(sessions)),
+        "/metrics" => return Response::Metrics(metrics(sessions)),
+        "/api/vitals/latest" => return latest_vitals(sessions, param("session").as_deref()),
+        _ => {}
+    }
+    let db = match db {
+        Some(db) => db,
+        None => return Response::Error(404, "no database configured".to_string()),
+    };
+    let result = match path.strip_prefix("/api/room/") {
+        Some(id) => db.room(&decode(id)).map(|room| match room {
+            Some(room) => Response::Json(room_json(&room)),
+            None => Response::Error(404, "no such room".to_string()),
+        }),
+        None => match path {
+            "/api/rooms" => match param("area") {
+                Some(area) => db.area(&area).map(|(rooms, links)| {
+                    Response::Json(json!({
+                        "area": area,
+                        "rooms": rooms.iter().map(room_json).collect::<Vec<_>>(),
+                        "links": links.iter().map(link_json).collect::<Vec<_>>(),
+                    }))
+                }),
+                None => Ok(Response::Error(400, "area is required".to_string())),
+            },
+            "/api/map" => match param("area") {
+                Some(area) => db.area(&area).map(|(rooms, links)| {
+                    if rooms.is_empty() {
+                        Response::Error(404, "no rooms in area".to_string())
+                    } else {
+                        Response::Svg(export::to_svg(&area, &rooms, &links))
+                    }
+                }),
+                None => Ok(Response::Error(400, "area is required".to_string())),
+            },
+            "/api/monsters" => db
+                .monsters(param("area").as_deref(), MONSTER_LIMIT)
+                .map(|monsters| Response::Json(monsters.iter().map(monster_json).collect())),
+            _ => Ok(Response::Error(404, "not found".to_string())),
+        },
+    };
+    result.unwrap_or_else(|e| Response::Error(500, format!("db: {}", e)))
+}
+
+fn room_json(room: &Room) -> Value {
+    json!({
+        "id": room.id,
+        "area": room.area,
+        "short_desc": room.short_desc,
+        "long_desc": room.long_desc,
+        "indoors": room.indoors,
+        "exits": room.exits,
+    })
+}
+
+fn link_json(link: &Link) -> Value {
+    json!({ "from": link.from, "to": link.to, "direction": link.direction })
+}
+
+fn monster_json(monster: &Monster) -> Value {
+    json!({
+        "name": monster.name,
+        "exp": monster.exp,
+        "area": monster.area,
+        "room_id": monster.room_id,
+        "killed_at": monster.killed_at,
+    })
+}
+
+fn sessions_json(sessions: &Sessions) -> Value {
+    sessions
+        .summaries()
+        .iter()
+        .map(|summary| {
+            json!({
+                "id": summary.id,
+                "peer": summary.peer,
+                "profile": summary.profile,
+                "connected_at": unix_time(summary.connected_at),
+                "room": summary.room.as_ref().map(room_json),
+                "output_queue": {
+                    "depth": summary.output_queue.depth,
+                    "peak": summary.output_queue.peak,
+                    "dropped": summary.output_queue.dropped,
+                },
+                "login": login_json(summary.login_state),
+                "stats": {
+                    "server": traffic_json(&summary.stats.server),
+                    "client": traffic_json(&summary.stats.client),
+                    "latency_ms": summary.stats.latency.map(|l| l.as_millis() as u64),
+                    "server_connected_secs": summary.stats.server_connected_at.elapsed().as_secs(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// The points last reported in session `id`, or in any session.
+fn latest_vitals(sessions: &Sessions, id: Option<&str>) -> Response {
+    let id = match id.map(str::parse::<u64>).transpose() {
+        Ok(id) => id,
+        Err(_) => return Response::Error(400, "session must be a number".to_string()),
+    };
+    let latest = sessions
+        .summaries()
+        .into_iter()
+        .filter(|summary| id.is_none_or(|id| summary.id == id))
+        .filter_map(|summary| Some((summary.id, summary.vitals?)))
+        .max_by_key(|(_, (_, reported_at))| *reported_at);
+    let (id, (vitals, reported_at)) = match latest {
+        Some(latest) => latest,
+        None => return Response::Error(404, "no vitals reported".to_string()),
+    };
+    Response::Json(json!({
+        "session": id,
+        "hp": vitals.hp,
+        "max_hp": vitals.max_hp,
+        "sp": vitals.sp,
+        "max_sp": vitals.max_sp,
+        "ep": vitals.ep,
+        "max_ep": vitals.max_ep,
+        "reported_at": unix_time(reported_at),
+    }))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn login_json(state: LoginState) -> Value {
+    let reason = match state {
+        LoginState::Failed(reason) => Some(reason.name()),
+        _ => None,
+    };
+    json!({ "state": state.name(), "reason": reason })
+}
+
+fn traffic_json(traffic: &Traffic) -> Value {
+    json!({ "read": traffic.read, "written": traffic.written, "frames": traffic.frames })
+}
+
+/// The value of a gauge for a session, if it has one.
+type Gauge = fn(&Summary) -> Option<f64>;
+
+/// The metrics of each session, labeled with its id.
+fn metrics(sessions: &Sessions) -> String {
+    let summaries = sessions.summaries();
+    let mut out = String::new();
+    header(&mut out, "bcproxy_sessions", "gauge", "Connected sessions.");
+    let _ = writeln!(out, "bcproxy_sessions {}", summaries.len());
+    header(
+        &mut out,
+        "bcproxy_sessions_served_total",
+        "counter",
+        "Sessions since the proxy started.",
+    );
+    let _ = writeln!(out, "bcproxy_sessions_served_total {}", sessions.served());
+
+    header(
+        &mut out,
+        "bcproxy_session_bytes_total",
+        "counter",
+        "Bytes read from one side and written to the other, by the side they came from.",
+    );
+    for summary in &summaries {
+        let stats = &summary.stats;
+        for (from, traffic) in [("server", stats.server), ("client", stats.client)] {
+            for (op, bytes) in [("read", traffic.read), ("written", traffic.written)] {
+                let _ = writeln!(
+                    out,
+                    "bcproxy_session_bytes_total{{session=\"{}\",from=\"{}\",op=\"{}\"}} {}",
+                    summary.id, from, op, bytes
+                );
+            }
+        }
+    }
+    header(
+        &mut out,
+        "bcproxy_session_frames_total",
+        "counter",
+        "Frames decoded from the server and lines sent to it.",
+    );
+    for summary in &summaries {
+        let stats = &summary.stats;
+        for (from, traffic) in [("server", stats.server), ("client", stats.client)] {
+            let _ = writeln!(
+                out,
+                "bcproxy_session_frames_total{{session=\"{}\",from=\"{}\"}} {}",
+                summary.id, from, traffic.frames
+            );
+        }
+    }
+
+    let gauges: [(&str, &str, Gauge); 6] = [
+        (
+            "bcproxy_session_logged_in",
+            "Whether the player is logged in to the server.",
+            |summary| Some(summary.login_state.is_authenticated() as u8 as f64),
+        ),
+        (
+            "bcproxy_session_latency_seconds",
+            "Smoothed time from a command to the next prompt.",
+            |summary| summary.stats.latency.map(|latency| latency.as_secs_f64()),
+        ),
+        (
+            "bcproxy_session_uptime_seconds",
+            "Time since the client connected.",
+            |summary| Some(summary.stats.connected_at.elapsed().as_secs_f64()),
+        ),
+        (
+            "bcproxy_session_server_uptime_seconds",
+            "Time since the server connection was opened.",
+            |summary| Some(summary.stats.server_connected_at.elapsed().as_secs_f64()),
+        ),
+        (
+            "bcproxy_session_output_queue_bytes",
+            "Server output waiting for the client.",
+            |summary| Some(summary.output_queue.depth as f64),
+        ),
+        (
+            "bcproxy_session_output_dropped_bytes",
+            "Server output dropped because the client fell behind.",
+            |summary| Some(summary.output_queue.dropped as f64),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        for summary in &summaries {
+            if let Some(value) = value(summary) {
+                let _ = writeln!(out, "{}{{session=\"{}\"}} {}", name, summary.id, value);
+            }
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Percent-decode a path segment or query value.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(b) => {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                None => out.push(b'%'),
+            },
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vitals::Vitals;
+
+    /// Send `request` and return the status line and the body.
+    async fn send(request: &str, db: Option<Db>, sessions: &Sessions) -> (String, String) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let handled = tokio::spawn(handle(server, db, sessions.clone()));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handled.await.unwrap().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    async fn get(target: &str, db: Option<Db>, sessions: &Sessions) -> (String, String) {
+        send(&format!("GET {} HTTP/1.0\r\n\r\n", target), db, sessions).await
+    }
+
+    fn vitals(hp: i64) -> Vitals {
+        Vitals {
+            hp,
+            max_hp: 100,
+            sp: 20,
+            max_sp: 50,
+            ep: 30,
+            max_ep: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_latest_vitals_are_served() {
+        let sessions = Sessions::default();
+        let (status, _) = get("/api/vitals/latest", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+
+        let first = sessions.register("a".to_string(), None);
+        let second = sessions.register("b".to_string(), None);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        first.update(|summary| summary.vitals = Some((vitals(80), at(200))));
+        second.update(|summary| summary.vitals = Some((vitals(40), at(100))));
+
+        let (status, body) = get("/api/vitals/latest", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["session"], first.id());
+        assert_eq!(json["hp"], 80);
+        assert_eq!(json["max_ep"], 60);
+        assert_eq!(json["reported_at"], 200);
+
+        let target = format!("/api/vitals/latest?session={}", second.id());
+        let (_, body) = get(&target, None, &sessions).await;
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["hp"], 40);
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_refused() {
+        let sessions = Sessions::default();
+        let (status, body) = get("/api/vitals/latest?session=me", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 400 Bad Request");
+        assert_eq!(body, r#"{"error":"session must be a number"}"#);
+        let (status, _) = get("/api/vitals/latest?session=7", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+
+        let (status, _) = send("POST /api/sessions HTTP/1.0\r\n\r\n", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 405 Method Not Allowed");
+        // Routes of the database without one.
+        let (status, body) = get("/api/rooms?area=x", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+        assert_eq!(body, r#"{"error":"no database configured"}"#);
+    }
+
+    #[tokio::test]
+    async fn database_routes_check_their_parameters() {
+        let path = std::env::temp_dir().join(format!("bcproxy-api-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Db::open(&path).unwrap();
+        let sessions = Sessions::default();
+
+        for target in ["/api/rooms", "/api/map"] {
+            let (status, body) = get(target, Some(db.clone()), &sessions).await;
+            assert_eq!(status, "HTTP/1.0 400 Bad Request", "{}", target);
+            assert_eq!(body, r#"{"error":"area is required"}"#);
+        }
+        let (status, _) = get("/api/room/nowhere", Some(db.clone()), &sessions).await;
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+        let (status, _) = get("/api/nothing", Some(db.clone()), &sessions).await;
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+        let (status, body) = get("/api/rooms?area=a%20b", Some(db.clone()), &sessions).await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert_eq!(body, r#"{"area":"a b","links":[],"rooms":[]}"#);
+        let (status, body) = get("/api/monsters", Some(db), &sessions).await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert_eq!(body, "[]");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_and_metrics_are_listed() {
+        let sessions = Sessions::default();
+        let listing = sessions.register("127.0.0.1:5000".to_string(), Some("alt".to_string()));
+        let (status, body) = get("/api/sessions", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["id"], listing.id());
+        assert_eq!(json[0]["peer"], "127.0.0.1:5000");
+        assert_eq!(json[0]["profile"], "alt");
+
+        let (status, body) = get("/metrics", None, &sessions).await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert!(body.contains("\nbcproxy_sessions 1\n"), "{}", body);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_request_not_sent_in_time_is_dropped() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let e = handle(server, None, Sessions::default()).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
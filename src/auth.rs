@@ -0,0 +1,262 @@
+//! The password clients give before anything is sent to the server, asked
+//! on addresses other machines can reach. Clients type it as their first
+//! line, or send it as the telnet NEW-ENVIRON variable `BCPROXY_PASSWORD`.
+//!
+//! After `auth_max_failures` wrong passwords in a row from a host, it is
+//! locked out for `auth_lockout_secs`: its connections are closed straight
+//! away, right password or not, including those already asked for one.
+//!
+//! The password is resolved when the first client gives one and kept for
+//! as long as the config, so `auth_password_command` does not run for
+//! every connection.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::OnceCell,
+};
+use tracing::{info, warn};
+
+use crate::{
+    login::{Password, Secret},
+    telnet::{self, Command, Segment, DO, ECHO, IAC, NEW_ENVIRON, WILL, WONT},
+};
+
+/// The NEW-ENVIRON user variable clients can send the password in.
+pub const ENVIRON_VAR: &str = "BCPROXY_PASSWORD";
+
+const PROMPT: &str = "[bcproxy] password: ";
+/// Longest password line read.
+const MAX_LINE: usize = 256;
+
+/// Client authentication settings. Off while `password` is not set.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub password: Option<Secret>,
+    /// Wrong passwords in a row before a host is locked out.
+    pub max_failures: u32,
+    pub lockout: Duration,
+    /// `password` once resolved, shared by the clones of this config. A
+    /// reloaded config resolves it again.
+    resolved: Arc<OnceCell<Password>>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            password: None,
+            max_failures: 3,
+            lockout: Duration::from_secs(10 * 60),
+            resolved: Arc::default(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.password.is_some()
+    }
+}
+
+/// The wrong passwords of each host, shared by all listeners.
+#[derive(Debug, Clone, Default)]
+pub struct Lockouts {
+    hosts: Arc<Mutex<HashMap<String, Failures>>>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Lockouts {
+    /// What to tell a client of `host` while it is locked out, if it is.
+    fn refusal(&self, host: &str, config: &AuthConfig) -> Option<String> {
+        let left = self.locked(host, config)?;
+        Some(format!(
+            "[bcproxy] too many wrong passwords, try again in {} minutes\r\n",
+            left.as_secs().div_ceil(60)
+        ))
+    }
+
+    /// How long `host` is still locked out for, if it is.
+    fn locked(&self, host: &str, config: &AuthConfig) -> Option<Duration> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        // Failures are forgotten once a lockout would have run out.
+        hosts.retain(|_, failures| match failures.locked_until {
+            Some(until) => until > now,
+            None => now.duration_since(failures.last) < config.lockout,
+        });
+        hosts
+            .get(host)
+            .and_then(|failures| failures.locked_until)
+            .map(|until| until - now)
+    }
+
+    /// Count a wrong password from `host`. Returns whether it is now locked
+    /// out.
+    fn fail(&self, host: &str, config: &AuthConfig) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        let failures = hosts.entry(host.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= config.max_failures {
+            failures.locked_until = Some(now + config.lockout);
+            return true;
+        }
+        false
+    }
+
+    fn succeed(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+}
+
+/// The host part of a peer address, which is what gets locked out.
+fn host(peer: &str) -> String {
+    peer.parse::<SocketAddr>()
+        .map_or_else(|_| peer.to_string(), |addr| addr.ip().to_string())
+}
+
+/// Ask the client on `stream` for the password until it gets it right or
+/// is locked out. Returns whether it got in. Telnet clients are also asked
+/// for the password in NEW-ENVIRON and do not echo what they type.
+pub async fn authenticate<S>(
+    stream: &mut S,
+    peer: &str,
+    config: &AuthConfig,
+    lockouts: &Lockouts,
+    telnet: bool,
+) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = host(peer);
+    if let Some(reply) = lockouts.refusal(&host, config) {
+        warn!(%peer, "connection from a locked out host");
+        stream.write_all(reply.as_bytes()).await?;
+        return Ok(false);
+    }
+    let secret = match &config.password {
+        Some(secret) => secret,
+        None => return Ok(true),
+    };
+
+    let mut answers = Answers::new(telnet);
+    if telnet {
+        stream
+            .write_all(&[IAC, DO, NEW_ENVIRON, IAC, WILL, ECHO])
+            .await?;
+    }
+    loop {
+        stream.write_all(PROMPT.as_bytes()).await?;
+        let given = answers.next(stream).await?;
+        // Other connections from the host may have locked it out since.
+        if let Some(reply) = lockouts.refusal(&host, config) {
+            warn!(%peer, "password from a locked out host");
+            stream.write_all(b"\r\n").await?;
+            stream.write_all(reply.as_bytes()).await?;
+            return Ok(false);
+        }
+        let password = config.resolved.get_or_try_init(|| secret.resolve()).await?;
+        if password.matches(&given) {
+            lockouts.succeed(&host);
+            info!(%peer, "client authenticated");
+            if telnet {
+                stream.write_all(&[IAC, WONT, ECHO]).await?;
+            }
+            stream.write_all(b"\r\n").await?;
+            return Ok(true);
+        }
+        warn!(%peer, "wrong password");
+        if lockouts.fail(&host, config) {
+            warn!(%peer, host, "locked out after {} wrong passwords", config.max_failures);
+            stream
+                .write_all(b"\r\n[bcproxy] too many wrong passwords\r\n")
+                .await?;
+            return Ok(false);
+        }
+        stream
+            .write_all(b"\r\n[bcproxy] wrong password\r\n")
+            .await?;
+    }
+}
+
+/// Passwords a client gives, as lines or in NEW-ENVIRON.
+struct Answers {
+    parser: telnet::Parser,
+    line: Vec<u8>,
+    telnet: bool,
+}
+
+impl Answers {
+    fn new(telnet: bool) -> Self {
+        Self {
+            parser: telnet::Parser::new(),
+            line: Vec::new(),
+            telnet,
+        }
+    }
+
+    async fn next<S>(&mut self, stream: &mut S) -> io::Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            if let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+                let mut given: Vec<u8> = self.line.drain(..=end).collect();
+                while given.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+                    given.pop();
+                }
+                return Ok(given);
+            }
+            if self.line.len() > MAX_LINE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+            let mut buf = [0; 256];
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if !self.telnet {
+                self.line.extend_from_slice(&buf[..n]);
+                continue;
+            }
+            let mut segments = Vec::new();
+            self.parser.parse(&buf[..n], &mut segments);
+            let mut environ = None;
+            // Other telnet commands are dropped, the session negotiates
+            // again once it starts.
+            for segment in segments {
+                match segment {
+                    Segment::Data(data) => self.line.extend_from_slice(&data),
+                    Segment::Command(Command::Will(NEW_ENVIRON), _) => {
+                        stream.write_all(&telnet::environ_send(ENVIRON_VAR)).await?;
+                    }
+                    Segment::Command(Command::Sub(NEW_ENVIRON, data), _) => {
+                        environ = telnet::environ_value(&data, ENVIRON_VAR);
+                    }
+                    Segment::Command(..) => {}
+                }
+            }
+            if let Some(given) = environ {
+                return Ok(given);
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Combat as told by `spec_battle` messages: round markers, hits and
+//! deaths, tallied per fight.
+//!
+//! A hit is a line with a damage verb such as `You massacre Orc with a
+//! mighty blow.` or `Orc tickles you.`, read as attacker, verb, target and
+//! whatever follows the target as the special. A fight ends when somebody
+//! dies or no battle message has come for a while.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Sleep};
+
+use crate::{bc::Frame, color};
+
+pub const BATTLE_ATTR: &[u8] = b"spec_battle";
+
+/// Time without battle messages after which a fight is over.
+const FIGHT_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Damage verbs, weakest first, in the form used after `You`.
+const DAMAGE_VERBS: &[&str] = &[
+    "tickle",
+    "graze",
+    "scratch",
+    "bruise",
+    "hurt",
+    "injure",
+    "wound",
+    "hit",
+    "strike",
+    "cut",
+    "slash",
+    "pierce",
+    "smash",
+    "crush",
+    "maul",
+    "massacre",
+    "destroy",
+    "obliterate",
+    "annihilate",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub attacker: String,
+    pub target: String,
+    /// The verb as in `You <verb>`, e.g. `massacre`.
+    pub verb: String,
+    /// What the line says after the target, e.g. `with a mighty blow`.
+    pub special: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BattleEvent {
+    Round(u32),
+    Hit(Hit),
+    /// Somebody `is DEAD, R.I.P.`
+    Death(String),
+}
+
+/// Read a line of a `spec_battle` message.
+pub fn parse(line: &str) -> Option<BattleEvent> {
+    let line = line.trim();
+    if let Some(name) = line.strip_suffix(" is DEAD, R.I.P.") {
+        return Some(BattleEvent::Death(name.to_string()));
+    }
+    if line.starts_with('*') {
+        let round = line.trim_matches(|c: char| c == '*' || c.is_whitespace());
+        return round
+            .strip_prefix("Round ")
+            .and_then(|n| n.trim().parse().ok())
+            .map(BattleEvent::Round);
+    }
+
+    let line = line.trim_end_matches(['.', '!']);
+    let words: Vec<&str> = line.split(' ').collect();
+    let (i, verb) = words
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(i, word)| damage_verb(word).map(|verb| (i, verb)))?;
+    let attacker = words[..i].join(" ");
+    let rest = words[i + 1..].join(" ");
+    let (target, special) = match [" with ", ", ", " and "]
+        .iter()
+        .filter_map(|sep| rest.find(sep).map(|at| (at, sep.len())))
+        .min()
+    {
+        Some((at, len)) => (&rest[..at], Some(rest[at + len..].trim().to_string())),
+        None => (rest.as_str(), None),
+    };
+    if target.is_empty() {
+        return None;
+    }
+
+    Some(BattleEvent::Hit(Hit {
+        attacker,
+        target: target.to_string(),
+        verb: verb.to_string(),
+        special: special.filter(|s| !s.is_empty()),
+    }))
+}
+
+/// The damage verb `word` is a form of, `hits` and `smashes` included.
+fn damage_verb(word: &str) -> Option<&'static str> {
+    DAMAGE_VERBS.iter().copied().find(|verb| {
+        let suffix = word.strip_prefix(verb);
+        matches!(suffix, Some("" | "s" | "es"))
+    })
+}
+
+#[derive(Default)]
+struct Tally {
+    hits: u32,
+    specials: u32,
+    verbs: BTreeMap<&'static str, u32>,
+}
+
+#[derive(Default)]
+struct Fight {
+    rounds: u32,
+    tallies: BTreeMap<String, Tally>,
+    dead: Vec<String>,
+}
+
+impl Fight {
+    fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        for (attacker, tally) in &self.tallies {
+            // Strongest verbs first.
+            let mut verbs: Vec<(&&str, &u32)> = tally.verbs.iter().collect();
+            verbs.sort_by_key(|(verb, _)| {
+                std::cmp::Reverse(DAMAGE_VERBS.iter().position(|v| v == *verb))
+            });
+            let verbs: Vec<String> = verbs
+                .iter()
+                .map(|(verb, n)| format!("{} {}", n, verb))
+                .collect();
+            let mut part = format!(
+                "{} {} hit{} ({})",
+                attacker,
+                tally.hits,
+                if tally.hits == 1 { "" } else { "s" },
+                verbs.join(", ")
+            );
+            if tally.specials > 0 {
+                part.push_str(&format!(", {} special", tally.specials));
+            }
+            parts.push(part);
+        }
+
+        let mut summary = format!(
+            "fight over after {} round{}",
+            self.rounds,
+            if self.rounds == 1 { "" } else { "s" }
+        );
+        if !parts.is_empty() {
+            summary.push_str(": ");
+            summary.push_str(&parts.join("; "));
+        }
+        if !self.dead.is_empty() {
+            summary.push_str(&format!(", {} died", self.dead.join(" and ")));
+        }
+        summary
+    }
+}
+
+/// Follows the fights of a session.
+#[derive(Default)]
+pub struct Battle {
+    fight: Option<Fight>,
+    timeout: Option<Pin<Box<Sleep>>>,
+}
+
+impl Battle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the battle message in `frame`, if it is one. Returns the
+    /// summary of the fight if it ended.
+    pub fn observe(&mut self, frame: &Frame) -> Option<String> {
+        let text = match frame {
+            Frame::Code(code) if code.id == 10 && code.attr_is(BATTLE_ATTR) => code.text(),
+            // Deaths may come as plain text.
+            Frame::Text(text) if self.fight.is_some() => text.to_vec(),
+            _ => return None,
+        };
+
+        let text = color::strip_ansi(&text);
+        let battle = frame.code().is_some();
+        let mut ended = false;
+        for event in text.lines().filter_map(parse) {
+            if !battle && !matches!(event, BattleEvent::Death(_)) {
+                continue;
+            }
+            let fight = self.fight.get_or_insert_with(Fight::default);
+            match event {
+                BattleEvent::Round(_) => fight.rounds += 1,
+                BattleEvent::Hit(hit) => {
+                    let tally = fight.tallies.entry(hit.attacker).or_default();
+                    tally.hits += 1;
+                    tally.specials += u32::from(hit.special.is_some());
+                    if let Some(verb) = damage_verb(&hit.verb) {
+                        *tally.verbs.entry(verb).or_default() += 1;
+                    }
+                }
+                BattleEvent::Death(name) => {
+                    fight.dead.push(name);
+                    ended = true;
+                }
+            }
+        }
+
+        if ended {
+            return self.end();
+        }
+        if battle && self.fight.is_some() {
+            self.timeout = Some(Box::pin(sleep(FIGHT_TIMEOUT)));
+        }
+        None
+    }
+
+    /// The summary of the fight once it has gone quiet.
+    pub fn poll_end(&mut self, cx: &mut Context<'_>) -> Poll<String> {
+        let timeout = match self.timeout.as_mut() {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        ready!(timeout.as_mut().poll(cx));
+        match self.end() {
+            Some(summary) => Poll::Ready(summary),
+            None => Poll::Pending,
+        }
+    }
+
+    fn end(&mut self) -> Option<String> {
+        self.timeout = None;
+        self.fight.take().map(|fight| fight.summary())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::ControlCode;
+
+    fn hit(attacker: &str, verb: &str, target: &str, special: Option<&str>) -> Option<BattleEvent> {
+        Some(BattleEvent::Hit(Hit {
+            attacker: attacker.to_string(),
+            target: target.to_string(),
+            verb: verb.to_string(),
+            special: special.map(str::to_string),
+        }))
+    }
+
+    fn battle(text: &str) -> Frame {
+        Frame::Code(ControlCode::new(
+            10,
+            Some(BATTLE_ATTR),
+            vec![Frame::text(text.to_string())],
+        ))
+    }
+
+    #[test]
+    fn hits_are_read_as_attacker_verb_target_and_special() {
+        assert_eq!(
+            parse("You massacre Orc with a mighty blow."),
+            hit("You", "massacre", "Orc", Some("a mighty blow"))
+        );
+        assert_eq!(parse("Orc tickles you."), hit("Orc", "tickle", "you", None));
+        assert_eq!(
+            parse("Big orc smashes the small elf, sending it flying!"),
+            hit(
+                "Big orc",
+                "smash",
+                "the small elf",
+                Some("sending it flying")
+            )
+        );
+        assert_eq!(
+            parse("Al hits Bob and cackles"),
+            hit("Al", "hit", "Bob", Some("cackles"))
+        );
+    }
+
+    #[test]
+    fn rounds_and_deaths_are_read() {
+        assert_eq!(parse("*** Round 12 ***"), Some(BattleEvent::Round(12)));
+        assert_eq!(parse("**Round 3**"), Some(BattleEvent::Round(3)));
+        assert_eq!(
+            parse("Small orc is DEAD, R.I.P."),
+            Some(BattleEvent::Death("Small orc".to_string()))
+        );
+    }
+
+    #[test]
+    fn other_lines_are_no_events() {
+        for line in [
+            "*** Stars ***",
+            "Hit the road.",
+            "You hitch a ride.",
+            "You hit.",
+            "",
+        ] {
+            assert_eq!(parse(line), None, "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_death_ends_the_fight_with_its_summary() {
+        let mut battle_state = Battle::new();
+        assert_eq!(battle_state.observe(&battle("*** Round 1 ***\n")), None);
+        assert_eq!(
+            battle_state.observe(&battle(
+                "You hit Orc.\nYou massacre Orc with a kick.\nOrc tickles you.\n"
+            )),
+            None
+        );
+        assert_eq!(battle_state.observe(&battle("*** Round 2 ***\n")), None);
+        assert_eq!(
+            battle_state.observe(&Frame::text("Orc is DEAD, R.I.P.\r\n")),
+            Some(
+                "fight over after 2 rounds: Orc 1 hit (1 tickle); \
+                 You 2 hits (1 massacre, 1 hit), 1 special, Orc died"
+                    .to_string()
+            )
+        );
+        assert!(battle_state.fight.is_none());
+        assert!(battle_state.timeout.is_none());
+    }
+
+    #[tokio::test]
+    async fn plain_text_outside_a_fight_is_ignored() {
+        let mut battle_state = Battle::new();
+        assert_eq!(
+            battle_state.observe(&Frame::text("Orc is DEAD, R.I.P.\r\n")),
+            None
+        );
+        // Only deaths count in plain text.
+        battle_state.observe(&battle("*** Round 1 ***\n"));
+        battle_state.observe(&Frame::text("You hit Orc.\r\n"));
+        assert!(battle_state.fight.as_ref().unwrap().tallies.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_quiet_fight_ends_after_the_timeout() {
+        let mut battle_state = Battle::new();
+        battle_state.observe(&battle("You hit Orc.\n"));
+        let summary = std::future::poll_fn(|cx| battle_state.poll_end(cx)).await;
+        assert_eq!(summary, "fight over after 0 rounds: You 1 hit (1 hit)");
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use crate::{bc::Frame, mapper::Mapper};
+
+/// BC protocol features the server has been seen to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+const NAMES: [(Capabilities, &str); 6] = [
+    (Capabilities::CONTROL_CODES, "control-codes"),
+    (Capabilities::LOGIN, "login"),
+    (Capabilities::MESSAGES, "messages"),
+    (Capabilities::PROMPT, "prompt"),
+    (Capabilities::CLEAR_SCREEN, "clear-screen"),
+    (Capabilities::MAPPER, "mapper"),
+];
+
+impl Capabilities {
+    /// Any control code at all, i.e. the server is in BC mode.
+    pub const CONTROL_CODES: Self = Self(1 << 0);
+    /// Connection success or failure codes 05 and 06.
+    pub const LOGIN: Self = Self(1 << 1);
+    /// Typed messages, code 10.
+    pub const MESSAGES: Self = Self(1 << 2);
+    /// `spec_prompt` messages.
+    pub const PROMPT: Self = Self(1 << 3);
+    /// Clear screen, code 11.
+    pub const CLEAR_SCREEN: Self = Self(1 << 4);
+    /// `BAT_MAPPER` custom info.
+    pub const MAPPER: Self = Self(1 << 5);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn observe(&mut self, frame: &Frame) {
+        let code = match frame.code() {
+            Some(code) => code,
+            None => return,
+        };
+
+        self.insert(Self::CONTROL_CODES);
+        match code.id {
+            5 | 6 => self.insert(Self::LOGIN),
+            10 => {
+                self.insert(Self::MESSAGES);
+                if code.attr_is(b"spec_prompt") {
+                    self.insert(Self::PROMPT);
+                }
+            }
+            11 => self.insert(Self::CLEAR_SCREEN),
+            99 if Mapper::from_code(code).is_some() => self.insert(Self::MAPPER),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = NAMES
+            .iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(","))
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! A catalog of the control codes the proxy does not know, so that codes
+//! BatMUD adds can be found and supported instead of passing through
+//! unnoticed. Each code id and attribute is logged the first time a session
+//! sees it, counted with `#bc codes`, and kept in the `unknown_codes` table
+//! of the database with a count and a sample body.
+//!
+//! Every code a session sees, known or not, is also counted by id with its
+//! size, for `;;codes` to show what the server is sending.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    action::{ACTION_DONE, SKILL_STATUS, SPELL_STATUS},
+    bc::{ControlCode, Frame},
+    db::Event,
+    effect::PLAYER_EFFECT,
+    exp::PLAYER_FREE_EXP,
+    link::{HYPERLINK, IN_GAME_LINK},
+    login::{LOGIN_FAILURE, LOGIN_SUCCESS},
+    session::Session,
+    target::PLAYER_TARGET,
+    vitals::PLAYER_STATUS,
+};
+
+/// The codes something in the proxy looks at: login, messages, clear
+/// screen, colors, spells and skills, player status, free experience,
+/// outworld location, party status, hyperlinks and in-game links, effects,
+/// target and custom info such as `BAT_MAPPER`.
+pub const KNOWN: &[u8] = &[
+    LOGIN_SUCCESS,
+    LOGIN_FAILURE,
+    10,
+    11,
+    20,
+    21,
+    SPELL_STATUS,
+    SKILL_STATUS,
+    ACTION_DONE,
+    PLAYER_STATUS,
+    PLAYER_FREE_EXP,
+    60,
+    62,
+    HYPERLINK,
+    IN_GAME_LINK,
+    PLAYER_EFFECT,
+    PLAYER_TARGET,
+    99,
+];
+
+/// Longest sample body kept, in bytes.
+const MAX_SAMPLE: usize = 200;
+/// Most distinct codes a session counts, in case an attribute carries
+/// changing data.
+const MAX_CODES: usize = 256;
+
+/// The unknown codes a session has seen and how often.
+#[derive(Debug, Default)]
+pub struct UnknownCodes {
+    counts: HashMap<(u8, Option<Bytes>), u64>,
+}
+
+impl UnknownCodes {
+    /// The codes with their counts, by id and attribute.
+    pub fn counts(&self) -> Vec<(u8, Option<&[u8]>, u64)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .iter()
+            .map(|((id, attr), &count)| (*id, attr.as_deref(), count))
+            .collect();
+        counts.sort();
+        counts
+    }
+}
+
+/// What a session has seen of each control code id.
+#[derive(Debug, Default)]
+pub struct CodeStats {
+    codes: BTreeMap<u8, CodeStat>,
+}
+
+/// What a session has seen of one control code id.
+#[derive(Debug, Clone)]
+pub struct CodeStat {
+    pub count: u64,
+    /// Bytes of the codes as the server sent them, nested codes included.
+    pub bytes: u64,
+    pub last_seen: Instant,
+    /// The attribute of the last one that had one.
+    pub attr: Option<Bytes>,
+}
+
+impl CodeStats {
+    /// The codes seen, by id.
+    pub fn codes(&self) -> impl Iterator<Item = (u8, &CodeStat)> {
+        self.codes.iter().map(|(&id, stat)| (id, stat))
+    }
+
+    fn count(&mut self, code: &ControlCode, now: Instant) {
+        let stat = self.codes.entry(code.id).or_insert_with(|| CodeStat {
+            count: 0,
+            bytes: 0,
+            last_seen: now,
+            attr: None,
+        });
+        stat.count += 1;
+        stat.bytes += code.encoded_len() as u64;
+        stat.last_seen = now;
+        if code.attr.is_some() {
+            stat.attr = code.attr.clone();
+        }
+    }
+}
+
+/// Count the codes in `frame` and record the unknown ones, nested ones
+/// included.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let code = match frame.code() {
+        Some(code) => code,
+        None => return,
+    };
+    session.code_stats.count(code, Instant::now());
+    if !KNOWN.contains(&code.id) {
+        record(code, session);
+    }
+    for frame in &code.body {
+        observe(frame, session);
+    }
+}
+
+fn record(code: &ControlCode, session: &mut Session) {
+    let mut body = Vec::new();
+    for frame in &code.body {
+        frame.encode(&mut body);
+    }
+    body.truncate(MAX_SAMPLE);
+    let sample = String::from_utf8_lossy(&body).into_owned();
+    let attr = code
+        .attr
+        .as_deref()
+        .map(|attr| String::from_utf8_lossy(attr).into_owned());
+
+    let counts = &mut session.unknown_codes.counts;
+    let key = (code.id, code.attr.clone());
+    let full = counts.len() >= MAX_CODES;
+    match counts.get_mut(&key) {
+        Some(count) => *count += 1,
+        None if full => {}
+        None => {
+            tracing::info!(
+                id = code.id,
+                attr = attr.as_deref().unwrap_or(""),
+                sample = %sample.escape_debug(),
+                "unknown control code"
+            );
+            counts.insert(key, 1);
+        }
+    }
+    if let Some(db) = &session.db {
+        db.send(Event::UnknownCode {
+            id: code.id,
+            attr,
+            sample,
+        });
+    }
+}
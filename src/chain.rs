@@ -0,0 +1,32 @@
+//! Chaining proxies: `remote` pointing at another bcproxy, such as one on a
+//! home server that stays logged in, with a proxy on a laptop in front of
+//! it. The one in front, with `chain = on`, asks with telnet `DO` for an
+//! option only a bcproxy agrees to. Once the other one does, its output has
+//! been decoded and styled already and is passed through untouched rather
+//! than transformed twice. A server that refuses is decoded as usual.
+
+use crate::{
+    session::Session,
+    telnet::{Command, BCPROXY, DO, IAC, WILL},
+};
+
+/// Ask the server whether it is a bcproxy, if the session is chained.
+pub fn start(session: &mut Session) {
+    if session.chain {
+        session.write_server(vec![IAC, DO, BCPROXY]);
+    }
+}
+
+/// Answer a proxy in front of this one that asks whether this is a
+/// bcproxy. Returns whether `command` was that question, which is not
+/// passed on to the server.
+pub fn answer(command: &Command, session: &mut Session) -> bool {
+    match command {
+        Command::Do(BCPROXY) => {
+            session.write_client(vec![IAC, WILL, BCPROXY]);
+            true
+        }
+        Command::Dont(BCPROXY) => true,
+        _ => false,
+    }
+}
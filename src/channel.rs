@@ -0,0 +1,500 @@
+//! Routing of channel messages. Each channel can be muted, sent to the
+//! channel port or the channel log instead of the client, and given a color
+//! of its own, set with `channel` lines in the config and changed at runtime
+//! with `#bc chan`. Messages are also kept in the database for `#bc recall`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+
+use crate::{
+    bc::{ControlCode, Frame},
+    color::{self, Color},
+    config::Config,
+    db::{Db, Event},
+    listener::ACCEPT_RETRY,
+};
+
+/// Lines a slow channel port client can fall behind before it misses some.
+const PORT_BACKLOG: usize = 256;
+
+/// Where the messages of a channel go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Route {
+    #[default]
+    Client,
+    /// Dropped.
+    Muted,
+    /// The clients of the channel port.
+    Port,
+    /// The channel log.
+    Log,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Route::Client => "shown",
+            Route::Muted => "muted",
+            Route::Port => "sent to the channel port",
+            Route::Log => "sent to the channel log",
+        })
+    }
+}
+
+/// A change to the settings of a channel, as in
+/// `#bc chan <channel> <setting>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Route(Route),
+    /// A color of its own, or back to the default color if `None`.
+    Color(Option<Color>),
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "show" | "unmute" => Ok(Setting::Route(Route::Client)),
+            "mute" => Ok(Setting::Route(Route::Muted)),
+            "port" => Ok(Setting::Route(Route::Port)),
+            "log" => Ok(Setting::Route(Route::Log)),
+            "color off" => Ok(Setting::Color(None)),
+            _ => match s.strip_prefix("color ") {
+                Some(color) => Ok(Setting::Color(Some(color.trim().parse()?))),
+                None => Err(format!(
+                    "unknown channel setting `{}`, expected show, mute, port, log, \
+                     color <color> or color off",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Route(Route::Client) => f.write_str("show"),
+            Setting::Route(Route::Muted) => f.write_str("mute"),
+            Setting::Route(Route::Port) => f.write_str("port"),
+            Setting::Route(Route::Log) => f.write_str("log"),
+            Setting::Color(None) => f.write_str("color off"),
+            Setting::Color(Some(color)) => write!(f, "color {}", hex(*color)),
+        }
+    }
+}
+
+/// A `channel = <channel> <setting>` line of the config, a setting the
+/// channel starts with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelRule {
+    pub channel: String,
+    pub setting: Setting,
+}
+
+impl FromStr for ChannelRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, setting) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("channel `{}` has no setting", s))?;
+        Ok(Self {
+            channel: channel_name(name).to_string(),
+            setting: setting.trim().parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ChannelRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.channel, self.setting)
+    }
+}
+
+/// The channel settings of a session.
+pub struct Channels {
+    routes: HashMap<String, Route>,
+    colors: HashMap<String, Color>,
+    /// Where channels routed to the port go, if the proxy has one.
+    pub port: Option<ChannelPort>,
+    history: bool,
+    log_path: Option<PathBuf>,
+    log: Option<File>,
+    // The settings from the config, undone when it is reloaded.
+    rules: Vec<ChannelRule>,
+}
+
+impl Channels {
+    pub fn new(config: &Config) -> Self {
+        let mut channels = Self {
+            routes: HashMap::new(),
+            colors: HashMap::new(),
+            port: None,
+            history: config.chat_history,
+            log_path: config.channel_log.clone(),
+            log: None,
+            rules: Vec::new(),
+        };
+        channels.apply_rules(&config.channels);
+        channels
+    }
+
+    /// Take the channel settings of a reloaded config. Channels it no
+    /// longer names go back to the defaults, settings made with `#bc chan`
+    /// for other channels stay.
+    pub fn reload(&mut self, config: &Config) {
+        for rule in std::mem::take(&mut self.rules) {
+            self.routes.remove(&rule.channel);
+            self.colors.remove(&rule.channel);
+        }
+        self.history = config.chat_history;
+        if self.log_path != config.channel_log {
+            self.log_path = config.channel_log.clone();
+            self.log = None;
+        }
+        self.apply_rules(&config.channels);
+    }
+
+    // The config was checked to have a port or log for the routes to them.
+    fn apply_rules(&mut self, rules: &[ChannelRule]) {
+        for rule in rules {
+            match rule.setting {
+                Setting::Route(Route::Client) => {
+                    self.routes.remove(&rule.channel);
+                }
+                Setting::Route(route) => {
+                    self.routes.insert(rule.channel.clone(), route);
+                }
+                Setting::Color(color) => self.set_color(&rule.channel, color),
+            }
+        }
+        self.rules = rules.to_vec();
+    }
+
+    pub fn set_route(&mut self, channel: &str, route: Route) -> Result<(), String> {
+        match route {
+            Route::Port if self.port.is_none() => {
+                return Err("no channel port, set channel_listen in the config".to_string())
+            }
+            Route::Log if self.log_path.is_none() => {
+                return Err("no channel log, set channel_log in the config".to_string())
+            }
+            _ => {}
+        }
+        match route {
+            Route::Client => self.routes.remove(channel),
+            route => self.routes.insert(channel.to_string(), route),
+        };
+        Ok(())
+    }
+
+    pub fn set_color(&mut self, channel: &str, color: Option<Color>) {
+        match color {
+            Some(color) => self.colors.insert(channel.to_string(), color),
+            None => self.colors.remove(channel),
+        };
+    }
+
+    /// A line for each channel with settings, sorted by channel.
+    pub fn describe(&self) -> Vec<String> {
+        let mut names: Vec<&String> = self.routes.keys().chain(self.colors.keys()).collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| {
+                let route = self.routes.get(name).copied().unwrap_or_default();
+                match self.colors.get(name) {
+                    Some(color) => format!("{}: {}, color {}", name, route, hex(*color)),
+                    None => format!("{}: {}", name, route),
+                }
+            })
+            .collect()
+    }
+
+    /// Keep `frame` in the chat history of `db` if it is a channel message.
+    pub fn record(&self, frame: &Frame, db: &Db) {
+        let channel = match frame.channel() {
+            Some(channel) if self.history => channel,
+            _ => return,
+        };
+        let text = message_text(frame);
+        let (speaker, text) = split_speaker(&text);
+        db.send(Event::Chat {
+            channel: channel.name.to_string(),
+            speaker: speaker.map(str::to_string),
+            text: text.to_string(),
+        });
+    }
+
+    /// Pass `frame` on to `out` unless it is a channel message that goes
+    /// elsewhere, coloring it if its channel has a color.
+    pub fn route(&mut self, mut frame: Frame, out: &mut Vec<Frame>) -> io::Result<()> {
+        let (name, route) = match frame.channel() {
+            Some(channel) => (
+                channel.name.to_string(),
+                self.routes.get(channel.name).copied().unwrap_or_default(),
+            ),
+            None => {
+                out.push(frame);
+                return Ok(());
+            }
+        };
+
+        match route {
+            Route::Client => {
+                if let (Some(color), Frame::Code(code)) = (self.colors.get(&name), &mut frame) {
+                    let body = std::mem::take(&mut code.body);
+                    let hex = hex(*color).into_bytes();
+                    code.body = vec![Frame::Code(ControlCode::new(20, Some(hex), body))];
+                }
+                out.push(frame);
+            }
+            Route::Muted => {}
+            Route::Port => {
+                if let Some(port) = &self.port {
+                    port.send(line(&name, &frame));
+                }
+            }
+            Route::Log => self.write_log(&line(&name, &frame))?,
+        }
+        Ok(())
+    }
+
+    fn write_log(&mut self, line: &str) -> io::Result<()> {
+        let log = match (&mut self.log, &self.log_path) {
+            (Some(log), _) => log,
+            (None, Some(path)) => self
+                .log
+                .insert(OpenOptions::new().create(true).append(true).open(path)?),
+            (None, None) => return Ok(()),
+        };
+        log.write_all(line.as_bytes())
+    }
+}
+
+/// A TCP port clients connect to for the lines of routed channels.
+#[derive(Clone)]
+pub struct ChannelPort(broadcast::Sender<String>);
+
+impl ChannelPort {
+    pub fn new() -> Self {
+        Self(broadcast::channel(PORT_BACKLOG).0)
+    }
+
+    fn send(&self, line: String) {
+        // Nobody may be listening, which is fine.
+        let _ = self.0.send(line);
+    }
+
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("channel port: accepting a client failed: {}", e);
+                    sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+            let mut lines = self.0.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match lines.recv().await {
+                        Ok(line) => {
+                            if stream.write_all(line.as_bytes()).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl Default for ChannelPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The channel name for `name`, with or without the `chan_` prefix.
+pub fn channel_name(name: &str) -> &str {
+    name.strip_prefix("chan_").unwrap_or(name)
+}
+
+/// Split the name of the player speaking off a message such as
+/// `Bob [sales]: selling a sword`. Messages not starting with a single word
+/// and a colon have no speaker.
+pub fn split_speaker(text: &str) -> (Option<&str>, &str) {
+    let (head, said) = match text.split_once(": ") {
+        Some(split) => split,
+        None => return (None, text),
+    };
+    let speaker = match head.rsplit_once(" [") {
+        Some((speaker, tag)) if tag.ends_with(']') => speaker,
+        _ => head,
+    };
+    match speaker.is_empty() || speaker.contains(char::is_whitespace) {
+        true => (None, text),
+        false => (Some(speaker), said),
+    }
+}
+
+/// The message as a line for the port or log, e.g. `[sales] text\r\n`.
+fn line(name: &str, frame: &Frame) -> String {
+    format!("[{}] {}\r\n", name, message_text(frame))
+}
+
+/// The text of a message without colors or the line ending.
+pub(crate) fn message_text(frame: &Frame) -> String {
+    let mut text = Vec::new();
+    frame.push_text(&mut text);
+    let text = color::strip_ansi(&text);
+    text.trim_end_matches(['\r', '\n']).to_string()
+}
+
+fn hex(color: Color) -> String {
+    let (r, g, b) = color.to_rgb();
+    format!("{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bc::Decoder;
+
+    use super::*;
+
+    fn frame(bytes: &[u8]) -> Frame {
+        let mut frames = Vec::new();
+        Decoder::new().decode(bytes, &mut frames);
+        assert_eq!(frames.len(), 1, "{}", bytes.escape_ascii());
+        frames.remove(0)
+    }
+
+    fn channels(config: &str) -> Channels {
+        Channels::new(&Config::parse(config).unwrap())
+    }
+
+    fn route(channels: &mut Channels, bytes: &[u8]) -> Vec<Frame> {
+        let mut out = Vec::new();
+        channels.route(frame(bytes), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn settings_are_read_as_they_are_written() {
+        for setting in ["show", "mute", "port", "log", "color off", "color ff0000"] {
+            assert_eq!(setting.parse::<Setting>().unwrap().to_string(), setting);
+        }
+        assert_eq!("unmute".parse(), Ok(Setting::Route(Route::Client)));
+        assert_eq!(
+            "color #00ff00".parse(),
+            Ok(Setting::Color(Some(Color::Rgb(0, 255, 0))))
+        );
+        assert!("shout".parse::<Setting>().is_err());
+        assert!("color".parse::<Setting>().is_err());
+    }
+
+    #[test]
+    fn rules_name_channels_without_the_prefix() {
+        assert_eq!(
+            "chan_sales mute".parse(),
+            Ok(ChannelRule {
+                channel: "sales".to_string(),
+                setting: Setting::Route(Route::Muted),
+            })
+        );
+        assert!("sales".parse::<ChannelRule>().is_err());
+    }
+
+    #[test]
+    fn speakers_are_split_off_messages() {
+        assert_eq!(
+            split_speaker("Bob [sales]: selling a sword"),
+            (Some("Bob"), "selling a sword")
+        );
+        assert_eq!(split_speaker("Bob: hi: there"), (Some("Bob"), "hi: there"));
+        assert_eq!(
+            split_speaker("The guard says: halt"),
+            (None, "The guard says: halt")
+        );
+        assert_eq!(split_speaker(": hi"), (None, ": hi"));
+        assert_eq!(split_speaker("no colon"), (None, "no colon"));
+    }
+
+    #[test]
+    fn muted_channels_are_dropped_and_others_pass() {
+        let mut channels = channels("channel = sales mute\n");
+        assert!(route(&mut channels, b"\x1b<10chan_sales\x1b|Bob: a sword\x1b>10").is_empty());
+        assert_eq!(
+            route(&mut channels, b"\x1b<10chan_party\x1b|Al: hi\x1b>10"),
+            [frame(b"\x1b<10chan_party\x1b|Al: hi\x1b>10")]
+        );
+        assert_eq!(route(&mut channels, b"text"), [frame(b"text")]);
+
+        channels.set_route("sales", Route::Client).unwrap();
+        assert_eq!(
+            route(&mut channels, b"\x1b<10chan_sales\x1b|x\x1b>10").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn colored_channels_are_wrapped_in_their_color() {
+        let mut channels = channels("channel = sales color ff0000\n");
+        assert_eq!(
+            route(&mut channels, b"\x1b<10chan_sales\x1b|Bob: hi\x1b>10"),
+            [frame(
+                b"\x1b<10chan_sales\x1b|\x1b<20ff0000\x1b|Bob: hi\x1b>20\x1b>10"
+            )]
+        );
+    }
+
+    #[test]
+    fn the_port_and_log_need_configuring() {
+        let mut channels = channels("");
+        assert!(channels.set_route("sales", Route::Port).is_err());
+        assert!(channels.set_route("sales", Route::Log).is_err());
+        assert!(channels.set_route("sales", Route::Muted).is_ok());
+        assert_eq!(channels.describe(), ["sales: muted"]);
+    }
+
+    #[test]
+    fn a_reload_undoes_rules_it_drops_and_keeps_commands() {
+        let mut channels = channels("channel = sales mute\nchannel = party color 00ff00\n");
+        channels.set_route("tell", Route::Muted).unwrap();
+        assert_eq!(
+            channels.describe(),
+            ["party: shown, color 00ff00", "sales: muted", "tell: muted"]
+        );
+
+        channels.reload(&Config::parse("channel = party mute\n").unwrap());
+        assert_eq!(channels.describe(), ["party: muted", "tell: muted"]);
+    }
+
+    #[test]
+    fn lines_are_the_text_without_colors() {
+        let frame = frame(b"\x1b<10chan_sales\x1b|\x1b[31mBob\x1b[0m: hi\r\n\x1b>10");
+        assert_eq!(line("sales", &frame), "[sales] Bob: hi\r\n");
+    }
+}
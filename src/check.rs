@@ -0,0 +1,200 @@
+//! The self-test of `batproxy-rs --check`: connects to the server of the
+//! config and of each profile, turns BC mode on at the level of `bc_mode`
+//! unless it is off and waits for the control codes of the login screen,
+//! or asks a chained one whether it is a bcproxy, and opens each database,
+//! bringing its schema up to date. Nothing is logged in and the proxy is
+//! not started, so it can be run next to one, after the server or the host
+//! changed.
+
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rusqlite::Connection;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout_at,
+};
+
+use crate::{
+    bc::{Decoder, Frame},
+    capability::Capabilities,
+    config::Config,
+    db, io, login,
+    telnet::{self, Command, Segment, BCPROXY, DO, IAC},
+};
+
+/// How long the login screen may take to come once connected.
+const LOGIN_SCREEN_WAIT: Duration = Duration::from_secs(5);
+
+/// The outcome of one part of the self-test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    /// What was found, or why the check failed.
+    pub result: Result<String, String>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(found) => write!(f, "ok    {}: {}", self.name, found),
+            Err(e) => write!(f, "FAIL  {}: {}", self.name, e),
+        }
+    }
+}
+
+/// Check the servers and databases of `config` and its profiles, each once.
+pub async fn run(config: &Config) -> Vec<Check> {
+    let mut remotes: Vec<(&str, Option<u8>, bool)> = Vec::new();
+    let mut databases: Vec<&Path> = Vec::new();
+    let configs = std::iter::once(config).chain(config.profiles.iter().map(|p| &p.config));
+    for config in configs {
+        let remote = (config.remote.as_str(), config.bc_mode, config.chain);
+        if !remotes.contains(&remote) {
+            remotes.push(remote);
+        }
+        if let Some(path) = config.database.as_deref() {
+            if !databases.contains(&path) {
+                databases.push(path);
+            }
+        }
+    }
+
+    let mut checks = Vec::new();
+    for (remote, bc_mode, chain) in remotes {
+        let started = Instant::now();
+        match io::connect(remote, config.connect_timeout).await {
+            Ok(stream) => {
+                checks.push(Check {
+                    name: format!("server {}", remote),
+                    result: Ok(format!("connected in {}ms", started.elapsed().as_millis())),
+                });
+                if chain {
+                    checks.push(Check {
+                        name: format!("bcproxy on {}", remote),
+                        result: bcproxy(stream).await,
+                    });
+                } else if let Some(level) = bc_mode {
+                    checks.push(Check {
+                        name: format!("bc mode {} on {}", level, remote),
+                        result: login_screen(stream, level).await,
+                    });
+                }
+            }
+            Err(e) => checks.push(Check {
+                name: format!("server {}", remote),
+                result: Err(e.to_string()),
+            }),
+        }
+    }
+    for path in databases {
+        checks.push(Check {
+            name: format!("database {}", path.display()),
+            result: database(path).map_err(|e| e.to_string()),
+        });
+    }
+    checks
+}
+
+/// Turn BC mode on and read the login screen until its prompt, telling
+/// which control codes it came with.
+async fn login_screen<S>(mut stream: S, level: u8) -> Result<String, String>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let line = format!("{}\n", login::bc_mode(level));
+    stream
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| format!("failed to send: {}", e))?;
+
+    let deadline = (Instant::now() + LOGIN_SCREEN_WAIT).into();
+    let mut telnet = telnet::Parser::new();
+    let mut decoder = Decoder::new();
+    let mut capabilities = Capabilities::default();
+    let mut buf = [0; 4096];
+    while !capabilities.contains(Capabilities::PROMPT) {
+        let n = match timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => return Err(format!("failed to read: {}", e)),
+        };
+        let mut segments = Vec::new();
+        telnet.parse(&buf[..n], &mut segments);
+        let mut frames: Vec<Frame> = Vec::new();
+        for segment in segments {
+            if let Segment::Data(data) = segment {
+                decoder.decode(&data, &mut frames);
+            }
+        }
+        for frame in &frames {
+            capabilities.observe(frame);
+        }
+    }
+
+    if !capabilities.contains(Capabilities::CONTROL_CODES) {
+        return Err(format!(
+            "no control codes within {}s, the server did not turn BC mode on",
+            LOGIN_SCREEN_WAIT.as_secs()
+        ));
+    }
+    if !capabilities.contains(Capabilities::PROMPT) {
+        return Err(format!(
+            "no login prompt within {}s, got {}",
+            LOGIN_SCREEN_WAIT.as_secs(),
+            capabilities
+        ));
+    }
+    Ok(format!("login screen with {}", capabilities))
+}
+
+/// Ask the server whether it is a bcproxy, for a chained config.
+async fn bcproxy<S>(mut stream: S) -> Result<String, String>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[IAC, DO, BCPROXY])
+        .await
+        .map_err(|e| format!("failed to send: {}", e))?;
+
+    let deadline = (Instant::now() + LOGIN_SCREEN_WAIT).into();
+    let mut telnet = telnet::Parser::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = match timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => return Err(format!("failed to read: {}", e)),
+        };
+        let mut segments = Vec::new();
+        telnet.parse(&buf[..n], &mut segments);
+        for segment in segments {
+            match segment {
+                Segment::Command(Command::Will(BCPROXY), _) => {
+                    return Ok("chained, its output is passed through".to_string())
+                }
+                Segment::Command(Command::Wont(BCPROXY), _) => {
+                    return Err("not a bcproxy, chain is on".to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(format!(
+        "no answer within {}s, not a bcproxy or an older one",
+        LOGIN_SCREEN_WAIT.as_secs()
+    ))
+}
+
+/// Open the database at `path`, bring its schema up to date and count the
+/// rooms in it.
+fn database(path: &Path) -> rusqlite::Result<String> {
+    let conn = Connection::open(path)?;
+    db::create_schema(&conn)?;
+    let rooms: i64 = conn.query_row("SELECT count(*) FROM rooms", [], |row| row.get(0))?;
+    Ok(format!("schema up to date, {} rooms", rooms))
+}
@@ -0,0 +1,35 @@
+//! The session clock: when the client connected, how long the player has
+//! not typed anything and the experience gained since connecting. With
+//! `clock = prompt` it is added to the end of every prompt, with
+//! `clock = line` shown on a line of its own before it, so it is as fresh
+//! as the last prompt. `#bc clock` shows it once.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Local};
+
+use crate::session::Session;
+
+/// The clock as shown, e.g. `since 20:14 (1h02m), idle 3m, exp +12500`.
+pub fn status(session: &mut Session, now: Instant) -> String {
+    let stats = session.stats;
+    let up = now.saturating_duration_since(stats.connected_at);
+    let since: DateTime<Local> = (SystemTime::now() - up).into();
+    let idle = now.saturating_duration_since(stats.typed_at);
+    format!(
+        "since {} ({}), idle {}, exp +{}",
+        since.format("%H:%M"),
+        short(up),
+        short(idle),
+        session.exp.rate(now).total
+    )
+}
+
+/// `d` in the largest units that fit, as in `45s`, `3m` or `1h02m`.
+fn short(d: Duration) -> String {
+    match d.as_secs() {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+    }
+}
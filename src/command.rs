@@ -0,0 +1,701 @@
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    channel::{self, Setting},
+    clock, control, export,
+    link::LinkStyle,
+    logging, path,
+    session::Session,
+};
+
+const PREFIX: &str = "#bc";
+/// Most lines `#bc recall` shows at once.
+const MAX_RECALL: u32 = 500;
+
+/// Handle `line` if it is a command for the proxy itself. Returns false if it
+/// should go to the server.
+pub fn handle(line: &str, session: &mut Session) -> bool {
+    if control::handle(line, session) {
+        return true;
+    }
+
+    let args = match line.trim().strip_prefix(PREFIX) {
+        Some(args) if args.is_empty() || args.starts_with(' ') => args.trim(),
+        _ => return false,
+    };
+
+    let (name, args) = args.split_once(' ').unwrap_or((args, ""));
+    match (name, args.trim()) {
+        ("status", "") => status(session),
+        ("keepalive", toggle @ ("on" | "off")) => {
+            session.keepalive = toggle == "on";
+            session.notify(&format!("keepalive {}", toggle));
+        }
+        ("plain", toggle @ ("on" | "off")) => {
+            session.plain = toggle == "on";
+            session.notify(&format!("plain output {}", toggle));
+        }
+        ("wrap", toggle @ ("on" | "off")) => {
+            session.wrap = toggle == "on";
+            session.notify(&format!("wrap {}", toggle));
+        }
+        ("timestamps", toggle @ ("on" | "off")) => {
+            session.timestamps = toggle == "on";
+            session.notify(&format!("timestamps {}", toggle));
+        }
+        ("style", style) => match style.parse() {
+            Ok(style) => {
+                session.output_style = style;
+                session.notify(&format!("output style {}", style));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("links", style) => match style.parse::<LinkStyle>() {
+            Ok(style) => {
+                session.links = style;
+                let resolved = style.resolve(session);
+                session.notify(&format!("links {}, written as {}", style, resolved));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("map", render @ ("full" | "compact" | "coordinates" | "off")) => {
+            session.map_render = render.parse().unwrap_or_default();
+            session.notify(&format!("map {}", render));
+        }
+        ("path", room) if !room.is_empty() => {
+            let found = find_path(room, session, |room, steps, session| {
+                if steps.is_empty() {
+                    session.notify(&format!("already at {}", room));
+                } else {
+                    session.notify(&format!("path to {}: {}", room, steps.join(", ")));
+                }
+            });
+            if let Err(e) = found {
+                session.notify(&e);
+            }
+        }
+        ("go", room) if !room.is_empty() => {
+            let found = find_path(room, session, |room, steps, session| {
+                if steps.is_empty() {
+                    session.notify(&format!("already at {}", room));
+                } else {
+                    session.notify(&format!("walking to {}, {} steps", room, steps.len()));
+                    session.start_walk(steps);
+                }
+            });
+            if let Err(e) = found {
+                session.notify(&e);
+            }
+        }
+        ("map", args) if args.starts_with("export ") => {
+            let args = args["export ".len()..].trim();
+            let (area, file) = match args.split_once(" to ") {
+                Some((area, file)) => (area.trim(), Some(file.trim())),
+                None => (args, None),
+            };
+            if let Err(e) = export_map(area, file, session) {
+                session.notify(&e);
+            }
+        }
+        ("chan", args) => {
+            if let Err(e) = channel_command(args, session) {
+                session.notify(&e);
+            }
+        }
+        ("recall", args) if !args.is_empty() => {
+            if let Err(e) = recall(args, session) {
+                session.notify(&e);
+            }
+        }
+        ("stop", "") => {
+            session.walk.clear();
+            session.notify("walk stopped");
+        }
+        ("effects", "") => effects(session),
+        ("safety", "") => {
+            let state = if session.safety { "on" } else { "off" };
+            session.notify(&format!("safety {}", state));
+        }
+        ("safety", toggle @ ("on" | "off")) => {
+            session.safety = toggle == "on";
+            session.notify(&format!("safety {}", toggle));
+        }
+        ("tick", "") => tick(session),
+        ("tick", mode) => match mode.parse() {
+            Ok(mode) => {
+                session.tick_countdown = mode;
+                session.notify(&format!("tick countdown {}", mode));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("clock", "") => {
+            let status = clock::status(session, Instant::now());
+            session.notify(&status);
+        }
+        ("clock", mode) => match mode.parse() {
+            Ok(mode) => {
+                session.clock = mode;
+                session.notify(&format!("clock {}", mode));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("whereami", "") => {
+            if let Err(e) = where_am_i(session) {
+                session.notify(&e);
+            }
+        }
+        ("exprate", "") => exp_rate(session),
+        ("codes", "") => unknown_codes(session),
+        ("stats", "") => stats(session),
+        ("countdown", mode) => match mode.parse() {
+            Ok(mode) => {
+                session.countdown = mode;
+                session.notify(&format!("countdown {}", mode));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("log", "") => match logging::level() {
+            Some(level) => session.notify(&format!("log level {}", level)),
+            None => session.notify("logging is not set up"),
+        },
+        ("log", level) => match logging::set_level(level) {
+            Ok(()) => {
+                tracing::info!(level, "log level changed");
+                session.notify(&format!("log level {}", level));
+            }
+            Err(e) => session.notify(&e),
+        },
+        ("color", mode) => match mode.parse() {
+            Ok(mode) => {
+                session.color_mode = mode;
+                session.notify(&format!("color mode {}", mode));
+            }
+            Err(e) => session.notify(&e),
+        },
+        _ => session.notify(&format!(
+            "unknown command `{}`, try `{p} status`, `{p} keepalive on|off`, \
+             `{p} color <mode>`, `{p} countdown prompt|line|off`, `{p} effects`, `{p} safety [on|off]`, `{p} tick [prompt|line|off]`, `{p} clock [prompt|line|off]`, `{p} exprate`, `{p} stats`, `{p} codes`, `{p} whereami`, `{p} style <style>`, `{p} links <style>`, `{p} plain on|off`, `{p} wrap on|off`, `{p} timestamps on|off`, \
+             `{p} path <room>`, `{p} go <room>`, `{p} stop`, `{p} map full|compact|coordinates|off`, \
+             `{p} map export <area> [to <file>]` \
+             `{p} chan [<channel> show|mute|port|log|color <color>|color off]`, \
+             `{p} log [<level>]` or `{p} recall <channel> [count]`",
+            line.trim(),
+            p = PREFIX
+        )),
+    }
+    true
+}
+
+/// Whether `partial`, the start of a line, could still turn into a command.
+pub fn may_be_command(partial: &[u8]) -> bool {
+    [PREFIX, control::PREFIX].iter().any(|prefix| {
+        let n = partial.len().min(prefix.len());
+        partial[..n] == prefix.as_bytes()[..n]
+    })
+}
+
+fn status(session: &mut Session) {
+    let capabilities = format!("server capabilities: {}", session.capabilities);
+    session.notify(&capabilities);
+
+    let client = &session.client;
+    let size = match (client.width, client.height) {
+        (Some(width), Some(height)) => format!("{}x{}", width, height),
+        _ => "unknown size".to_string(),
+    };
+    let terminal = match client.terminal_types.is_empty() {
+        true => "unknown terminal".to_string(),
+        false => client.terminal_types.join(", "),
+    };
+    let message = format!(
+        "client: {}, {}, utf-8 {}, colors {}",
+        size,
+        terminal,
+        if client.utf8 { "yes" } else { "unknown" },
+        session.color_mode
+    );
+    session.notify(&message);
+
+    let message = match &session.db {
+        Some(db) => {
+            let status = db.status();
+            format!(
+                "db: {} events pending, {} committed, {} failed",
+                status.pending, status.committed, status.failed
+            )
+        }
+        None => "db: disabled".to_string(),
+    };
+    session.notify(&message);
+
+    let queue = session.output_queue;
+    session.notify(&format!(
+        "output queue: {} bytes waiting, {} at most, {} dropped",
+        queue.depth, queue.peak, queue.dropped
+    ));
+}
+
+/// List the channel settings, or change those of a channel.
+fn channel_command(args: &str, session: &mut Session) -> Result<(), String> {
+    if args.is_empty() {
+        let lines = session.channels.describe();
+        if lines.is_empty() {
+            session.notify("all channels shown");
+        }
+        for line in lines {
+            session.notify(&line);
+        }
+        return Ok(());
+    }
+
+    let (name, setting) = args.split_once(' ').unwrap_or((args, ""));
+    let name = channel::channel_name(name);
+    match setting.trim().parse()? {
+        Setting::Route(route) => {
+            session.channels.set_route(name, route)?;
+            session.notify(&format!("{}: {}", name, route));
+        }
+        Setting::Color(None) => {
+            session.channels.set_color(name, None);
+            session.notify(&format!("{}: default color", name));
+        }
+        Setting::Color(color) => {
+            session.channels.set_color(name, color);
+            let color = setting.trim().trim_start_matches("color").trim();
+            session.notify(&format!("{}: color {}", name, color));
+        }
+    }
+    Ok(())
+}
+
+/// Show the room the mapper last reported and the player's location, with
+/// what the database knows about it.
+fn where_am_i(session: &mut Session) -> Result<(), String> {
+    let mut lines = Vec::new();
+    if let Some(room) = &session.last_room {
+        lines.push(format!(
+            "room {} in {}: {}",
+            room.id, room.area, room.short_desc
+        ));
+    }
+    if let Some(location) = &session.location {
+        let mut line = format!("location {}", location);
+        if let Some(db) = &session.db {
+            let error = |e: rusqlite::Error| format!("db: {}", e);
+            if let Some(tile) = db.tile(location).map_err(error)? {
+                line.push_str(&format!(", map tile `{}`", tile));
+            }
+            let rooms = db.rooms_at(location).map_err(error)?;
+            if !rooms.is_empty() {
+                line.push_str(&format!(", rooms {}", rooms.join(", ")));
+            }
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Err("no room or location reported yet".to_string());
+    }
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn effects(session: &mut Session) {
+    if session.effects.is_empty() {
+        return session.notify("no effects");
+    }
+    let now = Instant::now();
+    let lines: Vec<String> = session
+        .effects
+        .iter()
+        .map(|effect| format!("{}: {}s left", effect.name, effect.time_left(now).as_secs()))
+        .collect();
+    for line in lines {
+        session.notify(&line);
+    }
+}
+
+fn tick(session: &mut Session) {
+    let now = Instant::now();
+    let line = match (session.tick.period(), session.tick.time_left(now)) {
+        (Some(period), Some(left)) => format!(
+            "tick every {:.1}s, next in {}s",
+            period.as_secs_f64(),
+            left.as_millis().div_ceil(1000)
+        ),
+        _ => "tick not learned yet".to_string(),
+    };
+    session.notify(&line);
+}
+
+fn exp_rate(session: &mut Session) {
+    let rate = session.exp.rate(Instant::now());
+    let minutes = rate.elapsed.as_secs() / 60;
+    session.notify(&format!(
+        "exp: {:.0}/h recently, {:.0}/h over the session, {} gained in {}h {}m",
+        rate.recent,
+        rate.session,
+        rate.total,
+        minutes / 60,
+        minutes % 60
+    ));
+}
+
+/// List the control codes the proxy does not know that the server sent.
+fn unknown_codes(session: &mut Session) {
+    let lines: Vec<String> = session
+        .unknown_codes
+        .counts()
+        .into_iter()
+        .map(|(id, attr, count)| {
+            let attr = attr.map_or(String::new(), |attr| {
+                format!(" {}", String::from_utf8_lossy(attr))
+            });
+            format!("code {:02}{}: seen {}", id, attr, count)
+        })
+        .collect();
+    if lines.is_empty() {
+        session.notify("no unknown control codes");
+    }
+    for line in lines {
+        session.notify(&line);
+    }
+}
+
+/// Show the traffic both ways, the latency, how long the session and its
+/// server connection have been up and whether the player is logged in.
+pub fn stats(session: &mut Session) {
+    let stats = session.stats;
+    session.notify(&format!(
+        "server: {} bytes in {} frames, {} bytes to the client",
+        stats.server.read, stats.server.frames, stats.server.written
+    ));
+    session.notify(&format!(
+        "client: {} bytes in {} lines, {} bytes to the server",
+        stats.client.read, stats.client.frames, stats.client.written
+    ));
+    let latency = match stats.latency {
+        Some(latency) => format!("{} ms", latency.as_millis()),
+        None => "unknown".to_string(),
+    };
+    session.notify(&format!("latency: {}", latency));
+    session.notify(&format!(
+        "up: {}, server connection {}",
+        uptime(stats.connected_at.elapsed()),
+        uptime(stats.server_connected_at.elapsed())
+    ));
+    let login = session.login_state.to_string();
+    session.notify(&login);
+}
+
+fn uptime(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!("{}h {}m {}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Show the last lines said on a channel, 20 unless a count is given.
+fn recall(args: &str, session: &mut Session) -> Result<(), String> {
+    let (name, count) = args.split_once(' ').unwrap_or((args, "20"));
+    let count: u32 = match count.trim().parse() {
+        Ok(count) if (1..=MAX_RECALL).contains(&count) => count,
+        _ => return Err(format!("count must be 1 to {}", MAX_RECALL)),
+    };
+    let name = channel::channel_name(name);
+    let db = session
+        .db
+        .as_ref()
+        .ok_or("no database to recall chat from")?;
+    let lines = db.chat(name, count).map_err(|e| format!("db: {}", e))?;
+    if lines.is_empty() {
+        return Err(format!("nothing said on {} yet", name));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    for line in lines {
+        let message = match &line.speaker {
+            Some(speaker) => format!("{}: {}", speaker, line.text),
+            None => line.text,
+        };
+        session.notify(&format!(
+            "[{}, {}] {}",
+            name,
+            age(now - line.said_at),
+            message
+        ));
+    }
+    Ok(())
+}
+
+/// `secs` as a short age such as `5m ago`.
+pub(crate) fn age(secs: i64) -> String {
+    match secs.max(0) {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3600 => format!("{}m ago", s / 60),
+        s if s < 86400 => format!("{}h ago", s / 3600),
+        s => format!("{}d ago", s / 86400),
+    }
+}
+
+/// Find the directions from the room the mapper last reported to `room`, a
+/// room id or short description, and hand them to `then`. The map is read
+/// and searched in the background.
+fn find_path<T>(room: &str, session: &mut Session, then: T) -> Result<(), String>
+where
+    T: FnOnce(&str, Vec<String>, &mut Session) + Send + 'static,
+{
+    let db = session.db.clone().ok_or("no database to find paths in")?;
+    let from = session
+        .last_room
+        .as_ref()
+        .ok_or("the mapper has not reported a room yet")?
+        .id
+        .clone();
+    let room = room.to_string();
+    let find = move || {
+        let error = |e: rusqlite::Error| format!("db: {}", e);
+        let found = (|| {
+            let targets: HashSet<String> =
+                db.find_rooms(&room).map_err(error)?.into_iter().collect();
+            if targets.is_empty() {
+                return Err(format!("no room matches `{}`", room));
+            }
+            let links = db.links().map_err(error)?;
+            path::find(&links, &from, &targets).ok_or_else(|| format!("no known way to {}", room))
+        })();
+        (room, found)
+    };
+    session.in_background(find, |(room, found), session| match found {
+        Ok(steps) => then(&room, steps, session),
+        Err(e) => session.notify(&e),
+    });
+    Ok(())
+}
+
+/// Write the map of `area` to `file` in the export directory, as JSON if
+/// it ends in `.json`, an SVG image if it ends in `.svg` and Graphviz DOT
+/// otherwise, or show the DOT to the client. The map is read and written
+/// in the background.
+fn export_map(area: &str, file: Option<&str>, session: &mut Session) -> Result<(), String> {
+    let db = session
+        .db
+        .clone()
+        .ok_or("no database to export maps from")?;
+    let file = match file {
+        Some(file) => Some((
+            export_path(session.export_dir.as_deref(), file)?,
+            file.to_string(),
+        )),
+        None => None,
+    };
+    let area = area.to_string();
+    let export = move || -> Result<Exported, String> {
+        let (rooms, links) = db.area(&area).map_err(|e| format!("db: {}", e))?;
+        if rooms.is_empty() {
+            return Err(format!("no rooms in area `{}`", area));
+        }
+        let (path, file) = match file {
+            Some(file) => file,
+            None => return Ok(Exported::Shown(export::to_dot(&area, &rooms, &links))),
+        };
+        let graph = match path.extension() {
+            Some(ext) if ext == "json" => export::to_json(&area, &rooms, &links),
+            Some(ext) if ext == "svg" => export::to_svg(&area, &rooms, &links),
+            _ => export::to_dot(&area, &rooms, &links),
+        };
+        let write = |path: &Path| {
+            std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+            std::fs::write(path, graph)
+        };
+        write(&path).map_err(|e| format!("failed to write {}: {}", file, e))?;
+        Ok(Exported::Written(format!(
+            "exported {} rooms and {} links of {} to {}",
+            rooms.len(),
+            links.len(),
+            area,
+            file
+        )))
+    };
+    session.in_background(export, |exported, session| match exported {
+        Ok(Exported::Shown(graph)) => {
+            session.write_client(graph.replace('\n', "\r\n").into_bytes())
+        }
+        Ok(Exported::Written(done)) => session.notify(&done),
+        Err(e) => session.notify(&e),
+    });
+    Ok(())
+}
+
+/// A map exported by `#bc map export`.
+enum Exported {
+    Shown(String),
+    Written(String),
+}
+
+/// Where `file` is written in `dir`. Only a plain file name is taken, so
+/// a client cannot write anywhere else.
+fn export_path(dir: Option<&Path>, file: &str) -> Result<PathBuf, String> {
+    let dir = dir.ok_or("exporting to a file is off, set export_dir")?;
+    let mut components = Path::new(file).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if !file.contains(['/', '\\']) => Ok(dir.join(name)),
+        _ => Err(format!("`{}` is not a plain file name", file)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Config,
+        db::{Db, Event, Location, Room},
+        session::ToClient,
+    };
+
+    use super::*;
+
+    fn messages(session: &mut Session) -> Vec<String> {
+        session
+            .to_client
+            .drain(..)
+            .filter_map(|message| match message {
+                ToClient::Message(message) => Some(message),
+                ToClient::Raw(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_only_go_to_plain_file_names_in_the_dir() {
+        let dir = Path::new("/srv/maps");
+        assert_eq!(
+            export_path(Some(dir), "arelium.svg"),
+            Ok(PathBuf::from("/srv/maps/arelium.svg"))
+        );
+        for file in [
+            "",
+            ".",
+            "..",
+            "../x.dot",
+            "/etc/passwd",
+            "a/b.dot",
+            "a\\b.dot",
+            "maps/",
+        ] {
+            assert!(export_path(Some(dir), file).is_err(), "{}", file);
+        }
+        assert!(export_path(None, "arelium.svg").is_err());
+    }
+
+    #[test]
+    fn recall_shows_the_last_lines_of_a_channel() {
+        let path = std::env::temp_dir().join(format!("bcproxy-recall-{}.db", std::process::id()));
+        let db = Db::open(&path).unwrap();
+        for (speaker, text) in [
+            (Some("Bob"), "a sword"),
+            (Some("Al"), "a shield"),
+            (None, "sold"),
+        ] {
+            db.send(Event::Chat {
+                channel: "sales".to_string(),
+                speaker: speaker.map(str::to_string),
+                text: text.to_string(),
+            });
+        }
+        db.flush();
+        let mut session = Session::new(&Config::default(), Some(db));
+
+        recall("chan_sales 2", &mut session).unwrap();
+        assert_eq!(
+            messages(&mut session),
+            ["[sales, just now] Al: a shield", "[sales, just now] sold"]
+        );
+        recall("sales", &mut session).unwrap();
+        assert_eq!(messages(&mut session).len(), 3);
+        assert_eq!(
+            recall("party", &mut session),
+            Err("nothing said on party yet".to_string())
+        );
+        for count in ["0", "501", "many"] {
+            assert!(recall(&format!("sales {}", count), &mut session).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recall_needs_a_database() {
+        let mut session = Session::new(&Config::default(), None);
+        assert_eq!(
+            recall("sales", &mut session),
+            Err("no database to recall chat from".to_string())
+        );
+    }
+
+    #[test]
+    fn ages_are_shown_in_the_largest_unit() {
+        assert_eq!(age(-5), "just now");
+        assert_eq!(age(59), "just now");
+        assert_eq!(age(60), "1m ago");
+        assert_eq!(age(3599), "59m ago");
+        assert_eq!(age(7200), "2h ago");
+        assert_eq!(age(86400 * 3), "3d ago");
+    }
+
+    #[test]
+    fn whereami_tells_the_room_and_what_is_known_of_the_location() {
+        let mut session = Session::new(&Config::default(), None);
+        assert_eq!(
+            where_am_i(&mut session),
+            Err("no room or location reported yet".to_string())
+        );
+
+        let room = Room {
+            area: "laenor".to_string(),
+            id: "7".to_string(),
+            direction: String::new(),
+            indoors: false,
+            short_desc: "A forest path".to_string(),
+            long_desc: "Trees.".to_string(),
+            exits: vec!["n".to_string()],
+        };
+        let location = Location {
+            realm: "laenor".to_string(),
+            x: 10,
+            y: 20,
+            z: None,
+        };
+        session.last_room = Some(room.clone());
+        session.location = Some(location.clone());
+        where_am_i(&mut session).unwrap();
+        assert_eq!(
+            messages(&mut session),
+            ["room 7 in laenor: A forest path", "location laenor 10,20"]
+        );
+
+        let path = std::env::temp_dir().join(format!("bcproxy-whereami-{}.db", std::process::id()));
+        let db = Db::open(&path).unwrap();
+        db.send(Event::Room(room));
+        db.send(Event::RoomLocation {
+            id: "7".to_string(),
+            location: location.clone(),
+        });
+        db.send(Event::RealmMap {
+            realm: "laenor".to_string(),
+            tiles: vec![(10, 20, 'f'), (11, 20, 'v')],
+        });
+        db.flush();
+        session.db = Some(db);
+        session.last_room = None;
+        where_am_i(&mut session).unwrap();
+        assert_eq!(
+            messages(&mut session),
+            ["location laenor 10,20, map tile `f`, rooms 7"]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1502 @@
+use std::{
+    borrow::Cow,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use regex::Regex;
+
+use crate::{
+    action::Countdown,
+    alias::Alias,
+    auth::AuthConfig,
+    bc::DecoderLimits,
+    channel::{ChannelRule, Route, Setting},
+    color::ColorMode,
+    db::Batch,
+    highlight::Highlight,
+    inventory::Header,
+    io::{CodeMatch, Keepalive, MergeWindow, OutputQueue, Reconnect, Strictness, Watchdog},
+    link::LinkStyle,
+    logging::{self, LogConfig},
+    login::{LoginConfig, Secret},
+    map::MapRender,
+    middleware::Layer,
+    mirror::Attach,
+    notifier::Notification,
+    queue::Pacing,
+    safety::Safeguard,
+    splitlog::SplitLogConfig,
+    style::Profile,
+    tap::TapRule,
+    target::BarStyle,
+    throttle::ThrottleRule,
+    timestamp::TimestampFormat,
+    translate::TranslateConfig,
+    trigger::Trigger,
+};
+
+pub const DEFAULT_PATH: &str = "bcproxy.conf";
+
+const DEFAULT_WALK_ABORT: &[&str] = &[
+    "^You can't go that way",
+    "^Alas, you cannot go that way",
+    "^You are too tired to move",
+];
+
+const DEFAULT_INVENTORY_HEADERS: &[&str] = &["inv You are carrying*", "eq You are using*"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Addresses clients connect to, at least one.
+    pub listen: Vec<Listen>,
+    pub remote: String,
+    /// How long each address of the remote host gets to accept the
+    /// connection before the next one is tried.
+    pub connect_timeout: Duration,
+    /// `remote` may be another bcproxy, whose output is passed through
+    /// untouched if it says it is one.
+    pub chain: bool,
+    pub merge: MergeWindow,
+    /// Control codes dropped when they repeat or come too often.
+    pub throttles: Vec<ThrottleRule>,
+    pub triggers: Vec<Trigger>,
+    /// Minimum time between two firings of the same trigger.
+    pub trigger_cooldown: Duration,
+    pub highlights: Vec<Highlight>,
+    /// Webhooks posted when a tell arrives, a party member dies or the
+    /// connection drops.
+    pub notifiers: Vec<Notification>,
+    /// Minimum time between two posts of the same notifier.
+    pub notifier_interval: Duration,
+    /// Colors clients start with, changed per client with `#bc color`.
+    pub color_mode: ColorMode,
+    /// Clients start with plain text output, see `#bc plain`.
+    pub plain_output: bool,
+    /// Ask clients for their window size and terminal type on connect.
+    pub client_negotiation: bool,
+    /// Pass the server's telnet ECHO negotiation on to clients, so they do
+    /// not show passwords as they are typed.
+    pub echo_negotiation: bool,
+    /// Wrap output at the width clients report, see `#bc wrap`.
+    pub wrap: bool,
+    /// Clients start with a timestamp before each line, see
+    /// `#bc timestamps`.
+    pub timestamps: bool,
+    /// The strftime format timestamps are written with.
+    pub timestamp_format: TimestampFormat,
+    /// Follow each timestamp with the time since the previous line.
+    pub timestamp_elapsed: bool,
+    /// How clients get control codes, see `#bc style`.
+    pub output_style: Profile,
+    /// How clients get hyperlinks, see `#bc links`.
+    pub hyperlinks: LinkStyle,
+    /// How the map around the player is drawn, see `#bc map`.
+    pub map_render: MapRender,
+    /// Address clients connect to for the maps, which are then not shown
+    /// to the player's client, off if not set.
+    pub map_listen: Option<String>,
+    /// Directory `#bc map export <area> to <file>` writes to, which is off
+    /// if not set.
+    pub export_dir: Option<PathBuf>,
+    /// Address party members connect to for the player's points, room and
+    /// target, off if not set. A path is a Unix socket, anything else a TCP
+    /// address on the loopback interface.
+    pub party_listen: Option<String>,
+    /// The name the player's lines on the party port start with,
+    /// `login_name` if not set.
+    pub party_name: Option<String>,
+    /// How much of an unterminated control code is buffered.
+    pub decoder: DecoderLimits,
+    /// What is done about server output breaking the protocol.
+    pub protocol: Strictness,
+    /// The BC mode level the auto-login asks for, output passed through
+    /// untouched if off.
+    pub bc_mode: Option<u8>,
+    /// Lua script with hooks run for every session.
+    pub script: Option<PathBuf>,
+    /// SQLite database mapper data is stored in.
+    pub database: Option<PathBuf>,
+    /// How writes to the database are gathered into transactions.
+    pub db_batch: Batch,
+    pub keepalive: Keepalive,
+    /// Time between the steps of `#bc go` and speedwalks.
+    pub walk_delay: Duration,
+    /// Expand speedwalks like `3n2e` typed by the player.
+    pub speedwalk: bool,
+    /// Server output that stops a walk, e.g. a blocked exit.
+    pub walk_abort: Vec<Regex>,
+    /// Lines that start a listing of the player's inventory or equipment.
+    pub inventory_headers: Vec<Header>,
+    /// When lines the player sends go to the server, see `;;queue`.
+    pub command_queue: Pacing,
+    /// Lines kept for `;;history`, none if zero.
+    pub history_size: usize,
+    /// How clients see the rounds left of a spell or skill, see
+    /// `#bc countdown`.
+    pub countdown: Countdown,
+    /// Warn this long before an effect on the player runs out, never if
+    /// zero.
+    pub effect_warning: Duration,
+    /// Ring the bell with the warning.
+    pub effect_bell: bool,
+    /// Commands sent when the player's points run low.
+    pub safeguards: Vec<Safeguard>,
+    /// Minimum time between two firings of the same safeguard.
+    pub safeguard_cooldown: Duration,
+    /// How clients see the time to the next tick, see `#bc tick`.
+    pub tick_countdown: Countdown,
+    /// How long before a tick the line of `tick_countdown = line` comes.
+    pub tick_warning: Duration,
+    /// How clients see the session clock, see `#bc clock`.
+    pub clock: Countdown,
+    /// How the player's target is shown.
+    pub target_bar: BarStyle,
+    /// Width of the target's health bar in characters.
+    pub target_bar_width: usize,
+    /// Record each target that reaches 0 hp in the database.
+    pub kill_log: bool,
+    /// Time the recent experience rate of `#bc exprate` is taken over.
+    pub exp_window: Duration,
+    /// Sum up each fight when it ends.
+    pub battle_summary: bool,
+    pub translate: TranslateConfig,
+    /// Address of the read-only HTTP API, off if not set. A path is a Unix
+    /// socket, anything else a TCP address on the loopback interface.
+    pub api_listen: Option<String>,
+    /// Address of the admin console, off if not set. A path is a Unix
+    /// socket, anything else a TCP address on the loopback interface.
+    pub admin_listen: Option<String>,
+    /// Path of the Unix socket a new proxy started with `--takeover`
+    /// connects to, to take over the listeners and server connections of
+    /// this one. Off if not set. Only a proxy running as the same user is
+    /// handed over to.
+    pub upgrade_socket: Option<PathBuf>,
+    /// Address browser clients connect to over WebSocket, off if not set.
+    pub websocket_listen: Option<String>,
+    /// Origins of the web pages whose browser clients need no password on
+    /// the loopback interface, such as `http://localhost:8080`. Pages of
+    /// other origins must give the password, or are refused without one.
+    pub websocket_origins: Vec<String>,
+    /// Address clients connect to for the lines of channels routed there
+    /// with `#bc chan`, off if not set.
+    pub channel_listen: Option<String>,
+    /// File channels routed there with `#bc chan` are appended to.
+    pub channel_log: Option<PathBuf>,
+    /// Files server output is written to by kind.
+    pub split_log: SplitLogConfig,
+    /// Settings channels start with, changed with `#bc chan`.
+    pub channels: Vec<ChannelRule>,
+    /// Keep channel messages in the database for `#bc recall`.
+    pub chat_history: bool,
+    /// Named pipes and Unix sockets some of the output is written to.
+    pub taps: Vec<TapRule>,
+    /// The layers server output goes through, in order.
+    pub middleware: Vec<Layer>,
+    /// How much output may wait for a slow client and what happens when
+    /// more comes.
+    pub output_queue: OutputQueue,
+    /// Whether and how to reconnect when the server drops the connection.
+    pub reconnect: Reconnect,
+    /// When a server that stopped answering is probed and given up on.
+    pub watchdog: Watchdog,
+    /// Name, password and commands to log in with when the server
+    /// connection opens.
+    pub login: LoginConfig,
+    /// The password clients of addresses other machines can reach give
+    /// first.
+    pub auth: AuthConfig,
+    /// Words typed by the player that stand for longer commands.
+    pub aliases: Vec<Alias>,
+    /// Named variants of this config, each on a listener of its own.
+    pub profiles: Vec<ConnectionProfile>,
+    /// What is logged and how.
+    pub log: LogConfig,
+}
+
+/// An address clients connect to, with settings its clients start with
+/// instead of those of the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listen {
+    /// `host:port`, or the path of a Unix socket.
+    pub addr: String,
+    pub color_mode: Option<ColorMode>,
+    pub output_style: Option<Profile>,
+    pub plain_output: Option<bool>,
+    pub wrap: Option<bool>,
+    pub client_negotiation: Option<bool>,
+    /// Ask clients for `auth_password`, by default only if the address is
+    /// not on the loopback interface or a Unix socket.
+    pub auth: Option<bool>,
+    /// Attach clients to a session already running instead of starting
+    /// one.
+    pub attach: Option<Attach>,
+}
+
+impl Listen {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            color_mode: None,
+            output_style: None,
+            plain_output: None,
+            wrap: None,
+            client_negotiation: None,
+            auth: None,
+            attach: None,
+        }
+    }
+
+    /// `config` with the settings of this address on top.
+    pub fn apply<'a>(&self, config: &'a Config) -> Cow<'a, Config> {
+        let switches = [self.plain_output, self.wrap, self.client_negotiation];
+        if self.color_mode.is_none()
+            && self.output_style.is_none()
+            && switches.iter().all(Option::is_none)
+        {
+            return Cow::Borrowed(config);
+        }
+        let mut config = config.clone();
+        config.color_mode = self.color_mode.unwrap_or(config.color_mode);
+        config.output_style = self.output_style.unwrap_or(config.output_style);
+        config.plain_output = self.plain_output.unwrap_or(config.plain_output);
+        config.wrap = self.wrap.unwrap_or(config.wrap);
+        config.client_negotiation = self.client_negotiation.unwrap_or(config.client_negotiation);
+        Cow::Owned(config)
+    }
+}
+
+/// Parses the address followed by `key=value` words.
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let mut listen = Listen::new(words.next().ok_or("listen needs an address")?);
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("expected `key=value` after the address, got `{}`", word))?;
+            let switch = || match value {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => Err(format!("{} must be on or off", key)),
+            };
+            match key {
+                "color_mode" => listen.color_mode = Some(value.parse()?),
+                "output_style" => listen.output_style = Some(value.parse()?),
+                "plain_output" => listen.plain_output = Some(switch()?),
+                "wrap" => listen.wrap = Some(switch()?),
+                "client_negotiation" => listen.client_negotiation = Some(switch()?),
+                "auth" => listen.auth = Some(switch()?),
+                "attach" => listen.attach = Some(value.parse()?),
+                _ => {
+                    return Err(format!(
+                        "unknown listen setting `{}`, expected color_mode, output_style, \
+                         plain_output, wrap, client_negotiation, auth or attach",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(listen)
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.addr)?;
+        if let Some(mode) = self.color_mode {
+            write!(f, " color_mode={}", mode)?;
+        }
+        if let Some(style) = self.output_style {
+            write!(f, " output_style={}", style)?;
+        }
+        for (key, value) in [
+            ("plain_output", self.plain_output),
+            ("wrap", self.wrap),
+            ("client_negotiation", self.client_negotiation),
+            ("auth", self.auth),
+        ] {
+            if let Some(value) = value {
+                write!(f, " {}={}", key, to_on_off(value))?;
+            }
+        }
+        if let Some(mode) = self.attach {
+            write!(f, " attach={}", mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `[profile <name>]` section of the config file: the settings before
+/// the first section with the section's own lines on top. Clients pick it
+/// by connecting to its `listen` address, by default the main port plus
+/// its position, or by typing its name on the main port.
+#[derive(Debug, Clone)]
+pub struct ConnectionProfile {
+    pub name: String,
+    /// The section's lines as written, for [`Config::to_file_string`].
+    pub lines: Vec<String>,
+    pub config: Config,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![Listen::new("127.0.0.1:7788")],
+            remote: "batmud.bat.org:2023".to_string(),
+            connect_timeout: Duration::from_secs(10),
+            chain: false,
+            merge: MergeWindow::default(),
+            throttles: Vec::new(),
+            triggers: Vec::new(),
+            trigger_cooldown: Duration::from_secs(1),
+            highlights: Vec::new(),
+            notifiers: Vec::new(),
+            notifier_interval: Duration::from_secs(30),
+            color_mode: ColorMode::default(),
+            plain_output: false,
+            client_negotiation: true,
+            echo_negotiation: true,
+            wrap: true,
+            timestamps: false,
+            timestamp_format: TimestampFormat::default(),
+            timestamp_elapsed: false,
+            output_style: Profile::default(),
+            hyperlinks: LinkStyle::default(),
+            map_render: MapRender::default(),
+            export_dir: None,
+            map_listen: None,
+            party_listen: None,
+            party_name: None,
+            decoder: DecoderLimits::default(),
+            protocol: Strictness::default(),
+            bc_mode: Some(1),
+            script: None,
+            database: None,
+            db_batch: Batch::default(),
+            keepalive: Keepalive::default(),
+            walk_delay: Duration::from_millis(500),
+            speedwalk: true,
+            walk_abort: DEFAULT_WALK_ABORT
+                .iter()
+                .map(|re| Regex::new(re).unwrap())
+                .collect(),
+            inventory_headers: DEFAULT_INVENTORY_HEADERS
+                .iter()
+                .map(|header| header.parse().unwrap())
+                .collect(),
+            command_queue: Pacing::default(),
+            history_size: 100,
+            countdown: Countdown::default(),
+            effect_warning: Duration::from_secs(10),
+            effect_bell: true,
+            safeguards: Vec::new(),
+            safeguard_cooldown: Duration::from_secs(10),
+            tick_countdown: Countdown::Off,
+            tick_warning: Duration::from_secs(3),
+            clock: Countdown::Off,
+            target_bar: BarStyle::default(),
+            target_bar_width: 10,
+            kill_log: false,
+            exp_window: Duration::from_secs(10 * 60),
+            battle_summary: true,
+            translate: TranslateConfig::default(),
+            api_listen: None,
+            admin_listen: None,
+            upgrade_socket: None,
+            websocket_listen: None,
+            websocket_origins: Vec::new(),
+            channel_listen: None,
+            channel_log: None,
+            split_log: SplitLogConfig::default(),
+            channels: Vec::new(),
+            chat_history: true,
+            taps: Vec::new(),
+            middleware: Layer::DEFAULT.to_vec(),
+            output_queue: OutputQueue::default(),
+            reconnect: Reconnect::default(),
+            watchdog: Watchdog::default(),
+            login: LoginConfig::default(),
+            auth: AuthConfig::default(),
+            aliases: Vec::new(),
+            profiles: Vec::new(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path`, falling back to the defaults if it
+    /// does not exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parse `key = value` lines. Blank lines and lines starting with `#` are
+    /// ignored. Lines after a `[profile <name>]` header belong to that
+    /// profile, which starts from the lines before the first header.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut base = Vec::new();
+        let mut profiles: Vec<(String, Vec<(usize, &str)>)> = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = match header.trim().strip_prefix("profile ") {
+                    Some(name) if !name.trim().is_empty() => name.trim(),
+                    _ => return Err(invalid(n, "expected `[profile <name>]`")),
+                };
+                if profiles.iter().any(|(other, _)| other == name) {
+                    return Err(invalid(n, &format!("profile `{}` is defined twice", name)));
+                }
+                profiles.push((name.to_string(), Vec::new()));
+                continue;
+            }
+            match profiles.last_mut() {
+                Some((_, lines)) => lines.push((n, line)),
+                None => base.push((n, line)),
+            }
+        }
+
+        let mut config = Self::parse_lines(&base)?;
+        for (i, (name, lines)) in profiles.into_iter().enumerate() {
+            let is_listen =
+                |line: &str| line.split('=').next().is_some_and(|k| k.trim() == "listen");
+            let own_listen = lines.iter().any(|(_, line)| is_listen(line));
+            // Addresses of its own replace those of the main config.
+            let all: Vec<(usize, &str)> = base
+                .iter()
+                .filter(|(_, line)| !own_listen || !is_listen(line))
+                .chain(&lines)
+                .copied()
+                .collect();
+            let mut profile = Self::parse_lines(&all)?;
+            // The nth profile listens on the port after n - 1 others unless
+            // it says otherwise.
+            if !own_listen {
+                let addr = next_port(&config.listen[0].addr, i + 1).ok_or_else(|| {
+                    invalid(
+                        lines.first().map_or(0, |(n, _)| *n),
+                        &format!("profile `{}` needs a listen address", name),
+                    )
+                })?;
+                profile.listen = vec![Listen::new(addr)];
+            }
+            config.profiles.push(ConnectionProfile {
+                name,
+                lines: lines.iter().map(|(_, line)| line.to_string()).collect(),
+                config: profile,
+            });
+        }
+        Ok(config)
+    }
+
+    fn parse_lines(lines: &[(usize, &str)]) -> io::Result<Self> {
+        let mut config = Self::default();
+        let mut merge_sequences = Vec::new();
+        let mut walk_abort = Vec::new();
+        let mut inventory_headers = Vec::new();
+        let mut channels: Vec<(usize, ChannelRule)> = Vec::new();
+        let mut listen = Vec::new();
+
+        for &(n, line) in lines {
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| invalid(n, "expected `key = value`"))?;
+
+            match key {
+                "listen" => listen.push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "remote" => config.remote = value.to_string(),
+                "connect_timeout_secs" => {
+                    let secs = value.parse().ok().filter(|&secs| secs > 0).ok_or_else(|| {
+                        invalid(n, "connect_timeout_secs must be a number above 0")
+                    })?;
+                    config.connect_timeout = Duration::from_secs(secs);
+                }
+                "chain" => config.chain = on_off(n, key, value)?,
+                "api_listen" => config.api_listen = Some(value.to_string()),
+                "admin_listen" => config.admin_listen = Some(value.to_string()),
+                "upgrade_socket" => config.upgrade_socket = Some(PathBuf::from(value)),
+                "websocket_listen" => config.websocket_listen = Some(value.to_string()),
+                "websocket_origins" => {
+                    config.websocket_origins = value.split_whitespace().map(String::from).collect()
+                }
+                "channel_listen" => config.channel_listen = Some(value.to_string()),
+                "channel_log" => config.channel_log = Some(PathBuf::from(value)),
+                "split_log_dir" => config.split_log.dir = Some(PathBuf::from(value)),
+                "split_log_files" => {
+                    config.split_log.files = value
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e: String| invalid(n, &e))?
+                }
+                "split_log_name" => config
+                    .split_log
+                    .set_name(value)
+                    .map_err(|e| invalid(n, &e))?,
+                "channel" => channels.push((n, value.parse().map_err(|e: String| invalid(n, &e))?)),
+                "chat_history" => config.chat_history = on_off(n, key, value)?,
+                "tap" => config
+                    .taps
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "merge_window_ms" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| invalid(n, "merge_window_ms must be a number"))?;
+                    config.merge.window = Duration::from_millis(ms);
+                }
+                "merge" => merge_sequences.push(
+                    value
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<Vec<CodeMatch>, _>>()
+                        .map_err(|e| invalid(n, &e))?,
+                ),
+                "throttle" => config
+                    .throttles
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "trigger" => config
+                    .triggers
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "trigger_cooldown_ms" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| invalid(n, "trigger_cooldown_ms must be a number"))?;
+                    config.trigger_cooldown = Duration::from_millis(ms);
+                }
+                "output_queue_kb" => {
+                    let kb: usize = value
+                        .parse()
+                        .map_err(|_| invalid(n, "output_queue_kb must be a number"))?;
+                    config.output_queue.limit = kb.max(1) * 1024;
+                }
+                "slow_client" => {
+                    config.output_queue.slow_client =
+                        value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "log_level" => {
+                    logging::parse_level(value).map_err(|e| invalid(n, &e))?;
+                    config.log.level = value.to_string();
+                }
+                "log_format" => {
+                    config.log.format = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "log_frames" => config.log.frames = on_off(n, key, value)?,
+                "reconnect" => config.reconnect.enabled = on_off(n, key, value)?,
+                "reconnect_max_delay_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "reconnect_max_delay_secs must be a number"))?;
+                    config.reconnect.max_delay = Duration::from_secs(secs);
+                }
+                "watchdog_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "watchdog_secs must be a number"))?;
+                    config.watchdog.timeout = Duration::from_secs(secs);
+                }
+                "watchdog_probe" => config.watchdog.probe = value.to_string(),
+                "login_name" => config.login.name = value.to_string(),
+                "login_password_env" => {
+                    config.login.password = Some(Secret::Env(value.to_string()))
+                }
+                "login_password_command" => {
+                    config.login.password = Some(Secret::Command(value.to_string()))
+                }
+                "login_command" => config.login.commands.push(value.to_string()),
+                "auth_password_env" => config.auth.password = Some(Secret::Env(value.to_string())),
+                "auth_password_command" => {
+                    config.auth.password = Some(Secret::Command(value.to_string()))
+                }
+                "auth_max_failures" => {
+                    config.auth.max_failures =
+                        value.parse().ok().filter(|&max| max > 0).ok_or_else(|| {
+                            invalid(n, "auth_max_failures must be a number above 0")
+                        })?
+                }
+                "auth_lockout_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "auth_lockout_secs must be a number"))?;
+                    config.auth.lockout = Duration::from_secs(secs);
+                }
+                "alias" => config
+                    .aliases
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "highlight" => config
+                    .highlights
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "notifier" => config
+                    .notifiers
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "notifier_interval_ms" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| invalid(n, "notifier_interval_ms must be a number"))?;
+                    config.notifier_interval = Duration::from_millis(ms);
+                }
+                "color_mode" => {
+                    config.color_mode = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "plain_output" => config.plain_output = on_off(n, key, value)?,
+                "client_negotiation" => config.client_negotiation = on_off(n, key, value)?,
+                "echo_negotiation" => config.echo_negotiation = on_off(n, key, value)?,
+                "wrap" => config.wrap = on_off(n, key, value)?,
+                "timestamps" => config.timestamps = on_off(n, key, value)?,
+                "timestamp_format" => {
+                    config.timestamp_format = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "timestamp_elapsed" => config.timestamp_elapsed = on_off(n, key, value)?,
+                "middleware" => {
+                    config.middleware = value
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e: String| invalid(n, &e))?
+                }
+                "output_style" => {
+                    config.output_style = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "hyperlinks" => {
+                    config.hyperlinks = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "map_listen" => config.map_listen = Some(value.to_string()),
+                "party_listen" => config.party_listen = Some(value.to_string()),
+                "party_name" => config.party_name = Some(value.to_string()),
+                "export_dir" => config.export_dir = Some(PathBuf::from(value)),
+                "map_render" => {
+                    config.map_render = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "max_code_bytes" => {
+                    config.decoder.max_code_bytes = value
+                        .parse()
+                        .map_err(|_| invalid(n, "max_code_bytes must be a number"))?
+                }
+                "max_code_depth" => {
+                    config.decoder.max_depth = value
+                        .parse()
+                        .map_err(|_| invalid(n, "max_code_depth must be a number"))?
+                }
+                "protocol" => {
+                    config.protocol = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "bc_mode" => {
+                    config.bc_mode =
+                        match value {
+                            "off" => None,
+                            level => Some(level.parse().map_err(|_| {
+                                invalid(n, "bc_mode must be off or a level such as 1")
+                            })?),
+                        }
+                }
+                "script" => config.script = Some(PathBuf::from(value)),
+                "database" => config.database = Some(PathBuf::from(value)),
+                "db_batch_ms" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| invalid(n, "db_batch_ms must be a number"))?;
+                    config.db_batch.interval = Duration::from_millis(ms);
+                }
+                "db_batch_events" => {
+                    config.db_batch.max_events = value
+                        .parse()
+                        .ok()
+                        .filter(|&events| events > 0)
+                        .ok_or_else(|| invalid(n, "db_batch_events must be a number above 0"))?
+                }
+                "keepalive_idle_minutes" => config.keepalive.idle = minutes(n, key, value)?,
+                "keepalive_jitter_seconds" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "keepalive_jitter_seconds must be a number"))?;
+                    config.keepalive.jitter = Duration::from_secs(secs);
+                }
+                "keepalive_command" => config.keepalive.command = value.to_string(),
+                "walk_delay_ms" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| invalid(n, "walk_delay_ms must be a number"))?;
+                    config.walk_delay = Duration::from_millis(ms);
+                }
+                "speedwalk" => config.speedwalk = on_off(n, key, value)?,
+                "inventory_header" => {
+                    inventory_headers.push(value.parse().map_err(|e: String| invalid(n, &e))?)
+                }
+                "walk_abort" => {
+                    walk_abort.push(Regex::new(value).map_err(|e| invalid(n, &e.to_string()))?)
+                }
+                "command_queue" => {
+                    config.command_queue = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "history_size" => {
+                    config.history_size = value
+                        .parse()
+                        .map_err(|_| invalid(n, "history_size must be a number"))?
+                }
+                "countdown" => {
+                    config.countdown = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "effect_warning_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "effect_warning_secs must be a number"))?;
+                    config.effect_warning = Duration::from_secs(secs);
+                }
+                "effect_bell" => config.effect_bell = on_off(n, key, value)?,
+                "safeguard" => config
+                    .safeguards
+                    .push(value.parse().map_err(|e: String| invalid(n, &e))?),
+                "safeguard_cooldown_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "safeguard_cooldown_secs must be a number"))?;
+                    config.safeguard_cooldown = Duration::from_secs(secs);
+                }
+                "tick_countdown" => {
+                    config.tick_countdown = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "clock" => config.clock = value.parse().map_err(|e: String| invalid(n, &e))?,
+                "tick_warning_secs" => {
+                    let secs = value
+                        .parse()
+                        .map_err(|_| invalid(n, "tick_warning_secs must be a number"))?;
+                    config.tick_warning = Duration::from_secs(secs);
+                }
+                "target_bar" => {
+                    config.target_bar = value.parse().map_err(|e: String| invalid(n, &e))?
+                }
+                "target_bar_width" => {
+                    config.target_bar_width = value
+                        .parse()
+                        .map_err(|_| invalid(n, "target_bar_width must be a number"))?
+                }
+                "kill_log" => config.kill_log = on_off(n, key, value)?,
+                "exp_window_minutes" => config.exp_window = minutes(n, key, value)?,
+                "battle_summary" => config.battle_summary = on_off(n, key, value)?,
+                "translate_url" => config.translate.url = value.to_string(),
+                "translate_source" => config.translate.source = value.to_string(),
+                "translate_target" => config.translate.target = value.to_string(),
+                "translate_channels" => {
+                    config.translate.channels =
+                        value.split_whitespace().map(str::to_string).collect()
+                }
+                _ => return Err(invalid(n, &format!("unknown key `{}`", key))),
+            }
+        }
+
+        if !merge_sequences.is_empty() {
+            config.merge.sequences = merge_sequences;
+        }
+        if !walk_abort.is_empty() {
+            config.walk_abort = walk_abort;
+        }
+        if !inventory_headers.is_empty() {
+            config.inventory_headers = inventory_headers;
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        if !config.auth.is_enabled() && config.listen.iter().any(|l| l.auth == Some(true)) {
+            return Err(invalid(
+                lines.last().map_or(0, |(n, _)| *n),
+                "listen with auth=on needs auth_password_env or auth_password_command",
+            ));
+        }
+        for (n, rule) in channels {
+            match rule.setting {
+                Setting::Route(Route::Port) if config.channel_listen.is_none() => {
+                    return Err(invalid(n, "channels sent to the port need channel_listen"))
+                }
+                Setting::Route(Route::Log) if config.channel_log.is_none() => {
+                    return Err(invalid(n, "channels sent to the log need channel_log"))
+                }
+                _ => config.channels.push(rule),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Render the config as a commented file that [`Config::parse`] accepts.
+    pub fn to_file_string(&self) -> String {
+        let mut s = String::new();
+        s.push_str("# bcproxy configuration\n");
+        s.push_str("# Read again on SIGHUP or `reload` on the admin console.\n\n");
+        s.push_str("# Addresses clients connect to, one line each: host:port, [::1]:port or\n");
+        s.push_str("# the path of a Unix socket. Words after the address set what its clients\n");
+        s.push_str("# start with: color_mode, output_style, plain_output, wrap and\n");
+        s.push_str("# client_negotiation, e.g. listen = [::]:7789 color_mode=16 wrap=on, and\n");
+        s.push_str("# whether they are asked for the password below: auth=on or auth=off.\n");
+        s.push_str("# With attach=ro its clients watch a session already running instead of\n");
+        s.push_str("# starting one, with attach=rw they can also send lines to it.\n");
+        for listen in &self.listen {
+            s.push_str(&format!("listen = {}\n", listen));
+        }
+        s.push('\n');
+        s.push_str("# The password clients give before the proxy connects for them, on\n");
+        s.push_str("# addresses other machines can reach. They type it as their first line\n");
+        s.push_str("# or send it as the telnet NEW-ENVIRON variable BCPROXY_PASSWORD. It is\n");
+        s.push_str("# read like the login password below. A host that gets it wrong\n");
+        s.push_str("# auth_max_failures times in a row is locked out for auth_lockout_secs.\n");
+        match &self.auth.password {
+            Some(Secret::Env(var)) => s.push_str(&format!("auth_password_env = {}\n", var)),
+            Some(Secret::Command(command)) => {
+                s.push_str(&format!("auth_password_command = {}\n", command))
+            }
+            None => s.push_str("# auth_password_env = BCPROXY_PASSWORD\n"),
+        }
+        s.push_str(&format!("auth_max_failures = {}\n", self.auth.max_failures));
+        s.push_str(&format!(
+            "auth_lockout_secs = {}\n\n",
+            self.auth.lockout.as_secs()
+        ));
+        s.push_str("# BatMUD server the proxy connects to for each client. Each address the\n");
+        s.push_str("# host resolves to is tried in turn, for connect_timeout_secs each, and\n");
+        s.push_str("# the host is resolved again on every reconnect.\n");
+        s.push_str(&format!("remote = {}\n", self.remote));
+        s.push_str(&format!(
+            "connect_timeout_secs = {}\n\n",
+            self.connect_timeout.as_secs()
+        ));
+        s.push_str("# remote is another bcproxy, e.g. one on a home server with this one on\n");
+        s.push_str("# a laptop. Its output, transformed already, is passed through as it\n");
+        s.push_str("# is once it says it is a bcproxy.\n");
+        s.push_str(&format!("chain = {}\n\n", to_on_off(self.chain)));
+        s.push_str("# Address of a read-only HTTP API with JSON endpoints for rooms,\n");
+        s.push_str("# monsters and connected sessions, e.g. 127.0.0.1:7789, and the traffic\n");
+        s.push_str("# of each session for Prometheus at /metrics. There is no password, so\n");
+        s.push_str("# a TCP address must be on the loopback interface. A path is a Unix\n");
+        s.push_str("# socket.\n");
+        match &self.api_listen {
+            Some(addr) => s.push_str(&format!("api_listen = {}\n\n", addr)),
+            None => s.push_str("# api_listen = 127.0.0.1:7789\n\n"),
+        }
+        s.push_str("# Address of the admin console, a line based prompt to list and kick\n");
+        s.push_str("# sessions, show metrics and reload this file. A path is a Unix socket,\n");
+        s.push_str("# a TCP address must be on the loopback interface. Try `help` there.\n");
+        match &self.admin_listen {
+            Some(addr) => s.push_str(&format!("admin_listen = {}\n\n", addr)),
+            None => s.push_str("# admin_listen = 127.0.0.1:7792\n\n"),
+        }
+        s.push_str("# Unix socket for live upgrades. A new proxy started with --takeover\n");
+        s.push_str("# connects to it and takes over the listeners and the server connections\n");
+        s.push_str("# of the running one, which then exits. Its clients connect again and\n");
+        s.push_str("# carry on, still logged in to the server. Both must run as the same\n");
+        s.push_str("# user.\n");
+        match &self.upgrade_socket {
+            Some(path) => s.push_str(&format!("upgrade_socket = {}\n\n", path.display())),
+            None => s.push_str("# upgrade_socket = /run/bcproxy/upgrade.sock\n\n"),
+        }
+        s.push_str("# Address browser clients connect to over WebSocket. They get output in\n");
+        s.push_str("# the json style, a JSON object per message, and each message they send\n");
+        s.push_str("# is a line of input.\n");
+        match &self.websocket_listen {
+            Some(addr) => s.push_str(&format!("websocket_listen = {}\n\n", addr)),
+            None => s.push_str("# websocket_listen = 127.0.0.1:7790\n\n"),
+        }
+        s.push_str("# Origins of the web pages allowed to connect there, separated by\n");
+        s.push_str("# spaces. Any other page open in the browser could drive the session, so\n");
+        s.push_str("# it must give the password, even on the loopback interface, and is\n");
+        s.push_str("# refused if there is none.\n");
+        if self.websocket_origins.is_empty() {
+            s.push_str("# websocket_origins = http://localhost:8080\n\n");
+        } else {
+            s.push_str(&format!(
+                "websocket_origins = {}\n\n",
+                self.websocket_origins.join(" ")
+            ));
+        }
+        s.push_str("# Channels can be sent elsewhere than the client with\n");
+        s.push_str("# `#bc chan <channel> port|log`: to clients of channel_listen, or to the\n");
+        s.push_str("# end of channel_log, as lines like `[sales] text`.\n");
+        match &self.channel_listen {
+            Some(addr) => s.push_str(&format!("channel_listen = {}\n", addr)),
+            None => s.push_str("# channel_listen = 127.0.0.1:7791\n"),
+        }
+        match &self.channel_log {
+            Some(path) => s.push_str(&format!("channel_log = {}\n\n", path.display())),
+            None => s.push_str("# channel_log = channels.log\n\n"),
+        }
+        s.push_str("# With split_log_dir set, server output is also written to a file per\n");
+        s.push_str("# kind in that directory: channels, tells, battle, mapper for the rooms\n");
+        s.push_str("# the mapper reports, and raw for everything as the server sent it.\n");
+        s.push_str("# split_log_files lists those written. Files are named with the strftime\n");
+        s.push_str("# format split_log_name, {file} standing for the kind, so a name with the\n");
+        s.push_str("# date in it starts new files every day.\n");
+        match &self.split_log.dir {
+            Some(dir) => s.push_str(&format!("split_log_dir = {}\n", dir.display())),
+            None => s.push_str("# split_log_dir = logs\n"),
+        }
+        let files: Vec<String> = self
+            .split_log
+            .files
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        s.push_str(&format!("split_log_files = {}\n", files.join(" ")));
+        s.push_str(&format!("split_log_name = {}\n\n", self.split_log.name()));
+        s.push_str("# Settings channels start with, one line each, taking the same settings\n");
+        s.push_str("# as `#bc chan`: show, mute, port, log, color <color> or color off.\n");
+        if self.channels.is_empty() {
+            s.push_str("# channel = sales mute\n");
+        }
+        for rule in &self.channels {
+            s.push_str(&format!("channel = {}\n", rule));
+        }
+        s.push('\n');
+        s.push_str("# Control code sequences written to the client in a single write, as\n");
+        s.push_str("# code ids optionally followed by `:attribute`. One line per sequence.\n");
+        for seq in &self.merge.sequences {
+            let codes: Vec<String> = seq.iter().map(ToString::to_string).collect();
+            s.push_str(&format!("merge = {}\n", codes.join(" ")));
+        }
+        s.push_str("\n# How long to wait for the rest of a sequence before writing anyway.\n");
+        s.push_str(&format!(
+            "merge_window_ms = {}\n",
+            self.merge.window.as_millis()
+        ));
+        s.push_str("\n# Control codes the server repeats often, thinned out before they are\n");
+        s.push_str("# logged or reach the client: `dedup` drops a code identical to the last\n");
+        s.push_str("# one, `<n>/s` lets n of them through a second. Codes are given as for\n");
+        s.push_str("# merge.\n");
+        if self.throttles.is_empty() {
+            s.push_str("# throttle = 50 dedup\n");
+            s.push_str("# throttle = 54 5/s\n");
+        }
+        for rule in &self.throttles {
+            s.push_str(&format!("throttle = {}\n", rule));
+        }
+        s.push_str("\n# Triggers run on each line of server output:\n");
+        s.push_str(
+            "#   trigger = [@<message type>] <glob or re:regex> => <action> [| <action>...]\n",
+        );
+        s.push_str(
+            "# Actions are `send <command>`, `highlight`, `bell` and `webhook <http(s) url>`.\n",
+        );
+        s.push_str("# e.g. trigger = @chan_sales *sword* => highlight | bell\n");
+        for trigger in &self.triggers {
+            s.push_str(&format!("trigger = {}\n", trigger));
+        }
+        s.push_str("\n# Minimum time between two firings of the same trigger.\n");
+        s.push_str(&format!(
+            "trigger_cooldown_ms = {}\n",
+            self.trigger_cooldown.as_millis()
+        ));
+        s.push_str("\n# Webhooks posted when something happens:\n");
+        s.push_str("#   notifier = <event> => <format> <http(s) url> [<template>]\n");
+        s.push_str("# Events are `@<message type> [<glob or re:regex>]`, `party_death` and\n");
+        s.push_str("# `disconnect`, formats discord, slack and json. The template may use\n");
+        s.push_str("# {event}, {type}, {name} and {text}.\n");
+        s.push_str(
+            "# e.g. notifier = @tell *Bob* => discord https://discord.com/api/webhooks/1/x\n",
+        );
+        for notifier in &self.notifiers {
+            s.push_str(&format!("notifier = {}\n", notifier));
+        }
+        s.push_str("\n# Minimum time between two posts of the same notifier. Those left out\n");
+        s.push_str("# are counted in the next one.\n");
+        s.push_str(&format!(
+            "notifier_interval_ms = {}\n",
+            self.notifier_interval.as_millis()
+        ));
+        s.push_str("\n# Server output waiting for a client that reads slower than the server\n");
+        s.push_str("# sends is kept up to output_queue_kb. Then slow_client says what\n");
+        s.push_str("# happens: block stops reading from the server until the client catches\n");
+        s.push_str("# up, drop drops the oldest waiting text but never control codes, and\n");
+        s.push_str("# disconnect closes the connection.\n");
+        s.push_str(&format!(
+            "output_queue_kb = {}\n",
+            self.output_queue.limit / 1024
+        ));
+        s.push_str(&format!(
+            "slow_client = {}\n",
+            self.output_queue.slow_client
+        ));
+        s.push_str("\n# What is logged to stderr: a level such as error, warn, info, debug or\n");
+        s.push_str("# trace, or a filter like `batproxy_rs=debug,warn`. `#bc log <level>`\n");
+        s.push_str("# changes it while running, as does SIGHUP after editing it here.\n");
+        s.push_str("# log_format is text or json, log_frames logs every decoded frame of\n");
+        s.push_str("# server output at trace level.\n");
+        s.push_str(&format!("log_level = {}\n", self.log.level));
+        s.push_str(&format!("log_format = {}\n", self.log.format));
+        s.push_str(&format!("log_frames = {}\n", to_on_off(self.log.frames)));
+        s.push_str("\n# Reconnect when the server drops the connection, unless the player\n");
+        s.push_str("# quit, while the client stays connected. The wait between attempts\n");
+        s.push_str("# starts at a second and doubles up to reconnect_max_delay_secs. The\n");
+        s.push_str("# auto-login below is sent again on the new connection.\n");
+        s.push_str(&format!(
+            "reconnect = {}\n",
+            to_on_off(self.reconnect.enabled)
+        ));
+        s.push_str(&format!(
+            "reconnect_max_delay_secs = {}\n",
+            self.reconnect.max_delay.as_secs()
+        ));
+        s.push_str("\n# When the server sends nothing for watchdog_secs after the client sent\n");
+        s.push_str("# something, send watchdog_probe and wait as long again. If it still\n");
+        s.push_str("# says nothing the connection is dropped and reconnected, or the client\n");
+        s.push_str("# is told when reconnect is off. 0 turns it off, an empty probe skips\n");
+        s.push_str("# the probe.\n");
+        s.push_str(&format!(
+            "watchdog_secs = {}\n",
+            self.watchdog.timeout.as_secs()
+        ));
+        s.push_str(&format!("watchdog_probe = {}\n", self.watchdog.probe));
+        s.push_str("\n# Log in when the server connection opens: the proxy turns on BC mode,\n");
+        s.push_str("# sends login_name and the password, and once the server accepts them\n");
+        s.push_str("# each login_command in turn. The password is read from the environment\n");
+        s.push_str("# variable login_password_env, or is the first line login_password_command\n");
+        s.push_str("# prints, e.g. `secret-tool lookup service batmud`. It is never stored\n");
+        s.push_str("# here.\n");
+        if self.login.is_enabled() {
+            s.push_str(&format!("login_name = {}\n", self.login.name));
+        } else {
+            s.push_str("# login_name = mychar\n");
+        }
+        match &self.login.password {
+            Some(Secret::Env(var)) => s.push_str(&format!("login_password_env = {}\n", var)),
+            Some(Secret::Command(command)) => {
+                s.push_str(&format!("login_password_command = {}\n", command))
+            }
+            None => s.push_str("# login_password_env = BATMUD_PASSWORD\n"),
+        }
+        for command in &self.login.commands {
+            s.push_str(&format!("login_command = {}\n", command));
+        }
+        s.push_str("\n# Words that stand for longer commands when typed at the start of a\n");
+        s.push_str("# line. `$*` is replaced by the rest of the line, which is otherwise\n");
+        s.push_str("# added to the end.\n");
+        s.push_str("# e.g. alias = ks => cast 'kiss of death' at $*\n");
+        for alias in &self.aliases {
+            s.push_str(&format!("alias = {}\n", alias));
+        }
+        s.push_str("\n# Color text wherever it appears in server output:\n");
+        s.push_str(
+            "#   highlight = <text or re:regex> => [fg=<color>] [bg=<color>] [bold] [underline]\n",
+        );
+        s.push_str("# Colors are RGB like ff8800 or xterm palette indexes like 208.\n");
+        s.push_str("# e.g. highlight = Bob => fg=ff8800 bold\n");
+        for highlight in &self.highlights {
+            s.push_str(&format!("highlight = {}\n", highlight));
+        }
+        s.push_str("\n# Colors the client can show: truecolor, 256, 16 or none. Server colors\n");
+        s.push_str("# and highlights are converted to fit. Each client can change it with\n");
+        s.push_str("# `#bc color <mode>`.\n");
+        s.push_str(&format!("color_mode = {}\n", self.color_mode));
+        s.push_str("\n# Plain text output for braille displays and other tools: ANSI codes are\n");
+        s.push_str("# removed, bold becomes *text* and underline _text_. Each client can\n");
+        s.push_str("# change it with `#bc plain on|off`.\n");
+        s.push_str(&format!(
+            "plain_output = {}\n",
+            to_on_off(self.plain_output)
+        ));
+        s.push_str("\n# Ask clients for their window size and terminal type with telnet\n");
+        s.push_str("# NAWS and TTYPE when they connect. A color depth the client reports\n");
+        s.push_str("# replaces color_mode for it.\n");
+        s.push_str(&format!(
+            "client_negotiation = {}\n",
+            to_on_off(self.client_negotiation)
+        ));
+        s.push_str("\n# Pass the server's telnet ECHO negotiation on, so clients stop showing\n");
+        s.push_str("# what is typed at password prompts. Lines typed while the server echoes\n");
+        s.push_str("# go to it as they are either way.\n");
+        s.push_str(&format!(
+            "echo_negotiation = {}\n",
+            to_on_off(self.echo_negotiation)
+        ));
+        s.push_str("\n# Wrap long lines at the window width the client reports, between words\n");
+        s.push_str("# where possible, and cut maps wider than the window. Each client can\n");
+        s.push_str("# change it with `#bc wrap on|off`.\n");
+        s.push_str(&format!("wrap = {}\n", to_on_off(self.wrap)));
+        s.push_str("\n# Start each line with the time, written with a strftime format such\n");
+        s.push_str("# as [%H:%M:%S], and with timestamp_elapsed the time since the line\n");
+        s.push_str("# before. Each client can change it with `#bc timestamps on|off`.\n");
+        s.push_str(&format!("timestamps = {}\n", to_on_off(self.timestamps)));
+        s.push_str(&format!("timestamp_format = {}\n", self.timestamp_format));
+        s.push_str(&format!(
+            "timestamp_elapsed = {}\n",
+            to_on_off(self.timestamp_elapsed)
+        ));
+        s.push_str("\n# How control codes reach the client: raw passes them on as BC codes,\n");
+        s.push_str("# legacy-bc, bat-emoji and pi-prefix turn them into text lines tagged\n");
+        s.push_str("# [chan_sales], 🦇chan_sales or πchan_sales, and json writes a JSON\n");
+        s.push_str("# object per message and line for scripts to read. Each client can\n");
+        s.push_str("# change it with `#bc style <style>`.\n");
+        s.push_str(&format!("output_style = {}\n", self.output_style));
+        s.push_str("\n# Hyperlinks from the server are written as OSC 8 links terminals let\n");
+        s.push_str("# you click with osc8, or as [text](url) with text. auto picks osc8\n");
+        s.push_str("# for clients shown true color. Each client can change it with\n");
+        s.push_str("# `#bc links <style>`.\n");
+        s.push_str(&format!("hyperlinks = {}\n", self.hyperlinks));
+        s.push_str("\n# The map drawn around the player is shown in full, compact without\n");
+        s.push_str("# blank lines and trailing spaces, with coordinates numbering its rows\n");
+        s.push_str("# and columns, or off. Each client can change it with\n");
+        s.push_str("# `#bc map <rendering>`.\n");
+        s.push_str(&format!("map_render = {}\n", self.map_render));
+        s.push_str("# With map_listen set, maps are sent to clients of that address, such\n");
+        s.push_str("# as a second terminal, instead of the player's client.\n");
+        match &self.map_listen {
+            Some(addr) => s.push_str(&format!("map_listen = {}\n", addr)),
+            None => s.push_str("# map_listen = 127.0.0.1:7793\n"),
+        }
+        s.push_str("# `#bc map export <area> to <file>` writes the file into export_dir,\n");
+        s.push_str("# a plain file name without a directory. Off if not set.\n");
+        match &self.export_dir {
+            Some(dir) => s.push_str(&format!("export_dir = {}\n", dir.display())),
+            None => s.push_str("# export_dir = maps\n"),
+        }
+        s.push_str("\n# With party_listen set, clients of that address get a line with the\n");
+        s.push_str("# player's points, room, outworld location and target each time one\n");
+        s.push_str("# changes, such as `Bob hp=120/150 sp=30/80 ep=90/100 room=arelium:1`.\n");
+        s.push_str("# Lines start with party_name, or login_name if it is not set. There is\n");
+        s.push_str("# no password, so a TCP address must be on the loopback interface; party\n");
+        s.push_str("# members on other machines reach it through a tunnel such as ssh -L.\n");
+        match &self.party_listen {
+            Some(addr) => s.push_str(&format!("party_listen = {}\n", addr)),
+            None => s.push_str("# party_listen = 127.0.0.1:7794\n"),
+        }
+        match &self.party_name {
+            Some(name) => s.push_str(&format!("party_name = {}\n", name)),
+            None => s.push_str("# party_name = Bob\n"),
+        }
+        s.push_str("\n# Control codes longer than max_code_bytes or nested deeper than\n");
+        s.push_str("# max_code_depth are passed on as text instead of being buffered.\n");
+        s.push_str(&format!(
+            "max_code_bytes = {}\n",
+            self.decoder.max_code_bytes
+        ));
+        s.push_str(&format!("max_code_depth = {}\n", self.decoder.max_depth));
+        s.push_str("\n# Server output breaking the BC protocol, such as a closing tag for a\n");
+        s.push_str("# code that is not open, is passed on as text. permissive warns about\n");
+        s.push_str("# it, strict logs it as an error with the output it was in, and\n");
+        s.push_str("# strict-disconnect also closes the session, to catch protocol changes\n");
+        s.push_str("# while developing.\n");
+        s.push_str(&format!("protocol = {}\n", self.protocol));
+        s.push_str("\n# The BC mode level the auto-login turns on. Server output with no\n");
+        s.push_str("# control codes in its first 16 kB after all is passed through as it\n");
+        s.push_str("# is, without decoding, until BC mode is asked for again. off passes it\n");
+        s.push_str("# through from the start, unless the player turns BC mode on.\n");
+        match self.bc_mode {
+            Some(level) => s.push_str(&format!("bc_mode = {}\n", level)),
+            None => s.push_str("bc_mode = off\n"),
+        }
+        s.push_str("\n# Lua script with hooks run on server output.\n");
+        match &self.script {
+            Some(path) => s.push_str(&format!("script = {}\n", path.display())),
+            None => s.push_str("# script = hooks.lua\n"),
+        }
+        s.push_str("\n# SQLite database rooms and outworld map tiles seen by the mapper are\n");
+        s.push_str("# stored in. Sessions also keep their room, prompt, effects and exp\n");
+        s.push_str("# there when the proxy shuts down, for the next session of the profile.\n");
+        match &self.database {
+            Some(path) => s.push_str(&format!("database = {}\n", path.display())),
+            None => s.push_str("# database = bcproxy.db\n"),
+        }
+        s.push_str("\n# Writes to the database are committed together once db_batch_ms have\n");
+        s.push_str("# passed since the first of them, or db_batch_events are waiting.\n");
+        s.push_str(&format!(
+            "db_batch_ms = {}\n",
+            self.db_batch.interval.as_millis()
+        ));
+        s.push_str(&format!("db_batch_events = {}\n", self.db_batch.max_events));
+        s.push_str("\n# Keep channel messages in the database, replayed with\n");
+        s.push_str("# `#bc recall <channel> [count]`.\n");
+        s.push_str(&format!(
+            "chat_history = {}\n",
+            to_on_off(self.chat_history)
+        ));
+        s.push_str("\n# Write channel messages, the player's points or the rooms the mapper\n");
+        s.push_str("# reports as JSON lines to a named pipe or Unix socket, for programs\n");
+        s.push_str("# such as notification daemons:\n");
+        s.push_str("#   tap = channels|vitals|rooms <path>\n");
+        if self.taps.is_empty() {
+            s.push_str("# tap = channels /tmp/bcproxy-channels\n");
+        }
+        for tap in &self.taps {
+            s.push_str(&format!("tap = {}\n", tap));
+        }
+        s.push_str("\n# Send keepalive_command after this many minutes without client input,\n");
+        s.push_str("# plus up to keepalive_jitter_seconds. 0 turns it off. An empty command\n");
+        s.push_str("# sends a blank line.\n");
+        s.push_str(&format!(
+            "keepalive_idle_minutes = {}\n",
+            self.keepalive.idle.as_secs() / 60
+        ));
+        s.push_str(&format!(
+            "keepalive_jitter_seconds = {}\n",
+            self.keepalive.jitter.as_secs()
+        ));
+        s.push_str(&format!("keepalive_command = {}\n", self.keepalive.command));
+        s.push_str("\n# Expand speedwalks typed by the player: `3n2e u` walks n, n, n, e, e, u\n");
+        s.push_str("# and `2(n e)` repeats n, e twice.\n");
+        s.push_str(&format!("speedwalk = {}\n", to_on_off(self.speedwalk)));
+        s.push_str("\n# Time between the steps of speedwalks and `#bc go`. 0 sends them all\n");
+        s.push_str("# at once.\n");
+        s.push_str(&format!(
+            "walk_delay_ms = {}\n",
+            self.walk_delay.as_millis()
+        ));
+        s.push_str("\n# Server lines matching these regexes stop a walk. One line per regex.\n");
+        for re in &self.walk_abort {
+            s.push_str(&format!("walk_abort = {}\n", re));
+        }
+        s.push_str("\n# Server lines that start a listing of the inventory or equipment, as\n");
+        s.push_str("# `inv|eq <glob or re:regex>`. The lines after one up to a blank line or\n");
+        s.push_str("# the prompt are its items, `;;inv diff` shows what changed between the\n");
+        s.push_str("# last two listings. One line per header.\n");
+        for header in &self.inventory_headers {
+            s.push_str(&format!("inventory_header = {}\n", header));
+        }
+        s.push_str("\n# Lines the player sends can go to the server one at a time, so that a\n");
+        s.push_str("# long paste is not cut short: prompt sends each after the server's\n");
+        s.push_str("# prompt for the last one, a number that many milliseconds after it.\n");
+        s.push_str("# `;;queue show` lists the lines waiting, `;;queue clear` drops them.\n");
+        s.push_str(&format!("command_queue = {}\n", self.command_queue));
+        s.push_str("\n# The last lines the player typed are kept for `;;history [<count>]`,\n");
+        s.push_str("# and `;;!<prefix>` sends the last one starting with prefix again. Lines\n");
+        s.push_str("# typed before the login or while the server hides them are not kept.\n");
+        s.push_str("# 0 keeps none.\n");
+        s.push_str(&format!("history_size = {}\n", self.history_size));
+        s.push_str("\n# How the rounds left of a spell or skill are shown: prompt adds them\n");
+        s.push_str("# to the end of the prompt, line shows a line on every change, off\n");
+        s.push_str("# hides them. Each client can change it with `#bc countdown <mode>`.\n");
+        s.push_str(&format!("countdown = {}\n", self.countdown));
+        s.push_str("\n# Warn this many seconds before an effect on the player runs out, with\n");
+        s.push_str("# a bell if effect_bell is on. 0 turns the warning off. `#bc effects`\n");
+        s.push_str("# lists the effects and their time left.\n");
+        s.push_str(&format!(
+            "effect_warning_secs = {}\n",
+            self.effect_warning.as_secs()
+        ));
+        s.push_str(&format!("effect_bell = {}\n", to_on_off(self.effect_bell)));
+        s.push_str("\n# Safeguards send commands when the player's points run low:\n");
+        s.push_str("#   safeguard = hp|sp|ep < <points>[%] => <action> [| <action>...]\n");
+        s.push_str("# Actions are `send <command>` and `flee`, which leaves the room the way\n");
+        s.push_str("# the player came in. Each fires at most once every\n");
+        s.push_str("# safeguard_cooldown_secs, with a line and a bell. `#bc safety off`\n");
+        s.push_str("# stops them all until `#bc safety on`.\n");
+        s.push_str("# e.g. safeguard = hp < 20% => send quaff heal | flee\n");
+        for safeguard in &self.safeguards {
+            s.push_str(&format!("safeguard = {}\n", safeguard));
+        }
+        s.push_str(&format!(
+            "safeguard_cooldown_secs = {}\n",
+            self.safeguard_cooldown.as_secs()
+        ));
+        s.push_str("\n# The tick is learned from the player's points going up. prompt adds\n");
+        s.push_str("# the time to the next one to the end of the prompt, line shows a line\n");
+        s.push_str("# tick_warning_secs before each, off hides it. Each client can change\n");
+        s.push_str("# it with `#bc tick <mode>`.\n");
+        s.push_str(&format!("tick_countdown = {}\n", self.tick_countdown));
+        s.push_str(&format!(
+            "tick_warning_secs = {}\n",
+            self.tick_warning.as_secs()
+        ));
+        s.push_str("\n# The session clock, with when the client connected, how long the\n");
+        s.push_str("# player has been idle and the experience gained. prompt adds it to the\n");
+        s.push_str("# end of each prompt, line shows it on a line before each prompt, off\n");
+        s.push_str("# hides it. Each client can change it with `#bc clock <mode>`.\n");
+        s.push_str(&format!("clock = {}\n", self.clock));
+        s.push_str("\n# How the health of the player's target is shown: blocks, ascii,\n");
+        s.push_str("# percent or off to pass the server's code on as it is.\n");
+        s.push_str(&format!("target_bar = {}\n", self.target_bar));
+        s.push_str(&format!("target_bar_width = {}\n", self.target_bar_width));
+        s.push_str("\n# Record each target that reaches 0 hp in the database, with the room\n");
+        s.push_str("# it died in.\n");
+        s.push_str(&format!("kill_log = {}\n", to_on_off(self.kill_log)));
+        s.push_str("\n# `#bc exprate` shows the experience per hour over this many minutes and\n");
+        s.push_str("# over the session.\n");
+        s.push_str(&format!(
+            "exp_window_minutes = {}\n",
+            self.exp_window.as_secs() / 60
+        ));
+        s.push_str("\n# Show who hit whom how often once a fight is over, counted from the\n");
+        s.push_str("# server's battle messages.\n");
+        s.push_str(&format!(
+            "battle_summary = {}\n",
+            to_on_off(self.battle_summary)
+        ));
+        s.push_str("\n# Translate messages on these channels with a LibreTranslate compatible\n");
+        s.push_str("# http:// or https:// API, e.g. http://localhost:5000/translate. The\n");
+        s.push_str("# translation is shown as an indented line below the message.\n");
+        if self.translate.url.is_empty() {
+            s.push_str("# translate_url = http://localhost:5000/translate\n");
+        } else {
+            s.push_str(&format!("translate_url = {}\n", self.translate.url));
+        }
+        s.push_str(&format!("translate_source = {}\n", self.translate.source));
+        s.push_str(&format!("translate_target = {}\n", self.translate.target));
+        s.push_str(&format!(
+            "translate_channels = {}\n",
+            self.translate.channels.join(" ")
+        ));
+        s.push_str("\n# The layers server output goes through, in order. Leave one out to turn\n");
+        s.push_str("# it off. Layers are logs, mapper, map, battle, exp, script, hooks, notify,\n");
+        s.push_str("# channels, triggers, translate, highlight, actions, effects, safety,\n");
+        s.push_str("# tick, target, links, color and wrap.\n");
+        let layers: Vec<String> = self.middleware.iter().map(ToString::to_string).collect();
+        s.push_str(&format!("middleware = {}\n", layers.join(" ")));
+        s.push_str("\n# Profiles for other characters or servers, each a section that starts\n");
+        s.push_str("# with `[profile <name>]` and runs to the next one or the end of the file.\n");
+        s.push_str("# It takes the settings above with its own lines on top, such as remote,\n");
+        s.push_str("# script, database, triggers and aliases. Clients connect to the\n");
+        s.push_str("# profile's listen address, by default the port above plus the number of\n");
+        s.push_str("# the profile, or type its name when asked on the port above. api_listen,\n");
+        s.push_str("# admin_listen, upgrade_socket, websocket_listen, channel_listen,\n");
+        s.push_str("# map_listen and party_listen only apply above the profiles.\n");
+        if self.profiles.is_empty() {
+            s.push_str("# [profile testchar]\n");
+            s.push_str("# remote = localhost:2023\n");
+            s.push_str("# database = testchar.db\n");
+        }
+        for profile in &self.profiles {
+            s.push_str(&format!("\n[profile {}]\n", profile.name));
+            for line in &profile.lines {
+                s.push_str(line);
+                s.push('\n');
+            }
+        }
+        s
+    }
+}
+
+/// `addr` with its port moved up by `n`.
+fn next_port(addr: &str, n: usize) -> Option<String> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let port = port
+        .parse::<u16>()
+        .ok()?
+        .checked_add(u16::try_from(n).ok()?)?;
+    Some(format!("{}:{}", host, port))
+}
+
+fn on_off(line: usize, key: &str, value: &str) -> io::Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(invalid(line, &format!("{} must be on or off", key))),
+    }
+}
+
+fn minutes(line: usize, key: &str, value: &str) -> io::Result<Duration> {
+    value
+        .parse::<u64>()
+        .ok()
+        .and_then(|minutes| minutes.checked_mul(60))
+        .map(Duration::from_secs)
+        .ok_or_else(|| invalid(line, &format!("{} must be a number of minutes", key)))
+}
+
+fn to_on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("config line {}: {}", line + 1, msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = "listen = 127.0.0.1:7000\n\
+                            remote = batmud.bat.org:2023\n\
+                            alias = k => kill $*\n\
+                            [profile test]\n\
+                            remote = localhost:2023\n\
+                            \n\
+                            [profile alt]\n\
+                            listen = 127.0.0.1:9000\n\
+                            wrap = off\n";
+
+    #[test]
+    fn profiles_take_the_main_settings_with_their_own_on_top() {
+        let config = Config::parse(PROFILES).unwrap();
+        assert_eq!(config.remote, "batmud.bat.org:2023");
+        let [test, alt] = &config.profiles[..] else {
+            panic!("{:?}", config.profiles);
+        };
+        assert_eq!(test.name, "test");
+        assert_eq!(test.lines, ["remote = localhost:2023"]);
+        assert_eq!(test.config.remote, "localhost:2023");
+        assert_eq!(test.config.aliases, config.aliases);
+        assert_eq!(alt.config.remote, "batmud.bat.org:2023");
+        assert!(!alt.config.wrap);
+        assert!(alt.config.profiles.is_empty());
+    }
+
+    #[test]
+    fn profiles_listen_after_the_main_port_unless_told() {
+        let config = Config::parse(PROFILES).unwrap();
+        assert_eq!(config.listen, [Listen::new("127.0.0.1:7000")]);
+        assert_eq!(
+            config.profiles[0].config.listen,
+            [Listen::new("127.0.0.1:7001")]
+        );
+        assert_eq!(
+            config.profiles[1].config.listen,
+            [Listen::new("127.0.0.1:9000")]
+        );
+
+        let error =
+            Config::parse("listen = 127.0.0.1:65535\n[profile test]\nwrap = off\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config line 3: profile `test` needs a listen address"
+        );
+    }
+
+    #[test]
+    fn profile_headers_are_checked() {
+        for (config, error) in [
+            ("[profile]\n", "config line 1: expected `[profile <name>]`"),
+            ("[test]\n", "config line 1: expected `[profile <name>]`"),
+            (
+                "[profile a]\n[profile a]\n",
+                "config line 2: profile `a` is defined twice",
+            ),
+            (
+                "[profile a]\nwrap = maybe\n",
+                "config line 2: wrap must be on or off",
+            ),
+        ] {
+            assert_eq!(Config::parse(config).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn profiles_are_written_back_as_their_lines() {
+        let config = Config::parse(PROFILES).unwrap();
+        let written = config.to_file_string();
+        assert!(written.ends_with(
+            "\n[profile test]\nremote = localhost:2023\n\
+             \n[profile alt]\nlisten = 127.0.0.1:9000\nwrap = off\n"
+        ));
+        let again = Config::parse(&written).unwrap();
+        let names: Vec<&str> = again.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["test", "alt"]);
+        assert_eq!(
+            again.profiles[0].config.listen,
+            [Listen::new("127.0.0.1:7001")]
+        );
+    }
+}
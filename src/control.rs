@@ -0,0 +1,818 @@
+//! Control lines let client scripts feed structured data to the proxy.
+//!
+//! A control line is `;;<topic>;;<field>;;<field>...`. It is taken out of
+//! the client input and handed to the topic's handler instead of being
+//! sent to the server. Topics are registered in [`TOPICS`]:
+//!
+//! - `;;monster:exp;;<monster name>;;<exp>` records the experience a kill
+//!   gave, together with the room the mapper last reported.
+//! - `;;monster:aggro;;<monster name>` records that the monster attacks on
+//!   sight, and the player is warned on entering the room again.
+//! - `;;mob;;find;;<name>` lists the monsters recorded with that in their
+//!   name.
+//! - `;;stats` shows the session's traffic and latency like `#bc stats`.
+//! - `;;link;;<n>` sends the command of in-game link `n` of the room, and
+//!   `;;link` lists them.
+//! - `;;queue;;show` lists the lines waiting in the command queue and
+//!   `;;queue;;clear` drops them.
+//! - `;;area;;stats;;<area>` shows how much of an area the mapper has
+//!   seen, of the current area without one.
+//! - `;;visits;;<area>` lists the rooms of an area the player went to the
+//!   most and the least, of the current area without one.
+//! - `;;room;;find;;<text>` lists the rooms of the current area whose
+//!   description has `text` in it, `;;room;;find;;<text>;;all` those of
+//!   every area.
+//! - `;;inv;;show` lists what the player carried at the last `i`, and
+//!   `;;inv;;diff` what changed since the one before. `;;inv;;show;;eq` and
+//!   `;;inv;;diff;;eq` do the same for the equipment.
+//! - `;;price;;<item>` shows the prices asked and bid for an item on the
+//!   sales channel.
+//! - `;;corpse` shows where the player last died and the way there,
+//!   `;;corpse;;go` walks there.
+//! - `;;explore` shows the way to the closest room with an exit not taken
+//!   yet.
+//! - `;;history [<count>]` lists the last lines the player typed, 20 of
+//!   them without a count, and `;;!<prefix>` sends the last one starting
+//!   with `prefix` again, the last one of all without a prefix.
+//! - `;;codes` lists every control code id the server sent this session,
+//!   how often, how many bytes, when last and the last attribute.
+//!
+//! The first field may also follow the topic after a space, as in
+//! `;;link 3`.
+
+use std::{
+    collections::HashSet,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    command,
+    db::{Db, Event, RoomVisits},
+    death::Corpse,
+    inventory::{Kind, Snapshot},
+    path,
+    sales::{self, Side},
+    session::Session,
+};
+
+pub const PREFIX: &str = ";;";
+
+pub struct Topic {
+    pub name: &'static str,
+    /// The fields the topic expects, for error messages.
+    pub usage: &'static str,
+    handler: fn(&[&str], &mut Session) -> Result<(), String>,
+}
+
+pub const TOPICS: &[Topic] = &[
+    Topic {
+        name: "monster:exp",
+        usage: "<monster name>;;<exp>",
+        handler: monster_exp,
+    },
+    Topic {
+        name: "monster:aggro",
+        usage: "<monster name>",
+        handler: monster_aggro,
+    },
+    Topic {
+        name: "mob",
+        usage: "find;;<name>",
+        handler: mob,
+    },
+    Topic {
+        name: "stats",
+        usage: "",
+        handler: stats,
+    },
+    Topic {
+        name: "codes",
+        usage: "",
+        handler: codes,
+    },
+    Topic {
+        name: "link",
+        usage: "<number>",
+        handler: link,
+    },
+    Topic {
+        name: "queue",
+        usage: "show|clear",
+        handler: queue,
+    },
+    Topic {
+        name: "area",
+        usage: "stats;;<area>",
+        handler: area,
+    },
+    Topic {
+        name: "visits",
+        usage: "<area>",
+        handler: visits,
+    },
+    Topic {
+        name: "room",
+        usage: "find;;<text>[;;all]",
+        handler: room,
+    },
+    Topic {
+        name: "inv",
+        usage: "show|diff[;;eq]",
+        handler: inv,
+    },
+    Topic {
+        name: "price",
+        usage: "<item>",
+        handler: price,
+    },
+    Topic {
+        name: "corpse",
+        usage: "[go]",
+        handler: corpse,
+    },
+    Topic {
+        name: "explore",
+        usage: "",
+        handler: explore,
+    },
+    Topic {
+        name: "history",
+        usage: "[<count>]",
+        handler: history,
+    },
+];
+
+/// Lines `;;history` lists without a count.
+const HISTORY_LINES: usize = 20;
+
+/// Handle `line` if it is a control line. Returns false if it should go to
+/// the server.
+pub fn handle(line: &str, session: &mut Session) -> bool {
+    let rest = match line.trim().strip_prefix(PREFIX) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    if let Some(prefix) = rest.strip_prefix('!') {
+        recall(prefix.trim(), session);
+        return true;
+    }
+
+    let mut fields = rest.split(PREFIX);
+    let name = fields.next().unwrap_or_default();
+    let (name, first) = match name.split_once(' ') {
+        Some((name, first)) => (name, Some(first.trim())),
+        None => (name, None),
+    };
+    let fields: Vec<&str> = first.into_iter().chain(fields).collect();
+
+    match TOPICS.iter().find(|topic| topic.name == name) {
+        Some(topic) => {
+            if let Err(e) = (topic.handler)(&fields, session) {
+                session.notify(&format!(
+                    "{}: {}, expected {}{}{}{}",
+                    topic.name, e, PREFIX, topic.name, PREFIX, topic.usage
+                ));
+            }
+        }
+        None => {
+            let names: Vec<&str> = TOPICS.iter().map(|topic| topic.name).collect();
+            session.notify(&format!(
+                "unknown control line topic `{}`, known topics: {}",
+                name,
+                names.join(", ")
+            ));
+        }
+    }
+    true
+}
+
+fn monster_exp(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let (name, exp) = match fields {
+        [name, exp] if !name.trim().is_empty() => (name.trim(), exp.trim()),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let exp = exp
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", exp))?;
+
+    if let Some(db) = &session.db {
+        db.send(Event::Monster {
+            name: name.to_string(),
+            exp,
+            area: session.last_room.as_ref().map(|room| room.area.clone()),
+            room_id: session.last_room.as_ref().map(|room| room.id.clone()),
+        });
+    }
+    Ok(())
+}
+
+fn monster_aggro(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let name = match fields {
+        [name] if !name.trim().is_empty() => name.trim(),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    if let Some(db) = &session.db {
+        db.send(Event::Aggro {
+            name: name.to_string(),
+            area: session.last_room.as_ref().map(|room| room.area.clone()),
+            room_id: session.last_room.as_ref().map(|room| room.id.clone()),
+        });
+    }
+    Ok(())
+}
+
+/// Monsters `;;mob find` lists at most.
+const MAX_MOBS: u32 = 20;
+
+fn mob(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let name = match split_action(fields)[..] {
+        ["find", name] if !name.is_empty() => name,
+        ["find"] | ["find", _] => return Err("nothing to find".to_string()),
+        [] => return Err("got 0 fields".to_string()),
+        [other, ..] if other != "find" => return Err(format!("unknown action `{}`", other)),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let db = session
+        .db
+        .as_ref()
+        .ok_or("no database to find monsters in")?;
+    let monsters = db
+        .find_monsters(name, MAX_MOBS)
+        .map_err(|e| format!("db: {}", e))?;
+    let lines: Vec<String> = match monsters.len() {
+        0 => vec![format!("no monsters match `{}`", name)],
+        _ => monsters
+            .iter()
+            .map(|monster| {
+                let mut line = format!(
+                    "{} ({}): {} kills",
+                    monster.name,
+                    monster.area.as_deref().unwrap_or("unknown area"),
+                    monster.kills
+                );
+                if let Some(exp) = monster.best_exp {
+                    line.push_str(&format!(", up to {} exp", exp));
+                }
+                if monster.aggro {
+                    line.push_str(", aggro");
+                }
+                line
+            })
+            .collect(),
+    };
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn stats(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    if !fields.is_empty() {
+        return Err(format!("got {} fields", fields.len()));
+    }
+    command::stats(session);
+    Ok(())
+}
+
+/// Longest attribute `;;codes` shows, in bytes.
+const MAX_ATTR: usize = 40;
+
+fn codes(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    if !fields.is_empty() {
+        return Err(format!("got {} fields", fields.len()));
+    }
+    let now = Instant::now();
+    let lines: Vec<String> = session
+        .code_stats
+        .codes()
+        .map(|(id, stat)| {
+            let attr = stat.attr.as_deref().map_or(String::new(), |attr| {
+                let attr = String::from_utf8_lossy(&attr[..attr.len().min(MAX_ATTR)]);
+                format!(", attr {}", attr)
+            });
+            format!(
+                "code {:02}: {} seen, {} bytes, last {:.1}s ago{}",
+                id,
+                stat.count,
+                stat.bytes,
+                now.duration_since(stat.last_seen).as_secs_f64(),
+                attr
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        session.notify("no control codes seen yet");
+    }
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn link(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let n = match fields {
+        [] => {
+            let lines: Vec<String> = match session.game_links.commands() {
+                [] => vec!["no links in this room".to_string()],
+                commands => commands
+                    .iter()
+                    .enumerate()
+                    .map(|(i, command)| format!("link {}: {}", i + 1, command))
+                    .collect(),
+            };
+            for line in lines {
+                session.notify(&line);
+            }
+            return Ok(());
+        }
+        [n] => n.trim(),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let n: usize = n.parse().map_err(|_| format!("`{}` is not a number", n))?;
+    let command = session
+        .game_links
+        .get(n)
+        .ok_or_else(|| format!("no link {} in this room", n))?
+        .to_string();
+    session.send_command(&command);
+    Ok(())
+}
+
+fn history(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let count = match fields {
+        [] => HISTORY_LINES,
+        [count] => count
+            .trim()
+            .parse()
+            .map_err(|_| format!("`{}` is not a number", count.trim()))?,
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let lines: Vec<String> = match session.history.is_empty() {
+        true => vec!["no lines typed yet".to_string()],
+        false => session
+            .history
+            .last(count)
+            .map(|(n, line)| format!("{:>4}  {}", n, line))
+            .collect(),
+    };
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+/// Run the last line typed that starts with `prefix` again, as if it was
+/// typed once more.
+fn recall(prefix: &str, session: &mut Session) {
+    let line = match session.history.find(prefix) {
+        Some(line) => line.to_string(),
+        None if prefix.is_empty() => return session.notify("no lines typed yet"),
+        None => {
+            return session.notify(&format!("no line starting with `{}` typed", prefix));
+        }
+    };
+    session.notify(&format!("!{}: {}", prefix, line));
+    let mut line = line.into_bytes();
+    line.push(b'\n');
+    session.attached_input.push_back(line);
+    session.wake();
+}
+
+fn queue(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    match fields.iter().map(|field| field.trim()).collect::<Vec<_>>()[..] {
+        [] | ["show"] => {
+            let lines: Vec<String> = match session.commands.len() {
+                0 => vec!["no lines queued".to_string()],
+                n => std::iter::once(format!("{} lines queued", n))
+                    .chain(session.commands.lines())
+                    .collect(),
+            };
+            for line in lines {
+                session.notify(&line);
+            }
+        }
+        ["clear"] => {
+            let n = session.commands.clear();
+            session.notify(&format!("{} queued lines dropped", n));
+        }
+        [other] => return Err(format!("unknown action `{}`", other)),
+        _ => return Err(format!("got {} fields", fields.len())),
+    }
+    Ok(())
+}
+
+/// `fields` with the action split off the first one, as in
+/// `;;area stats <area>`.
+fn split_action<'a>(fields: &[&'a str]) -> Vec<&'a str> {
+    match fields {
+        [first, rest @ ..] => first
+            .splitn(2, ' ')
+            .chain(rest.iter().copied())
+            .map(str::trim)
+            .collect(),
+        [] => Vec::new(),
+    }
+}
+
+fn area(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let fields = split_action(fields);
+    let name = match fields[..] {
+        ["stats"] => session
+            .last_room
+            .as_ref()
+            .map(|room| room.area.clone())
+            .ok_or("the mapper has not reported a room yet")?,
+        ["stats", name] => name.to_string(),
+        [] => return Err("got 0 fields".to_string()),
+        [other, ..] if other != "stats" => return Err(format!("unknown action `{}`", other)),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let db = session.db.as_ref().ok_or("no database to count areas in")?;
+    let stats = db
+        .area_stats(&name)
+        .map_err(|e| format!("db: {}", e))?
+        .ok_or_else(|| format!("area `{}` not seen yet", name))?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let explored = match stats.exits {
+        0 => 100,
+        exits => stats.explored.min(exits) * 100 / exits,
+    };
+    session.notify(&format!(
+        "area {}: {} rooms, {} of {} exits explored ({}%), first seen {}, last seen {}",
+        stats.name,
+        stats.rooms,
+        stats.explored.min(stats.exits),
+        stats.exits,
+        explored,
+        command::age(now - stats.first_seen),
+        command::age(now - stats.last_seen),
+    ));
+    Ok(())
+}
+
+/// Rooms `;;visits` lists at most of the most and of the least visited.
+const MAX_VISITED: usize = 5;
+
+fn visits(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let name = match fields {
+        [] | [""] => session
+            .last_room
+            .as_ref()
+            .map(|room| room.area.clone())
+            .ok_or("the mapper has not reported a room yet")?,
+        [name] => name.to_string(),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let db = session
+        .db
+        .as_ref()
+        .ok_or("no database to count visits in")?;
+    let rooms = db.visits(&name).map_err(|e| format!("db: {}", e))?;
+    if rooms.is_empty() {
+        return Err(format!("area `{}` not seen yet", name));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let line = |room: &RoomVisits| {
+        let last = room
+            .last_visited_at
+            .map_or("some time ago".to_string(), |at| command::age(now - at));
+        format!(
+            "  {} ({}): {} visits, last {}",
+            room.short_desc, room.id, room.visits, last
+        )
+    };
+    let total: i64 = rooms.iter().map(|room| room.visits).sum();
+    let mut lines = vec![format!(
+        "area {}: {} visits to {} rooms",
+        name,
+        total,
+        rooms.len()
+    )];
+    if rooms.len() <= 2 * MAX_VISITED {
+        lines.extend(rooms.iter().map(line));
+    } else {
+        lines.push("most visited:".to_string());
+        lines.extend(rooms[..MAX_VISITED].iter().map(line));
+        lines.push("least visited:".to_string());
+        lines.extend(rooms[rooms.len() - MAX_VISITED..].iter().rev().map(line));
+    }
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+/// Rooms `;;room find` lists at most.
+const MAX_FOUND: u32 = 20;
+
+fn room(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let fields = split_action(fields);
+    let (text, everywhere) = match fields[..] {
+        ["find", text] => (text, false),
+        ["find", text, "all"] => (text, true),
+        [] => return Err("got 0 fields".to_string()),
+        [other, ..] if other != "find" => return Err(format!("unknown action `{}`", other)),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    if text.is_empty() {
+        return Err("nothing to find".to_string());
+    }
+    let db = session.db.as_ref().ok_or("no database to find rooms in")?;
+    let area = match &session.last_room {
+        Some(room) if !everywhere => Some(room.area.as_str()),
+        _ => None,
+    };
+    let mut rooms = db
+        .search_rooms(text, area, MAX_FOUND + 1)
+        .map_err(|e| format!("db: {}", e))?;
+
+    let place = area.map_or(String::new(), |area| format!(" in {}", area));
+    let mut lines = match rooms.len() {
+        0 => vec![format!("no rooms{} match `{}`", place, text)],
+        n if n > MAX_FOUND as usize => {
+            rooms.truncate(MAX_FOUND as usize);
+            vec![format!(
+                "more than {} rooms{} match `{}`, the first {}:",
+                MAX_FOUND, place, text, MAX_FOUND
+            )]
+        }
+        n => vec![format!("{} rooms{} match `{}`:", n, place, text)],
+    };
+    lines.extend(
+        rooms
+            .iter()
+            .map(|room| format!("{} ({}, {})", room.short_desc, room.area, room.id)),
+    );
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn inv(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let fields = split_action(fields);
+    let (diff, kind) = match fields[..] {
+        [] | ["show"] => (false, Kind::Inventory),
+        ["diff"] => (true, Kind::Inventory),
+        [action @ ("show" | "diff"), kind] => (action == "diff", kind.parse()?),
+        [other, ..] if other != "show" && other != "diff" => {
+            return Err(format!("unknown action `{}`", other))
+        }
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let what = match kind {
+        Kind::Inventory => "inventory",
+        Kind::Equipment => "equipment",
+    };
+    let (previous, last) = match session.inventory.snapshots(kind) {
+        Some(snapshots) => snapshots,
+        None => {
+            session.notify(&format!("no {} seen yet", what));
+            return Ok(());
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let lines: Vec<String> = match (diff, previous) {
+        (false, _) => std::iter::once(format!(
+            "{} {}, {} items:",
+            what,
+            command::age(now - last.taken_at),
+            last.items.len()
+        ))
+        .chain(last.items.iter().cloned())
+        .collect(),
+        (true, None) => vec![format!("only one {} seen yet", what)],
+        (true, Some(previous)) => inventory_diff(what, previous, last, now),
+    };
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn inventory_diff(what: &str, previous: &Snapshot, last: &Snapshot, now: i64) -> Vec<String> {
+    let (added, removed) = last.diff(previous);
+    let since = command::age(now - previous.taken_at);
+    if added.is_empty() && removed.is_empty() {
+        return vec![format!("{} unchanged since {}", what, since)];
+    }
+    std::iter::once(format!("{} since {}:", what, since))
+        .chain(added.iter().map(|item| format!("+ {}", item)))
+        .chain(removed.iter().map(|item| format!("- {}", item)))
+        .collect()
+}
+
+/// Offers `;;price` looks at at most.
+const MAX_OFFERS: u32 = 200;
+
+fn price(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let item = match fields {
+        [item] if !item.trim().is_empty() => item.trim(),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let db = session.db.as_ref().ok_or("no database with prices")?;
+    let offers = db
+        .offers(item, MAX_OFFERS)
+        .map_err(|e| format!("db: {}", e))?;
+    if offers.is_empty() {
+        session.notify(&format!("no prices for `{}` seen yet", item));
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let mut lines = Vec::new();
+    for (side, what) in [(Side::Sell, "asked"), (Side::Buy, "bid")] {
+        let offers: Vec<_> = offers.iter().filter(|o| o.side == side).collect();
+        let last = match offers.first() {
+            Some(last) => last,
+            None => continue,
+        };
+        let mut prices: Vec<u64> = offers.iter().map(|o| o.price.max(0) as u64).collect();
+        prices.sort();
+        lines.push(format!(
+            "{} {} times for `{}`: {} to {}, median {}, last {} for {} by {} {}",
+            what,
+            prices.len(),
+            item,
+            sales::format_price(prices[0]),
+            sales::format_price(prices[prices.len() - 1]),
+            sales::format_price(prices[prices.len() / 2]),
+            sales::format_price(last.price.max(0) as u64),
+            last.item,
+            last.seller,
+            command::age(now - last.offered_at),
+        ));
+    }
+    for line in lines {
+        session.notify(&line);
+    }
+    Ok(())
+}
+
+fn corpse(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    let go = match split_action(fields)[..] {
+        [] => false,
+        ["go"] => true,
+        [other] => return Err(format!("unknown action `{}`", other)),
+        _ => return Err(format!("got {} fields", fields.len())),
+    };
+    let error = |e: rusqlite::Error| format!("db: {}", e);
+    // A death of an earlier session is in the database.
+    if session.corpse.is_none() {
+        if let Some(db) = &session.db {
+            if let Some((id, died_at)) = db.last_death().map_err(error)? {
+                session.corpse = db
+                    .room(&id)
+                    .map_err(error)?
+                    .map(|room| Corpse { room, died_at });
+            }
+        }
+    }
+    let corpse = match &session.corpse {
+        Some(corpse) => corpse.clone(),
+        None => {
+            session.notify("no death recorded");
+            return Ok(());
+        }
+    };
+
+    let (db, from) = match (&session.db, &session.last_room) {
+        (Some(db), Some(from)) => (db.clone(), from.id.clone()),
+        _ => return show_corpse(&corpse, None, go, session),
+    };
+    // The map is read and searched in the background.
+    let to = HashSet::from([corpse.room.id.clone()]);
+    let find = move || {
+        let links = db.links().map_err(error)?;
+        Ok(path::find(&links, &from, &to))
+    };
+    session.in_background(find, move |steps, session| {
+        if let Err(e) = steps.and_then(|steps| show_corpse(&corpse, steps, go, session)) {
+            session.notify(&format!("corpse: {}", e));
+        }
+    });
+    Ok(())
+}
+
+/// Show where `corpse` is and the way there, or walk it if `go`.
+fn show_corpse(
+    corpse: &Corpse,
+    steps: Option<Vec<String>>,
+    go: bool,
+    session: &mut Session,
+) -> Result<(), String> {
+    if go {
+        match steps {
+            Some(steps) if steps.is_empty() => session.notify("already at your corpse"),
+            Some(steps) => {
+                session.notify(&format!("walking to your corpse, {} steps", steps.len()));
+                session.start_walk(steps);
+            }
+            None => return Err("no known way to your corpse".to_string()),
+        }
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let way = match steps {
+        Some(steps) if steps.is_empty() => "here".to_string(),
+        Some(steps) => format!("path: {}", steps.join(", ")),
+        None => "no known way there".to_string(),
+    };
+    session.notify(&format!(
+        "corpse in {} ({}, {}), died {}, {}",
+        corpse.room.short_desc,
+        corpse.room.area,
+        corpse.room.id,
+        command::age(now - corpse.died_at),
+        way
+    ));
+    Ok(())
+}
+
+fn explore(fields: &[&str], session: &mut Session) -> Result<(), String> {
+    if !fields.is_empty() {
+        return Err(format!("got {} fields", fields.len()));
+    }
+    let db = session.db.clone().ok_or("no database to explore from")?;
+    let from = session
+        .last_room
+        .as_ref()
+        .ok_or("the mapper has not reported a room yet")?
+        .id
+        .clone();
+    // The map is read and searched in the background.
+    session.in_background(
+        move || closest_unexplored(&db, &from),
+        |message, session| match message {
+            Ok(message) => session.notify(&message),
+            Err(e) => session.notify(&format!("explore: {}", e)),
+        },
+    );
+    Ok(())
+}
+
+/// The unexplored exits of the room closest to `from` that has some, and
+/// the way there.
+fn closest_unexplored(db: &Db, from: &str) -> Result<String, String> {
+    let error = |e: rusqlite::Error| format!("db: {}", e);
+    let unexplored = db.unexplored().map_err(error)?;
+    if unexplored.is_empty() {
+        return Ok("no unexplored exits known".to_string());
+    }
+    let rooms: HashSet<String> = unexplored.iter().map(|(id, _)| id.clone()).collect();
+    let links = db.links().map_err(error)?;
+    let steps = match path::find(&links, from, &rooms) {
+        Some(steps) => steps,
+        None => {
+            return Ok(format!(
+                "{} unexplored exits, none with a known way there",
+                unexplored.len()
+            ));
+        }
+    };
+
+    // Where the walk ends, the room with the exits to take.
+    let mut room = from.to_string();
+    for step in &steps {
+        if let Some(link) = links
+            .iter()
+            .find(|l| l.from == room && &l.direction == step)
+        {
+            room = link.to.clone();
+        }
+    }
+    let exits: Vec<&str> = unexplored
+        .iter()
+        .filter(|(id, _)| *id == room)
+        .map(|(_, exit)| exit.as_str())
+        .collect();
+    let message = if steps.is_empty() {
+        format!("unexplored exits here: {}", exits.join(", "))
+    } else {
+        let name = db
+            .room(&room)
+            .map_err(error)?
+            .map_or(room, |room| room.short_desc);
+        format!(
+            "unexplored exits of {}: {}, path: {}",
+            name,
+            exits.join(", "),
+            steps.join(", ")
+        )
+    };
+    Ok(message)
+}
@@ -0,0 +1,81 @@
+//! Where the player's corpse is. A death is seen from the server saying so,
+//! or from the mapper reporting a room of the nunnery the dead wake up in,
+//! and the room the mapper last reported before it is kept as the corpse's.
+//! `;;corpse` shows it and the way there, `;;corpse;;go` walks there.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    bc::Frame,
+    color,
+    db::Event,
+    mapper::{Mapper, Room},
+    session::Session,
+};
+
+/// Lines the server tells the player's death with.
+const DEATH_LINES: &[&str] = &["You die.", "You have died.", "You are dead."];
+/// The area the dead are taken to.
+const NUNNERY: &str = "nunnery";
+
+/// The room the player last died in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corpse {
+    pub room: Room,
+    /// Unix time of the death.
+    pub died_at: i64,
+}
+
+/// Whether the player is between dying and waking up in the nunnery.
+#[derive(Debug, Default)]
+pub struct Deaths {
+    dead: bool,
+}
+
+impl Deaths {
+    /// Note a death `frame` tells of, before the mapper takes the room of
+    /// `frame` if it reports one.
+    pub fn observe(&mut self, frame: &Frame, session: &mut Session) {
+        match frame {
+            Frame::Code(code) if code.id == 99 => {
+                let woke = matches!(
+                    Mapper::from_code(code),
+                    Some(Mapper::Room(room)) if room.area.eq_ignore_ascii_case(NUNNERY)
+                );
+                if woke && !std::mem::take(&mut self.dead) {
+                    died(session);
+                }
+            }
+            Frame::Text(_) | Frame::Code(_) => {
+                let mut text = Vec::new();
+                frame.push_text(&mut text);
+                let text = color::strip_ansi(&text);
+                if text.lines().any(|line| DEATH_LINES.contains(&line.trim())) && !self.dead {
+                    self.dead = true;
+                    died(session);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Keep the room the mapper last reported as the corpse's, unless it is
+/// the nunnery already.
+fn died(session: &mut Session) {
+    let room = match &session.last_room {
+        Some(room) if !room.area.eq_ignore_ascii_case(NUNNERY) => room.clone(),
+        _ => return,
+    };
+    if let Some(db) = &session.db {
+        db.send(Event::Death {
+            room_id: room.id.clone(),
+            area: room.area.clone(),
+        });
+    }
+    let died_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    session.notify(&format!("corpse left in {}", room.short_desc));
+    session.corpse = Some(Corpse { room, died_at });
+}
@@ -0,0 +1,179 @@
+//! Effects on the player, such as buffs, from code 64 `player_effect`:
+//! `<effect> <seconds left>`, 0 when it is gone.
+//!
+//! The time left counts down between updates and is checked whenever a
+//! prompt comes, warning the player once when an effect is about to
+//! expire.
+
+use std::time::{Duration, Instant};
+
+use crate::bc::ControlCode;
+
+pub const PLAYER_EFFECT: u8 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effect {
+    pub name: String,
+    pub expires_at: Instant,
+    warned: bool,
+}
+
+impl Effect {
+    pub fn time_left(&self, now: Instant) -> Duration {
+        self.expires_at.saturating_duration_since(now)
+    }
+}
+
+/// The effects on the player, in the order they started.
+#[derive(Debug, Default)]
+pub struct Effects {
+    effects: Vec<Effect>,
+}
+
+impl Effects {
+    /// Update the effects if `code` is a code 64.
+    pub fn observe(&mut self, code: &ControlCode, now: Instant) {
+        if code.id != PLAYER_EFFECT {
+            return;
+        }
+        let text = String::from_utf8_lossy(&code.text()).into_owned();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let (name, secs) = match fields.as_slice() {
+            [name @ .., secs] if !name.is_empty() => match secs.parse::<u64>() {
+                Ok(secs) => (name.join(" "), secs),
+                Err(_) => return,
+            },
+            _ => return,
+        };
+
+        self.effects.retain(|effect| effect.name != name);
+        if secs > 0 {
+            self.effects.push(Effect {
+                name,
+                expires_at: now + Duration::from_secs(secs),
+                warned: false,
+            });
+        }
+    }
+
+    /// Drop expired effects and return those expiring within `warning`
+    /// that were not warned about yet.
+    pub fn tick(&mut self, now: Instant, warning: Duration) -> Vec<Effect> {
+        self.effects.retain(|effect| effect.expires_at > now);
+        let mut expiring = Vec::new();
+        for effect in &mut self.effects {
+            if !effect.warned && effect.time_left(now) <= warning {
+                effect.warned = true;
+                expiring.push(effect.clone());
+            }
+        }
+        expiring
+    }
+
+    /// The effects with the seconds they have left, for a restart of the
+    /// proxy.
+    pub fn save(&self, now: Instant) -> Vec<(String, u64)> {
+        self.effects
+            .iter()
+            .map(|effect| (effect.name.clone(), effect.time_left(now).as_secs()))
+            .collect()
+    }
+
+    /// Take the effects `save` returned `since` ago, those that ran out
+    /// meanwhile left out.
+    pub fn restore(&mut self, saved: Vec<(String, u64)>, since: Duration, now: Instant) {
+        for (name, secs) in saved {
+            let left = Duration::from_secs(secs).saturating_sub(since);
+            if left.is_zero() {
+                continue;
+            }
+            self.effects.retain(|effect| effect.name != name);
+            self.effects.push(Effect {
+                name,
+                expires_at: now + left,
+                warned: false,
+            });
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Effect> {
+        self.effects.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::Frame;
+
+    fn code(text: &'static str) -> ControlCode {
+        ControlCode::new(PLAYER_EFFECT, None::<&[u8]>, vec![Frame::text(text)])
+    }
+
+    fn names(effects: &Effects) -> Vec<&str> {
+        effects.iter().map(|effect| effect.name.as_str()).collect()
+    }
+
+    #[test]
+    fn effects_start_change_and_end_with_their_codes() {
+        let now = Instant::now();
+        let mut effects = Effects::default();
+        effects.observe(&code("haste 60"), now);
+        effects.observe(&code("stone skin 120"), now);
+        effects.observe(&code("haste 30"), now);
+        assert_eq!(names(&effects), ["stone skin", "haste"]);
+        assert_eq!(
+            effects.save(now),
+            [("stone skin".to_string(), 120), ("haste".to_string(), 30)]
+        );
+
+        effects.observe(&code("stone skin 0"), now);
+        assert_eq!(names(&effects), ["haste"]);
+    }
+
+    #[test]
+    fn codes_without_a_name_or_time_are_ignored() {
+        let now = Instant::now();
+        let mut effects = Effects::default();
+        for text in ["60", "haste", "haste soon", ""] {
+            effects.observe(&code(text), now);
+        }
+        let other = ControlCode::new(65, None::<&[u8]>, vec![Frame::text("haste 60")]);
+        effects.observe(&other, now);
+        assert!(effects.is_empty());
+    }
+
+    #[test]
+    fn expiring_effects_are_warned_of_once() {
+        let now = Instant::now();
+        let warning = Duration::from_secs(10);
+        let mut effects = Effects::default();
+        effects.observe(&code("haste 15"), now);
+        effects.observe(&code("blur 60"), now);
+
+        assert!(effects.tick(now, warning).is_empty());
+        let later = now + Duration::from_secs(5);
+        let expiring = effects.tick(later, warning);
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].name, "haste");
+        assert_eq!(expiring[0].time_left(later), Duration::from_secs(10));
+        assert!(effects.tick(later, warning).is_empty());
+
+        effects.tick(now + Duration::from_secs(15), warning);
+        assert_eq!(names(&effects), ["blur"]);
+    }
+
+    #[test]
+    fn restored_effects_lose_the_time_since_they_were_saved() {
+        let now = Instant::now();
+        let mut effects = Effects::default();
+        effects.observe(&code("haste 60"), now);
+        let saved = vec![("haste".to_string(), 30), ("blur".to_string(), 5)];
+        effects.restore(saved, Duration::from_secs(10), now);
+        assert_eq!(effects.save(now), [("haste".to_string(), 20)]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Experience gained over time, from code 53 `player_free_exp`: the free
+//! experience the player has.
+//!
+//! Free experience drops when it is spent, so only increases count as
+//! gains. The rate is taken over a sliding window and over the session.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bc::ControlCode;
+
+pub const PLAYER_FREE_EXP: u8 = 53;
+
+/// Time between two snapshots kept in the database.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn free_exp(code: &ControlCode) -> Option<i64> {
+    if code.id != PLAYER_FREE_EXP {
+        return None;
+    }
+    String::from_utf8_lossy(&code.text()).trim().parse().ok()
+}
+
+pub struct ExpTracker {
+    window: Duration,
+    started: Instant,
+    last: Option<i64>,
+    total: i64,
+    // Gains within the window, oldest first.
+    gains: VecDeque<(Instant, i64)>,
+    last_snapshot: Option<Instant>,
+}
+
+/// What is kept of a tracker across a restart of the proxy, in seconds
+/// of the session before it was saved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedExp {
+    pub last: Option<i64>,
+    pub total: i64,
+    pub elapsed: u64,
+    /// The gains within the window and how long before the save they were.
+    pub gains: Vec<(u64, i64)>,
+}
+
+/// The experience rates at some point.
+pub struct ExpRate {
+    /// Per hour over the window, or the session if it is shorter.
+    pub recent: f64,
+    pub session: f64,
+    pub total: i64,
+    pub elapsed: Duration,
+}
+
+impl ExpTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            last: None,
+            total: 0,
+            gains: VecDeque::new(),
+            last_snapshot: None,
+        }
+    }
+
+    /// Take in a reading of free experience. Returns true if a snapshot is
+    /// due.
+    pub fn observe(&mut self, free_exp: i64, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            let gain = free_exp - last;
+            if gain > 0 {
+                self.total += gain;
+                self.gains.push_back((now, gain));
+            }
+        }
+        self.last = Some(free_exp);
+        self.expire(now);
+
+        let due = self
+            .last_snapshot
+            .is_none_or(|t| now.duration_since(t) >= SNAPSHOT_INTERVAL);
+        if due {
+            self.last_snapshot = Some(now);
+        }
+        due
+    }
+
+    pub fn rate(&mut self, now: Instant) -> ExpRate {
+        self.expire(now);
+        let elapsed = now.duration_since(self.started);
+        let recent: i64 = self.gains.iter().map(|(_, gain)| gain).sum();
+        ExpRate {
+            recent: per_hour(recent, elapsed.min(self.window)),
+            session: per_hour(self.total, elapsed),
+            total: self.total,
+            elapsed,
+        }
+    }
+
+    pub fn save(&mut self, now: Instant) -> SavedExp {
+        self.expire(now);
+        SavedExp {
+            last: self.last,
+            total: self.total,
+            elapsed: now.duration_since(self.started).as_secs(),
+            gains: self
+                .gains
+                .iter()
+                .map(|&(at, gain)| (now.duration_since(at).as_secs(), gain))
+                .collect(),
+        }
+    }
+
+    /// Go on from `saved` as if the proxy had not stopped in between.
+    pub fn restore(&mut self, saved: SavedExp, now: Instant) {
+        let ago = |secs| now.checked_sub(Duration::from_secs(secs)).unwrap_or(now);
+        self.started = ago(saved.elapsed);
+        self.last = saved.last;
+        self.total = saved.total;
+        self.gains = saved
+            .gains
+            .into_iter()
+            .map(|(secs, gain)| (ago(secs), gain))
+            .collect();
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.gains.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            self.gains.pop_front();
+        }
+    }
+}
+
+fn per_hour(exp: i64, over: Duration) -> f64 {
+    // Too short a time makes for wild rates.
+    let secs = over.as_secs_f64().max(60.0);
+    exp as f64 * 3600.0 / secs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::Frame;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// A tracker over a 10 minute window, started at the instant returned.
+    fn started() -> (ExpTracker, Instant) {
+        let mut tracker = ExpTracker::new(10 * MINUTE);
+        let start = Instant::now();
+        tracker.started = start;
+        (tracker, start)
+    }
+
+    #[test]
+    fn free_exp_is_read_from_its_code() {
+        let code = |id, text| ControlCode::new(id, None::<&[u8]>, vec![Frame::text(text)]);
+        assert_eq!(free_exp(&code(PLAYER_FREE_EXP, " 12345\r\n")), Some(12345));
+        assert_eq!(free_exp(&code(PLAYER_FREE_EXP, "lots")), None);
+        assert_eq!(free_exp(&code(52, "12345")), None);
+    }
+
+    #[test]
+    fn only_increases_count_as_gains() {
+        let (mut tracker, start) = started();
+        tracker.observe(1000, start);
+        tracker.observe(1500, start + MINUTE);
+        // Spent.
+        tracker.observe(200, start + 2 * MINUTE);
+        tracker.observe(300, start + 3 * MINUTE);
+        let rate = tracker.rate(start + 60 * MINUTE);
+        assert_eq!(rate.total, 600);
+        assert_eq!(rate.session, 600.0);
+        assert_eq!(rate.elapsed, 60 * MINUTE);
+    }
+
+    #[test]
+    fn the_recent_rate_is_over_the_window() {
+        let (mut tracker, start) = started();
+        tracker.observe(0, start);
+        tracker.observe(100, start + MINUTE);
+        tracker.observe(400, start + 15 * MINUTE);
+        let rate = tracker.rate(start + 20 * MINUTE);
+        // 300 in the last 10 minutes, the 100 before dropped.
+        assert_eq!(rate.recent, 1800.0);
+        assert_eq!(rate.session, 1200.0);
+
+        // A session shorter than a minute is taken as a minute.
+        let (mut tracker, start) = started();
+        tracker.observe(0, start);
+        tracker.observe(10, start + Duration::from_secs(1));
+        assert_eq!(tracker.rate(start + Duration::from_secs(2)).recent, 600.0);
+    }
+
+    #[test]
+    fn snapshots_are_due_once_a_minute() {
+        let (mut tracker, start) = started();
+        assert!(tracker.observe(0, start));
+        assert!(!tracker.observe(1, start + MINUTE / 2));
+        assert!(tracker.observe(2, start + MINUTE));
+        assert!(!tracker.observe(3, start + MINUTE));
+    }
+
+    #[test]
+    fn a_restored_tracker_goes_on_where_it_was_saved() {
+        let (mut tracker, start) = started();
+        tracker.observe(0, start);
+        tracker.observe(100, start + MINUTE);
+        tracker.observe(400, start + 5 * MINUTE);
+        let saved = tracker.save(start + 6 * MINUTE);
+        assert_eq!(
+            saved,
+            SavedExp {
+                last: Some(400),
+                total: 400,
+                elapsed: 360,
+                gains: vec![(300, 100), (60, 300)],
+            }
+        );
+
+        let (mut restored, now) = started();
+        restored.restore(saved, now);
+        restored.observe(500, now);
+        let rate = restored.rate(now + 5 * MINUTE + Duration::from_secs(30));
+        assert_eq!(rate.total, 500);
+        assert_eq!(rate.elapsed, 11 * MINUTE + Duration::from_secs(30));
+        // The first gain left the window.
+        assert_eq!(rate.recent, 2400.0);
+    }
+}
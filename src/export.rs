@@ -0,0 +1,278 @@
+//! Area maps built from what the mapper stored, for rendering with other
+//! tools or as SVG images.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+};
+
+use crate::{db::Link, mapper::Room};
+
+/// Graphviz DOT for the rooms of `area`. A pair of links between two rooms
+/// in opposite directions is drawn as a single edge labeled with both, and
+/// rooms of other areas that links lead to are drawn dashed.
+pub fn to_dot(area: &str, rooms: &[Room], links: &[Link]) -> String {
+    let mut dot = String::new();
+    let _ = writeln!(dot, "digraph {} {{", quote(area));
+    let _ = writeln!(dot, "    node [shape=box];");
+
+    let ids: HashSet<&str> = rooms.iter().map(|room| room.id.as_str()).collect();
+    for room in rooms {
+        let _ = writeln!(
+            dot,
+            "    {} [label={}];",
+            quote(&room.id),
+            quote(&room.short_desc)
+        );
+    }
+    let mut outside = HashSet::new();
+    for link in links {
+        if !ids.contains(link.to.as_str()) && outside.insert(link.to.as_str()) {
+            let _ = writeln!(dot, "    {} [style=dashed];", quote(&link.to));
+        }
+    }
+
+    let mut drawn = HashSet::new();
+    for link in links {
+        if !drawn.insert((&link.from, &link.to, &link.direction)) {
+            continue;
+        }
+        let back = links
+            .iter()
+            .find(|back| back.from == link.to && back.to == link.from && link.from != link.to)
+            .filter(|back| drawn.insert((&back.from, &back.to, &back.direction)));
+        match back {
+            Some(back) => {
+                let label = format!("{}/{}", link.direction, back.direction);
+                let _ = writeln!(
+                    dot,
+                    "    {} -> {} [label={}, dir=both];",
+                    quote(&link.from),
+                    quote(&link.to),
+                    quote(&label)
+                );
+            }
+            None => {
+                let _ = writeln!(
+                    dot,
+                    "    {} -> {} [label={}];",
+                    quote(&link.from),
+                    quote(&link.to),
+                    quote(&link.direction)
+                );
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// The rooms and links of `area` as JSON:
+/// `{"area": ..., "rooms": [{"id", "short_desc", "long_desc", "indoors", "exits"}], "links": [{"from", "to", "direction"}]}`.
+pub fn to_json(area: &str, rooms: &[Room], links: &[Link]) -> String {
+    let rooms: Vec<_> = rooms
+        .iter()
+        .map(|room| {
+            serde_json::json!({
+                "id": room.id,
+                "short_desc": room.short_desc,
+                "long_desc": room.long_desc,
+                "indoors": room.indoors,
+                "exits": room.exits,
+            })
+        })
+        .collect();
+    let links: Vec<_> = links
+        .iter()
+        .map(|link| {
+            serde_json::json!({
+                "from": link.from,
+                "to": link.to,
+                "direction": link.direction,
+            })
+        })
+        .collect();
+    serde_json::json!({ "area": area, "rooms": rooms, "links": links }).to_string()
+}
+
+/// Horizontal distance between rooms in SVG maps.
+const CELL_WIDTH: i32 = 160;
+/// Vertical distance between rooms in SVG maps.
+const CELL_HEIGHT: i32 = 80;
+const BOX_WIDTH: i32 = 136;
+const BOX_HEIGHT: i32 = 36;
+/// Most characters of a room name shown in its box.
+const MAX_LABEL: usize = 18;
+
+/// An SVG image of the rooms of `area`, each a box labeled with its name,
+/// with lines for the links between them. Rooms are placed on a grid the
+/// way their exits lead, a room north of another above it, and where the
+/// exit says no direction or the place is taken, in the next free place
+/// below. Rooms no link leads to start a new part of the map under the
+/// rest. Indoor rooms are shaded.
+pub fn to_svg(area: &str, rooms: &[Room], links: &[Link]) -> String {
+    let places = layout(rooms, links);
+    let (cols, rows) = places.values().fold((0, 0), |(cols, rows), &(x, y)| {
+        (cols.max(x + 1), rows.max(y + 1))
+    });
+    let center = |id: &str| {
+        let (x, y) = places[id];
+        (
+            x * CELL_WIDTH + CELL_WIDTH / 2,
+            y * CELL_HEIGHT + CELL_HEIGHT / 2,
+        )
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = cols * CELL_WIDTH,
+        h = rows * CELL_HEIGHT
+    );
+    let _ = writeln!(svg, "<title>{}</title>", escape(area));
+
+    svg.push_str("<g stroke=\"#888\" stroke-width=\"2\">\n");
+    let mut drawn = HashSet::new();
+    let mut labels = Vec::new();
+    for link in links {
+        if link.from == link.to || !places.contains_key(link.to.as_str()) {
+            continue;
+        }
+        let pair = if link.from < link.to {
+            (&link.from, &link.to)
+        } else {
+            (&link.to, &link.from)
+        };
+        if !drawn.insert(pair) {
+            continue;
+        }
+        let (x1, y1) = center(&link.from);
+        let (x2, y2) = center(&link.to);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}"/>"#,
+            x1, y1, x2, y2
+        );
+        if offset(&link.direction).is_none() {
+            labels.push(((x1 + x2) / 2, (y1 + y2) / 2, &link.direction));
+        }
+    }
+    svg.push_str("</g>\n");
+    for (x, y, direction) in labels {
+        let _ = writeln!(
+            svg,
+            r##"<text x="{}" y="{}" text-anchor="middle" fill="#555">{}</text>"##,
+            x,
+            y - 4,
+            escape(direction)
+        );
+    }
+
+    for room in rooms {
+        let (x, y) = center(&room.id);
+        let fill = if room.indoors { "#e8e8f4" } else { "#fff" };
+        let mut label: String = room.short_desc.chars().take(MAX_LABEL).collect();
+        if label.len() < room.short_desc.len() {
+            label.push('…');
+        }
+        let _ = writeln!(
+            svg,
+            r##"<g><title>{}</title><rect x="{}" y="{}" width="{}" height="{}" rx="4" fill="{}" stroke="#333"/><text x="{}" y="{}" text-anchor="middle">{}</text></g>"##,
+            escape(&room.id),
+            x - BOX_WIDTH / 2,
+            y - BOX_HEIGHT / 2,
+            BOX_WIDTH,
+            BOX_HEIGHT,
+            fill,
+            x,
+            y + 4,
+            escape(&label)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// A column and row on the grid of an SVG map, or a step between two.
+type Place = (i32, i32);
+
+/// Where on the grid each room of `rooms` goes.
+fn layout<'a>(rooms: &'a [Room], links: &'a [Link]) -> HashMap<&'a str, Place> {
+    let ids: HashSet<&str> = rooms.iter().map(|room| room.id.as_str()).collect();
+    let mut neighbors: HashMap<&str, Vec<(&str, Option<Place>)>> = HashMap::new();
+    for link in links {
+        if !ids.contains(link.to.as_str()) || link.from == link.to {
+            continue;
+        }
+        let step = offset(&link.direction);
+        neighbors
+            .entry(&link.from)
+            .or_default()
+            .push((&link.to, step));
+        neighbors
+            .entry(&link.to)
+            .or_default()
+            .push((&link.from, step.map(|(x, y)| (-x, -y))));
+    }
+
+    let mut places = HashMap::new();
+    let mut taken = HashSet::new();
+    let mut bottom = 0;
+    for room in rooms {
+        if places.contains_key(room.id.as_str()) {
+            continue;
+        }
+        let mut queue = VecDeque::from([(room.id.as_str(), (0, bottom))]);
+        while let Some((id, mut place)) = queue.pop_front() {
+            if places.contains_key(id) {
+                continue;
+            }
+            while !taken.insert(place) {
+                place.1 += 1;
+            }
+            places.insert(id, place);
+            for &(next, step) in neighbors.get(id).into_iter().flatten() {
+                if !places.contains_key(next) {
+                    let (x, y) = step.unwrap_or((1, 0));
+                    queue.push_back((next, (place.0 + x, place.1 + y)));
+                }
+            }
+        }
+        bottom = places.values().map(|&(_, y)| y + 1).max().unwrap_or(0);
+    }
+
+    // Rooms west or north of the first go at negative places.
+    let left = places.values().map(|&(x, _)| x).min().unwrap_or(0);
+    let top = places.values().map(|&(_, y)| y).min().unwrap_or(0);
+    for place in places.values_mut() {
+        *place = (place.0 - left, place.1 - top);
+    }
+    places
+}
+
+/// Which way on the map `direction` goes, if it is a compass direction.
+fn offset(direction: &str) -> Option<Place> {
+    match direction {
+        "n" | "north" => Some((0, -1)),
+        "s" | "south" => Some((0, 1)),
+        "e" | "east" => Some((1, 0)),
+        "w" | "west" => Some((-1, 0)),
+        "ne" | "northeast" => Some((1, -1)),
+        "nw" | "northwest" => Some((-1, -1)),
+        "se" | "southeast" => Some((1, 1)),
+        "sw" | "southwest" => Some((-1, 1)),
+        _ => None,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
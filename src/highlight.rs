@@ -0,0 +1,361 @@
+use std::{ops::Range, str::FromStr};
+
+use regex::bytes::Regex;
+
+use crate::{
+    bc::{ControlCode, Frame},
+    color::{Color, ColorMode, Style},
+};
+
+/// BC codes setting the foreground and background color of their body, with
+/// the color as `RRGGBB` in the attribute.
+const FOREGROUND: u8 = 20;
+const BACKGROUND: u8 = 21;
+
+/// Text to color wherever it appears in server output, written as
+/// `<text or re:regex> => <style>`, e.g. `Bob => fg=ff8800 bold`.
+#[derive(Debug, Clone)]
+pub struct Highlight {
+    source: String,
+    pattern: Regex,
+    style: Style,
+}
+
+impl FromStr for Highlight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, style) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("highlight `{}` has no `=>`", s))?;
+        let pattern = pattern.trim();
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => re.to_string(),
+            None => regex::escape(pattern),
+        };
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            pattern: Regex::new(&regex).map_err(|e| e.to_string())?,
+            style: style.parse()?,
+        })
+    }
+}
+
+impl std::fmt::Display for Highlight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Color the matches of every highlight in the text of `frame`, including
+/// text inside control codes such as channel messages. Where matches
+/// overlap, the one starting first wins. Only the visible text is matched,
+/// not the ANSI sequences in it, and the colors a match was in are set
+/// again after it.
+pub fn apply(highlights: &[Highlight], mode: ColorMode, frame: &mut Frame) {
+    apply_with(highlights, mode, frame, &Pen::default());
+}
+
+fn apply_with(highlights: &[Highlight], mode: ColorMode, frame: &mut Frame, pen: &Pen) {
+    match frame {
+        Frame::Text(text) => {
+            if let Some(colored) = colorize(highlights, mode, text, pen.clone()) {
+                *text = colored.into();
+            }
+        }
+        Frame::Code(code) | Frame::Prompt(code) | Frame::LoginResult(code) => {
+            let pen = pen.inside(code, mode);
+            for child in &mut code.body {
+                apply_with(highlights, mode, child, &pen);
+            }
+        }
+        Frame::Map(map) => {
+            let pen = pen.inside(&map.code, mode);
+            for child in &mut map.code.body {
+                apply_with(highlights, mode, child, &pen);
+            }
+        }
+    }
+}
+
+/// The SGR state text is written in: the colors as SGR parameters, such
+/// as `38;5;196`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Pen {
+    fg: Option<String>,
+    bg: Option<String>,
+    bold: bool,
+    underline: bool,
+}
+
+impl Pen {
+    /// The pen the body of `code` is written with, once its color is
+    /// rendered.
+    fn inside(&self, code: &ControlCode, mode: ColorMode) -> Pen {
+        let mut pen = self.clone();
+        let color = code
+            .attr
+            .as_deref()
+            .and_then(|attr| std::str::from_utf8(attr).ok())
+            .and_then(|attr| attr.parse::<Color>().ok());
+        match (code.id, color) {
+            (FOREGROUND, Some(color)) => pen.fg = color.sgr(mode, false).or(pen.fg),
+            (BACKGROUND, Some(color)) => pen.bg = color.sgr(mode, true).or(pen.bg),
+            _ => {}
+        }
+        pen
+    }
+
+    /// Follow the CSI sequence `sequence`, if it sets colors or attributes.
+    fn follow(&mut self, sequence: &[u8]) {
+        let params = match sequence {
+            [0x1b, b'[', params @ .., b'm'] => String::from_utf8_lossy(params),
+            _ => return,
+        };
+        let mut params = params.split(';');
+        while let Some(param) = params.next() {
+            match param {
+                "" | "0" => *self = Pen::default(),
+                "1" => self.bold = true,
+                "22" => self.bold = false,
+                "4" => self.underline = true,
+                "24" => self.underline = false,
+                "39" => self.fg = None,
+                "49" => self.bg = None,
+                "38" => self.fg = extended(param, &mut params),
+                "48" => self.bg = extended(param, &mut params),
+                param => match param.parse::<u8>() {
+                    Ok(30..=37 | 90..=97) => self.fg = Some(param.to_string()),
+                    Ok(40..=47 | 100..=107) => self.bg = Some(param.to_string()),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// SGR parameters going back to this pen from text written in `style`.
+    fn restore(&self, style: &Style, mode: ColorMode) -> String {
+        let mut params = Vec::new();
+        if style.bold && !self.bold {
+            params.push("22");
+        }
+        if style.underline && !self.underline {
+            params.push("24");
+        }
+        if style.fg.and_then(|fg| fg.sgr(mode, false)).is_some() {
+            params.push(self.fg.as_deref().unwrap_or("39"));
+        }
+        if style.bg.and_then(|bg| bg.sgr(mode, true)).is_some() {
+            params.push(self.bg.as_deref().unwrap_or("49"));
+        }
+        params.join(";")
+    }
+}
+
+/// The 256 color or RGB color after `38` or `48` in `params`, as in
+/// `38;5;196` or `38;2;255;0;0`.
+fn extended<'a>(first: &str, params: &mut impl Iterator<Item = &'a str>) -> Option<String> {
+    let kind = params.next()?;
+    let count = match kind {
+        "5" => 1,
+        "2" => 3,
+        _ => return None,
+    };
+    let mut color = vec![first, kind];
+    for _ in 0..count {
+        color.push(params.next()?);
+    }
+    Some(color.join(";"))
+}
+
+/// Where the ANSI CSI sequences in `text` are, in order.
+fn sequences(text: &[u8]) -> Vec<Range<usize>> {
+    let mut sequences = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == 0x1b && text.get(i + 1) == Some(&b'[') {
+            let start = i;
+            i += 2;
+            while i < text.len() && !(0x40..=0x7e).contains(&text[i]) {
+                i += 1;
+            }
+            i = (i + 1).min(text.len());
+            sequences.push(start..i);
+        } else {
+            i += 1;
+        }
+    }
+    sequences
+}
+
+fn colorize(
+    highlights: &[Highlight],
+    mode: ColorMode,
+    text: &[u8],
+    mut pen: Pen,
+) -> Option<Vec<u8>> {
+    let sequences = sequences(text);
+    // The text without the sequences, and where each of its bytes is.
+    let mut visible = Vec::with_capacity(text.len());
+    let mut at = Vec::with_capacity(text.len());
+    let mut next = sequences.iter().peekable();
+    let mut i = 0;
+    while i < text.len() {
+        if let Some(sequence) = next.next_if(|sequence| sequence.start == i) {
+            i = sequence.end;
+            continue;
+        }
+        visible.push(text[i]);
+        at.push(i);
+        i += 1;
+    }
+
+    let at = &at;
+    let mut matches: Vec<(usize, usize, &Style)> = highlights
+        .iter()
+        .flat_map(|h| {
+            h.pattern
+                .find_iter(&visible)
+                .filter(|m| !m.is_empty())
+                .map(move |m| (at[m.start()], at[m.end() - 1] + 1, &h.style))
+        })
+        .collect();
+    matches.sort_by_key(|&(start, _, _)| start);
+
+    let mut out = Vec::with_capacity(text.len() + 32 * matches.len());
+    let mut last = 0;
+    for (start, end, style) in matches {
+        let on = match style.sgr(mode) {
+            Some(on) if start >= last => format!("\x1b[{}m", on),
+            _ => continue,
+        };
+        copy(text, last..start, &sequences, &mut pen, &mut out, b"");
+        out.extend_from_slice(on.as_bytes());
+        // Colors set inside the match give way to the highlight.
+        copy(
+            text,
+            start..end,
+            &sequences,
+            &mut pen,
+            &mut out,
+            on.as_bytes(),
+        );
+        out.extend_from_slice(format!("\x1b[{}m", pen.restore(style, mode)).as_bytes());
+        last = end;
+    }
+    if last == 0 {
+        return None;
+    }
+    out.extend_from_slice(&text[last..]);
+    Some(out)
+}
+
+/// Copy `range` of `text` to `out`, following the sequences in it with
+/// `pen` and writing `again` after each of them.
+fn copy(
+    text: &[u8],
+    range: Range<usize>,
+    sequences: &[Range<usize>],
+    pen: &mut Pen,
+    out: &mut Vec<u8>,
+    again: &[u8],
+) {
+    let mut i = range.start;
+    for sequence in sequences
+        .iter()
+        .filter(|sequence| sequence.start >= range.start && sequence.end <= range.end)
+    {
+        out.extend_from_slice(&text[i..sequence.end]);
+        pen.follow(&text[sequence.clone()]);
+        out.extend_from_slice(again);
+        i = sequence.end;
+    }
+    out.extend_from_slice(&text[i..range.end]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highlight(text: &[u8], highlights: &[&str]) -> Vec<u8> {
+        let highlights: Vec<Highlight> = highlights.iter().map(|h| h.parse().unwrap()).collect();
+        let mut frame = Frame::text(text.to_vec());
+        apply(&highlights, ColorMode::Xterm256, &mut frame);
+        let mut out = Vec::new();
+        frame.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn plain_text_is_colored_and_reset() {
+        assert_eq!(
+            highlight(b"a sword!", &["sword => fg=ff0000"]),
+            b"a \x1b[38;5;196msword\x1b[39m!"
+        );
+        assert_eq!(highlight(b"a shield", &["sword => fg=ff0000"]), b"a shield");
+    }
+
+    #[test]
+    fn sequences_are_not_matched() {
+        // The 31 of the color is no number of the text.
+        assert_eq!(
+            highlight(b"\x1b[1;31mHp 42\x1b[0m", &[r"re:\d+ => underline"]),
+            b"\x1b[1;31mHp \x1b[4m42\x1b[24m\x1b[0m"
+        );
+        assert_eq!(
+            highlight(b"\x1b[31mred\x1b[0m", &["m => bold"]),
+            b"\x1b[31mred\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn the_colors_of_the_text_are_set_again() {
+        assert_eq!(
+            highlight(b"\x1b[32mgreen sword here\x1b[0m", &["sword => fg=ff0000"]),
+            b"\x1b[32mgreen \x1b[38;5;196msword\x1b[32m here\x1b[0m"
+        );
+        assert_eq!(
+            highlight(
+                b"\x1b[38;5;208;48;2;0;0;255mx sword",
+                &["sword => fg=ff0000 bg=00ff00"]
+            ),
+            b"\x1b[38;5;208;48;2;0;0;255mx \x1b[38;5;196;48;5;46msword\x1b[38;5;208;48;2;0;0;255m"
+        );
+        // Attributes the text has stay on.
+        assert_eq!(
+            highlight(b"\x1b[1mbold sword", &["sword => bold fg=ff0000"]),
+            b"\x1b[1mbold \x1b[1;38;5;196msword\x1b[39m"
+        );
+        // Nothing to set again after a reset.
+        assert_eq!(
+            highlight(b"\x1b[32mgreen\x1b[0m sword", &["sword => fg=ff0000"]),
+            b"\x1b[32mgreen\x1b[0m \x1b[38;5;196msword\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn colors_set_inside_a_match_give_way_to_it() {
+        assert_eq!(
+            highlight(b"B\x1b[34mob left", &["Bob => fg=ff0000"]),
+            b"\x1b[38;5;196mB\x1b[34m\x1b[38;5;196mob\x1b[34m left"
+        );
+    }
+
+    #[test]
+    fn the_colors_of_codes_around_a_match_are_set_again() {
+        let highlights: Vec<Highlight> = vec!["sword => fg=00ff00 bg=0000ff".parse().unwrap()];
+        let mut frame = Frame::Code(ControlCode::new(
+            20,
+            Some(&b"ff0000"[..]),
+            vec![Frame::text(&b"a sword!"[..])],
+        ));
+        apply(&highlights, ColorMode::Xterm256, &mut frame);
+        let mut out = Vec::new();
+        frame.encode(&mut out);
+        assert_eq!(
+            out,
+            b"\x1b<20ff0000\x1b|a \x1b[38;5;46;48;5;21msword\x1b[38;5;196;49m!\x1b>20"
+        );
+    }
+}
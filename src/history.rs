@@ -0,0 +1,80 @@
+//! The lines the player typed this session, for clients without a history
+//! of their own such as plain telnet. `;;history [<count>]` lists the last
+//! ones and `;;!<prefix>` runs the last one starting with `prefix` again.
+//!
+//! Lines typed before the login succeeded or while the server hides what
+//! is typed, such as the name and the password, are not kept.
+
+use std::collections::VecDeque;
+
+use crate::control;
+
+/// The last lines typed, oldest first, numbered from the first line of the
+/// session.
+#[derive(Debug)]
+pub struct History {
+    lines: VecDeque<String>,
+    size: usize,
+    // The number of the oldest line kept.
+    first: usize,
+}
+
+impl History {
+    /// A history of at most `size` lines, none if zero.
+    pub fn new(size: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            size,
+            first: 1,
+        }
+    }
+
+    /// Add `line` unless it is empty, the same as the last one or a line
+    /// that shows or recalls the history.
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if self.size == 0 || line.trim().is_empty() || is_history(line) {
+            return;
+        }
+        if self.lines.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.lines.len() == self.size {
+            self.lines.pop_front();
+            self.first += 1;
+        }
+        self.lines.push_back(line.to_string());
+    }
+
+    /// The last `count` lines with their numbers, oldest first.
+    pub fn last(&self, count: usize) -> impl Iterator<Item = (usize, &str)> {
+        let skip = self.lines.len().saturating_sub(count);
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(skip)
+            .map(|(i, line)| (self.first + i, line.as_str()))
+    }
+
+    /// The last line starting with `prefix`.
+    pub fn find(&self, prefix: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .rev()
+            .find(|line| line.trim_start().starts_with(prefix))
+            .map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// Whether `line` is `;;history` or `;;!`, which would only recall
+/// themselves.
+fn is_history(line: &str) -> bool {
+    match line.trim().strip_prefix(control::PREFIX) {
+        Some(rest) => rest.starts_with('!') || rest.starts_with("history"),
+        None => false,
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    io,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// How long a request may take, from connecting to the end of the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// POST a JSON `body` to an `http://` or `https://` url and return the
+/// response body.
+///
+/// This is a minimal HTTP/1.0 client, which keeps servers from answering
+/// with chunked encoding. Anything but a 2xx status is an error, as is a
+/// server taking longer than [`TIMEOUT`]. HTTPS servers are checked against
+/// the Mozilla root certificates.
+pub async fn post_json(url: &str, body: &str) -> io::Result<Vec<u8>> {
+    timeout(TIMEOUT, post(url, body))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response in time"))?
+}
+
+/// The parts of a url a request needs.
+#[derive(Debug, PartialEq, Eq)]
+struct Url<'a> {
+    tls: bool,
+    /// The host and port as given, for the `Host` header.
+    authority: &'a str,
+    /// The host without the brackets of an IPv6 address.
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> io::Result<Self> {
+        let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => {
+                return Err(invalid(
+                    "only http:// and https:// urls are supported".to_string(),
+                ))
+            }
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid port in {}", url)))?;
+                (host, port)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid(format!("no host in {}", url)));
+        }
+        Ok(Self {
+            tls,
+            authority,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+async fn post(url: &str, body: &str) -> io::Result<Vec<u8>> {
+    let url = Url::parse(url)?;
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        url.path,
+        url.authority,
+        body.len(),
+        body
+    );
+
+    let stream = TcpStream::connect((url.host, url.port)).await?;
+    let response = if url.tls {
+        let name = ServerName::try_from(url.host.to_string())
+            .map_err(|e| invalid(format!("invalid host {}: {}", url.host, e)))?;
+        let stream = tls().connect(name, stream).await?;
+        exchange(stream, &request).await?
+    } else {
+        exchange(stream, &request).await?
+    };
+
+    let status = response
+        .split(|&b| b == b' ')
+        .nth(1)
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(invalid(format!("server responded with status {}", status)));
+    }
+
+    let body = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(Vec::new(), |i| response[i + 4..].to_vec());
+    Ok(body)
+}
+
+/// Send `request` on `stream` and read the response up to the end of the
+/// connection.
+async fn exchange<S>(mut stream: S, request: &str) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        Ok(_) => Ok(response),
+        // Plenty of HTTPS servers close the connection without a TLS
+        // close_notify once the response is sent.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(e),
+    }
+}
+
+/// The TLS client of all HTTPS requests, built on first use.
+fn tls() -> TlsConnector {
+    static CONNECTOR: OnceLock<TlsConnector> = OnceLock::new();
+    CONNECTOR
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default TLS versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            TlsConnector::from(Arc::new(config))
+        })
+        .clone()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_for_the_request() {
+        let url = Url::parse("https://discord.com/api/webhooks/1/x").unwrap();
+        assert_eq!(
+            url,
+            Url {
+                tls: true,
+                authority: "discord.com",
+                host: "discord.com",
+                port: 443,
+                path: "/api/webhooks/1/x",
+            }
+        );
+
+        let url = Url::parse("http://localhost:5000").unwrap();
+        assert_eq!(
+            (url.tls, url.host, url.port, url.path),
+            (false, "localhost", 5000, "/")
+        );
+
+        let url = Url::parse("http://[::1]:8080/hook").unwrap();
+        assert_eq!(
+            (url.authority, url.host, url.port),
+            ("[::1]:8080", "::1", 8080)
+        );
+        let url = Url::parse("https://[::1]/hook").unwrap();
+        assert_eq!((url.host, url.port), ("::1", 443));
+
+        for bad in ["ftp://h/x", "h/x", "http:///x", "http://h:99999/x"] {
+            assert!(Url::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn https_needs_a_tls_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 1];
+            stream.read_exact(&mut hello).await.unwrap();
+            // A TLS handshake record, not a request.
+            assert_eq!(hello[0], 0x16);
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+        });
+        assert!(post_json(&url, "{}").await.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::SocketAddr,
+    path::Path,
+};
+
+use batproxy_rs::{
+    auth,
+    config::{Config, Listen},
+    db,
+    login::Secret,
+};
+
+/// Interactively build a config file at `path`.
+pub fn run(path: &Path) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut config = Config::default();
+
+    println!(
+        "Generating {}. Press enter to keep the default.",
+        path.display()
+    );
+
+    if path.exists()
+        && !ask_yes_no(
+            &mut input,
+            &format!("{} exists, overwrite?", path.display()),
+            false,
+        )?
+    {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let listen = loop {
+        let listen = ask(&mut input, "Listen address", &config.listen[0].addr)?;
+        match listen.parse::<SocketAddr>() {
+            Ok(addr) => {
+                config.listen = vec![Listen::new(listen)];
+                break addr;
+            }
+            Err(_) => println!("  expected an address like 127.0.0.1:7788"),
+        }
+    };
+    if !listen.ip().is_loopback() {
+        println!(
+            "Other machines can reach {}; their clients give a password first.",
+            listen
+        );
+        config.auth.password = Some(ask_secret(
+            &mut input,
+            "Client password",
+            auth::ENVIRON_VAR,
+        )?);
+    }
+
+    loop {
+        let remote = ask(&mut input, "BatMUD server", &config.remote)?;
+        match remote.rsplit_once(':').map(|(_, port)| port.parse::<u16>()) {
+            Some(Ok(_)) => {
+                config.remote = remote;
+                break;
+            }
+            _ => println!("  expected host:port, e.g. batmud.bat.org:2023"),
+        }
+    }
+
+    loop {
+        let default = config.merge.window.as_millis().to_string();
+        let ms = ask(
+            &mut input,
+            "Merge window for clear screen + map in ms (0 to disable)",
+            &default,
+        )?;
+        match ms.parse() {
+            Ok(ms) => {
+                config.merge.window = std::time::Duration::from_millis(ms);
+                break;
+            }
+            Err(_) => println!("  expected a number"),
+        }
+    }
+
+    if ask_yes_no(&mut input, "Log in automatically when connected?", false)? {
+        loop {
+            let name = ask(&mut input, "Character name", "")?;
+            if !name.is_empty() {
+                config.login.name = name;
+                break;
+            }
+            println!("  expected a name");
+        }
+        println!("The password is never stored in the config.");
+        config.login.password = Some(ask_secret(&mut input, "Login password", "BATMUD_PASSWORD")?);
+    }
+
+    if ask_yes_no(&mut input, "Store mapper rooms in a SQLite database?", true)? {
+        let db = ask(&mut input, "Database file", "bcproxy.db")?;
+        let conn = rusqlite::Connection::open(&db).map_err(io::Error::other)?;
+        db::create_schema(&conn).map_err(io::Error::other)?;
+        println!("Created schema in {}.", db);
+        config.database = Some(db.into());
+    }
+
+    std::fs::write(path, config.to_file_string())?;
+    println!("Wrote {}.", path.display());
+
+    Ok(())
+}
+
+fn ask(input: &mut impl BufRead, question: &str, default: &str) -> io::Result<String> {
+    print!("{} [{}]: ", question, default);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input closed"));
+    }
+
+    let line = line.trim();
+    Ok(if line.is_empty() { default } else { line }.to_string())
+}
+
+/// Ask where the password `what` is read from: an environment variable,
+/// `var` by default, or a command printing it.
+fn ask_secret(input: &mut impl BufRead, what: &str, var: &str) -> io::Result<Secret> {
+    loop {
+        let source = ask(input, &format!("{} from (env/command)", what), "env")?;
+        match source.as_str() {
+            "env" => return Ok(Secret::Env(ask(input, "Environment variable", var)?)),
+            "command" => {
+                let command = ask(
+                    input,
+                    "Command printing it",
+                    "secret-tool lookup service batmud",
+                )?;
+                return Ok(Secret::Command(command));
+            }
+            _ => println!("  expected env or command"),
+        }
+    }
+}
+
+fn ask_yes_no(input: &mut impl BufRead, question: &str, default: bool) -> io::Result<bool> {
+    let answer = ask(input, question, if default { "y" } else { "n" })?;
+    Ok(matches!(answer.as_str(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,167 @@
+//! What the player carries and wears, read from the output of `i` and `eq`.
+//! A line matching an `inventory_header` starts a listing, each line after
+//! it is an item and the listing ends at a blank line or the prompt. The
+//! last two listings of each kind are kept, and stored when there is a
+//! database, so `;;inv diff` can show what was gained or lost in between.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+
+use crate::{bc::Frame, color, db::Event, session::Session, trigger::glob_to_regex};
+
+pub use crate::db::{Kind, Snapshot};
+
+/// Listings longer than this are cut short.
+const MAX_ITEMS: usize = 500;
+
+/// An `inventory_header = <inv|eq> <glob or re:regex>` line of the config.
+#[derive(Debug, Clone)]
+pub struct Header {
+    source: String,
+    pub kind: Kind,
+    pattern: Regex,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, pattern) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("inventory header `{}` has no pattern", s))?;
+        let pattern = pattern.trim();
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => re.to_string(),
+            None => glob_to_regex(pattern),
+        };
+        Ok(Self {
+            source: s.trim().to_string(),
+            kind: kind.parse()?,
+            pattern: Regex::new(&regex).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The listings of a session.
+#[derive(Debug)]
+pub struct Inventory {
+    headers: Vec<Header>,
+    // Text of the line seen so far.
+    line: Vec<u8>,
+    reading: Option<(Kind, Vec<String>)>,
+    /// The previous and the last listing of each kind.
+    snapshots: BTreeMap<Kind, (Option<Snapshot>, Snapshot)>,
+}
+
+impl Inventory {
+    pub fn new(headers: Vec<Header>) -> Self {
+        Self {
+            headers,
+            line: Vec::new(),
+            reading: None,
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    /// The last listing of `kind` and the one before it.
+    pub fn snapshots(&self, kind: Kind) -> Option<(Option<&Snapshot>, &Snapshot)> {
+        self.snapshots
+            .get(&kind)
+            .map(|(previous, last)| (previous.as_ref(), last))
+    }
+
+    /// Read `frame`, returning the listing it ended.
+    fn push(&mut self, frame: &Frame) -> Option<Snapshot> {
+        let text = match frame {
+            Frame::Text(text) => text,
+            Frame::Prompt(_) => {
+                self.line.clear();
+                return self.end();
+            }
+            _ => return None,
+        };
+        let mut ended = None;
+        let mut text = &text[..];
+        while let Some(i) = text.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&text[..i]);
+            text = &text[i + 1..];
+            let line = color::strip_ansi(&std::mem::take(&mut self.line));
+            ended = self.read_line(line.trim()).or(ended);
+        }
+        self.line.extend_from_slice(text);
+        ended
+    }
+
+    fn read_line(&mut self, line: &str) -> Option<Snapshot> {
+        if let Some(header) = self.headers.iter().find(|h| h.pattern.is_match(line)) {
+            let kind = header.kind;
+            let ended = self.end();
+            self.reading = Some((kind, Vec::new()));
+            return ended;
+        }
+        match &mut self.reading {
+            Some(_) if line.is_empty() => self.end(),
+            Some((_, items)) if items.len() < MAX_ITEMS => {
+                items.push(line.to_string());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn end(&mut self) -> Option<Snapshot> {
+        let (kind, items) = self.reading.take()?;
+        let taken_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Some(Snapshot {
+            kind,
+            items,
+            taken_at,
+        })
+    }
+
+    fn keep(&mut self, snapshot: Snapshot, previous: Option<Snapshot>) {
+        let previous = match self.snapshots.remove(&snapshot.kind) {
+            Some((_, last)) => Some(last),
+            None => previous,
+        };
+        self.snapshots.insert(snapshot.kind, (previous, snapshot));
+    }
+}
+
+/// Read the listings in server output, storing each one read whole.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let snapshot = match session.inventory.push(frame) {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    // The first listing of a session is compared with the last one stored.
+    let mut previous = None;
+    if let Some(db) = &session.db {
+        if session.inventory.snapshots(snapshot.kind).is_none() {
+            match db.last_inventory(snapshot.kind) {
+                Ok(stored) => previous = stored,
+                Err(e) => tracing::warn!("failed to read the last inventory: {}", e),
+            }
+        }
+        db.send(Event::Inventory {
+            kind: snapshot.kind,
+            items: snapshot.items.clone(),
+        });
+    }
+    session.inventory.keep(snapshot, previous);
+}
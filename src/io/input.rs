@@ -0,0 +1,313 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use tokio::time::{sleep, Sleep};
+
+use crate::{
+    alias::{self, Alias},
+    chain, command,
+    config::Config,
+    login,
+    queue::Pacing,
+    session::Session,
+    speedwalk,
+    telnet::{self, Command, Segment},
+};
+
+use super::{keepalive::IdleTimer, proxy::Filter, walk::Walker};
+
+/// Forwards client input to the server, along with commands the proxy
+/// queued up itself. Lines for the proxy are taken out of the stream,
+/// aliases replaced and speedwalks expanded into their steps.
+pub(super) struct ClientInput {
+    idle: IdleTimer,
+    walker: Walker,
+    // When the next queued line may go, with a delay between lines.
+    pace: Option<Pin<Box<Sleep>>>,
+    speedwalk: bool,
+    aliases: Vec<Alias>,
+    telnet: telnet::Parser,
+    // Start of a line that may turn out to be a proxy command or speedwalk.
+    held: Vec<u8>,
+    // The current line is known to be neither.
+    passing: bool,
+    // The current line as typed, for the history.
+    typed: Vec<u8>,
+}
+
+impl ClientInput {
+    pub(super) fn new(config: &Config) -> Self {
+        Self {
+            idle: IdleTimer::new(config.keepalive.clone()),
+            walker: Walker::new(),
+            pace: None,
+            speedwalk: config.speedwalk,
+            aliases: config.aliases.clone(),
+            telnet: telnet::Parser::new(),
+            held: Vec::new(),
+            passing: false,
+            typed: Vec::new(),
+        }
+    }
+
+    /// Take the aliases of a reloaded config.
+    pub(super) fn reload(&mut self, config: &Config) {
+        self.aliases = config.aliases.clone();
+    }
+
+    fn process_data(&mut self, input: &[u8], output: &mut Vec<u8>, session: &mut Session) {
+        let mut rest = input;
+        while !rest.is_empty() {
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(rest.len(), |i| i + 1);
+            let (segment, tail) = rest.split_at(end);
+            let complete = segment.ends_with(b"\n");
+            rest = tail;
+            self.record(segment, complete, session);
+
+            if self.passing {
+                output.extend_from_slice(segment);
+                self.passing = !complete;
+                continue;
+            }
+
+            self.held.extend_from_slice(segment);
+            if complete {
+                let start = output.len();
+                let line = String::from_utf8_lossy(&self.held);
+                session.quit = !session.server_echo && line.trim() == "quit";
+                if session.server_echo {
+                    // Typed at a password prompt.
+                    output.extend_from_slice(&self.held);
+                } else if session.login.is_some() && login::is_bc_mode(&line) {
+                    // The auto-login turned BC mode on before the client
+                    // could.
+                } else if login::is_bc_mode(&line) {
+                    session.bc_requested = true;
+                    output.extend_from_slice(&self.held);
+                } else if !command::handle(&line, session) {
+                    match alias::expand(&self.aliases, &line) {
+                        Some(command) => match self.expand(&command) {
+                            Some(steps) => walk(steps, output, session),
+                            None => {
+                                output.extend_from_slice(command.as_bytes());
+                                output.push(b'\n');
+                            }
+                        },
+                        None => match self.expand(&line) {
+                            Some(steps) => walk(steps, output, session),
+                            None => output.extend_from_slice(&self.held),
+                        },
+                    }
+                }
+                self.held.clear();
+                if session.commands.is_on() {
+                    queue(output.split_off(start), session);
+                }
+            } else if !session.commands.is_on() && (session.server_echo || !self.may_be_for_proxy())
+            {
+                output.append(&mut self.held);
+                self.passing = true;
+            }
+        }
+    }
+
+    /// Add the line `segment` ends to the history once it is complete,
+    /// unless it may be the name or the password.
+    fn record(&mut self, segment: &[u8], complete: bool, session: &mut Session) {
+        self.typed.extend_from_slice(segment);
+        if !complete {
+            return;
+        }
+        if !session.server_echo && session.login_state.is_authenticated() {
+            session.history.push(&String::from_utf8_lossy(&self.typed));
+        }
+        self.typed.clear();
+    }
+
+    /// Whether the held start of a line could still be a command, an alias
+    /// or a speedwalk.
+    fn may_be_for_proxy(&self) -> bool {
+        command::may_be_command(&self.held)
+            || self.may_be_alias()
+            || (self.speedwalk && speedwalk::may_be_speedwalk(&self.held))
+    }
+
+    fn may_be_alias(&self) -> bool {
+        let held = self.held.trim_ascii_start();
+        match held.iter().position(|b| b.is_ascii_whitespace()) {
+            Some(end) => self
+                .aliases
+                .iter()
+                .any(|a| a.name.as_bytes() == &held[..end]),
+            None => self
+                .aliases
+                .iter()
+                .any(|a| a.name.as_bytes().starts_with(held)),
+        }
+    }
+
+    fn expand(&self, line: &str) -> Option<Vec<String>> {
+        self.speedwalk.then(|| speedwalk::expand(line)).flatten()
+    }
+
+    /// Release the queued lines that are due.
+    fn poll_queued(&mut self, cx: &mut Context<'_>, output: &mut Vec<u8>, session: &mut Session) {
+        match session.commands.pacing {
+            // Turned off with lines left.
+            Pacing::Off => {
+                while let Some(line) = session.commands.pop() {
+                    output.extend_from_slice(&line);
+                }
+            }
+            Pacing::Prompt => {
+                if let Some(line) = session.commands.pop_prompted() {
+                    output.extend_from_slice(&line);
+                }
+            }
+            Pacing::Delay(delay) => {
+                if let Some(pace) = self.pace.as_mut() {
+                    if pace.as_mut().poll(cx).is_pending() {
+                        return;
+                    }
+                    self.pace = None;
+                }
+                if let Some(line) = session.commands.pop() {
+                    output.extend_from_slice(&line);
+                    let mut pace = Box::pin(sleep(delay));
+                    if pace.as_mut().poll(cx).is_ready() {
+                        cx.waker().wake_by_ref();
+                    }
+                    self.pace = Some(pace);
+                }
+            }
+        }
+    }
+}
+
+/// Count the lines in `output` as commands sent to the server.
+fn count_lines(output: &[u8], session: &mut Session) {
+    let lines = output.iter().filter(|&&b| b == b'\n').count();
+    if lines > 0 {
+        session.stats.client.frames += lines as u64;
+        session.stats.command_sent();
+    }
+}
+
+/// Whether a telnet command from the client is for the server: anything
+/// but an answer about an option only the proxy asked for.
+fn answers_server(command: &Command, session: &Session) -> bool {
+    match command {
+        Command::Will(option) | Command::Wont(option) | Command::Sub(option, _) => {
+            !session.client_options.contains(option) || session.server_options.contains(option)
+        }
+        _ => true,
+    }
+}
+
+/// Queue the lines of `sent` instead of sending them.
+fn queue(sent: Vec<u8>, session: &mut Session) {
+    for line in sent.split_inclusive(|&b| b == b'\n') {
+        session.commands.push(line.to_vec());
+    }
+    session.wake();
+}
+
+/// Send the steps of a speedwalk, all at once if there is no delay between
+/// them.
+fn walk(steps: Vec<String>, output: &mut Vec<u8>, session: &mut Session) {
+    if !session.walk_delay.is_zero() {
+        return session.start_walk(steps);
+    }
+    session.walk.clear();
+    for step in steps {
+        output.extend_from_slice(step.as_bytes());
+        output.push(b'\n');
+    }
+}
+
+impl Filter for ClientInput {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>, session: &mut Session) {
+        tracing::trace!(bytes = input.len(), "read");
+        session.stats.client.read += input.len() as u64;
+        self.idle.reset();
+
+        let mut segments = Vec::new();
+        self.telnet.parse(input, &mut segments);
+        for segment in segments {
+            match segment {
+                Segment::Data(data) => {
+                    session.stats.typed_at = Instant::now();
+                    let start = output.len();
+                    self.process_data(&data, output, session);
+                    count_lines(&output[start..], session);
+                }
+                Segment::Command(command, raw) => {
+                    if chain::answer(&command, session) {
+                        continue;
+                    }
+                    let reported = session.client.color_mode;
+                    if let Some(reply) = session.client.observe(&command) {
+                        session.write_client(reply);
+                    }
+                    if session.client.color_mode != reported {
+                        if let Some(mode) = session.client.color_mode {
+                            session.color_mode = mode;
+                        }
+                    }
+                    // Telnet commands are not part of a line. Answers to the
+                    // proxy's own questions stay with it.
+                    if answers_server(&command, session) {
+                        output.extend_from_slice(&raw);
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_release(
+        &mut self,
+        cx: &mut Context<'_>,
+        output: &mut Vec<u8>,
+        session: &mut Session,
+    ) -> Poll<()> {
+        if let Poll::Ready(command) = self.idle.poll_idle(cx) {
+            if session.keepalive {
+                output.extend_from_slice(command.as_bytes());
+                output.push(b'\n');
+            }
+        }
+        if let Poll::Ready(step) = self.walker.poll_step(cx, session) {
+            output.extend_from_slice(step.as_bytes());
+            output.push(b'\n');
+        }
+        self.poll_queued(cx, output, session);
+        // Lines of attached clients do not cut into a line of the client.
+        if !self.passing && self.held.is_empty() {
+            while let Some(line) = session.attached_input.pop_front() {
+                self.process_data(&line, output, session);
+            }
+        }
+
+        if output.is_empty() && session.to_server.is_empty() {
+            return Poll::Pending;
+        }
+        for command in session.to_server.drain(..) {
+            output.extend_from_slice(&command);
+        }
+        count_lines(output, session);
+        Poll::Ready(())
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>, _session: &mut Session) {
+        // A line cut off by the disconnect is not run as a command.
+        output.append(&mut self.held);
+        output.append(&mut self.telnet.finish());
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Send `command` to the server after `idle` without client input, so the
+/// character is not dropped as link-dead. A zero `idle` turns it off.
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    pub idle: Duration,
+    /// Up to this much is added to `idle` at random each time.
+    pub jitter: Duration,
+    pub command: String,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::ZERO,
+            jitter: Duration::from_secs(30),
+            command: String::new(),
+        }
+    }
+}
+
+pub(super) struct IdleTimer {
+    config: Keepalive,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl IdleTimer {
+    pub(super) fn new(config: Keepalive) -> Self {
+        let deadline = (!config.idle.is_zero()).then(|| Box::pin(sleep(next_delay(&config))));
+        Self { config, deadline }
+    }
+
+    /// The client sent something, start counting again.
+    pub(super) fn reset(&mut self) {
+        if let Some(deadline) = self.deadline.as_mut() {
+            deadline
+                .as_mut()
+                .reset(Instant::now() + next_delay(&self.config));
+        }
+    }
+
+    /// Resolves with the command to send once the client has been idle for
+    /// long enough.
+    pub(super) fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<&str> {
+        match self.deadline.as_mut() {
+            Some(deadline) => {
+                ready!(deadline.as_mut().poll(cx));
+                self.reset();
+                Poll::Ready(&self.config.command)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+fn next_delay(config: &Keepalive) -> Duration {
+    let jitter = config.jitter.as_millis() as u64;
+    let random = RandomState::new().build_hasher().finish();
+    config.idle + Duration::from_millis(random % (jitter + 1))
+}
@@ -0,0 +1,241 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Sleep};
+
+use crate::{
+    bc::{ControlCode, Frame},
+    style::OutputStyle,
+};
+
+/// Matches a control code by id and, optionally, by its attribute.
+#[derive(Debug, Clone)]
+pub struct CodeMatch {
+    id: u8,
+    attr: Option<Vec<u8>>,
+}
+
+impl CodeMatch {
+    pub fn new(id: u8, attr: Option<&[u8]>) -> Self {
+        Self {
+            id,
+            attr: attr.map(<[u8]>::to_vec),
+        }
+    }
+
+    pub fn matches(&self, code: &ControlCode) -> bool {
+        code.id == self.id && self.attr.as_ref().is_none_or(|attr| code.attr_is(attr))
+    }
+}
+
+/// Parses `11` or `10:spec_map`.
+impl std::str::FromStr for CodeMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, attr) = match s.split_once(':') {
+            Some((id, attr)) => (id, Some(attr.as_bytes())),
+            None => (s, None),
+        };
+        match id.parse() {
+            Ok(id) if id < 100 => Ok(Self::new(id, attr)),
+            _ => Err(format!("invalid control code `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for CodeMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}", self.id)?;
+        if let Some(attr) = &self.attr {
+            write!(f, ":{}", String::from_utf8_lossy(attr))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sequences of control codes that must reach the client in a single write,
+/// e.g. clear screen (11) followed by a `spec_map` message.
+#[derive(Debug, Clone)]
+pub struct MergeWindow {
+    pub sequences: Vec<Vec<CodeMatch>>,
+    pub window: Duration,
+}
+
+impl Default for MergeWindow {
+    fn default() -> Self {
+        Self {
+            sequences: vec![vec![
+                CodeMatch::new(11, None),
+                CodeMatch::new(10, Some(b"spec_map")),
+            ]],
+            window: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Holds back output once the first code of a sequence is seen, until the
+/// sequence completes or the window runs out.
+pub(super) struct Merger {
+    config: MergeWindow,
+    held: Vec<u8>,
+    // Index of the sequence being held and how many of its codes were seen.
+    active: Option<(usize, usize)>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl Merger {
+    pub(super) fn new(config: MergeWindow) -> Self {
+        Self {
+            config,
+            held: Vec::new(),
+            active: None,
+            deadline: None,
+        }
+    }
+
+    pub(super) fn push(&mut self, frame: Frame, style: &dyn OutputStyle, output: &mut Vec<u8>) {
+        if let Some((seq, seen)) = self.active {
+            match frame.code() {
+                Some(code) if self.config.sequences[seq][seen].matches(code) => {
+                    style.render(&frame, &mut self.held);
+                    if seen + 1 == self.config.sequences[seq].len() {
+                        self.release(output);
+                    } else {
+                        self.active = Some((seq, seen + 1));
+                    }
+                    return;
+                }
+                None => {
+                    style.render(&frame, &mut self.held);
+                    return;
+                }
+                Some(_) => self.release(output),
+            }
+        }
+
+        if let Some(code) = frame.code() {
+            if self.config.window.is_zero() {
+                style.render(&frame, output);
+                return;
+            }
+
+            let start = self
+                .config
+                .sequences
+                .iter()
+                .position(|seq| seq.len() > 1 && seq[0].matches(code));
+            if let Some(seq) = start {
+                style.render(&frame, &mut self.held);
+                self.active = Some((seq, 1));
+                self.deadline = Some(Box::pin(sleep(self.config.window)));
+                return;
+            }
+        }
+
+        style.render(&frame, output);
+    }
+
+    pub(super) fn poll_expire(&mut self, cx: &mut Context<'_>, output: &mut Vec<u8>) -> Poll<()> {
+        match self.deadline.as_mut() {
+            Some(deadline) => {
+                ready!(deadline.as_mut().poll(cx));
+                self.release(output);
+                Poll::Ready(())
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    pub(super) fn release(&mut self, output: &mut Vec<u8>) {
+        output.append(&mut self.held);
+        self.active = None;
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::time::{timeout, Instant};
+
+    use super::*;
+    use crate::{bc::Decoder, style::Raw};
+
+    const CLEAR: &[u8] = b"\x1b<11\x1b>11";
+    const MAP: &[u8] = b"\x1b<10spec_map\x1b|map\x1b>10";
+
+    fn push(merger: &mut Merger, bytes: &[u8]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        Decoder::new().decode(bytes, &mut frames);
+        let mut output = Vec::new();
+        for frame in frames {
+            merger.push(frame, &Raw, &mut output);
+        }
+        output
+    }
+
+    async fn expire(merger: &mut Merger) -> Vec<u8> {
+        let mut output = Vec::new();
+        poll_fn(|cx| merger.poll_expire(cx, &mut output)).await;
+        output
+    }
+
+    #[test]
+    fn code_matches_are_read_with_an_optional_attribute() {
+        let map: CodeMatch = "10:spec_map".parse().unwrap();
+        assert_eq!(map.to_string(), "10:spec_map");
+        assert_eq!("5".parse::<CodeMatch>().unwrap().to_string(), "05");
+        assert!("100".parse::<CodeMatch>().is_err());
+        assert!("x:spec_map".parse::<CodeMatch>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_sequence_completed_in_the_window_is_written_at_once() {
+        let mut merger = Merger::new(MergeWindow::default());
+        assert_eq!(push(&mut merger, b"a"), b"a");
+        assert_eq!(push(&mut merger, CLEAR), b"");
+        // Text inside the sequence is held along with it.
+        assert_eq!(push(&mut merger, b"b"), b"");
+        tokio::time::advance(Duration::from_millis(40)).await;
+        assert_eq!(push(&mut merger, MAP), [CLEAR, b"b", MAP].concat());
+        // Nothing is left to expire.
+        assert!(timeout(Duration::from_secs(1), expire(&mut merger))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_output_is_written_when_the_window_runs_out() {
+        let mut merger = Merger::new(MergeWindow::default());
+        assert_eq!(push(&mut merger, CLEAR), b"");
+        let start = Instant::now();
+        assert_eq!(expire(&mut merger).await, CLEAR);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // The rest of the sequence is no longer held.
+        assert_eq!(push(&mut merger, MAP), MAP);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn another_code_ends_the_sequence() {
+        let mut merger = Merger::new(MergeWindow::default());
+        push(&mut merger, CLEAR);
+        let chan = b"\x1b<10chan_sales\x1b|hi\x1b>10";
+        assert_eq!(push(&mut merger, chan), [CLEAR, chan].concat());
+    }
+
+    #[test]
+    fn nothing_is_held_without_a_window() {
+        let mut merger = Merger::new(MergeWindow {
+            window: Duration::ZERO,
+            ..MergeWindow::default()
+        });
+        assert_eq!(push(&mut merger, CLEAR), CLEAR);
+    }
+}
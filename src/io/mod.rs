@@ -1,52 +1,348 @@
+mod input;
+mod keepalive;
+mod merge;
+mod output;
 mod proxy;
+mod upstream;
+mod walk;
+mod watchdog;
 
 use std::{
     future::poll_fn,
     pin::Pin,
+    sync::Arc,
     task::{ready, Context, Poll},
 };
 
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug_span;
 
-use self::proxy::ProxyBuffer;
+use crate::{
+    bc::Frame,
+    chain, command,
+    config::Config,
+    login,
+    middleware::MiddlewareFactory,
+    mirror::Tee,
+    session::{Listing, Request, Session, ToClient},
+    state,
+};
+
+pub use self::{
+    keepalive::Keepalive,
+    merge::{CodeMatch, MergeWindow},
+    output::Strictness,
+    proxy::{OutputQueue, SlowClient},
+    upstream::{connect, Connect, Reconnect},
+    watchdog::Watchdog,
+};
+
+use self::{
+    input::ClientInput,
+    output::ServerOutput,
+    proxy::{Filter, ProxyBuffer},
+    upstream::{Upstream, UpstreamEvent},
+};
 
-enum ProxyState {
-    Running(ProxyBuffer),
+/// Called with each frame of server output, returning the frame to pass on
+/// or `None` to drop it.
+pub type FrameHook = Arc<dyn Fn(Frame, &mut Session) -> Option<Frame> + Send + Sync>;
+
+enum ProxyState<F> {
+    Running(ProxyBuffer<F>),
     ShuttingDown(u64),
     Done(u64),
 }
 
+impl<F: Filter> ProxyState<F> {
+    /// Bytes written so far.
+    fn written(&self) -> u64 {
+        match self {
+            ProxyState::Running(buf) => buf.written(),
+            ProxyState::ShuttingDown(count) | ProxyState::Done(count) => *count,
+        }
+    }
+}
+
 pub async fn proxy_bidirection<A, B>(
     server: &mut A,
     client: &mut B,
+    config: &Config,
+    session: Session,
+    hooks: &[FrameHook],
+    middleware: &[MiddlewareFactory],
 ) -> Result<(u64, u64), std::io::Error>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-    let mut inbound = ProxyState::Running(ProxyBuffer::new());
-    let mut outbound = ProxyState::Running(ProxyBuffer::new());
+    let mut server = Upstream::new(server, None, Reconnect::default(), config.watchdog.clone());
+    proxy(&mut server, client, config, session, hooks, middleware).await
+}
+
+/// Like [`proxy_bidirection`], but when the server drops the connection a
+/// new one is opened with `connect` as `config.reconnect` says, the
+/// client staying connected meanwhile.
+pub async fn proxy_reconnecting<A, B>(
+    server: A,
+    connect: Connect<A>,
+    client: &mut B,
+    config: &Config,
+    session: Session,
+    hooks: &[FrameHook],
+    middleware: &[MiddlewareFactory],
+) -> Result<(u64, u64), std::io::Error>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut server = Upstream::new(
+        server,
+        Some(connect),
+        config.reconnect.clone(),
+        config.watchdog.clone(),
+    );
+    proxy(&mut server, client, config, session, hooks, middleware).await
+}
+
+/// Run server output read from `input` through the decoding and middleware
+/// of a session and write what a client would get to `output`, e.g. to look
+/// at a capture file. Commands the session would send to the server are
+/// dropped. Returns the bytes written.
+pub async fn pipe<R, W>(
+    input: &mut R,
+    output: &mut W,
+    config: &Config,
+    hooks: &[FrameHook],
+    middleware: &[MiddlewareFactory],
+) -> Result<u64, std::io::Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut session = Session::new(config, None);
+    let mut buf = ProxyBuffer::with_queue(
+        ServerOutput::new(config, hooks.to_vec(), middleware),
+        config.output_queue.clone(),
+    );
+    let span = debug_span!("server");
     poll_fn(|cx| {
-        let inbound = server_to_client(cx, &mut inbound, server, client)?;
-        let outbound = client_to_server(cx, &mut outbound, client, server)?;
+        let result = span.in_scope(|| {
+            buf.poll_copy(
+                cx,
+                Pin::new(&mut *input),
+                Pin::new(&mut *output),
+                &mut session,
+            )
+        });
+        session.to_server.clear();
+        session.walk.clear();
+        if let Some(error) = session.protocol_error.take() {
+            return Poll::Ready(Err(protocol_error(error)));
+        }
+        if session.take_queued() {
+            cx.waker().wake_by_ref();
+        }
+        result
+    })
+    .await
+}
+
+async fn proxy<A, B>(
+    server: &mut Upstream<A>,
+    client: &mut B,
+    config: &Config,
+    mut session: Session,
+    hooks: &[FrameHook],
+    middleware: &[MiddlewareFactory],
+) -> Result<(u64, u64), std::io::Error>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut inbound = ProxyState::Running(ProxyBuffer::with_queue(
+        ServerOutput::new(config, hooks.to_vec(), middleware),
+        config.output_queue.clone(),
+    ));
+    let mut outbound = ProxyState::Running(ProxyBuffer::new(ClientInput::new(config)));
+    // What the client gets also goes to the clients attached to the session.
+    let mirror = session.listing.as_ref().map(Listing::mirror);
+    let client = &mut Tee::new(client, mirror);
+    let mut dropped = false;
+    let server_span = debug_span!("server");
+    let client_span = debug_span!("client");
+    let result: std::io::Result<(u64, u64)> = poll_fn(|cx| {
+        if let ProxyState::Running(buf) = &mut inbound {
+            while let Some(event) = server.events.pop_front() {
+                upstream_event(event, buf.filter_mut(), &mut session);
+            }
+            session.set_output_queue(buf.queue_stats());
+        }
+        while let Some(request) = session.listing.as_mut().and_then(|l| l.poll_request(cx)) {
+            handle_request(request, &mut inbound, &mut outbound, &mut session);
+        }
+        session.poll_background(cx);
+        // The new proxy reads and writes the server connection now, what is
+        // left here is dropped when the session ends.
+        if session.handed_over {
+            return Poll::Pending;
+        }
+        if std::mem::take(&mut server.dropped_input) {
+            session.notify("not connected to the server, input dropped");
+        }
+        // A player quitting closes the connection for good.
+        server.retry = !session.quit;
+
+        let to_client = server_span
+            .in_scope(|| server_to_client(cx, &mut inbound, server, client, &mut session))?;
+        let to_server = client_span
+            .in_scope(|| client_to_server(cx, &mut outbound, client, server, &mut session))?;
+        session.stats.server.written = inbound.written();
+        session.stats.client.written = outbound.written();
+        session.publish_stats();
+        if let Some(error) = session.protocol_error.take() {
+            return Poll::Ready(Err(protocol_error(error)));
+        }
+
+        // The server closing first is a dropped connection, the client
+        // quitting is not.
+        if to_client.is_ready() && to_server.is_pending() && !dropped {
+            dropped = true;
+            session
+                .notifier
+                .disconnected("the server closed the connection");
+        }
+
+        // Either direction may have queued output for the other one, which
+        // was polled before it or is not woken by its own IO.
+        if session.take_queued() || !server.events.is_empty() || server.dropped_input {
+            cx.waker().wake_by_ref();
+        }
 
         // It is not a problem if ready! returns early because transfer_one_direction for the
         // other direction will keep returning TransferState::Done(count) in future calls to poll
-        let inbound = ready!(inbound);
-        let outbound = ready!(outbound);
+        let inbound = ready!(to_client);
+        let outbound = ready!(to_server);
 
         Poll::Ready(Ok((inbound, outbound)))
     })
-    .await
+    .await;
+
+    if let Err(e) = &result {
+        if !dropped {
+            session.notifier.disconnected(&e.to_string());
+        }
+    }
+    result
+}
+
+/// The error a session ends with in `strict-disconnect` mode.
+fn protocol_error(error: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("protocol error: {}", error),
+    )
+}
+
+/// Tell the client what happened to the server connection and start over
+/// on a new one.
+fn upstream_event(event: UpstreamEvent, output: &mut ServerOutput, session: &mut Session) {
+    match event {
+        UpstreamEvent::Lost { reason, retry_in } => {
+            // Whatever was cut off by the loss is gone with it.
+            output.reconnected(session);
+            session.notifier.disconnected(&reason);
+            session.notify(&format!(
+                "connection lost: {}, reconnecting in {}s",
+                reason,
+                retry_in.as_secs()
+            ));
+        }
+        UpstreamEvent::Failed {
+            error,
+            attempt,
+            retry_in,
+        } => session.notify(&format!(
+            "reconnect attempt {} failed: {}, trying again in {}s",
+            attempt,
+            error,
+            retry_in.as_secs()
+        )),
+        UpstreamEvent::Reconnected => {
+            session.reconnected();
+            session.notify("reconnected to the server");
+            chain::start(session);
+            login::start(session);
+        }
+        UpstreamEvent::Probe(probe) => session.send_command(&probe),
+        UpstreamEvent::Stalled(silent) => session.notify(&format!(
+            "the server has not answered for {}s",
+            silent.as_secs()
+        )),
+    }
+}
+
+/// Answer a request from outside the session. The lines a command shows
+/// go with the answer rather than to the client.
+fn handle_request(
+    request: Request,
+    output: &mut ProxyState<ServerOutput>,
+    input: &mut ProxyState<ClientInput>,
+    session: &mut Session,
+) {
+    match request {
+        Request::Command(line, reply) => {
+            let shown = session.to_client.len();
+            command::handle(&format!("#bc {}", line), session);
+            let lines = session
+                .to_client
+                .drain(shown..)
+                .flat_map(|message| match message {
+                    ToClient::Message(line) => vec![line],
+                    ToClient::Raw(bytes) => String::from_utf8_lossy(&bytes)
+                        .lines()
+                        .map(|line| line.trim_end().to_string())
+                        .collect(),
+                })
+                .collect();
+            let _ = reply.send(lines);
+        }
+        Request::Reload(config) => {
+            if let ProxyState::Running(buf) = output {
+                buf.filter_mut().reload(&config);
+            }
+            if let ProxyState::Running(buf) = input {
+                buf.filter_mut().reload(&config);
+            }
+            session.channels.reload(&config);
+            session.export_dir = config.export_dir.clone();
+            session.notify("config reloaded");
+        }
+        Request::Input(line) => {
+            session.attached_input.push_back(line);
+            session.wake();
+        }
+        Request::Save(reply) => {
+            state::save(session);
+            let _ = reply.send(());
+        }
+        Request::Handover(reply) => {
+            session.handed_over = true;
+            let _ = reply.send(state::snapshot(session));
+        }
+        Request::Resume => session.handed_over = false,
+    }
 }
 
-fn server_to_client<R, W>(
+fn server_to_client<F, R, W>(
     cx: &mut Context<'_>,
-    state: &mut ProxyState,
+    state: &mut ProxyState<F>,
     server: &mut R,
     client: &mut W,
+    session: &mut Session,
 ) -> Poll<std::io::Result<u64>>
 where
+    F: Filter,
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
@@ -56,7 +352,7 @@ where
     loop {
         match state {
             ProxyState::Running(buf) => {
-                let count = ready!(buf.poll_copy(cx, server.as_mut(), client.as_mut()))?;
+                let count = ready!(buf.poll_copy(cx, server.as_mut(), client.as_mut(), session))?;
                 *state = ProxyState::ShuttingDown(count);
             }
             ProxyState::ShuttingDown(count) => {
@@ -69,13 +365,15 @@ where
     }
 }
 
-fn client_to_server<R, W>(
+fn client_to_server<F, R, W>(
     cx: &mut Context<'_>,
-    state: &mut ProxyState,
+    state: &mut ProxyState<F>,
     client: &mut R,
     server: &mut W,
+    session: &mut Session,
 ) -> Poll<std::io::Result<u64>>
 where
+    F: Filter,
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
@@ -85,7 +383,7 @@ where
     loop {
         match state {
             ProxyState::Running(buf) => {
-                let count = ready!(buf.poll_copy(cx, client.as_mut(), server.as_mut()))?;
+                let count = ready!(buf.poll_copy(cx, client.as_mut(), server.as_mut(), session))?;
                 *state = ProxyState::ShuttingDown(count);
             }
             ProxyState::ShuttingDown(count) => {
@@ -0,0 +1,477 @@
+use std::{
+    fmt,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use regex::Regex;
+use tracing::{trace, Level};
+
+use crate::{
+    bc::{Decoder, Frame},
+    capability::Capabilities,
+    catalog,
+    color::{self, Plain},
+    config::Config,
+    inventory, login,
+    middleware::{Chain, MiddlewareFactory},
+    party,
+    session::{Session, ToClient},
+    telnet::{self, Command, Segment, BCPROXY, ECHO, GA, IAC, NAWS, WILL, WONT},
+    throttle::Throttle,
+    timestamp::Stamper,
+    vitals,
+};
+
+use super::{merge::Merger, proxy::Filter, FrameHook};
+
+/// How much server output to look at before deciding it does not speak BC.
+const PROBE_BYTES: usize = 16 * 1024;
+/// Most bytes of a read logged along with a protocol error.
+const MAX_LOGGED: usize = 512;
+
+/// What to do when server output breaks the BC protocol: a closing tag for a
+/// code that is not open, or a code over the decoder limits. The output is
+/// passed on as text either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Warn about it and carry on.
+    #[default]
+    Permissive,
+    /// Log it as an error along with the output it was found in.
+    Strict,
+    /// As strict, then close the session.
+    Disconnect,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "permissive" => Ok(Strictness::Permissive),
+            "strict" => Ok(Strictness::Strict),
+            "strict-disconnect" => Ok(Strictness::Disconnect),
+            _ => Err(format!(
+                "invalid protocol mode `{}`, expected permissive, strict or strict-disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strictness::Permissive => "permissive",
+            Strictness::Strict => "strict",
+            Strictness::Disconnect => "strict-disconnect",
+        })
+    }
+}
+
+/// Decodes server output into frames and re-encodes them for the client.
+pub(super) struct ServerOutput {
+    telnet: telnet::Parser,
+    decoder: Decoder,
+    // Bytes left to see before the probe for control codes ends.
+    probe_left: usize,
+    // The BC mode level asked for, none if off.
+    bc_mode: Option<u8>,
+    // The server does not speak BC, pass its output on untouched.
+    passthrough: bool,
+    // The server is another bcproxy, its output is transformed already.
+    chained: bool,
+    chain: Chain,
+    merger: Merger,
+    plain: Plain,
+    stamper: Stamper,
+    frames: Vec<Frame>,
+    walk_abort: Vec<Regex>,
+    // Only text was written since the last read.
+    text_only: bool,
+    log_frames: bool,
+    throttle: Throttle,
+    strictness: Strictness,
+    echo_negotiation: bool,
+}
+
+impl ServerOutput {
+    pub(super) fn new(
+        config: &Config,
+        hooks: Vec<FrameHook>,
+        middleware: &[MiddlewareFactory],
+    ) -> Self {
+        Self {
+            telnet: telnet::Parser::new(),
+            decoder: Decoder::with_limits(config.decoder),
+            probe_left: PROBE_BYTES,
+            bc_mode: config.bc_mode,
+            passthrough: config.bc_mode.is_none(),
+            chained: false,
+            chain: Chain::new(config, hooks, middleware),
+            merger: Merger::new(config.merge.clone()),
+            plain: Plain::new(),
+            stamper: Stamper::new(config.timestamp_format.clone(), config.timestamp_elapsed),
+            frames: Vec::new(),
+            walk_abort: config.walk_abort.clone(),
+            text_only: true,
+            log_frames: config.log.frames,
+            throttle: Throttle::new(config.throttles.clone()),
+            strictness: config.protocol,
+            echo_negotiation: config.echo_negotiation,
+        }
+    }
+
+    /// Start over on a new server connection. A code cut off by the old
+    /// one is dropped, and the client echoes again if the old one echoed
+    /// for it.
+    pub(super) fn reconnected(&mut self, session: &mut Session) {
+        self.telnet = telnet::Parser::new();
+        self.decoder.finish(&mut Vec::new());
+        self.probe_left = PROBE_BYTES;
+        self.passthrough = self.bc_mode.is_none();
+        self.chained = false;
+        session.server_options.clear();
+        if std::mem::take(&mut session.server_echo)
+            && self.echo_negotiation
+            && session.output_style.style().is_terminal()
+        {
+            session.write_client(vec![IAC, WONT, ECHO]);
+        }
+    }
+
+    /// Take the triggers and highlights of a reloaded config.
+    pub(super) fn reload(&mut self, config: &Config) {
+        self.chain.reload(config);
+        self.strictness = config.protocol;
+        self.echo_negotiation = config.echo_negotiation;
+        // Taken on the next server connection.
+        self.bc_mode = config.bc_mode;
+        self.throttle.set_rules(config.throttles.clone());
+        self.stamper
+            .set_format(config.timestamp_format.clone(), config.timestamp_elapsed);
+    }
+
+    fn emit(&mut self, output: &mut Vec<u8>, session: &mut Session) {
+        let start = output.len();
+        session.stats.server.frames += self.frames.len() as u64;
+        for frame in &self.frames {
+            if matches!(frame, Frame::Prompt(_)) {
+                session.stats.prompt_received();
+                session.commands.prompt();
+                session.wake();
+            }
+            session.capabilities.observe(frame);
+            catalog::observe(frame, session);
+            login::observe(frame, session);
+            inventory::observe(frame, session);
+            party::observe(frame, session);
+            vitals::observe(frame, session);
+            for tap in session.taps.iter() {
+                tap.send(frame);
+            }
+        }
+        if !session.walk.is_empty() {
+            self.check_walk(session);
+        }
+        let frames = self.chain.run(std::mem::take(&mut self.frames), session);
+        self.write(frames, output, session);
+        self.stamp(output, start, session);
+        self.make_plain(output, start, session);
+    }
+
+    fn write(&mut self, frames: Vec<Frame>, output: &mut Vec<u8>, session: &mut Session) {
+        let style = session.output_style.style();
+        for frame in frames {
+            if let (Frame::Map(map), Some(port)) = (&frame, &session.map_port) {
+                port.send(map);
+                continue;
+            }
+            self.text_only &= matches!(frame, Frame::Text(_));
+            let prompt = matches!(frame, Frame::Prompt(_)) && style.is_terminal();
+            if prompt {
+                let mut bytes = Vec::new();
+                style.render(&frame, &mut bytes);
+                bytes.extend_from_slice(&[IAC, GA]);
+                session.prompt = Some(bytes);
+            }
+            self.merger.push(frame, style, output);
+            if prompt {
+                // Mark the end of the prompt for telnet clients.
+                self.merger.push(Frame::text(vec![IAC, GA]), style, output);
+            }
+        }
+    }
+
+    /// Stop the walk under way if the server says it cannot go on.
+    fn check_walk(&self, session: &mut Session) {
+        let mut text = Vec::new();
+        for frame in self
+            .frames
+            .iter()
+            .filter(|f| !matches!(f, Frame::Prompt(_)))
+        {
+            frame.push_text(&mut text);
+        }
+        let text = color::strip_ansi(&text);
+        let line = text
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .find(|line| self.walk_abort.iter().any(|re| re.is_match(line)));
+        if let Some(line) = line {
+            session.walk.clear();
+            session.notify(&format!("walk stopped: {}", line));
+        }
+    }
+
+    /// Report what the decoder passed on as text from `input`, the way
+    /// `protocol` says.
+    fn protocol_errors(&mut self, input: &[u8], session: &mut Session) {
+        let warnings = self.decoder.take_warnings();
+        if warnings.is_empty() {
+            return;
+        }
+        for warning in &warnings {
+            match self.strictness {
+                Strictness::Permissive => {
+                    tracing::warn!("{}", warning);
+                    session.notify(warning);
+                }
+                Strictness::Strict | Strictness::Disconnect => {
+                    let logged = &input[..input.len().min(MAX_LOGGED)];
+                    tracing::error!(
+                        bytes = input.len(),
+                        input = %logged.escape_ascii(),
+                        "protocol error: {}",
+                        warning
+                    );
+                    session.notify(&format!("protocol error: {}", warning));
+                }
+            }
+        }
+        if self.strictness == Strictness::Disconnect && session.protocol_error.is_none() {
+            session.protocol_error = Some(warnings[0].clone());
+        }
+    }
+
+    /// Start the lines written to `output` after `start` with a timestamp
+    /// if the client asked for it.
+    fn stamp(&mut self, output: &mut Vec<u8>, start: usize, session: &Session) {
+        if !session.timestamps || !session.output_style.style().is_terminal() {
+            self.stamper.reset();
+            return;
+        }
+        if output.len() == start {
+            return;
+        }
+        let written = output.split_off(start);
+        self.stamper.convert(&written, output);
+    }
+
+    /// Convert what was written to `output` after `start` to plain text if
+    /// the client asked for it.
+    fn make_plain(&mut self, output: &mut Vec<u8>, start: usize, session: &Session) {
+        if !session.plain || !session.output_style.style().is_terminal() || output.len() == start {
+            return;
+        }
+        let written = output.split_off(start);
+        self.plain.convert(&written, output);
+    }
+
+    /// Decode server output other than telnet commands and write it for
+    /// the client.
+    fn process_data(&mut self, input: &[u8], output: &mut Vec<u8>, session: &mut Session) {
+        if std::mem::take(&mut session.bc_requested) && self.passthrough && !self.chained {
+            self.passthrough = false;
+            self.probe_left = PROBE_BYTES;
+        }
+        if self.passthrough {
+            self.merger.release(output);
+            output.extend_from_slice(input);
+            return;
+        }
+
+        self.decoder.decode(input, &mut self.frames);
+        let now = Instant::now();
+        self.frames.retain(|frame| self.throttle.allow(frame, now));
+        if self.log_frames && tracing::enabled!(Level::TRACE) {
+            self.frames.iter().for_each(log_frame);
+        }
+        self.emit(output, session);
+        self.protocol_errors(input, session);
+
+        if self.probe_left > 0 {
+            self.probe_left = self.probe_left.saturating_sub(input.len());
+            if self.probe_left == 0 && !session.capabilities.contains(Capabilities::CONTROL_CODES) {
+                // Not a BC server, or it ignored BC mode. Stop decoding so
+                // stray escapes are not mistaken for codes, until BC mode
+                // is asked for again.
+                self.passthrough = true;
+                self.decoder.finish(&mut self.frames);
+                self.emit(output, session);
+                session.notify(
+                    "no control codes from the server, BC mode is off, passing its output through",
+                );
+            }
+        }
+    }
+
+    /// Pass the output of a bcproxy through from now on, after what was
+    /// decoded before it said it is one.
+    fn chained(&mut self, output: &mut Vec<u8>, session: &mut Session) {
+        self.chained = true;
+        self.passthrough = true;
+        self.decoder.finish(&mut self.frames);
+        self.emit(output, session);
+        session.notify("the server is a bcproxy, passing its output through");
+    }
+
+    /// Follow the server's ECHO option and pass a telnet command on to
+    /// terminal clients, after the output before it.
+    fn command(
+        &mut self,
+        command: &Command,
+        raw: Vec<u8>,
+        output: &mut Vec<u8>,
+        session: &mut Session,
+    ) {
+        if session.chain {
+            match command {
+                Command::Will(BCPROXY) => return self.chained(output, session),
+                Command::Wont(BCPROXY) => {
+                    session.notify("the server is not a bcproxy, decoding its output");
+                    return;
+                }
+                _ => {}
+            }
+        }
+        if let Command::Do(option) | Command::Dont(option) = command {
+            if !session.server_options.contains(option) {
+                session.server_options.push(*option);
+            }
+        }
+        // The client may have sent its size before, to the proxy or to an
+        // earlier server connection, and would not answer again.
+        if let (Command::Do(NAWS), Some((width, height))) = (command, session.client.window()) {
+            let mut reply = vec![IAC, WILL, NAWS];
+            reply.extend(telnet::naws(width, height));
+            session.write_server(reply);
+            return;
+        }
+        let echo = match command {
+            Command::Will(ECHO) => Some(true),
+            Command::Wont(ECHO) => Some(false),
+            _ => None,
+        };
+        if let Some(echo) = echo {
+            session.server_echo = echo;
+            if !self.echo_negotiation {
+                return;
+            }
+        }
+        // Telnet negotiation would get in the way of JSON output.
+        if session.output_style.style().is_terminal() {
+            let start = output.len();
+            self.merger.release(output);
+            self.stamp(output, start, session);
+            self.make_plain(output, start, session);
+            output.extend_from_slice(&raw);
+        }
+    }
+}
+
+impl Filter for ServerOutput {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>, session: &mut Session) {
+        trace!(bytes = input.len(), "read");
+        session.stats.server.read += input.len() as u64;
+        self.text_only = true;
+        let mut segments = Vec::new();
+        self.telnet.parse(input, &mut segments);
+        for segment in segments {
+            match segment {
+                Segment::Data(data) => self.process_data(&data, output, session),
+                Segment::Command(command, raw) => self.command(&command, raw, output, session),
+            }
+        }
+    }
+
+    fn poll_release(
+        &mut self,
+        cx: &mut Context<'_>,
+        output: &mut Vec<u8>,
+        session: &mut Session,
+    ) -> Poll<()> {
+        let start = output.len();
+        let style = session.output_style.style();
+        let mut released = !session.to_client.is_empty();
+        let mut lines = false;
+        for message in session.to_client.drain(..) {
+            match message {
+                ToClient::Message(message) => {
+                    lines = true;
+                    style.message(&message, output);
+                }
+                ToClient::Raw(bytes) => {
+                    lines |= bytes.ends_with(b"\n");
+                    output.extend_from_slice(&bytes);
+                }
+            }
+        }
+
+        let mut frames = Vec::new();
+        if self.chain.poll(cx, &mut frames, session).is_ready() {
+            released = true;
+            lines = true;
+            self.write(frames, output, session);
+        }
+        // Keep the prompt on the last line for clients that redraw it.
+        let redraw = lines && style.is_terminal();
+        if let Some(prompt) = session.prompt.as_ref().filter(|_| redraw) {
+            output.extend_from_slice(prompt);
+        }
+        released |= self.merger.poll_expire(cx, output).is_ready();
+        self.stamp(output, start, session);
+        self.make_plain(output, start, session);
+
+        if released {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn droppable(&self) -> bool {
+        self.text_only
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>, session: &mut Session) {
+        self.decoder.finish(&mut self.frames);
+        self.emit(output, session);
+        let start = output.len();
+        let frames = self.chain.finish(session);
+        self.write(frames, output, session);
+        self.merger.release(output);
+        self.stamp(output, start, session);
+        self.make_plain(output, start, session);
+        // A telnet command cut off goes where a whole one would.
+        let raw = self.telnet.finish();
+        if session.output_style.style().is_terminal() {
+            output.extend_from_slice(&raw);
+        }
+    }
+}
+
+/// Log a decoded frame with its code and size.
+fn log_frame(frame: &Frame) {
+    let mut bytes = Vec::new();
+    frame.encode(&mut bytes);
+    trace!(
+        code = frame.code().map(|code| code.id),
+        prompt = matches!(frame, Frame::Prompt(_)),
+        bytes = bytes.len(),
+        frame = %bytes.escape_ascii(),
+        "frame"
+    );
+}
@@ -1,66 +1,201 @@
 use std::{
+    collections::VecDeque,
+    fmt,
     pin::Pin,
+    str::FromStr,
     task::{ready, Context, Poll},
 };
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::session::{QueueStats, Session};
+
 const DEFAULT_BUF_SIZE: usize = 8 * 1024;
 
-pub(super) struct ProxyBuffer {
+/// What to do when the client takes output slower than the server sends it
+/// and the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowClient {
+    /// Stop reading from the server until the client catches up.
+    #[default]
+    Block,
+    /// Drop the oldest queued output that is only text, never control codes.
+    DropText,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl FromStr for SlowClient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(SlowClient::Block),
+            "drop" => Ok(SlowClient::DropText),
+            "disconnect" => Ok(SlowClient::Disconnect),
+            _ => Err(format!(
+                "invalid slow client policy `{}`, expected block, drop or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SlowClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SlowClient::Block => "block",
+            SlowClient::DropText => "drop",
+            SlowClient::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// How much output may wait for a slow writer, in bytes.
+#[derive(Debug, Clone)]
+pub struct OutputQueue {
+    pub limit: usize,
+    pub slow_client: SlowClient,
+}
+
+impl Default for OutputQueue {
+    fn default() -> Self {
+        Self {
+            limit: 256 * 1024,
+            slow_client: SlowClient::Block,
+        }
+    }
+}
+
+/// Rewrites the bytes flowing through a [`ProxyBuffer`].
+pub(super) trait Filter {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>, session: &mut Session);
+
+    /// Release output the filter is holding back, or output it wants to
+    /// write on its own, once it is due.
+    fn poll_release(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _output: &mut Vec<u8>,
+        _session: &mut Session,
+    ) -> Poll<()> {
+        Poll::Pending
+    }
+
+    /// Called once the reader has reached EOF. Whatever the filter still
+    /// holds back is written out as received: unterminated control codes and
+    /// partial lines are passed on as they are, nothing is added to them.
+    fn finish(&mut self, _output: &mut Vec<u8>, _session: &mut Session) {}
+
+    /// Whether the output of the last `process` call is only text, which a
+    /// writer that falls behind can do without.
+    fn droppable(&self) -> bool {
+        false
+    }
+}
+
+pub(super) struct ProxyBuffer<F> {
     read_done: bool,
     need_flush: bool,
     pos: usize,
-    cap: usize,
     amt: u64,
     buf: Box<[u8]>,
+    out: Vec<u8>,
+    // Lengths of the parts of `out` not yet written, each from one read or
+    // release, and whether they may be dropped.
+    units: VecDeque<(usize, bool)>,
+    queue: OutputQueue,
+    stats: QueueStats,
+    // Bytes dropped since the writer last caught up.
+    dropped: u64,
+    filter: F,
 }
 
-impl ProxyBuffer {
-    pub(super) fn new() -> Self {
+impl<F: Filter> ProxyBuffer<F> {
+    pub(super) fn new(filter: F) -> Self {
+        Self::with_queue(
+            filter,
+            OutputQueue {
+                limit: DEFAULT_BUF_SIZE,
+                slow_client: SlowClient::Block,
+            },
+        )
+    }
+
+    pub(super) fn with_queue(filter: F, queue: OutputQueue) -> Self {
         Self {
             read_done: false,
             need_flush: false,
             pos: 0,
-            cap: 0,
             amt: 0,
             buf: vec![0; DEFAULT_BUF_SIZE].into_boxed_slice(),
+            out: Vec::with_capacity(DEFAULT_BUF_SIZE),
+            units: VecDeque::new(),
+            queue,
+            stats: QueueStats::default(),
+            dropped: 0,
+            filter,
         }
     }
 
+    /// The output waiting for the writer now, at most and dropped so far.
+    pub(super) fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.out.len() - self.pos,
+            ..self.stats
+        }
+    }
+
+    /// Bytes written so far.
+    pub(super) fn written(&self) -> u64 {
+        self.amt
+    }
+
+    pub(super) fn filter_mut(&mut self) -> &mut F {
+        &mut self.filter
+    }
+
     pub(super) fn poll_copy<R, W>(
         &mut self,
         cx: &mut Context<'_>,
         mut reader: Pin<&mut R>,
         mut writer: Pin<&mut W>,
+        session: &mut Session,
     ) -> Poll<std::io::Result<u64>>
     where
         R: AsyncRead + ?Sized,
         W: AsyncWrite + ?Sized,
     {
         loop {
-            // If our buffer is empty, then we need to read some data to
+            // If our output is empty, then we need to read some data to
             // continue.
-            if self.pos == self.cap && !self.read_done {
+            if self.pos == self.out.len() && !self.read_done {
                 self.pos = 0;
-                self.cap = 0;
+                self.out.clear();
+                self.units.clear();
 
-                match self.poll_fill_buf(cx, reader.as_mut()) {
+                match self.poll_fill_buf(cx, reader.as_mut(), session) {
                     Poll::Ready(Ok(())) => {}
                     Poll::Pending => {
-                        if self.need_flush {
-                            ready!(writer.as_mut().poll_flush(cx))?;
-                            self.need_flush = false;
+                        // Nothing new to read, but the filter may have output
+                        // it was holding back that is due now.
+                        let released = self.filter.poll_release(cx, &mut self.out, session);
+                        self.push_unit(self.out.len(), false);
+                        if released.is_pending() {
+                            if self.need_flush {
+                                ready!(writer.as_mut().poll_flush(cx))?;
+                                self.need_flush = false;
+                            }
+                            return Poll::Pending;
                         }
-                        return Poll::Pending;
                     }
                     Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                 }
             }
 
-            // If our buffer has some data, let's write it out!
-            while self.pos < self.cap {
-                let i = ready!(self.poll_write_buf(cx, reader.as_mut(), writer.as_mut()))?;
+            // If our output has some data, let's write it out!
+            while self.pos < self.out.len() {
+                let i = ready!(self.poll_write_buf(cx, reader.as_mut(), writer.as_mut(), session))?;
                 if i == 0 {
                     return Poll::Ready(Err(std::io::Error::new(
                         std::io::ErrorKind::WriteZero,
@@ -70,64 +205,155 @@ impl ProxyBuffer {
                     self.pos += i;
                     self.amt += i as u64;
                     self.need_flush = true;
+                    self.consume(i);
                 }
             }
 
-            // If pos larger than cap, this loop will never stop.
+            if self.dropped > 0 {
+                session.notify(&format!(
+                    "the client fell behind, {} bytes of server output were dropped",
+                    std::mem::take(&mut self.dropped)
+                ));
+            }
+
+            // If pos larger than the output, this loop will never stop.
             // In particular, user's wrong poll_write implementation returning
             // incorrect written length may lead to thread blocking.
             debug_assert!(
-                self.pos <= self.cap,
+                self.pos <= self.out.len(),
                 "writer returned length larger than input slice"
             );
 
             // If we've written all the data and we've seen EOF, flush out the
             // data and finish the transfer.
-            if self.pos == self.cap && self.read_done {
+            if self.pos == self.out.len() && self.read_done {
                 ready!(writer.as_mut().poll_flush(cx))?;
                 return Poll::Ready(Ok(self.amt));
             }
         }
     }
 
+    /// Read once from `reader` and append the filtered bytes to the output.
     fn poll_fill_buf<R>(
         &mut self,
         cx: &mut Context<'_>,
         reader: Pin<&mut R>,
+        session: &mut Session,
     ) -> Poll<std::io::Result<()>>
     where
         R: AsyncRead + ?Sized,
     {
         let me = &mut *self;
         let mut buf = tokio::io::ReadBuf::new(&mut me.buf);
-        buf.set_filled(me.cap);
 
         let res = reader.poll_read(cx, &mut buf);
         if let Poll::Ready(Ok(())) = res {
-            let filled_len = buf.filled().len();
-            me.read_done = me.cap == filled_len;
-            me.cap = filled_len;
+            let start = me.out.len();
+            let filled = buf.filled();
+            if filled.is_empty() {
+                me.read_done = true;
+                me.filter.finish(&mut me.out, session);
+                me.push_unit(me.out.len() - start, false);
+            } else {
+                me.filter.process(filled, &mut me.out, session);
+                let droppable = me.filter.droppable();
+                me.push_unit(me.out.len() - start, droppable);
+            }
         }
         res
     }
 
+    fn push_unit(&mut self, len: usize, droppable: bool) {
+        if len > 0 {
+            self.units.push_back((len, droppable));
+        }
+        self.stats.peak = self.stats.peak.max(self.out.len() - self.pos);
+    }
+
+    /// Take `written` bytes off the front of the units.
+    fn consume(&mut self, mut written: usize) {
+        while let Some((len, droppable)) = self.units.front_mut() {
+            if *len > written {
+                *len -= written;
+                // Half of it is out, the rest must follow.
+                *droppable = false;
+                return;
+            }
+            written -= *len;
+            self.units.pop_front();
+        }
+    }
+
+    /// Whether more may be read while the writer is not taking output,
+    /// making room as the queue's policy says if it is full.
+    fn make_room(&mut self) -> std::io::Result<bool> {
+        let depth = self.out.len() - self.pos;
+        if depth >= self.queue.limit {
+            match self.queue.slow_client {
+                SlowClient::Block => return Ok(false),
+                SlowClient::Disconnect => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("client too slow, {} bytes of output queued", depth),
+                    ))
+                }
+                SlowClient::DropText => {
+                    if !self.drop_text(depth + 1 - self.queue.limit) {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        // Make room at the front once half of it was written.
+        if self.pos > 0 && self.pos >= self.out.len() / 2 {
+            self.out.drain(..self.pos);
+            self.pos = 0;
+        }
+        Ok(true)
+    }
+
+    /// Drop the oldest droppable units, at least `excess` bytes if there
+    /// are enough. Returns false if there were none.
+    fn drop_text(&mut self, mut excess: usize) -> bool {
+        let mut start = self.pos;
+        let mut dropped = Vec::new();
+        self.units.retain(|&(len, droppable)| {
+            let keep = !droppable || excess == 0;
+            if !keep {
+                dropped.push(start..start + len);
+                excess = excess.saturating_sub(len);
+            }
+            start += len;
+            keep
+        });
+        // Later ranges first, so the earlier ones stay where they are.
+        for range in dropped.iter().rev() {
+            self.out.drain(range.clone());
+        }
+        let bytes: usize = dropped.iter().map(|range| range.len()).sum();
+        self.dropped += bytes as u64;
+        self.stats.dropped += bytes as u64;
+        bytes > 0
+    }
+
     fn poll_write_buf<R, W>(
         &mut self,
         cx: &mut Context<'_>,
         mut reader: Pin<&mut R>,
         mut writer: Pin<&mut W>,
+        session: &mut Session,
     ) -> Poll<std::io::Result<usize>>
     where
         R: AsyncRead + ?Sized,
         W: AsyncWrite + ?Sized,
     {
         let me = &mut *self;
-        match writer.as_mut().poll_write(cx, &me.buf[me.pos..me.cap]) {
+        match writer.as_mut().poll_write(cx, &me.out[me.pos..]) {
             Poll::Pending => {
-                // Top up the buffer towards full if we can read a bit more
-                // data - this should improve the chances of a large write
-                if !me.read_done && me.cap < me.buf.len() {
-                    ready!(me.poll_fill_buf(cx, reader.as_mut()))?;
+                // Keep reading into the queue while there is room, so the
+                // reader is not held up by a slow writer.
+                while !me.read_done && me.make_room()? {
+                    ready!(me.poll_fill_buf(cx, reader.as_mut(), session))?;
                 }
                 Poll::Pending
             }
@@ -135,3 +361,123 @@ impl ProxyBuffer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pass;
+
+    impl Filter for Pass {
+        fn process(&mut self, input: &[u8], output: &mut Vec<u8>, _session: &mut Session) {
+            output.extend_from_slice(input);
+        }
+    }
+
+    fn buffer(limit: usize, slow_client: SlowClient) -> ProxyBuffer<Pass> {
+        ProxyBuffer::with_queue(Pass, OutputQueue { limit, slow_client })
+    }
+
+    /// Queue `bytes` as the output of one read.
+    fn queue(buffer: &mut ProxyBuffer<Pass>, bytes: &[u8], droppable: bool) {
+        buffer.out.extend_from_slice(bytes);
+        buffer.push_unit(bytes.len(), droppable);
+    }
+
+    /// Write `len` bytes of the queue, as the writer would.
+    fn write(buffer: &mut ProxyBuffer<Pass>, len: usize) {
+        buffer.pos += len;
+        buffer.consume(len);
+    }
+
+    fn waiting(buffer: &ProxyBuffer<Pass>) -> &[u8] {
+        &buffer.out[buffer.pos..]
+    }
+
+    #[test]
+    fn a_full_queue_blocks_reading() {
+        let mut buffer = buffer(8, SlowClient::Block);
+        queue(&mut buffer, b"1234567", true);
+        assert!(buffer.make_room().unwrap());
+        queue(&mut buffer, b"8", true);
+        assert!(!buffer.make_room().unwrap());
+        // Nothing is dropped while blocked.
+        assert_eq!(waiting(&buffer), b"12345678");
+
+        write(&mut buffer, 4);
+        assert!(buffer.make_room().unwrap());
+        assert_eq!(waiting(&buffer), b"5678");
+        assert_eq!(buffer.queue_stats().dropped, 0);
+    }
+
+    #[test]
+    fn a_full_queue_disconnects() {
+        let mut buffer = buffer(8, SlowClient::Disconnect);
+        queue(&mut buffer, b"1234", true);
+        assert!(buffer.make_room().unwrap());
+        queue(&mut buffer, b"5678", true);
+        let e = buffer.make_room().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn the_oldest_text_is_dropped_and_codes_are_kept() {
+        let mut buffer = buffer(16, SlowClient::DropText);
+        queue(&mut buffer, b"aaaa", true);
+        queue(&mut buffer, b"\x1b<10x\x1b>10", false);
+        queue(&mut buffer, b"bbbb", true);
+        queue(&mut buffer, b"cccc", true);
+        assert!(buffer.make_room().unwrap());
+        // Just enough text to get below the limit, oldest first.
+        assert_eq!(waiting(&buffer), b"\x1b<10x\x1b>10cccc");
+        assert_eq!(buffer.queue_stats().dropped, 8);
+        assert_eq!(buffer.dropped, 8);
+
+        // The code is whole and the rest of the text follows it.
+        write(&mut buffer, 2);
+        assert_eq!(waiting(&buffer), b"10x\x1b>10cccc");
+        write(&mut buffer, 11);
+        assert!(buffer.units.is_empty());
+    }
+
+    #[test]
+    fn a_queue_of_codes_blocks_instead_of_dropping() {
+        let mut buffer = buffer(8, SlowClient::DropText);
+        queue(&mut buffer, b"\x1b<10x\x1b>10", false);
+        assert!(!buffer.make_room().unwrap());
+        assert_eq!(waiting(&buffer), b"\x1b<10x\x1b>10");
+        assert_eq!(buffer.queue_stats().dropped, 0);
+    }
+
+    #[test]
+    fn text_partly_written_is_not_dropped() {
+        let mut buffer = buffer(8, SlowClient::DropText);
+        queue(&mut buffer, b"aaaa", true);
+        queue(&mut buffer, b"bbbb", true);
+        queue(&mut buffer, b"cccc", true);
+        write(&mut buffer, 2);
+        assert!(buffer.make_room().unwrap());
+        assert_eq!(waiting(&buffer), b"aacccc");
+        assert_eq!(buffer.queue_stats().dropped, 4);
+    }
+
+    #[test]
+    fn writes_are_taken_off_the_units_across_their_ends() {
+        let mut buffer = buffer(64, SlowClient::DropText);
+        queue(&mut buffer, b"aaaa", true);
+        queue(&mut buffer, b"bbbb", true);
+        queue(&mut buffer, b"cc", false);
+        write(&mut buffer, 2);
+        assert_eq!(
+            Vec::from(buffer.units.clone()),
+            [(2, false), (4, true), (2, false)]
+        );
+        write(&mut buffer, 3);
+        assert_eq!(Vec::from(buffer.units.clone()), [(3, false), (2, false)]);
+        write(&mut buffer, 3);
+        assert_eq!(Vec::from(buffer.units.clone()), [(2, false)]);
+        write(&mut buffer, 2);
+        assert!(buffer.units.is_empty());
+        assert_eq!(waiting(&buffer), b"");
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, TcpStream},
+    time::{sleep, timeout, Sleep},
+};
+use tracing::debug;
+
+use super::watchdog::{Stall, StallTimer, Watchdog};
+
+/// Opens a new connection to the server.
+pub type Connect<S> =
+    Box<dyn FnMut() -> Pin<Box<dyn Future<Output = io::Result<S>> + Send>> + Send>;
+
+/// Connect to `remote`, a `host:port`, trying each address the host
+/// resolves to in turn and giving each `attempt_timeout`. The host is
+/// resolved again on every call, so a reconnect follows a changed record.
+pub async fn connect(remote: &str, attempt_timeout: Duration) -> io::Result<TcpStream> {
+    let mut last = None;
+    for addr in lookup_host(remote).await? {
+        let error = match timeout(attempt_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => e,
+            Err(_) => io::Error::new(io::ErrorKind::TimedOut, "connect timed out"),
+        };
+        debug!(%addr, "failed to connect: {}", error);
+        last = Some(error);
+    }
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolves to no address", remote),
+        )
+    }))
+}
+
+/// Reconnect to the server when it drops the connection, waiting twice as
+/// long after each failed attempt up to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub enabled: bool,
+    pub max_delay: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Wait before the first attempt, doubled after each failed one.
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+
+impl Reconnect {
+    /// The wait before the nth attempt.
+    fn delay(&self, attempt: u32) -> Duration {
+        FIRST_DELAY
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay)
+    }
+}
+
+/// What happened to the server connection, for the proxy to tell the
+/// client about.
+#[derive(Debug)]
+pub(super) enum UpstreamEvent {
+    Lost {
+        reason: String,
+        retry_in: Duration,
+    },
+    Failed {
+        error: String,
+        attempt: u32,
+        retry_in: Duration,
+    },
+    Reconnected,
+    /// Send the watchdog's probe to the server.
+    Probe(String),
+    /// The server has not answered for this long and is not reconnected.
+    Stalled(Duration),
+}
+
+enum State<S> {
+    Connected(S),
+    Waiting(Pin<Box<Sleep>>),
+    Connecting(Pin<Box<dyn Future<Output = io::Result<S>> + Send>>),
+}
+
+/// The server side of a proxied connection. While it may reconnect, the
+/// server closing the connection is not passed on as EOF: reads wait for
+/// the next connection and writes are dropped until then.
+pub(super) struct Upstream<S> {
+    state: State<S>,
+    connect: Option<Connect<S>>,
+    policy: Reconnect,
+    /// Whether losing the connection now is followed by a reconnect.
+    pub(super) retry: bool,
+    // The client is gone, the connection is being closed for good.
+    closing: bool,
+    // Attempts that failed since the connection was lost.
+    attempt: u32,
+    /// Input was dropped while the server was not connected.
+    pub(super) dropped_input: bool,
+    watch: StallTimer,
+    pub(super) events: VecDeque<UpstreamEvent>,
+}
+
+impl<S> Upstream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(super) fn new(
+        server: S,
+        connect: Option<Connect<S>>,
+        policy: Reconnect,
+        watchdog: Watchdog,
+    ) -> Self {
+        Self {
+            state: State::Connected(server),
+            connect,
+            policy,
+            retry: false,
+            closing: false,
+            attempt: 0,
+            dropped_input: false,
+            watch: StallTimer::new(watchdog),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn may_retry(&self) -> bool {
+        self.retry && !self.closing && self.policy.enabled && self.connect.is_some()
+    }
+
+    /// Probe a server that stopped answering, returning why the connection
+    /// is given up on if it is.
+    fn poll_watchdog(&mut self, cx: &mut Context<'_>) -> Option<String> {
+        while let Poll::Ready(stall) = self.watch.poll_stall(cx) {
+            match stall {
+                Stall::Probe => {
+                    let probe = self.watch.probe().to_string();
+                    self.events.push_back(UpstreamEvent::Probe(probe));
+                }
+                Stall::Stalled(silent) => {
+                    if self.may_retry() {
+                        return Some(format!(
+                            "the server did not answer for {}s",
+                            silent.as_secs()
+                        ));
+                    }
+                    self.events.push_back(UpstreamEvent::Stalled(silent));
+                }
+            }
+        }
+        None
+    }
+
+    fn lose(&mut self, reason: String) {
+        self.attempt = 0;
+        self.watch.heard();
+        let retry_in = self.policy.delay(1);
+        self.state = State::Waiting(Box::pin(sleep(retry_in)));
+        self.events
+            .push_back(UpstreamEvent::Lost { reason, retry_in });
+    }
+}
+
+impl<S> AsyncRead for Upstream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = self.get_mut();
+        loop {
+            if matches!(me.state, State::Connected(_)) && !me.closing {
+                if let Some(reason) = me.poll_watchdog(cx) {
+                    me.lose(reason);
+                    continue;
+                }
+            }
+            match &mut me.state {
+                State::Connected(server) => {
+                    let filled = buf.filled().len();
+                    let result = ready!(Pin::new(server).poll_read(cx, buf));
+                    let reason = match result {
+                        Ok(()) if buf.filled().len() == filled => {
+                            "the server closed the connection".to_string()
+                        }
+                        Err(ref e) => e.to_string(),
+                        Ok(()) => {
+                            me.watch.heard();
+                            return Poll::Ready(Ok(()));
+                        }
+                    };
+                    if !me.may_retry() {
+                        return Poll::Ready(result);
+                    }
+                    me.lose(reason);
+                }
+                // Reads end once the client is gone.
+                _ if me.closing => return Poll::Ready(Ok(())),
+                State::Waiting(delay) => {
+                    ready!(delay.as_mut().poll(cx));
+                    let connect = me.connect.as_mut().expect("waiting without a connector");
+                    me.state = State::Connecting(connect());
+                }
+                State::Connecting(connecting) => match ready!(connecting.as_mut().poll(cx)) {
+                    Ok(server) => {
+                        me.state = State::Connected(server);
+                        me.attempt = 0;
+                        me.watch.heard();
+                        me.events.push_back(UpstreamEvent::Reconnected);
+                    }
+                    Err(e) => {
+                        me.attempt += 1;
+                        let retry_in = me.policy.delay(me.attempt + 1);
+                        me.state = State::Waiting(Box::pin(sleep(retry_in)));
+                        me.events.push_back(UpstreamEvent::Failed {
+                            error: e.to_string(),
+                            attempt: me.attempt,
+                            retry_in,
+                        });
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for Upstream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        let retry = me.may_retry();
+        match &mut me.state {
+            State::Connected(server) => match ready!(Pin::new(server).poll_write(cx, buf)) {
+                Ok(n) => {
+                    // The read side polls the watchdog.
+                    if n > 0 && me.watch.sent() {
+                        cx.waker().wake_by_ref();
+                    }
+                    Poll::Ready(Ok(n))
+                }
+                // The read side notices the loss and reconnects.
+                Err(_) if retry => Poll::Ready(Ok(buf.len())),
+                result => Poll::Ready(result),
+            },
+            _ => {
+                me.dropped_input = true;
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let me = self.get_mut();
+        let retry = me.may_retry();
+        match &mut me.state {
+            State::Connected(server) => match ready!(Pin::new(server).poll_flush(cx)) {
+                Err(_) if retry => Poll::Ready(Ok(())),
+                result => Poll::Ready(result),
+            },
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let me = self.get_mut();
+        me.closing = true;
+        match &mut me.state {
+            State::Connected(server) => Pin::new(server).poll_shutdown(cx),
+            // Wake the read side to end it.
+            _ => {
+                cx.waker().wake_by_ref();
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        time::{timeout, Instant},
+    };
+
+    use super::*;
+
+    /// An upstream that may reconnect, each connection being the next of
+    /// `connections`, failing once they run out.
+    fn upstream(
+        server: DuplexStream,
+        connections: Vec<DuplexStream>,
+        max_delay: Duration,
+    ) -> Upstream<DuplexStream> {
+        let connections = Arc::new(Mutex::new(VecDeque::from(connections)));
+        let connect: Connect<DuplexStream> = Box::new(move || {
+            let next = connections.lock().unwrap().pop_front();
+            Box::pin(async move { next.ok_or_else(|| io::Error::other("refused")) })
+        });
+        let policy = Reconnect {
+            enabled: true,
+            max_delay,
+        };
+        let mut upstream = Upstream::new(server, Some(connect), policy, Watchdog::default());
+        upstream.retry = true;
+        upstream
+    }
+
+    async fn read(upstream: &mut Upstream<DuplexStream>) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let n = upstream.read(&mut buf).await.unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dropped_server_is_reconnected_to_while_the_client_waits() {
+        let (server, mut first) = duplex(64);
+        let (reconnected, mut second) = duplex(64);
+        let mut upstream = upstream(server, vec![reconnected], Duration::from_secs(60));
+        first.write_all(b"one").await.unwrap();
+        assert_eq!(read(&mut upstream).await, b"one");
+
+        drop(first);
+        second.write_all(b"two").await.unwrap();
+        let lost = Instant::now();
+        // No EOF, the read goes on with the next connection.
+        assert_eq!(read(&mut upstream).await, b"two");
+        assert!(lost.elapsed() >= FIRST_DELAY);
+        assert!(matches!(
+            upstream.events.pop_front(),
+            Some(UpstreamEvent::Lost { reason, retry_in })
+                if reason == "the server closed the connection" && retry_in == FIRST_DELAY
+        ));
+        assert!(matches!(
+            upstream.events.pop_front(),
+            Some(UpstreamEvent::Reconnected)
+        ));
+
+        upstream.write_all(b"look\n").await.unwrap();
+        let mut received = [0; 5];
+        second.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"look\n");
+        assert!(!upstream.dropped_input);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_attempts_wait_longer_up_to_the_cap() {
+        let (server, first) = duplex(64);
+        let mut upstream = upstream(server, vec![], Duration::from_secs(5));
+        drop(first);
+        // Reads wait for a connection that never comes.
+        assert!(timeout(Duration::from_secs(30), read(&mut upstream))
+            .await
+            .is_err());
+
+        let waits: Vec<(u32, u64)> = upstream
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                UpstreamEvent::Failed {
+                    attempt, retry_in, ..
+                } => Some((*attempt, retry_in.as_secs())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(&waits[..5], [(1, 2), (2, 4), (3, 5), (4, 5), (5, 5)]);
+
+        // Writes while disconnected are dropped, not errors.
+        upstream.write_all(b"look\n").await.unwrap();
+        assert!(upstream.dropped_input);
+    }
+
+    #[test]
+    fn the_delay_doubles_without_overflowing() {
+        let policy = Reconnect {
+            enabled: true,
+            max_delay: Duration::MAX,
+        };
+        assert_eq!(policy.delay(1), FIRST_DELAY);
+        assert_eq!(policy.delay(4), FIRST_DELAY * 8);
+        assert_eq!(policy.delay(u32::MAX), FIRST_DELAY * (1 << 16));
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::time::{sleep, Sleep};
+
+use crate::session::Session;
+
+/// Sends the steps of the walk queued in the session to the server one at a
+/// time, the session's walk delay apart.
+#[derive(Default)]
+pub(super) struct Walker {
+    next: Option<Pin<Box<Sleep>>>,
+}
+
+impl Walker {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves with the next step once it is due.
+    pub(super) fn poll_step(
+        &mut self,
+        cx: &mut Context<'_>,
+        session: &mut Session,
+    ) -> Poll<String> {
+        if session.walk.is_empty() {
+            self.next = None;
+            return Poll::Pending;
+        }
+        if let Some(next) = self.next.as_mut() {
+            ready!(next.as_mut().poll(cx));
+        }
+
+        let step = session.walk.pop_front().unwrap_or_default();
+        self.next = None;
+        if !session.walk.is_empty() {
+            let mut next = Box::pin(sleep(session.walk_delay));
+            // Register for the wakeup, or come back right away if it is due.
+            if next.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+            self.next = Some(next);
+        }
+        Poll::Ready(step)
+    }
+}
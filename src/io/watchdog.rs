@@ -0,0 +1,90 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Notice a server that stopped answering: when nothing comes from it for
+/// `timeout` after the client sent something, send `probe` and wait as long
+/// again, then reconnect or tell the client. A zero `timeout` turns it off,
+/// an empty `probe` skips the probe.
+#[derive(Debug, Clone, Default)]
+pub struct Watchdog {
+    pub timeout: Duration,
+    pub probe: String,
+}
+
+/// What the watchdog found.
+pub(super) enum Stall {
+    /// Send the probe and wait for an answer.
+    Probe,
+    /// The server has not answered for this long.
+    Stalled(Duration),
+}
+
+pub(super) struct StallTimer {
+    config: Watchdog,
+    deadline: Option<Pin<Box<Sleep>>>,
+    // When the server was first left without an answer.
+    since: Instant,
+    probed: bool,
+    // The client was told about this stall already.
+    reported: bool,
+}
+
+impl StallTimer {
+    pub(super) fn new(config: Watchdog) -> Self {
+        Self {
+            config,
+            deadline: None,
+            since: Instant::now(),
+            probed: false,
+            reported: false,
+        }
+    }
+
+    /// Something was sent to the server, start waiting for an answer
+    /// unless already waiting. Returns whether it started, and the timer
+    /// needs polling.
+    pub(super) fn sent(&mut self) -> bool {
+        if self.config.timeout.is_zero() || self.deadline.is_some() || self.reported {
+            return false;
+        }
+        self.since = Instant::now();
+        self.deadline = Some(Box::pin(sleep(self.config.timeout)));
+        true
+    }
+
+    /// The server answered, or the connection is new.
+    pub(super) fn heard(&mut self) {
+        self.deadline = None;
+        self.probed = false;
+        self.reported = false;
+    }
+
+    /// Resolves once the server has not answered for too long.
+    pub(super) fn poll_stall(&mut self, cx: &mut Context<'_>) -> Poll<Stall> {
+        let deadline = match self.deadline.as_mut() {
+            Some(deadline) => deadline,
+            None => return Poll::Pending,
+        };
+        if deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if !self.probed && !self.config.probe.is_empty() {
+            self.probed = true;
+            self.deadline = Some(Box::pin(sleep(self.config.timeout)));
+            return Poll::Ready(Stall::Probe);
+        }
+        self.deadline = None;
+        self.reported = true;
+        Poll::Ready(Stall::Stalled(self.since.elapsed()))
+    }
+
+    pub(super) fn probe(&self) -> &str {
+        &self.config.probe
+    }
+}
@@ -0,0 +1,73 @@
+//! A proxy between a MUD client and BatMUD that understands the server's BC
+//! control codes. [`ProxyServer`] runs it, the other modules can be used on
+//! their own, e.g. [`bc::Decoder`] to decode server output.
+
+pub mod action;
+mod admin;
+pub mod alias;
+mod api;
+pub mod auth;
+pub mod battle;
+pub mod capability;
+pub mod catalog;
+pub mod chain;
+pub mod channel;
+pub mod check;
+pub mod clock;
+mod command;
+pub mod config;
+mod control;
+pub mod death;
+pub mod effect;
+pub mod exp;
+pub mod export;
+pub mod highlight;
+pub mod history;
+mod http;
+pub mod inventory;
+pub mod io;
+pub mod link;
+mod listener;
+pub mod logging;
+pub mod login;
+pub mod map;
+pub mod mapper;
+pub mod middleware;
+pub mod mirror;
+pub mod notifier;
+pub mod party;
+pub mod path;
+pub mod queue;
+pub mod safety;
+pub mod sales;
+pub mod script;
+mod server;
+pub mod session;
+pub mod speedwalk;
+pub mod splitlog;
+pub mod state;
+pub mod style;
+pub mod tap;
+pub mod target;
+pub mod throttle;
+pub mod tick;
+pub mod timestamp;
+pub mod translate;
+pub mod trigger;
+#[cfg(unix)]
+mod upgrade;
+pub mod vitals;
+mod webhook;
+mod websocket;
+
+pub use bcproxy_codec as bc;
+use bcproxy_protocol::wrap;
+pub use bcproxy_protocol::{color, telnet};
+pub use bcproxy_storage as db;
+
+pub use self::{
+    config::Config,
+    io::FrameHook,
+    middleware::Middleware,
+    server::{ProxyServer, ProxyServerBuilder},
+};
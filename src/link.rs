@@ -0,0 +1,169 @@
+//! Hyperlinks the server sends as code 30, `ESC<30url ESC|text ESC>30`.
+//! Terminals that support OSC 8 show them as links to click, other clients
+//! get them as `[text](url)`.
+//!
+//! In-game links, code 31 `ESC<31command ESC|text ESC>31`, are numbered
+//! in the order they arrive and shown underlined with their number, as in
+//! `north gate[2]`. `;;link 2` sends the command of the second one. The
+//! numbers start over when the player enters another room.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    bc::{ControlCode, Frame},
+    color::ColorMode,
+    session::Session,
+};
+
+pub const HYPERLINK: u8 = 30;
+pub const IN_GAME_LINK: u8 = 31;
+
+/// Most in-game links numbered before the numbers start over, for areas
+/// the mapper does not report rooms in.
+const MAX_GAME_LINKS: usize = 100;
+
+/// How hyperlinks are written to the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkStyle {
+    /// OSC 8 for clients shown true color, text for the rest.
+    #[default]
+    Auto,
+    /// OSC 8 escape sequences.
+    Osc8,
+    /// `[text](url)`.
+    Text,
+}
+
+impl FromStr for LinkStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(LinkStyle::Auto),
+            "osc8" => Ok(LinkStyle::Osc8),
+            "text" => Ok(LinkStyle::Text),
+            _ => Err(format!(
+                "invalid link style `{}`, expected auto, osc8 or text",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LinkStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LinkStyle::Auto => "auto",
+            LinkStyle::Osc8 => "osc8",
+            LinkStyle::Text => "text",
+        })
+    }
+}
+
+impl LinkStyle {
+    /// The style links are written in for `session`. Terminals with true
+    /// color are new enough to know OSC 8, and plain output has no escape
+    /// sequences at all.
+    pub fn resolve(self, session: &Session) -> LinkStyle {
+        match self {
+            _ if session.plain => LinkStyle::Text,
+            LinkStyle::Auto if session.color_mode == ColorMode::TrueColor => LinkStyle::Osc8,
+            LinkStyle::Auto => LinkStyle::Text,
+            style => style,
+        }
+    }
+}
+
+/// The commands of the in-game links of the room the player is in.
+#[derive(Debug, Default)]
+pub struct GameLinks {
+    room: Option<String>,
+    commands: Vec<String>,
+}
+
+impl GameLinks {
+    /// The command of link `n`, counting from 1.
+    pub fn get(&self, n: usize) -> Option<&str> {
+        n.checked_sub(1)
+            .and_then(|i| self.commands.get(i))
+            .map(String::as_str)
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    /// Number the link to `command` seen in `room`.
+    fn add(&mut self, room: Option<&str>, command: String) -> usize {
+        if self.room.as_deref() != room || self.commands.len() >= MAX_GAME_LINKS {
+            self.room = room.map(str::to_string);
+            self.commands.clear();
+        }
+        self.commands.push(command);
+        self.commands.len()
+    }
+}
+
+/// Replace the links in `frame` with text, hyperlinks in `style`, which
+/// must be resolved, writing the result to `out`. In-game links are added
+/// to `links` as seen in `room`. The text of a link keeps its codes, such
+/// as colors.
+pub fn render(
+    frame: Frame,
+    style: LinkStyle,
+    links: &mut GameLinks,
+    room: Option<&str>,
+    out: &mut Vec<Frame>,
+) {
+    let (code, kind): (_, fn(ControlCode) -> Frame) = match frame {
+        Frame::Code(code) => (code, Frame::Code),
+        Frame::Prompt(code) => (code, Frame::Prompt),
+        Frame::LoginResult(code) => (code, Frame::LoginResult),
+        text => return out.push(text),
+    };
+    if code.id == IN_GAME_LINK {
+        let command = match &code.attr {
+            Some(attr) => String::from_utf8_lossy(attr).into_owned(),
+            None => String::from_utf8_lossy(&code.text()).into_owned(),
+        };
+        let n = links.add(room, command);
+        out.push(Frame::text(&b"\x1b[4m"[..]));
+        for frame in code.body {
+            render(frame, style, links, room, out);
+        }
+        out.push(Frame::text(format!("\x1b[24m[{}]", n)));
+        return;
+    }
+    if code.id != HYPERLINK {
+        let mut body = Vec::with_capacity(code.body.len());
+        for frame in code.body {
+            render(frame, style, links, room, &mut body);
+        }
+        return out.push(kind(ControlCode::new(code.id, code.attr, body)));
+    }
+
+    // Without an attribute the text is the address.
+    let url = match &code.attr {
+        Some(attr) => attr.to_vec(),
+        None => code.text(),
+    };
+    // Control characters could end the escape sequence early.
+    let url: Vec<u8> = url.into_iter().filter(|b| !b.is_ascii_control()).collect();
+    let (open, close) = match style {
+        LinkStyle::Osc8 => (
+            [&b"\x1b]8;;"[..], &url, b"\x1b\\"].concat(),
+            b"\x1b]8;;\x1b\\".to_vec(),
+        ),
+        _ if code.attr.is_none() => (Vec::new(), Vec::new()),
+        _ => (b"[".to_vec(), [&b"]("[..], &url, b")"].concat()),
+    };
+    if !open.is_empty() {
+        out.push(Frame::text(open));
+    }
+    for frame in code.body {
+        render(frame, style, links, room, out);
+    }
+    if !close.is_empty() {
+        out.push(Frame::text(close));
+    }
+}
@@ -0,0 +1,160 @@
+//! Listening on a TCP address or, where there are Unix sockets, on a path.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// A connection accepted by a [`Listener`].
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// How long to wait before accepting again after a failed accept, such as
+/// when the proxy ran out of file descriptors, rather than failing again at
+/// once or giving up on the port.
+pub const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Whether `addr` is the path of a Unix socket rather than a TCP address.
+pub fn is_path(addr: &str) -> bool {
+    addr.contains('/')
+}
+
+/// Listen on `addr`, a Unix socket if it is a path.
+pub async fn bind(addr: &str) -> io::Result<Listener> {
+    if is_path(addr) {
+        #[cfg(unix)]
+        return bind_unix(std::path::Path::new(addr)).map(Listener::Unix);
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        ));
+    }
+    TcpListener::bind(addr).await.map(Listener::Tcp)
+}
+
+/// Refuse `listener`, listening on `addr` for `setting`, if other machines
+/// can reach it. For ports that trust whoever connects.
+pub fn local_only(setting: &str, addr: &str, listener: Listener) -> io::Result<Listener> {
+    if !listener.is_local()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} {} is not on the loopback interface", setting, addr),
+        ));
+    }
+    Ok(listener)
+}
+
+/// The socket is only open to its owner.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket left behind by a proxy that did not shut down cleanly.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket()
+            && std::os::unix::net::UnixStream::connect(path).is_err()
+        {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+impl Listener {
+    /// Whether only this machine can connect.
+    pub fn is_local(&self) -> io::Result<bool> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.ip().is_loopback()),
+            #[cfg(unix)]
+            Listener::Unix(_) => Ok(true),
+        }
+    }
+
+    /// The next connection and the address of its peer.
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), "unix".to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsFd for Listener {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
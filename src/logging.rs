@@ -0,0 +1,117 @@
+//! Logging through `tracing`, to stderr as text or JSON lines. Sessions log
+//! in a `session` span with their id, peer and profile, and the two
+//! directions of a session in `server` and `client` spans below it.
+//!
+//! The level is an `EnvFilter` directive such as `info` or
+//! `batproxy_rs=debug,warn`. It can be changed while running with
+//! `#bc log <level>` or `log <level>` on the admin console, or by editing
+//! `log_level` and sending the proxy SIGHUP.
+
+use std::{
+    fmt, io,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human readable line per event.
+    #[default]
+    Text,
+    /// One JSON object per event, with the fields of its spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format `{}`, expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// An `EnvFilter` directive.
+    pub level: String,
+    pub format: LogFormat,
+    /// Log every decoded frame of server output at trace level.
+    pub frames: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            frames: false,
+        }
+    }
+}
+
+/// Check that `level` is a valid filter.
+pub fn parse_level(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|e| format!("invalid log level `{}`: {}", level, e))
+}
+
+struct Logger {
+    filter: reload::Handle<EnvFilter, Registry>,
+    level: Mutex<String>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Install the global subscriber. Only the first call does anything.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let filter =
+        parse_level(&config.level).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let result = match config.format {
+        LogFormat::Text => registry.with(fmt).try_init(),
+        LogFormat::Json => registry.with(fmt.json()).try_init(),
+    };
+    if result.is_ok() {
+        let _ = LOGGER.set(Logger {
+            filter: handle,
+            level: Mutex::new(config.level.clone()),
+        });
+    }
+    Ok(())
+}
+
+/// The level logged at, if [`init`] was called.
+pub fn level() -> Option<String> {
+    LOGGER
+        .get()
+        .map(|logger| logger.level.lock().unwrap().clone())
+}
+
+/// Change the level logged at.
+pub fn set_level(level: &str) -> Result<(), String> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| "logging is not set up".to_string())?;
+    let filter = parse_level(level)?;
+    logger.filter.reload(filter).map_err(|e| e.to_string())?;
+    *logger.level.lock().unwrap() = level.to_string();
+    Ok(())
+}
@@ -0,0 +1,429 @@
+//! Logging in to BatMUD on the player's behalf when the server connection
+//! opens. The proxy turns on BC mode, sends the name and password, and once
+//! the server answers with code 05 `connection success` sends the commands
+//! to run after login. Code 06 means the login failed.
+//!
+//! Whoever logs in, the proxy or the player, each session keeps its
+//! [`LoginState`]: not logged in until code 05 arrives, and again after a
+//! reconnect. A code 06 is shown to the client with the reason read from it.
+//!
+//! The password is never kept in the config: it comes from an environment
+//! variable or from the output of a command, such as one reading it from
+//! the OS keyring.
+
+use std::{fmt, io};
+
+use tokio::process::Command;
+
+use crate::{
+    bc::{ControlCode, Frame},
+    session::Session,
+};
+
+pub use crate::bc::{LOGIN_FAILURE, LOGIN_SUCCESS};
+
+/// The line that turns on BC mode at `level`, the first thing the server
+/// must see.
+pub fn bc_mode(level: u8) -> String {
+    format!("\x1bbc {}", level)
+}
+
+/// Why the server refused a login, as told by the text of code 06.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginFailure {
+    WrongPassword,
+    UnknownCharacter,
+    Banned,
+    /// A reason the proxy does not recognize.
+    Other,
+}
+
+impl LoginFailure {
+    pub fn from_code(code: &ControlCode) -> Self {
+        let text = String::from_utf8_lossy(&code.text()).to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|word| text.contains(word));
+        if has(&["password"]) {
+            LoginFailure::WrongPassword
+        } else if has(&["no such", "does not exist", "not found", "unknown"]) {
+            LoginFailure::UnknownCharacter
+        } else if has(&["banned", "denied"]) {
+            LoginFailure::Banned
+        } else {
+            LoginFailure::Other
+        }
+    }
+
+    /// The name of the reason in JSON output and the APIs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoginFailure::WrongPassword => "wrong_password",
+            LoginFailure::UnknownCharacter => "unknown_character",
+            LoginFailure::Banned => "banned",
+            LoginFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for LoginFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LoginFailure::WrongPassword => "wrong password",
+            LoginFailure::UnknownCharacter => "no such character",
+            LoginFailure::Banned => "banned",
+            LoginFailure::Other => "refused by the server",
+        })
+    }
+}
+
+/// Whether the player of a session is logged in to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoginState {
+    #[default]
+    Unauthenticated,
+    Authenticated,
+    /// The last login was refused. The player is not logged in.
+    Failed(LoginFailure),
+}
+
+impl LoginState {
+    pub fn is_authenticated(&self) -> bool {
+        *self == LoginState::Authenticated
+    }
+
+    /// The state a login result leaves the session in.
+    pub fn from_result(code: &ControlCode) -> Self {
+        match code.id {
+            LOGIN_SUCCESS => LoginState::Authenticated,
+            _ => LoginState::Failed(LoginFailure::from_code(code)),
+        }
+    }
+
+    /// The name of the state in the APIs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoginState::Unauthenticated => "unauthenticated",
+            LoginState::Authenticated => "authenticated",
+            LoginState::Failed(_) => "failed",
+        }
+    }
+}
+
+impl fmt::Display for LoginState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginState::Unauthenticated => f.write_str("not logged in"),
+            LoginState::Authenticated => f.write_str("logged in"),
+            LoginState::Failed(reason) => write!(f, "login failed: {}", reason),
+        }
+    }
+}
+
+/// Where the password comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    /// The value of an environment variable.
+    Env(String),
+    /// The first line a shell command prints, e.g.
+    /// `secret-tool lookup service batmud`.
+    Command(String),
+}
+
+impl Secret {
+    pub async fn resolve(&self) -> io::Result<Password> {
+        match self {
+            Secret::Env(var) => std::env::var(var).map(Password).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("environment variable {} is not set", var),
+                )
+            }),
+            Secret::Command(command) => {
+                let output = Command::new("sh").arg("-c").arg(command).output().await?;
+                if !output.status.success() {
+                    return Err(io::Error::other(format!(
+                        "`{}` failed with {}",
+                        command, output.status
+                    )));
+                }
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(Password(stdout.lines().next().unwrap_or("").to_string()))
+            }
+        }
+    }
+}
+
+/// A password, left out of debug output.
+#[derive(Clone)]
+pub struct Password(String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+impl Password {
+    /// Whether `given` is this password, taking as long wherever they
+    /// differ.
+    pub fn matches(&self, given: &[u8]) -> bool {
+        let expected = self.0.as_bytes();
+        let differing = expected
+            .iter()
+            .zip(given)
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        differing == 0 && expected.len() == given.len()
+    }
+}
+
+/// Auto-login settings. Off while `name` is empty.
+#[derive(Debug, Clone, Default)]
+pub struct LoginConfig {
+    pub name: String,
+    pub password: Option<Secret>,
+    /// Sent one by one after the server accepts the login.
+    pub commands: Vec<String>,
+}
+
+impl LoginConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.name.is_empty()
+    }
+}
+
+/// An auto-login of a session, from sending the credentials until the
+/// server answers.
+#[derive(Debug, Clone)]
+pub struct Login {
+    name: String,
+    password: Password,
+    commands: Vec<String>,
+    /// The BC mode level asked for, none if off.
+    bc_mode: Option<u8>,
+    waiting: bool,
+}
+
+impl Login {
+    /// Get the password of `config`, which must be enabled. BC mode is
+    /// turned on at level `bc_mode` first, if set.
+    pub async fn new(config: &LoginConfig, bc_mode: Option<u8>) -> io::Result<Self> {
+        let password = match &config.password {
+            Some(secret) => secret.resolve().await?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "login_password_env or login_password_command is not set",
+                ))
+            }
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            password,
+            commands: config.commands.clone(),
+            bc_mode,
+            waiting: false,
+        })
+    }
+
+    /// The lines to send the server: BC mode, the name and the password.
+    pub fn start(&mut self) -> Vec<String> {
+        self.waiting = true;
+        self.bc_mode
+            .map(bc_mode)
+            .into_iter()
+            .chain([self.name.clone(), self.password.0.clone()])
+            .collect()
+    }
+}
+
+/// Log `session` in, if it has an auto-login.
+pub fn start(session: &mut Session) {
+    let lines = session.login.as_mut().map(Login::start).unwrap_or_default();
+    for line in lines {
+        session.bc_requested |= is_bc_mode(&line);
+        session.send_command(&line);
+    }
+}
+
+/// Move the login state of `session` on if `frame` is a login result. Once
+/// an auto-login worked the commands after it are sent, a refused login is
+/// shown to the client with its reason.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let state = match frame {
+        Frame::LoginResult(code) => LoginState::from_result(code),
+        _ => return,
+    };
+    session.set_login_state(state);
+    let login = session.login.as_mut().filter(|login| login.waiting);
+    let auto = login.map(|login| {
+        login.waiting = false;
+        (login.name.clone(), login.commands.clone())
+    });
+    match (state, auto) {
+        (LoginState::Authenticated, Some((_, commands))) => {
+            for command in commands {
+                session.send_command(&command);
+            }
+        }
+        (LoginState::Failed(reason), Some((name, _))) => {
+            tracing::warn!(name, %reason, "auto-login failed");
+            session.notify(&format!("auto-login as {} failed: {}", name, reason));
+        }
+        (LoginState::Failed(reason), None) => {
+            session.notify(&format!("login failed: {}", reason));
+        }
+        _ => {}
+    }
+}
+
+/// Whether a line only turns on BC mode, at any level.
+pub fn is_bc_mode(line: &str) -> bool {
+    line.trim()
+        .strip_prefix("\x1bbc ")
+        .is_some_and(|level| level.parse::<u8>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, session::ToClient};
+
+    fn result(id: u8, text: &'static str) -> Frame {
+        Frame::LoginResult(ControlCode::new(id, None::<&[u8]>, vec![Frame::text(text)]))
+    }
+
+    fn failure(text: &'static str) -> LoginFailure {
+        LoginFailure::from_code(&ControlCode::new(
+            LOGIN_FAILURE,
+            None::<&[u8]>,
+            vec![Frame::text(text)],
+        ))
+    }
+
+    fn sent(session: &mut Session) -> Vec<String> {
+        session
+            .to_server
+            .drain(..)
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect()
+    }
+
+    fn messages(session: &mut Session) -> Vec<String> {
+        session
+            .to_client
+            .drain(..)
+            .filter_map(|message| match message {
+                ToClient::Message(message) => Some(message),
+                ToClient::Raw(_) => None,
+            })
+            .collect()
+    }
+
+    fn login() -> Login {
+        Login {
+            name: "bob".to_string(),
+            password: Password("hunter2".to_string()),
+            commands: vec!["look".to_string(), "score".to_string()],
+            bc_mode: Some(1),
+            waiting: false,
+        }
+    }
+
+    #[test]
+    fn failures_are_read_from_what_the_server_says() {
+        assert_eq!(failure("Wrong PASSWORD."), LoginFailure::WrongPassword);
+        assert_eq!(failure("No such player."), LoginFailure::UnknownCharacter);
+        assert_eq!(
+            failure("That character does not exist"),
+            LoginFailure::UnknownCharacter
+        );
+        assert_eq!(failure("You are banned."), LoginFailure::Banned);
+        assert_eq!(failure("Access denied"), LoginFailure::Banned);
+        assert_eq!(failure(""), LoginFailure::Other);
+        assert_eq!(failure("").name(), "other");
+    }
+
+    #[test]
+    fn results_move_the_state() {
+        let code = |id| ControlCode::new(id, None::<&[u8]>, vec![Frame::text("bad password")]);
+        assert_eq!(
+            LoginState::from_result(&code(LOGIN_SUCCESS)),
+            LoginState::Authenticated
+        );
+        assert_eq!(
+            LoginState::from_result(&code(LOGIN_FAILURE)),
+            LoginState::Failed(LoginFailure::WrongPassword)
+        );
+        assert!(!LoginState::default().is_authenticated());
+    }
+
+    #[test]
+    fn bc_mode_lines_are_told_apart() {
+        assert!(is_bc_mode(&bc_mode(1)));
+        assert!(is_bc_mode(" \x1bbc 255\r\n"));
+        assert!(!is_bc_mode("\x1bbc on"));
+        assert!(!is_bc_mode("bc 1"));
+    }
+
+    #[test]
+    fn passwords_match_only_themselves() {
+        let password = Password("hunter2".to_string());
+        assert!(password.matches(b"hunter2"));
+        assert!(!password.matches(b"hunter"));
+        assert!(!password.matches(b"hunter22"));
+        assert!(!password.matches(b"Hunter2"));
+        assert_eq!(format!("{:?}", password), "Password(..)");
+    }
+
+    #[tokio::test]
+    async fn secrets_come_from_the_environment_or_a_command() {
+        let command = Secret::Command("echo hunter2; echo more".to_string());
+        assert!(command.resolve().await.unwrap().matches(b"hunter2"));
+        assert!(Secret::Command("exit 3".to_string())
+            .resolve()
+            .await
+            .is_err());
+        let unset = Secret::Env("BCPROXY_TEST_UNSET_PASSWORD".to_string());
+        assert_eq!(
+            unset.resolve().await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(Login::new(&LoginConfig::default(), None).await.is_err());
+    }
+
+    #[test]
+    fn an_auto_login_sends_its_commands_once_accepted() {
+        let mut session = Session::new(&Config::default(), None);
+        session.login = Some(login());
+        start(&mut session);
+        assert_eq!(sent(&mut session), ["\x1bbc 1\n", "bob\n", "hunter2\n"]);
+        assert!(session.bc_requested);
+
+        observe(&result(LOGIN_SUCCESS, ""), &mut session);
+        assert_eq!(session.login_state, LoginState::Authenticated);
+        assert_eq!(sent(&mut session), ["look\n", "score\n"]);
+        // Only the login the proxy made is followed by them.
+        observe(&result(LOGIN_SUCCESS, ""), &mut session);
+        assert!(sent(&mut session).is_empty());
+    }
+
+    #[test]
+    fn refused_logins_are_shown_with_the_reason() {
+        let mut session = Session::new(&Config::default(), None);
+        session.login = Some(login());
+        start(&mut session);
+        sent(&mut session);
+        observe(&result(LOGIN_FAILURE, "Wrong password."), &mut session);
+        assert_eq!(
+            messages(&mut session),
+            ["auto-login as bob failed: wrong password"]
+        );
+        assert!(sent(&mut session).is_empty());
+
+        observe(&result(LOGIN_FAILURE, "You are banned."), &mut session);
+        assert_eq!(messages(&mut session), ["login failed: banned"]);
+        assert_eq!(
+            session.login_state,
+            LoginState::Failed(LoginFailure::Banned)
+        );
+    }
+}
@@ -1,28 +1,120 @@
-use tokio::io::copy_bidirectional;
-use tokio::net::TcpStream;
+use std::path::{Path, PathBuf};
 
-mod io;
+use batproxy_rs::{check, config, logging, translate::TranslateConfig, Config, ProxyServer};
+
+mod init;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:7788").await?;
-
-    while let Ok((mut inbound, _)) = listener.accept().await {
-        let mut outbound = TcpStream::connect("batmud.bat.org:2023").await?;
-
-        tokio::spawn(async move {
-            let result = io::proxy_bidirection(&mut inbound, &mut outbound).await;
-            match result {
-                Err(e) => {
-                    eprintln!("failed to copy: {}", e);
-                }
-                Ok((x, y)) => {
-                    println!("{} bytes copied from a to b", x);
-                    println!("{} bytes copied from b to a", y);
-                }
+    let mut args = std::env::args().skip(1);
+    let mut config_path = PathBuf::from(config::DEFAULT_PATH);
+    let mut pipe = false;
+    let mut self_test = false;
+    let mut take_over = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "pipe" => pipe = true,
+            "--check" => self_test = true,
+            "--takeover" => take_over = true,
+            "init" => {
+                let path = args.next().map(PathBuf::from).unwrap_or(config_path);
+                return init::run(&path);
+            }
+            "--config" => {
+                config_path = args.next().map(PathBuf::from).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "--config needs a path")
+                })?;
             }
-        });
+            _ => {
+                eprintln!(
+                    "usage: batproxy-rs [--config <path>] [pipe | --check | --takeover] | init [<path>]"
+                );
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let config = Config::load(&config_path)?;
+    if self_test {
+        return run_check(&config_path, config).await;
+    }
+    logging::init(&config.log)?;
+    if pipe {
+        return run_pipe(config).await;
+    }
+    let server = ProxyServer::builder()
+        .config(config)
+        .config_path(config_path)
+        .build()?;
+    if take_over {
+        #[cfg(unix)]
+        server.take_over().await?;
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "--takeover needs Unix sockets",
+        ));
+    }
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(server.clone()));
+
+    tokio::select! {
+        result = server.clone().run() => {
+            // Handed over to a new proxy, which goes on with the sessions.
+            tokio::task::spawn_blocking(move || server.flush()).await?;
+            result
+        }
+        _ = tokio::signal::ctrl_c() => {
+            server.save_sessions().await;
+            // Writes still gathered for the database are not lost.
+            tokio::task::spawn_blocking(move || server.flush()).await?;
+            Ok(())
+        }
     }
+}
 
+/// Turn server output on stdin into client output on stdout, with the
+/// config's triggers, highlights and colors but no sockets or database.
+/// Webhooks and translations are left out, webhook actions of triggers
+/// still post.
+async fn run_pipe(mut config: Config) -> std::io::Result<()> {
+    config.database = None;
+    config.client_negotiation = false;
+    config.notifiers.clear();
+    config.translate = TranslateConfig::default();
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    batproxy_rs::io::pipe(&mut stdin, &mut stdout, &config, &[], &[]).await?;
+    Ok(())
+}
+
+/// Print the self-test of the config's servers and databases, exiting with
+/// 1 if any part failed.
+async fn run_check(path: &Path, config: Config) -> std::io::Result<()> {
+    println!("ok    config {}", path.display());
+    let checks = check::run(&config).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+    if checks.iter().any(|check| check.result.is_err()) {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// Reload the config file on each SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(server: ProxyServer) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => return tracing::warn!("cannot listen for SIGHUP: {}", e),
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(e) = server.reload() {
+            tracing::warn!("failed to reload the config: {}", e);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! The map BatMUD draws around the player: a `spec_map` message, usually
+//! right after a clear screen code, its tiles sent as many small color
+//! codes. The map layer gathers the two into one [`MapFrame`] that knows
+//! the size of the map, and draws it the way `map_render` says. With `#bc
+//! wrap` on, maps wider than the client's window are cut at its width.
+//!
+//! With `map_listen` set, maps go to the clients of that port instead,
+//! for a terminal of their own, and the client's screen is not cleared.
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use tokio::{io::AsyncWriteExt, net::TcpListener, sync::watch, time::sleep};
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    bc::{ControlCode, Frame, MapFrame},
+    color,
+    listener::ACCEPT_RETRY,
+    wrap,
+};
+
+pub const CLEAR_SCREEN: u8 = 11;
+pub const MAP_ATTR: &[u8] = b"spec_map";
+
+/// How maps are drawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MapRender {
+    /// As the server sent them.
+    #[default]
+    Full,
+    /// Without blank lines and trailing spaces.
+    Compact,
+    /// With column numbers above and row numbers on the left.
+    Coordinates,
+    /// Not at all, the clear screen before them included.
+    Off,
+}
+
+impl FromStr for MapRender {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(MapRender::Full),
+            "compact" => Ok(MapRender::Compact),
+            "coordinates" => Ok(MapRender::Coordinates),
+            "off" => Ok(MapRender::Off),
+            _ => Err(format!(
+                "invalid map rendering `{}`, expected full, compact, coordinates or off",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MapRender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MapRender::Full => "full",
+            MapRender::Compact => "compact",
+            MapRender::Coordinates => "coordinates",
+            MapRender::Off => "off",
+        })
+    }
+}
+
+/// Whether `code` is a `spec_map` message.
+pub fn is_map(code: &ControlCode) -> bool {
+    code.id == 10 && code.attr_is(MAP_ATTR)
+}
+
+/// Build the map frame of `code`, measuring the map.
+pub fn map_frame(clear: Option<ControlCode>, code: ControlCode) -> MapFrame {
+    let text = color::strip_ansi(&code.text());
+    let lines: Vec<&str> = text.lines().collect();
+    MapFrame {
+        rows: lines.len(),
+        cols: lines.iter().map(|line| line.width()).max().unwrap_or(0),
+        clear,
+        code,
+    }
+}
+
+/// Gathers a clear screen code and the `spec_map` message after it into a
+/// map frame. A clear screen is held until the next frame of the read.
+#[derive(Debug, Default)]
+pub struct Gatherer {
+    clear: Option<ControlCode>,
+}
+
+impl Gatherer {
+    /// Take `frame`, drawing maps as `render` says and no wider than
+    /// `width` columns if it is set.
+    pub fn push(
+        &mut self,
+        frame: Frame,
+        render: MapRender,
+        width: Option<usize>,
+        out: &mut Vec<Frame>,
+    ) {
+        match frame {
+            Frame::Code(code) if code.id == CLEAR_SCREEN => {
+                self.flush(out);
+                self.clear = Some(code);
+            }
+            Frame::Code(code) if is_map(&code) => {
+                let map = map_frame(self.clear.take(), code);
+                if let Some(map) = draw(map, render, width) {
+                    out.push(Frame::Map(map));
+                }
+            }
+            frame => {
+                self.flush(out);
+                out.push(frame);
+            }
+        }
+    }
+
+    /// Pass on a clear screen no map followed.
+    pub fn flush(&mut self, out: &mut Vec<Frame>) {
+        if let Some(clear) = self.clear.take() {
+            out.push(Frame::Code(clear));
+        }
+    }
+}
+
+/// Draw `map` as `render` says, `None` if it is not shown. Lines wider
+/// than `width` are cut, rather than wrapped over the map.
+pub fn draw(mut map: MapFrame, render: MapRender, width: Option<usize>) -> Option<MapFrame> {
+    let ending: &[u8] = if map.code.text().windows(2).any(|w| w == b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    let margin = match render {
+        MapRender::Coordinates => map.rows.saturating_sub(1).to_string().len() + 1,
+        _ => 0,
+    };
+    let width = width.filter(|&width| margin + map.cols > width);
+    let mut lines = match render {
+        MapRender::Full if width.is_none() => return Some(map),
+        MapRender::Off => return None,
+        MapRender::Full | MapRender::Compact | MapRender::Coordinates => {
+            split_lines(std::mem::take(&mut map.code.body))
+        }
+    };
+    // The line after the last line ending.
+    if lines.last().is_some_and(Vec::is_empty) {
+        lines.pop();
+    }
+
+    if render == MapRender::Compact {
+        for line in &mut lines {
+            trim_end(line);
+        }
+        lines.retain(|line| !line.is_empty());
+    } else if render == MapRender::Coordinates {
+        let margin = lines.len().saturating_sub(1).to_string().len();
+        let header: String = (0..map.cols)
+            .map(|col| char::from(b'0' + (col % 10) as u8))
+            .collect();
+        let mut numbered = vec![vec![Frame::text(format!(
+            "{:margin$} {}",
+            "",
+            header,
+            margin = margin
+        ))]];
+        for (row, mut line) in lines.into_iter().enumerate() {
+            line.insert(
+                0,
+                Frame::text(format!("{:>margin$} ", row, margin = margin)),
+            );
+            numbered.push(line);
+        }
+        lines = numbered;
+    }
+    if let Some(width) = width {
+        for line in &mut lines {
+            let mut cols = width;
+            cut(line, &mut cols);
+        }
+    }
+
+    for line in lines {
+        map.code.body.extend(line);
+        map.code.body.push(Frame::text(ending));
+    }
+    Some(map)
+}
+
+/// Split `frames` into lines, without their line endings. Codes spanning
+/// several lines are split into one per line.
+fn split_lines(frames: Vec<Frame>) -> Vec<Vec<Frame>> {
+    let mut lines = vec![Vec::new()];
+    for frame in frames {
+        split_frame(frame, &mut lines);
+    }
+    lines
+}
+
+fn split_frame(frame: Frame, lines: &mut Vec<Vec<Frame>>) {
+    match frame {
+        Frame::Text(text) => {
+            let mut start = 0;
+            for end in text
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == b'\n')
+                .map(|(i, _)| i)
+                .chain([text.len()])
+            {
+                if start > 0 {
+                    lines.push(Vec::new());
+                }
+                let mut part = text.slice(start..end);
+                if end < text.len() && part.ends_with(b"\r") {
+                    part.truncate(part.len() - 1);
+                }
+                if !part.is_empty() {
+                    lines.last_mut().unwrap().push(Frame::Text(part));
+                }
+                start = end + 1;
+            }
+        }
+        Frame::Code(code) => {
+            let inner = split_lines(code.body);
+            let single = inner.len() == 1;
+            for (i, body) in inner.into_iter().enumerate() {
+                if i > 0 {
+                    lines.push(Vec::new());
+                }
+                if single || !body.is_empty() {
+                    let code = ControlCode::new(code.id, code.attr.clone(), body);
+                    lines.last_mut().unwrap().push(Frame::Code(code));
+                }
+            }
+        }
+        frame => lines.last_mut().unwrap().push(frame),
+    }
+}
+
+/// Cut `line` to the `cols` columns left.
+fn cut(line: &mut [Frame], cols: &mut usize) {
+    for frame in line {
+        match frame {
+            Frame::Text(text) => *text = wrap::cut(text, cols).into(),
+            Frame::Code(code) => cut(&mut code.body, cols),
+            _ => {}
+        }
+    }
+}
+
+/// Drop the spaces at the end of `line`, and codes left empty by it.
+fn trim_end(line: &mut Vec<Frame>) {
+    while let Some(last) = line.last_mut() {
+        let empty = match last {
+            Frame::Text(text) => {
+                let kept = text.len() - text.iter().rev().take_while(|b| **b == b' ').count();
+                text.truncate(kept);
+                text.is_empty()
+            }
+            Frame::Code(code) => {
+                trim_end(&mut code.body);
+                code.body.is_empty()
+            }
+            _ => false,
+        };
+        if !empty {
+            return;
+        }
+        line.pop();
+    }
+}
+
+/// A TCP port clients connect to for the maps, each drawn on a cleared
+/// screen. Clients get the last map as soon as they connect.
+#[derive(Clone)]
+pub struct MapPort(Arc<watch::Sender<Arc<[u8]>>>);
+
+impl MapPort {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(Arc::from(&[][..]))))
+    }
+
+    /// Show `map`, rendered for a terminal, to the clients of the port.
+    pub fn send(&self, map: &MapFrame) {
+        let mut screen = b"\x1b[H\x1b[2J".to_vec();
+        screen.extend_from_slice(&map.code.text());
+        self.0.send_replace(screen.into());
+    }
+
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("map port: accepting a client failed: {}", e);
+                    sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+            let mut maps = self.0.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    let map = maps.borrow_and_update().clone();
+                    if !map.is_empty() && stream.write_all(&map).await.is_err() {
+                        break;
+                    }
+                    if maps.changed().await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl Default for MapPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bc::{ControlCode, Frame},
+    color,
+    db::{Event, Link},
+    map,
+    session::Session,
+};
+
+pub use crate::db::{Location, Room};
+
+/// The tag `BAT_MAPPER` payloads start and end with.
+pub const TAG: &[u8] = b"BAT_MAPPER";
+
+/// Written with serde as `{"type":"room",...}` with the fields of the room,
+/// or `{"type":"realm_map"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mapper {
+    Room(Room),
+    /// The player is on the outworld map.
+    RealmMap,
+}
+
+impl Mapper {
+    pub fn from_code(code: &ControlCode) -> Option<Self> {
+        if code.id != 99 {
+            return None;
+        }
+        Self::parse(&code.text())
+    }
+
+    /// The room of `payload`. The area and room id must be there, fields
+    /// missing after them are left empty. Extra fields in a payload that
+    /// has its closing tag are `;;` in the long description.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let payload = Payload::parse(payload)?;
+        let mut fields: Vec<String> = payload
+            .fields()
+            .iter()
+            .map(|f| String::from_utf8_lossy(f).into_owned())
+            .collect();
+        if fields.first().is_some_and(|area| area == "REALM_MAP") {
+            return Some(Mapper::RealmMap);
+        }
+        if payload.is_closed() && fields.len() > ROOM_FIELDS {
+            let extra = fields.len() - ROOM_FIELDS;
+            let long_desc: Vec<String> = fields.drain(LONG_DESC..=LONG_DESC + extra).collect();
+            fields.insert(LONG_DESC, long_desc.join(";;"));
+        }
+
+        let mut fields = fields.into_iter();
+        let area = fields.next().filter(|area| !area.is_empty())?;
+        let id = fields.next().filter(|id| !id.is_empty())?;
+        let mut next = || fields.next().unwrap_or_default();
+        Some(Mapper::Room(Room {
+            area,
+            id,
+            direction: next(),
+            indoors: next() == "1",
+            short_desc: next(),
+            long_desc: next(),
+            exits: next()
+                .split(',')
+                .filter(|exit| !exit.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }))
+    }
+}
+
+/// Fields of a room payload between the tags.
+const ROOM_FIELDS: usize = 7;
+/// Index of the long description among them.
+const LONG_DESC: usize = 5;
+
+/// The fields of a `BAT_MAPPER` payload, split on `;;` between the opening
+/// and the closing tag. `\;` stands for a `;` within a field. Anything after
+/// the closing tag is left out, and a payload cut off before it keeps the
+/// fields it has.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    fields: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+impl Payload {
+    /// The fields of `payload`, if it starts with the tag.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let mut fields = split(payload).into_iter();
+        if fields.next()? != TAG {
+            return None;
+        }
+        let mut closed = false;
+        let fields = fields
+            .take_while(|field| {
+                closed = field == TAG;
+                !closed
+            })
+            .collect();
+        Some(Self { fields, closed })
+    }
+
+    pub fn fields(&self) -> &[Vec<u8>] {
+        &self.fields
+    }
+
+    /// Whether the payload ended with the closing tag.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// The tiles of a `spec_map` message drawn around `location`. The map is
+/// centered on the player, whose marker hides the tile underneath. Blanks
+/// are left out as nothing is known there.
+pub fn map_tiles(map: &[u8], location: &Location) -> Vec<(i64, i64, char)> {
+    let text = color::strip_ansi(map);
+    let rows: Vec<Vec<char>> = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r').chars().collect())
+        .filter(|row: &Vec<char>| !row.is_empty())
+        .collect();
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let (center_x, center_y) = ((width / 2) as i64, (rows.len() / 2) as i64);
+
+    let mut tiles = Vec::new();
+    for (row, line) in rows.iter().enumerate() {
+        for (col, &tile) in line.iter().enumerate() {
+            let player = (col as i64, row as i64) == (center_x, center_y);
+            if !tile.is_whitespace() && !player {
+                tiles.push((
+                    location.x + col as i64 - center_x,
+                    location.y + row as i64 - center_y,
+                    tile,
+                ));
+            }
+        }
+    }
+    tiles
+}
+
+/// Split a mapper payload on `;;`, taking `\;` as a `;`.
+fn split(payload: &[u8]) -> Vec<Vec<u8>> {
+    let mut fields = vec![Vec::new()];
+    let mut i = 0;
+    while i < payload.len() {
+        match &payload[i..] {
+            [b'\\', b';', ..] => {
+                fields.last_mut().unwrap().push(b';');
+                i += 2;
+            }
+            [b';', b';', ..] => {
+                fields.push(Vec::new());
+                i += 2;
+            }
+            [b, ..] => {
+                fields.last_mut().unwrap().push(*b);
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    fields
+}
+
+/// Record rooms and the links between them as the player moves, and the
+/// outworld maps shown on the way. Outdoor rooms are stored with the
+/// player's location.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let code = match frame {
+        Frame::Code(code) => code,
+        Frame::Map(frame) => return observe_map(&frame.code, session),
+        Frame::Text(_) | Frame::Prompt(_) | Frame::LoginResult(_) => return,
+    };
+    if let Some(location) = Location::from_code(code) {
+        session.location = Some(location);
+        return;
+    }
+    if map::is_map(code) {
+        return observe_map(code, session);
+    }
+    let room = match Mapper::from_code(code) {
+        Some(Mapper::Room(room)) => room,
+        Some(Mapper::RealmMap) => {
+            session.last_room = None;
+            if let Some(listing) = &session.listing {
+                listing.update(|summary| summary.room = None);
+            }
+            return;
+        }
+        None => return,
+    };
+
+    let from = session.last_room.replace(room.clone());
+    if let Some(listing) = &session.listing {
+        listing.update(|summary| summary.room = Some(room.clone()));
+    }
+    if let Some(db) = &session.db {
+        let link = match from {
+            Some(from) if !room.direction.is_empty() => Some(Event::Link(Link {
+                from: from.id,
+                to: room.id.clone(),
+                direction: room.direction.clone(),
+            })),
+            _ => None,
+        };
+        let located = match (&session.location, room.indoors) {
+            (Some(location), false) => Some(Event::RoomLocation {
+                id: room.id.clone(),
+                location: location.clone(),
+            }),
+            _ => None,
+        };
+        db.send(Event::Room(room));
+        if let Some(link) = link {
+            db.send(link);
+        }
+        if let Some(located) = located {
+            db.send(located);
+        }
+    }
+}
+
+/// The warning to show before the room of `frame` if aggressive monsters
+/// were met there, once [`observe`] has taken the room.
+pub fn aggro_warning(frame: &Frame, session: &Session) -> Option<String> {
+    match frame {
+        Frame::Code(code) if matches!(Mapper::from_code(code), Some(Mapper::Room(_))) => {}
+        _ => return None,
+    }
+    let (room, db) = (session.last_room.as_ref()?, session.db.as_ref()?);
+    match db.aggro_in(&room.id, &room.area) {
+        Ok(names) if !names.is_empty() => Some(format!(
+            "[warning] known aggro here: {}\r\n",
+            names.join(", ")
+        )),
+        Ok(_) => None,
+        Err(e) => {
+            tracing::warn!("failed to look up aggressive monsters: {}", e);
+            None
+        }
+    }
+}
+
+fn observe_map(code: &ControlCode, session: &mut Session) {
+    // Maps inside rooms are drawn from the room's own data, not the realm.
+    if session.last_room.is_some() {
+        return;
+    }
+    let (Some(location), Some(db)) = (&session.location, &session.db) else {
+        return;
+    };
+    let tiles = map_tiles(&code.text(), location);
+    if !tiles.is_empty() {
+        db.send(Event::RealmMap {
+            realm: location.realm.clone(),
+            tiles,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(x: i64, y: i64) -> Location {
+        Location {
+            realm: "laenor".to_string(),
+            x,
+            y,
+            z: None,
+        }
+    }
+
+    #[test]
+    fn tiles_are_placed_around_the_player() {
+        let map = b"abc\r\nd@e\r\nfgh\r\n";
+        assert_eq!(
+            map_tiles(map, &location(10, 20)),
+            vec![
+                (9, 19, 'a'),
+                (10, 19, 'b'),
+                (11, 19, 'c'),
+                (9, 20, 'd'),
+                (11, 20, 'e'),
+                (9, 21, 'f'),
+                (10, 21, 'g'),
+                (11, 21, 'h'),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_widest_row_centers_the_map() {
+        let map = b"..\n.....\n...\n";
+        let tiles = map_tiles(map, &location(0, 0));
+        assert_eq!(tiles.first(), Some(&(-2, -1, '.')));
+        assert_eq!(tiles.last(), Some(&(0, 1, '.')));
+        assert!(!tiles.contains(&(0, 0, '.')));
+        assert_eq!(tiles.len(), 2 + 4 + 3);
+    }
+
+    #[test]
+    fn colors_and_blanks_are_not_tiles() {
+        let map = b"\x1b[32mf\x1b[0m f\nf@ \n\x1b[1;34m~~~\x1b[0m\n";
+        assert_eq!(
+            map_tiles(map, &location(5, 5)),
+            vec![
+                (4, 4, 'f'),
+                (6, 4, 'f'),
+                (4, 5, 'f'),
+                (4, 6, '~'),
+                (5, 6, '~'),
+                (6, 6, '~'),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_rows_are_no_tiles() {
+        assert_eq!(map_tiles(b"", &location(0, 0)), vec![]);
+        assert_eq!(map_tiles(b"\r\n\r\n", &location(0, 0)), vec![]);
+    }
+}
@@ -0,0 +1,752 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::time::{sleep_until, Sleep};
+
+use crate::{
+    action::{ActionEvent, Countdown},
+    battle::Battle,
+    bc::{ControlCode, Frame},
+    clock, color,
+    config::Config,
+    db::Event,
+    death::Deaths,
+    exp,
+    highlight::{self, Highlight},
+    io::FrameHook,
+    link, map, mapper,
+    safety::Safeguards,
+    sales,
+    script::{Outcome, Script},
+    session::Session,
+    splitlog::SplitLogs,
+    target::{self, BarStyle, Target},
+    translate::Translator,
+    trigger::Triggers,
+    vitals::Vitals,
+    wrap::Wrapper,
+};
+
+/// A layer of the server output pipeline. Each frame passes through the
+/// layers in order, every layer writing what it passes on to `out`.
+pub trait Middleware: Send {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session);
+
+    /// Pass on frames held back once the frames of a read have been seen.
+    fn flush(&mut self, _out: &mut Vec<Frame>, _session: &mut Session) {}
+
+    /// Pass on every frame still held back, as the server output ended.
+    fn finish(&mut self, out: &mut Vec<Frame>, session: &mut Session) {
+        self.flush(out, session);
+    }
+
+    /// Release frames the layer produces on its own once they are due. They
+    /// continue through the layers after this one.
+    fn poll_frames(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _out: &mut Vec<Frame>,
+        _session: &mut Session,
+    ) -> Poll<()> {
+        Poll::Pending
+    }
+
+    /// Take the settings of a reloaded config that can change while the
+    /// session runs.
+    fn reload(&mut self, _config: &Config) {}
+}
+
+/// Creates a fresh middleware for each session.
+pub type MiddlewareFactory = Arc<dyn Fn(&Config) -> Box<dyn Middleware> + Send + Sync>;
+
+/// The built-in layers, in the order they run by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    /// Writes server output to the split logs.
+    Logs,
+    /// Records rooms from `BAT_MAPPER` codes and where the player died,
+    /// and warns of aggressive monsters met in them.
+    Mapper,
+    /// Gathers `spec_map` messages into map frames and draws them.
+    Map,
+    /// Tallies fights from `spec_battle` messages and sums them up.
+    Battle,
+    /// Counts experience gained from code 53.
+    Exp,
+    /// The Lua script's hooks.
+    Script,
+    /// Frame hooks added through the library.
+    Hooks,
+    /// Posts the configured notifier webhooks.
+    Notify,
+    /// Mutes, redirects and colors channels as set with `#bc chan`.
+    Channels,
+    Triggers,
+    Translate,
+    Highlight,
+    /// Follows spells and skills under way and shows their countdown.
+    Actions,
+    /// Follows effects on the player and warns before they run out.
+    Effects,
+    /// Sends the safeguards' commands when the player's points run low.
+    Safety,
+    /// Learns the tick and shows the time to the next one.
+    Tick,
+    /// Shows the session clock with the prompt.
+    Clock,
+    /// Shows the player's target as a health bar.
+    Target,
+    /// Renders hyperlinks as the client wants them and numbers in-game
+    /// links.
+    Links,
+    /// Renders BC color codes for the client's color mode.
+    Color,
+    Wrap,
+}
+
+impl Layer {
+    pub const DEFAULT: &'static [Layer] = &[
+        Layer::Logs,
+        Layer::Mapper,
+        Layer::Map,
+        Layer::Battle,
+        Layer::Exp,
+        Layer::Script,
+        Layer::Hooks,
+        Layer::Notify,
+        Layer::Channels,
+        Layer::Triggers,
+        Layer::Translate,
+        Layer::Highlight,
+        Layer::Actions,
+        Layer::Effects,
+        Layer::Safety,
+        Layer::Tick,
+        Layer::Clock,
+        Layer::Target,
+        Layer::Links,
+        Layer::Color,
+        Layer::Wrap,
+    ];
+
+    const NAMES: &'static [(Layer, &'static str)] = &[
+        (Layer::Logs, "logs"),
+        (Layer::Mapper, "mapper"),
+        (Layer::Map, "map"),
+        (Layer::Battle, "battle"),
+        (Layer::Exp, "exp"),
+        (Layer::Script, "script"),
+        (Layer::Hooks, "hooks"),
+        (Layer::Notify, "notify"),
+        (Layer::Channels, "channels"),
+        (Layer::Triggers, "triggers"),
+        (Layer::Translate, "translate"),
+        (Layer::Highlight, "highlight"),
+        (Layer::Actions, "actions"),
+        (Layer::Effects, "effects"),
+        (Layer::Safety, "safety"),
+        (Layer::Tick, "tick"),
+        (Layer::Clock, "clock"),
+        (Layer::Target, "target"),
+        (Layer::Links, "links"),
+        (Layer::Color, "color"),
+        (Layer::Wrap, "wrap"),
+    ];
+}
+
+impl FromStr for Layer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(layer, _)| *layer)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::NAMES.iter().map(|(_, name)| *name).collect();
+                format!(
+                    "unknown layer `{}`, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find(|(layer, _)| layer == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default();
+        f.write_str(name)
+    }
+}
+
+/// The middleware of one session, built from the configured layers followed
+/// by those added through the library.
+pub struct Chain {
+    layers: Vec<Box<dyn Middleware>>,
+}
+
+impl Chain {
+    pub fn new(config: &Config, hooks: Vec<FrameHook>, extra: &[MiddlewareFactory]) -> Self {
+        let mut layers: Vec<Box<dyn Middleware>> = Vec::new();
+        for layer in &config.middleware {
+            match layer {
+                // Kept without the logs on, a reload may turn them on.
+                Layer::Logs => layers.push(Box::new(SplitLogs::new(config.split_log.clone()))),
+                Layer::Mapper => layers.push(Box::new(MapperLayer::default())),
+                Layer::Map => layers.push(Box::new(MapLayer::default())),
+                Layer::Battle if config.battle_summary => {
+                    layers.push(Box::new(BattleLayer(Battle::new())))
+                }
+                Layer::Battle => {}
+                Layer::Exp => layers.push(Box::new(ExpLayer)),
+                Layer::Script => {
+                    if let Some(path) = config.script.as_deref() {
+                        match Script::load(path) {
+                            Ok(script) => layers.push(Box::new(script)),
+                            Err(e) => tracing::warn!("failed to load {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                Layer::Hooks if !hooks.is_empty() => {
+                    layers.push(Box::new(HooksLayer(hooks.clone())))
+                }
+                Layer::Hooks => {}
+                Layer::Notify if !config.notifiers.is_empty() => layers.push(Box::new(NotifyLayer)),
+                Layer::Notify => {}
+                Layer::Channels => layers.push(Box::new(ChannelLayer)),
+                // Kept without triggers or highlights, a reload may add some.
+                Layer::Triggers => layers.push(Box::new(Triggers::new(
+                    config.triggers.clone(),
+                    config.trigger_cooldown,
+                ))),
+                Layer::Translate if config.translate.is_enabled() => {
+                    layers.push(Box::new(Translator::new(config.translate.clone())))
+                }
+                Layer::Translate => {}
+                Layer::Highlight => {
+                    layers.push(Box::new(HighlightLayer(config.highlights.clone())))
+                }
+                Layer::Actions => layers.push(Box::new(ActionLayer)),
+                Layer::Effects => layers.push(Box::new(EffectLayer {
+                    warning: config.effect_warning,
+                    bell: config.effect_bell,
+                })),
+                // Kept without safeguards, a reload may add some.
+                Layer::Safety => layers.push(Box::new(Safeguards::new(
+                    config.safeguards.clone(),
+                    config.safeguard_cooldown,
+                ))),
+                Layer::Tick => layers.push(Box::new(TickLayer {
+                    warning: config.tick_warning,
+                    timer: None,
+                })),
+                Layer::Clock => layers.push(Box::new(ClockLayer)),
+                Layer::Target => layers.push(Box::new(TargetLayer {
+                    style: config.target_bar,
+                    width: config.target_bar_width,
+                    kill_log: config.kill_log,
+                })),
+                Layer::Links => layers.push(Box::new(LinkLayer)),
+                Layer::Color => layers.push(Box::new(ColorLayer)),
+                Layer::Wrap => layers.push(Box::new(Wrapper::new())),
+            }
+        }
+        layers.extend(extra.iter().map(|factory| factory(config)));
+        Self { layers }
+    }
+
+    /// Pass a reloaded config on to every layer.
+    pub fn reload(&mut self, config: &Config) {
+        for layer in &mut self.layers {
+            layer.reload(config);
+        }
+    }
+
+    /// Run `frames` through every layer.
+    pub fn run(&mut self, frames: Vec<Frame>, session: &mut Session) -> Vec<Frame> {
+        self.run_from(0, frames, session)
+    }
+
+    /// Run the frames every layer still holds back through the layers after
+    /// it, as the server output ended.
+    pub fn finish(&mut self, session: &mut Session) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for layer in &mut self.layers {
+            let mut next = Vec::with_capacity(frames.len());
+            for frame in frames.drain(..) {
+                layer.on_frame(frame, &mut next, session);
+            }
+            layer.finish(&mut next, session);
+            frames = next;
+        }
+        frames
+    }
+
+    /// Collect the frames layers release on their own, run through the
+    /// layers after them.
+    pub fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut Vec<Frame>,
+        session: &mut Session,
+    ) -> Poll<()> {
+        let mut released = false;
+        for i in 0..self.layers.len() {
+            let mut frames = Vec::new();
+            if self.layers[i]
+                .poll_frames(cx, &mut frames, session)
+                .is_ready()
+            {
+                released = true;
+                out.append(&mut self.run_from(i + 1, frames, session));
+            }
+        }
+        if released {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn run_from(
+        &mut self,
+        start: usize,
+        mut frames: Vec<Frame>,
+        session: &mut Session,
+    ) -> Vec<Frame> {
+        let mut next = Vec::with_capacity(frames.len());
+        for layer in &mut self.layers[start..] {
+            for frame in frames.drain(..) {
+                layer.on_frame(frame, &mut next, session);
+            }
+            layer.flush(&mut next, session);
+            std::mem::swap(&mut frames, &mut next);
+        }
+        frames
+    }
+}
+
+impl Middleware for SplitLogs {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        self.write(&frame, session);
+        out.push(frame);
+    }
+
+    fn reload(&mut self, config: &Config) {
+        self.set_config(config.split_log.clone());
+    }
+}
+
+#[derive(Default)]
+struct MapperLayer(Deaths);
+
+impl Middleware for MapperLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        self.0.observe(&frame, session);
+        mapper::observe(&frame, session);
+        if let Some(warning) = mapper::aggro_warning(&frame, session) {
+            out.push(Frame::text(warning.into_bytes()));
+        }
+        out.push(frame);
+    }
+}
+
+#[derive(Default)]
+struct MapLayer(map::Gatherer);
+
+impl Middleware for MapLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        // Maps on a map port are not drawn for the client's window.
+        let width = session
+            .client
+            .width
+            .filter(|&w| session.wrap && w > 0 && session.map_port.is_none());
+        self.0
+            .push(frame, session.map_render, width.map(usize::from), out);
+    }
+
+    fn flush(&mut self, out: &mut Vec<Frame>, _session: &mut Session) {
+        self.0.flush(out);
+    }
+}
+
+struct BattleLayer(Battle);
+
+impl Middleware for BattleLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Some(summary) = self.0.observe(&frame) {
+            session.notify(&summary);
+        }
+        out.push(frame);
+    }
+
+    fn poll_frames(
+        &mut self,
+        cx: &mut Context<'_>,
+        _out: &mut Vec<Frame>,
+        session: &mut Session,
+    ) -> Poll<()> {
+        if let Poll::Ready(summary) = self.0.poll_end(cx) {
+            session.notify(&summary);
+        }
+        Poll::Pending
+    }
+}
+
+struct ExpLayer;
+
+impl Middleware for ExpLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Some(free_exp) = frame.code().and_then(exp::free_exp) {
+            let snapshot = session.exp.observe(free_exp, Instant::now());
+            if let (true, Some(db)) = (snapshot, &session.db) {
+                db.send(Event::ExpSnapshot {
+                    free_exp,
+                    session_gain: session.exp.rate(Instant::now()).total,
+                });
+            }
+        }
+        out.push(frame);
+    }
+}
+
+impl Middleware for Script {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        match Script::on_frame(self, &frame, session) {
+            Outcome::Default => out.push(frame),
+            Outcome::Replace(bytes) => out.push(Frame::text(bytes)),
+            Outcome::Handled => {}
+        }
+    }
+}
+
+struct HooksLayer(Vec<FrameHook>);
+
+impl Middleware for HooksLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        out.extend(
+            self.0
+                .iter()
+                .try_fold(frame, |frame, hook| hook(frame, session)),
+        );
+    }
+}
+
+struct NotifyLayer;
+
+impl Middleware for NotifyLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        session.notifier.on_frame(&frame);
+        out.push(frame);
+    }
+}
+
+struct ChannelLayer;
+
+impl Middleware for ChannelLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Some(db) = &session.db {
+            session.channels.record(&frame, db);
+            sales::record(&frame, db);
+        }
+        if let Err(e) = session.channels.route(frame, out) {
+            session.notify(&format!("failed to write the channel log: {}", e));
+        }
+    }
+}
+
+impl Middleware for Triggers {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        self.push(frame, out, session);
+    }
+
+    fn flush(&mut self, out: &mut Vec<Frame>, _session: &mut Session) {
+        Triggers::flush(self, out);
+    }
+
+    fn finish(&mut self, out: &mut Vec<Frame>, _session: &mut Session) {
+        self.release(out);
+    }
+
+    fn poll_frames(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut Vec<Frame>,
+        _session: &mut Session,
+    ) -> Poll<()> {
+        self.poll_held(cx, out)
+    }
+
+    fn reload(&mut self, config: &Config) {
+        self.set_triggers(config.triggers.clone(), config.trigger_cooldown);
+    }
+}
+
+impl Middleware for Translator {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, _session: &mut Session) {
+        let mut translation = Vec::new();
+        self.observe(&frame, &mut translation);
+        out.push(frame);
+        out.append(&mut translation);
+    }
+
+    fn poll_frames(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut Vec<Frame>,
+        _session: &mut Session,
+    ) -> Poll<()> {
+        self.poll_translated(cx, out)
+    }
+}
+
+struct HighlightLayer(Vec<Highlight>);
+
+impl Middleware for HighlightLayer {
+    fn on_frame(&mut self, mut frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if !self.0.is_empty() {
+            highlight::apply(&self.0, session.color_mode, &mut frame);
+        }
+        out.push(frame);
+    }
+
+    fn reload(&mut self, config: &Config) {
+        self.0 = config.highlights.clone();
+    }
+}
+
+struct ActionLayer;
+
+impl Middleware for ActionLayer {
+    fn on_frame(&mut self, mut frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        match (&mut frame, &session.action) {
+            (Frame::Code(code), _) => match ActionEvent::from_code(code) {
+                Some(ActionEvent::Status(status)) => {
+                    if session.countdown == Countdown::Line {
+                        session.notify(&status.to_string());
+                    }
+                    session.action = Some(status);
+                }
+                Some(ActionEvent::Done) => {
+                    if let Some(action) = session.action.take() {
+                        if session.countdown == Countdown::Line {
+                            session.notify(&format!("{} {}: done", action.kind, action.name));
+                        }
+                    }
+                }
+                None => {}
+            },
+            (Frame::Prompt(prompt), Some(action)) if session.countdown == Countdown::Prompt => {
+                let countdown =
+                    format!("[{} {}: {}]", action.kind, action.name, action.rounds_left);
+                add_to_prompt(prompt, &countdown);
+            }
+            _ => {}
+        }
+        out.push(frame);
+    }
+}
+
+struct EffectLayer {
+    warning: Duration,
+    bell: bool,
+}
+
+impl Middleware for EffectLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        let now = Instant::now();
+        match &frame {
+            Frame::Code(code) => session.effects.observe(code, now),
+            // Prompts are the tick effects are checked on.
+            Frame::Prompt(_) if !self.warning.is_zero() => {
+                for effect in session.effects.tick(now, self.warning) {
+                    session.notify(&format!(
+                        "{} runs out in {}s",
+                        effect.name,
+                        effect.time_left(now).as_secs()
+                    ));
+                    if self.bell {
+                        session.write_client(b"\x07".to_vec());
+                    }
+                }
+            }
+            _ => {}
+        }
+        out.push(frame);
+    }
+}
+
+impl Middleware for Safeguards {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Frame::Code(code) = &frame {
+            if let Some(vitals) = Vitals::from_code(code) {
+                self.check(&vitals, session, Instant::now());
+            }
+        }
+        out.push(frame);
+    }
+
+    fn reload(&mut self, config: &Config) {
+        self.set_safeguards(config.safeguards.clone(), config.safeguard_cooldown);
+    }
+}
+
+struct TickLayer {
+    warning: Duration,
+    // Set to when the line before the next tick is due.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl TickLayer {
+    fn arm(&mut self, session: &Session, now: Instant) {
+        self.timer = session
+            .tick
+            .next(now + self.warning)
+            .map(|next| Box::pin(sleep_until((next - self.warning).into())));
+    }
+}
+
+impl Middleware for TickLayer {
+    fn on_frame(&mut self, mut frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        let now = Instant::now();
+        match &mut frame {
+            Frame::Code(code) if session.tick.observe(code, now) => self.arm(session, now),
+            Frame::Prompt(prompt) if session.tick_countdown == Countdown::Prompt => {
+                if let Some(left) = session.tick.time_left(now) {
+                    let countdown = format!("[tick in {}s]", left.as_millis().div_ceil(1000));
+                    add_to_prompt(prompt, &countdown);
+                }
+            }
+            _ => {}
+        }
+        out.push(frame);
+    }
+
+    fn poll_frames(
+        &mut self,
+        cx: &mut Context<'_>,
+        _out: &mut Vec<Frame>,
+        session: &mut Session,
+    ) -> Poll<()> {
+        let timer = match self.timer.as_mut() {
+            Some(timer) => timer,
+            None => return Poll::Pending,
+        };
+        ready!(timer.as_mut().poll(cx));
+        let now = Instant::now();
+        if let (Countdown::Line, Some(left)) = (session.tick_countdown, session.tick.time_left(now))
+        {
+            session.notify(&format!("tick in {}s", left.as_millis().div_ceil(1000)));
+        }
+        // On to the tick after, which the timer waits for from here.
+        self.arm(session, now + Duration::from_secs(1));
+        if let Some(timer) = self.timer.as_mut() {
+            let _ = timer.as_mut().poll(cx);
+        }
+        Poll::Pending
+    }
+}
+
+struct ClockLayer;
+
+impl Middleware for ClockLayer {
+    fn on_frame(&mut self, mut frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Frame::Prompt(prompt) = &mut frame {
+            match session.clock {
+                Countdown::Prompt => {
+                    let status = format!("[{}]", clock::status(session, Instant::now()));
+                    add_to_prompt(prompt, &status);
+                }
+                Countdown::Line => {
+                    let status = format!("[{}]\r\n", clock::status(session, Instant::now()));
+                    out.push(Frame::text(status.into_bytes()));
+                }
+                Countdown::Off => {}
+            }
+        }
+        out.push(frame);
+    }
+}
+
+/// Add `text` to the end of `prompt`, a space apart from what is there.
+fn add_to_prompt(prompt: &mut ControlCode, text: &str) {
+    let suffix = match prompt.text().last() {
+        Some(b) if b.is_ascii_whitespace() => format!("{} ", text),
+        _ => format!(" {}", text),
+    };
+    prompt.body.push(Frame::text(suffix.into_bytes()));
+}
+
+struct TargetLayer {
+    style: BarStyle,
+    width: usize,
+    kill_log: bool,
+}
+
+impl Middleware for TargetLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        let target = match frame.code().and_then(Target::from_code) {
+            Some(target) => target,
+            None => return out.push(frame),
+        };
+
+        let killed = target.hp_percent == 0
+            && session
+                .target
+                .as_ref()
+                .is_some_and(|last| last.name == target.name && last.hp_percent > 0);
+        if let (true, true, Some(db)) = (killed, self.kill_log, &session.db) {
+            db.send(Event::Kill {
+                name: target.name.clone(),
+                area: session.last_room.as_ref().map(|room| room.area.clone()),
+                room_id: session.last_room.as_ref().map(|room| room.id.clone()),
+            });
+        }
+
+        if self.style == BarStyle::Off || !session.output_style.style().is_terminal() {
+            out.push(frame);
+        } else {
+            out.extend(target::render(&target, self.style, self.width));
+        }
+        session.target = Some(target);
+    }
+}
+
+struct LinkLayer;
+
+impl Middleware for LinkLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        let style = session.links.resolve(session);
+        let room = session.last_room.as_ref().map(|room| room.id.clone());
+        link::render(frame, style, &mut session.game_links, room.as_deref(), out);
+    }
+}
+
+struct ColorLayer;
+
+impl Middleware for ColorLayer {
+    fn on_frame(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        color::render_codes(frame, session.color_mode, out);
+    }
+}
+
+impl Middleware for Wrapper {
+    fn on_frame(&mut self, mut frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if let Some(width) = session.client.width.filter(|&w| session.wrap && w > 0) {
+            self.wrap(&mut frame, width.into());
+        }
+        out.push(frame);
+    }
+}
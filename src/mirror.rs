@@ -0,0 +1,227 @@
+//! Clients attached to another client's session, to watch it from a second
+//! machine. They connect to a `listen` address with `attach=ro` or
+//! `attach=rw` and pick the session, and get what its client gets from
+//! then on. Read-write clients can also send lines, which the session takes
+//! between the lines of its own client.
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::broadcast,
+};
+use tracing::info;
+
+use crate::{
+    session::{Request, Sessions},
+    telnet::{self, Segment},
+};
+
+/// Writes of a session's client kept for attached clients that fall
+/// behind, before they miss some.
+pub(crate) const BACKLOG: usize = 1024;
+/// Longest line read from an attached client.
+const MAX_LINE: usize = 4096;
+
+/// What an attached client may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attach {
+    /// Watch the session.
+    ReadOnly,
+    /// Watch it and send lines to it.
+    ReadWrite,
+}
+
+impl FromStr for Attach {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ro" => Ok(Attach::ReadOnly),
+            "rw" => Ok(Attach::ReadWrite),
+            _ => Err(format!("invalid attach mode `{}`, expected ro or rw", s)),
+        }
+    }
+}
+
+impl fmt::Display for Attach {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Attach::ReadOnly => "ro",
+            Attach::ReadWrite => "rw",
+        })
+    }
+}
+
+/// The client of a session, what is written to it copied to the clients
+/// attached.
+pub(crate) struct Tee<'a, C: ?Sized> {
+    client: &'a mut C,
+    mirror: Option<broadcast::Sender<Bytes>>,
+}
+
+impl<'a, C: ?Sized> Tee<'a, C> {
+    pub(crate) fn new(client: &'a mut C, mirror: Option<broadcast::Sender<Bytes>>) -> Self {
+        Self { client, mirror }
+    }
+}
+
+impl<C: AsyncRead + Unpin + ?Sized> AsyncRead for Tee<'_, C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().client).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tee<'_, C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut *this.client).poll_write(cx, buf))?;
+        if let Some(mirror) = this.mirror.as_ref().filter(|m| m.receiver_count() > 0) {
+            let _ = mirror.send(Bytes::copy_from_slice(&buf[..n]));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().client).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().client).poll_shutdown(cx)
+    }
+}
+
+/// Attach the client on `stream` to a session of `sessions` it picks, the
+/// only one if there is just one, until either goes away.
+pub(crate) async fn serve<S>(
+    stream: S,
+    peer: &str,
+    sessions: &Sessions,
+    mode: Attach,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut parser = telnet::Parser::new();
+    let mut line = Vec::new();
+
+    let attached = loop {
+        let summaries = sessions.summaries();
+        let id = match summaries.as_slice() {
+            [] => {
+                writer
+                    .write_all(b"[bcproxy] no session to attach to\r\n")
+                    .await?;
+                return Ok(());
+            }
+            [only] => only.id,
+            _ => {
+                let choices: Vec<String> = summaries
+                    .iter()
+                    .map(|s| format!("{} from {}", s.id, s.peer))
+                    .collect();
+                let question = format!("[bcproxy] session ({}): ", choices.join(", "));
+                writer.write_all(question.as_bytes()).await?;
+                let answer = match next_line(&mut reader, &mut parser, &mut line).await? {
+                    Some(answer) => answer,
+                    None => return Ok(()),
+                };
+                match String::from_utf8_lossy(&answer).trim().parse() {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                }
+            }
+        };
+        match sessions.attach(id, mode) {
+            Some(attached) => break attached,
+            None => {
+                let reply = format!("[bcproxy] no session {}\r\n", id);
+                writer.write_all(reply.as_bytes()).await?;
+            }
+        }
+    };
+    let (id, mut output, input) = attached;
+    info!(%peer, session = id, %mode, "client attached");
+    let reply = match mode {
+        Attach::ReadOnly => format!("[bcproxy] watching session {}\r\n", id),
+        Attach::ReadWrite => format!("[bcproxy] attached to session {}\r\n", id),
+    };
+    writer.write_all(reply.as_bytes()).await?;
+
+    loop {
+        tokio::select! {
+            written = output.recv() => match written {
+                Ok(bytes) => writer.write_all(&bytes).await?,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    let reply = format!("\r\n[bcproxy] fell behind, {} writes missed\r\n", n);
+                    writer.write_all(reply.as_bytes()).await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    writer.write_all(b"\r\n[bcproxy] session ended\r\n").await?;
+                    break;
+                }
+            },
+            read = next_line(&mut reader, &mut parser, &mut line) => match read? {
+                Some(read) => match &input {
+                    Some(input) => {
+                        if input.send(Request::Input(read)).is_err() {
+                            break;
+                        }
+                    }
+                    None => writer.write_all(b"[bcproxy] read-only, line dropped\r\n").await?,
+                },
+                None => break,
+            },
+        }
+    }
+    info!(%peer, session = id, "client detached");
+    Ok(())
+}
+
+/// The next line the client sends, with its line ending, or `None` once it
+/// is gone. Telnet commands are dropped. `line` keeps what was read of the
+/// line after it.
+async fn next_line<R>(
+    reader: &mut R,
+    parser: &mut telnet::Parser,
+    line: &mut Vec<u8>,
+) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(end) = line.iter().position(|&b| b == b'\n') {
+            return Ok(Some(line.drain(..=end).collect()));
+        }
+        if line.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        let mut buf = [0; 1024];
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        let mut segments = Vec::new();
+        parser.parse(&buf[..n], &mut segments);
+        for segment in segments {
+            if let Segment::Data(data) = segment {
+                line.extend_from_slice(&data);
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Webhook notifications for things worth knowing about while away from
+//! the client: a tell, a party member dying or the connection dropping.
+//!
+//! A notifier is written as `<event> => <format> <url> [<template>]`:
+//!
+//! - events are `@<message type> [<glob or re:regex>]` for messages such as
+//!   `@tell *Bob*`, `party_death` for a party member whose code 62 status
+//!   (`<name> hp=<hp> ...`) drops to 0 hp, and `disconnect`
+//! - formats are `discord`, `slack` and `json`, the body each expects
+//! - the template is the text sent, with `{event}`, `{type}`, `{name}` and
+//!   `{text}` filled in
+//!
+//! Each notifier posts at most once per `notifier_interval_ms`. The count of
+//! notifications left out in between is added to the next one.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde_json::json;
+
+use crate::{bc::Frame, color, trigger::glob_to_regex, webhook};
+
+/// Party member status, `<name> hp=<hp> ...`.
+const PARTY_STATUS: u8 = 62;
+
+#[derive(Debug, Clone)]
+enum Event {
+    Message {
+        kind: String,
+        pattern: Option<Regex>,
+    },
+    PartyDeath,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Discord,
+    Slack,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    source: String,
+    event: Event,
+    format: Format,
+    url: String,
+    template: Option<String>,
+}
+
+impl FromStr for Notification {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, target) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("notifier `{}` has no `=>`", s))?;
+
+        let event = match event.trim() {
+            "party_death" => Event::PartyDeath,
+            "disconnect" => Event::Disconnect,
+            event => {
+                let scoped = event
+                    .strip_prefix('@')
+                    .ok_or_else(|| format!("unknown notifier event `{}`", event))?;
+                let (kind, pattern) = scoped.split_once(' ').unwrap_or((scoped, ""));
+                let pattern = match pattern.trim() {
+                    "" => None,
+                    pattern => {
+                        let regex = match pattern.strip_prefix("re:") {
+                            Some(re) => re.to_string(),
+                            None => glob_to_regex(pattern),
+                        };
+                        Some(Regex::new(&regex).map_err(|e| e.to_string())?)
+                    }
+                };
+                Event::Message {
+                    kind: kind.to_string(),
+                    pattern,
+                }
+            }
+        };
+
+        let mut target = target.trim().splitn(3, ' ');
+        let format = match target.next().unwrap_or_default() {
+            "discord" => Format::Discord,
+            "slack" => Format::Slack,
+            "json" => Format::Json,
+            format => {
+                return Err(format!(
+                    "invalid notifier format `{}`, expected discord, slack or json",
+                    format
+                ))
+            }
+        };
+        let url = match target.next() {
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                url.to_string()
+            }
+            _ => return Err("notifier needs an http:// or https:// url".to_string()),
+        };
+        let template = target
+            .next()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string);
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            event,
+            format,
+            url,
+            template,
+        })
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// What an event is about, for the template.
+struct Fields<'a> {
+    event: &'a str,
+    kind: &'a str,
+    name: &'a str,
+    text: &'a str,
+}
+
+impl Notification {
+    fn render(&self, fields: &Fields<'_>) -> String {
+        let default = match self.event {
+            Event::Message { .. } => "{text}",
+            Event::PartyDeath => "{name} died",
+            Event::Disconnect => "the connection to the server dropped",
+        };
+        self.template
+            .as_deref()
+            .unwrap_or(default)
+            .replace("{event}", fields.event)
+            .replace("{type}", fields.kind)
+            .replace("{name}", fields.name)
+            .replace("{text}", fields.text)
+    }
+
+    fn body(&self, fields: &Fields<'_>, message: &str) -> String {
+        match self.format {
+            Format::Discord => json!({ "content": message }),
+            Format::Slack => json!({ "text": message }),
+            Format::Json => json!({
+                "event": fields.event,
+                "type": fields.kind,
+                "name": fields.name,
+                "text": fields.text,
+                "message": message,
+            }),
+        }
+        .to_string()
+    }
+}
+
+/// Runs the notifiers of a session.
+pub struct Notifier {
+    notifications: Vec<Notification>,
+    interval: Duration,
+    last_sent: Vec<Option<Instant>>,
+    suppressed: Vec<u32>,
+    // Party members last seen at 0 hp, so a death is only reported once.
+    dead: HashSet<String>,
+}
+
+impl Notifier {
+    pub fn new(notifications: Vec<Notification>, interval: Duration) -> Self {
+        Self {
+            last_sent: vec![None; notifications.len()],
+            suppressed: vec![0; notifications.len()],
+            notifications,
+            interval,
+            dead: HashSet::new(),
+        }
+    }
+
+    pub fn on_frame(&mut self, frame: &Frame) {
+        if self.notifications.is_empty() {
+            return;
+        }
+        let code = match frame.code() {
+            Some(code) => code,
+            None => return,
+        };
+
+        match code.id {
+            10 => {
+                let kind = String::from_utf8_lossy(code.attr.as_deref().unwrap_or_default());
+                let text = color::strip_ansi(&code.text());
+                let text = text.trim_end_matches(['\r', '\n']);
+                self.fire(
+                    |event| match event {
+                        Event::Message { kind: k, pattern } => {
+                            *k == kind && pattern.as_ref().is_none_or(|p| p.is_match(text))
+                        }
+                        _ => false,
+                    },
+                    &Fields {
+                        event: "message",
+                        kind: &kind,
+                        name: "",
+                        text,
+                    },
+                );
+            }
+            PARTY_STATUS => {
+                let text = color::strip_ansi(&code.text());
+                let mut fields = text.split_whitespace();
+                let name = match fields.next() {
+                    Some(name) if !name.contains('=') => name,
+                    _ => return,
+                };
+                let hp = fields
+                    .find_map(|field| field.strip_prefix("hp="))
+                    .and_then(|hp| hp.parse::<i64>().ok());
+                match hp {
+                    Some(hp) if hp > 0 => {
+                        self.dead.remove(name);
+                    }
+                    Some(_) if self.dead.insert(name.to_string()) => self.fire(
+                        |event| matches!(event, Event::PartyDeath),
+                        &Fields {
+                            event: "party_death",
+                            kind: "",
+                            name,
+                            text: text.trim(),
+                        },
+                    ),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Report that the connection to the server is gone.
+    pub fn disconnected(&mut self, reason: &str) {
+        self.fire(
+            |event| matches!(event, Event::Disconnect),
+            &Fields {
+                event: "disconnect",
+                kind: "",
+                name: "",
+                text: reason,
+            },
+        );
+    }
+
+    fn fire(&mut self, matches: impl Fn(&Event) -> bool, fields: &Fields<'_>) {
+        let now = Instant::now();
+        for (i, notification) in self.notifications.iter().enumerate() {
+            if !matches(&notification.event) {
+                continue;
+            }
+            if matches!(self.last_sent[i], Some(t) if now.duration_since(t) < self.interval) {
+                self.suppressed[i] += 1;
+                continue;
+            }
+            self.last_sent[i] = Some(now);
+
+            let mut message = notification.render(fields);
+            match std::mem::take(&mut self.suppressed[i]) {
+                0 => {}
+                1 => message.push_str(" (and 1 more since the last notification)"),
+                n => message.push_str(&format!(" (and {} more since the last notification)", n)),
+            }
+            webhook::post(&notification.url, notification.body(fields, &message));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::bc::Decoder;
+
+    fn frame(bytes: &[u8]) -> Frame {
+        let mut frames = Vec::new();
+        Decoder::new().decode(bytes, &mut frames);
+        frames.remove(0)
+    }
+
+    fn notifier(notifications: &[&str], interval: Duration) -> Notifier {
+        let notifications = notifications.iter().map(|n| n.parse().unwrap()).collect();
+        Notifier::new(notifications, interval)
+    }
+
+    /// The body of the next request to `listener`, answered with 204.
+    async fn posted(listener: &TcpListener) -> serde_json::Value {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let body = loop {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if body.len() == length {
+                    break body.to_string();
+                }
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    fn notifiers_are_read_with_their_event_format_and_template() {
+        let n: Notification = "@tell *Bob* => json http://localhost:9/hook {name} said {text}"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            &n.event,
+            Event::Message { kind, pattern: Some(p) } if kind == "tell" && p.is_match("Bob tells you: hi")
+        ));
+        assert_eq!(n.format, Format::Json);
+        assert_eq!(n.url, "http://localhost:9/hook");
+        assert_eq!(n.template.as_deref(), Some("{name} said {text}"));
+
+        let n: Notification = "party_death => slack https://hooks.slack.com/x"
+            .parse()
+            .unwrap();
+        assert!(matches!(n.event, Event::PartyDeath));
+        assert_eq!(n.url, "https://hooks.slack.com/x");
+        assert_eq!(n.template, None);
+
+        for bad in [
+            "disconnect discord http://h/x",
+            "tell => discord http://h/x",
+            "disconnect => email http://h/x",
+            "disconnect => discord ftp://h/x",
+            "disconnect => discord",
+            "@tell re:( => discord http://h/x",
+        ] {
+            assert!(bad.parse::<Notification>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bodies_take_the_shape_of_their_format() {
+        let fields = Fields {
+            event: "party_death",
+            kind: "",
+            name: "Bob",
+            text: "Bob hp=0",
+        };
+        let body = |format: &str| {
+            let n: Notification = format!("party_death => {} http://h/x", format)
+                .parse()
+                .unwrap();
+            let message = n.render(&fields);
+            serde_json::from_str::<serde_json::Value>(&n.body(&fields, &message)).unwrap()
+        };
+        assert_eq!(body("discord"), json!({ "content": "Bob died" }));
+        assert_eq!(body("slack"), json!({ "text": "Bob died" }));
+        assert_eq!(
+            body("json"),
+            json!({
+                "event": "party_death",
+                "type": "",
+                "name": "Bob",
+                "text": "Bob hp=0",
+                "message": "Bob died",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn matching_messages_are_posted_and_others_are_not() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut notifier = notifier(
+            &[&format!(
+                "@tell *Bob* => discord {} [{{type}}] {{text}}",
+                url
+            )],
+            Duration::ZERO,
+        );
+
+        notifier.on_frame(&frame(b"\x1b<10tell\x1b|Al tells you: hi\r\n\x1b>10"));
+        notifier.on_frame(&frame(b"\x1b<10chan_sales\x1b|Bob: hi\x1b>10"));
+        notifier.on_frame(&frame(b"Bob tells you: plain text\r\n"));
+        notifier.on_frame(&frame(
+            b"\x1b<10tell\x1b|\x1b[1mBob\x1b[0m tells you: hi\r\n\x1b>10",
+        ));
+        assert_eq!(
+            posted(&listener).await,
+            json!({ "content": "[tell] Bob tells you: hi" })
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_within_the_interval_are_counted_into_the_next() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let interval = Duration::from_secs(60);
+        let mut notifier = notifier(&[&format!("disconnect => slack {}", url)], interval);
+
+        notifier.disconnected("reset");
+        posted(&listener).await;
+        notifier.disconnected("reset");
+        notifier.disconnected("reset");
+        assert_eq!(notifier.suppressed, [2]);
+
+        notifier.last_sent[0] = Instant::now().checked_sub(interval);
+        notifier.disconnected("reset");
+        assert_eq!(
+            posted(&listener).await,
+            json!({
+                "text": "the connection to the server dropped \
+                         (and 2 more since the last notification)"
+            })
+        );
+        assert_eq!(notifier.suppressed, [0]);
+    }
+
+    #[tokio::test]
+    async fn a_party_member_dies_once_until_seen_alive() {
+        // Nothing listens, posts fail quietly.
+        let mut notifier = notifier(&["party_death => json http://127.0.0.1:9/"], Duration::MAX);
+        let status = |status: &[u8]| frame(&[b"\x1b<62", status, b"\x1b>62"].concat());
+
+        notifier.on_frame(&status(b"Bob hp=10 sp=5"));
+        assert!(notifier.last_sent[0].is_none());
+        notifier.on_frame(&status(b"Bob hp=0 sp=5"));
+        assert!(notifier.last_sent[0].is_some());
+        notifier.on_frame(&status(b"Bob hp=-3 sp=5"));
+        assert_eq!(notifier.suppressed, [0]);
+        assert!(notifier.dead.contains("Bob"));
+
+        notifier.on_frame(&status(b"Bob hp=1"));
+        notifier.on_frame(&status(b"Bob hp=0"));
+        assert_eq!(notifier.suppressed, [1]);
+        // Lines without a name or hp are not members.
+        notifier.on_frame(&status(b"hp=0"));
+        notifier.on_frame(&status(b"Al sp=0"));
+        assert_eq!(notifier.dead.len(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! The player's status for party members, on the port `party_listen` sets:
+//! a line such as
+//!
+//! ```text
+//! Bob hp=120/150 sp=30/80 ep=90/100 room=arelium:1 loc=rothikgen:120,45 target=orc:80
+//! ```
+//!
+//! each time the points, the room, the place on the outworld map or the
+//! target change, with the fields not known yet left out. Party members
+//! read it with their own proxies or scripts, for more than the party
+//! status of code 62 tells. Clients get the last line of every player of
+//! the proxy as soon as they connect.
+//!
+//! Whoever connects gets the lines, so the port must be on the loopback
+//! interface, as the admin console's. Party members on other machines reach
+//! it through a tunnel of their own, such as `ssh -L`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+
+use crate::{
+    bc::Frame,
+    listener::{Listener, ACCEPT_RETRY},
+    mapper::{Location, Mapper},
+    session::Session,
+    target::Target,
+    vitals::Vitals,
+};
+
+/// Lines kept for a client that is slow to read.
+const PORT_BACKLOG: usize = 64;
+
+/// What party members are told of the player.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartyStatus {
+    /// The name lines start with.
+    pub name: String,
+    pub vitals: Option<Vitals>,
+    /// The area and id of the room the mapper last reported.
+    pub room: Option<(String, String)>,
+    pub location: Option<Location>,
+    pub target: Option<Target>,
+}
+
+impl PartyStatus {
+    /// The status of the player called `name`, or `player` if it is empty.
+    pub fn new(name: &str) -> Self {
+        let name = match name.trim() {
+            "" => "player",
+            name => name,
+        };
+        Self {
+            name: field(name),
+            ..Self::default()
+        }
+    }
+
+    /// The status as sent, without the line ending.
+    pub fn line(&self) -> String {
+        let mut line = self.name.clone();
+        if let Some(v) = &self.vitals {
+            let _ = write!(
+                line,
+                " hp={}/{} sp={}/{} ep={}/{}",
+                v.hp, v.max_hp, v.sp, v.max_sp, v.ep, v.max_ep
+            );
+        }
+        if let Some((area, id)) = &self.room {
+            let _ = write!(line, " room={}:{}", field(area), field(id));
+        }
+        if let Some(location) = &self.location {
+            let _ = write!(
+                line,
+                " loc={}:{},{}",
+                field(&location.realm),
+                location.x,
+                location.y
+            );
+        }
+        if let Some(target) = &self.target {
+            let _ = write!(
+                line,
+                " target={}:{}",
+                field(&target.name),
+                target.hp_percent
+            );
+        }
+        line
+    }
+}
+
+/// `value` as a field value, which ends at the first space.
+fn field(value: &str) -> String {
+    value.replace(char::is_whitespace, "_")
+}
+
+/// Update the status with `frame`, and send it to the party port if it
+/// changed.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let port = match &session.party_port {
+        Some(port) => port,
+        None => return,
+    };
+    let code = match frame {
+        Frame::Code(code) => code,
+        _ => return,
+    };
+    let status = &mut session.party;
+    let changed = if let Some(vitals) = Vitals::from_code(code) {
+        set(&mut status.vitals, vitals)
+    } else if let Some(target) = Target::from_code(code) {
+        set(&mut status.target, target)
+    } else if let Some(location) = Location::from_code(code) {
+        set(&mut status.location, location)
+    } else if let Some(Mapper::Room(room)) = Mapper::from_code(code) {
+        set(&mut status.room, (room.area, room.id))
+    } else {
+        false
+    };
+    if changed {
+        port.send(&status.name, status.line());
+    }
+}
+
+/// Put `value` in `slot`, and whether that changed it.
+fn set<T: PartialEq>(slot: &mut Option<T>, value: T) -> bool {
+    if slot.as_ref() == Some(&value) {
+        return false;
+    }
+    *slot = Some(value);
+    true
+}
+
+/// A TCP port party members connect to for the status of the players.
+#[derive(Clone)]
+pub struct PartyPort {
+    lines: broadcast::Sender<Arc<str>>,
+    // The last line of each player, for clients that connect later.
+    last: Arc<Mutex<BTreeMap<String, Arc<str>>>>,
+}
+
+impl PartyPort {
+    pub fn new() -> Self {
+        Self {
+            lines: broadcast::channel(PORT_BACKLOG).0,
+            last: Arc::default(),
+        }
+    }
+
+    fn send(&self, name: &str, mut line: String) {
+        line.push('\n');
+        let line: Arc<str> = line.into();
+        self.last
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), line.clone());
+        // Nobody may be listening, which is fine.
+        let _ = self.lines.send(line);
+    }
+
+    pub async fn serve(self, listener: Listener) {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("party port: accepting a client failed: {}", e);
+                    sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+            let mut lines = self.lines.subscribe();
+            let last: Vec<Arc<str>> = self.last.lock().unwrap().values().cloned().collect();
+            tokio::spawn(async move {
+                for line in last {
+                    if stream.write_all(line.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                loop {
+                    match lines.recv().await {
+                        Ok(line) => {
+                            if stream.write_all(line.as_bytes()).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl Default for PartyPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
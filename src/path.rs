@@ -0,0 +1,122 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::db::Link;
+
+/// The directions of a shortest walk from `from` to any room in `to` over
+/// `links`, or `None` if the mapper has not seen a way there. Every exit
+/// counts as one step.
+pub fn find(links: &[Link], from: &str, to: &HashSet<String>) -> Option<Vec<String>> {
+    if to.contains(from) {
+        return Some(Vec::new());
+    }
+
+    let mut exits: HashMap<&str, Vec<&Link>> = HashMap::new();
+    for link in links {
+        exits.entry(&link.from).or_default().push(link);
+    }
+
+    // The link each room was first reached through.
+    let mut reached: HashMap<&str, &Link> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(room) = queue.pop_front() {
+        for link in exits.get(room).into_iter().flatten() {
+            if link.to == from || reached.contains_key(link.to.as_str()) {
+                continue;
+            }
+            reached.insert(&link.to, link);
+            if to.contains(&link.to) {
+                return Some(walk_back(&reached, from, &link.to));
+            }
+            queue.push_back(&link.to);
+        }
+    }
+    None
+}
+
+fn walk_back(reached: &HashMap<&str, &Link>, from: &str, to: &str) -> Vec<String> {
+    let mut steps = Vec::new();
+    let mut room = to;
+    while room != from {
+        let link = reached[room];
+        steps.push(link.direction.clone());
+        room = &link.from;
+    }
+    steps.reverse();
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(links: &[(&str, &str, &str)]) -> Vec<Link> {
+        links
+            .iter()
+            .map(|&(from, direction, to)| Link {
+                from: from.to_string(),
+                to: to.to_string(),
+                direction: direction.to_string(),
+            })
+            .collect()
+    }
+
+    fn to(rooms: &[&str]) -> HashSet<String> {
+        rooms.iter().map(|room| room.to_string()).collect()
+    }
+
+    #[test]
+    fn the_shortest_walk_is_taken() {
+        let links = links(&[
+            ("a", "n", "b"),
+            ("b", "n", "c"),
+            ("c", "e", "d"),
+            ("a", "e", "e"),
+            ("e", "ne", "d"),
+        ]);
+        assert_eq!(
+            find(&links, "a", &to(&["d"])),
+            Some(vec!["e".into(), "ne".into()])
+        );
+        assert_eq!(
+            find(&links, "a", &to(&["c"])),
+            Some(vec!["n".into(), "n".into()])
+        );
+    }
+
+    #[test]
+    fn the_closest_of_several_rooms_is_walked_to() {
+        let links = links(&[("a", "n", "b"), ("b", "n", "c"), ("a", "s", "d")]);
+        assert_eq!(find(&links, "a", &to(&["c", "d"])), Some(vec!["s".into()]));
+    }
+
+    #[test]
+    fn a_room_already_in_is_no_steps_away() {
+        assert_eq!(find(&[], "a", &to(&["a"])), Some(Vec::new()));
+    }
+
+    #[test]
+    fn no_route_is_none() {
+        // Links only go one way.
+        let links = links(&[("a", "n", "b"), ("c", "s", "a")]);
+        assert_eq!(find(&links, "a", &to(&["c"])), None);
+        assert_eq!(find(&links, "a", &to(&["unknown"])), None);
+    }
+
+    #[test]
+    fn cycles_end_the_search() {
+        let links = links(&[
+            ("a", "n", "b"),
+            ("b", "s", "a"),
+            ("b", "e", "c"),
+            ("c", "w", "b"),
+            ("c", "s", "a"),
+            ("b", "up", "b"),
+        ]);
+        assert_eq!(find(&links, "a", &to(&["d"])), None);
+        assert_eq!(find(&links, "c", &to(&["b"])), Some(vec!["w".into()]));
+        assert_eq!(
+            find(&links, "a", &to(&["c"])),
+            Some(vec!["n".into(), "e".into()])
+        );
+    }
+}
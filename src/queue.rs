@@ -0,0 +1,116 @@
+//! The command queue. With `command_queue` on, lines the player sends go to
+//! the server one at a time, each once the last one got its prompt or a
+//! delay after it, so that a long batch pasted at once is not cut short by
+//! the server. `;;queue show` lists the lines waiting and `;;queue clear`
+//! drops them.
+
+use std::{collections::VecDeque, fmt, str::FromStr, time::Duration};
+
+/// When the next queued line goes to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Lines are not queued.
+    #[default]
+    Off,
+    /// After the server's prompt for the last one.
+    Prompt,
+    /// This long after the last one.
+    Delay(Duration),
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Pacing::Off),
+            "prompt" => Ok(Pacing::Prompt),
+            _ => match s.parse() {
+                Ok(ms) if ms > 0 => Ok(Pacing::Delay(Duration::from_millis(ms))),
+                _ => Err(format!(
+                    "invalid command queue `{}`, expected off, prompt or a delay in milliseconds",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pacing::Off => f.write_str("off"),
+            Pacing::Prompt => f.write_str("prompt"),
+            Pacing::Delay(delay) => write!(f, "{}", delay.as_millis()),
+        }
+    }
+}
+
+/// The lines of a session waiting to go to the server.
+#[derive(Debug)]
+pub struct CommandQueue {
+    pub pacing: Pacing,
+    lines: VecDeque<Vec<u8>>,
+    // The last line sent got its prompt, or none was sent yet.
+    prompted: bool,
+}
+
+impl CommandQueue {
+    pub fn new(pacing: Pacing) -> Self {
+        Self {
+            pacing,
+            lines: VecDeque::new(),
+            prompted: true,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.pacing != Pacing::Off
+    }
+
+    /// Queue `line`, with its line ending.
+    pub fn push(&mut self, line: Vec<u8>) {
+        self.lines.push_back(line);
+    }
+
+    /// The next line, whether or not it is due.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.prompted = false;
+        self.lines.pop_front()
+    }
+
+    /// The next line, if the last one got its prompt.
+    pub fn pop_prompted(&mut self) -> Option<Vec<u8>> {
+        if !self.prompted {
+            return None;
+        }
+        self.lines.pop_front().inspect(|_| self.prompted = false)
+    }
+
+    /// Note that the server prompted for the next line.
+    pub fn prompt(&mut self) {
+        self.prompted = true;
+    }
+
+    /// Drop the lines waiting, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let n = self.lines.len();
+        self.lines.clear();
+        n
+    }
+
+    /// The lines waiting, without their line endings.
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.lines
+            .iter()
+            .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
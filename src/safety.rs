@@ -0,0 +1,230 @@
+//! Safeguards: commands sent on their own when the player's points run low,
+//! such as quaffing a potion or fleeing. Each is written as
+//!
+//! ```text
+//! safeguard = hp < 20% => send quaff heal | flee
+//! ```
+//!
+//! and checked on every code 50. `flee` leaves the room the way the player
+//! came in, or by its first exit. A safeguard fires again only after
+//! `safeguard_cooldown_secs`, and tells the player loudly each time.
+//! `#bc safety off` stops them all until `#bc safety on`.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{session::Session, vitals::Vitals};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Points {
+    Hp,
+    Sp,
+    Ep,
+}
+
+impl Points {
+    /// The points and their maximum in `vitals`.
+    fn of(self, vitals: &Vitals) -> (i64, i64) {
+        match self {
+            Points::Hp => (vitals.hp, vitals.max_hp),
+            Points::Sp => (vitals.sp, vitals.max_sp),
+            Points::Ep => (vitals.ep, vitals.max_ep),
+        }
+    }
+}
+
+impl fmt::Display for Points {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Points::Hp => "hp",
+            Points::Sp => "sp",
+            Points::Ep => "ep",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Send(String),
+    /// Leave the room the way the player came in, or by its first exit.
+    Flee,
+}
+
+/// Points falling below a threshold and the actions that are then taken.
+#[derive(Debug, Clone)]
+pub struct Safeguard {
+    source: String,
+    points: Points,
+    below: i64,
+    percent: bool,
+    actions: Vec<Action>,
+}
+
+impl FromStr for Safeguard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (condition, actions) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("safeguard `{}` has no `=>`", s))?;
+
+        let expected = || {
+            format!(
+                "expected `hp|sp|ep < <points>[%]`, got `{}`",
+                condition.trim()
+            )
+        };
+        let (points, below) = condition.split_once('<').ok_or_else(expected)?;
+        let points = match points.trim() {
+            "hp" => Points::Hp,
+            "sp" => Points::Sp,
+            "ep" => Points::Ep,
+            _ => return Err(expected()),
+        };
+        let below = below.trim();
+        let (below, percent) = match below.strip_suffix('%') {
+            Some(below) => (below.trim_end(), true),
+            None => (below, false),
+        };
+        let below = below.parse().map_err(|_| expected())?;
+
+        let actions = actions
+            .split('|')
+            .map(|action| {
+                let action = action.trim();
+                let (name, arg) = action.split_once(' ').unwrap_or((action, ""));
+                let arg = arg.trim();
+                match (name, arg.is_empty()) {
+                    ("send", false) => Ok(Action::Send(arg.to_string())),
+                    ("flee", true) => Ok(Action::Flee),
+                    _ => Err(format!("invalid safeguard action `{}`", action)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            points,
+            below,
+            percent,
+            actions,
+        })
+    }
+}
+
+impl fmt::Display for Safeguard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Safeguard {
+    /// Whether the points in `vitals` are below the threshold. Points with
+    /// no maximum yet are not below any percentage.
+    fn is_below(&self, vitals: &Vitals) -> bool {
+        let (points, max) = self.points.of(vitals);
+        match self.percent {
+            true => max > 0 && points * 100 < self.below * max,
+            false => points < self.below,
+        }
+    }
+}
+
+/// Checks the safeguards against the player's points.
+pub struct Safeguards {
+    safeguards: Vec<Safeguard>,
+    cooldown: Duration,
+    last_fired: Vec<Option<Instant>>,
+}
+
+impl Safeguards {
+    pub fn new(safeguards: Vec<Safeguard>, cooldown: Duration) -> Self {
+        Self {
+            last_fired: vec![None; safeguards.len()],
+            safeguards,
+            cooldown,
+        }
+    }
+
+    /// Check `safeguards` from now on, none of them having fired yet.
+    pub fn set_safeguards(&mut self, safeguards: Vec<Safeguard>, cooldown: Duration) {
+        *self = Self::new(safeguards, cooldown);
+    }
+
+    /// Take the actions of the safeguards `vitals` are below, unless they
+    /// fired within the cooldown or the player turned them off.
+    pub fn check(&mut self, vitals: &Vitals, session: &mut Session, now: Instant) {
+        if !session.safety {
+            return;
+        }
+        for (safeguard, last_fired) in self.safeguards.iter().zip(self.last_fired.iter_mut()) {
+            if !safeguard.is_below(vitals) {
+                continue;
+            }
+            if matches!(last_fired, Some(t) if now.duration_since(*t) < self.cooldown) {
+                continue;
+            }
+            *last_fired = Some(now);
+
+            let mut sent = Vec::new();
+            for action in &safeguard.actions {
+                let command = match action {
+                    Action::Send(command) => command.clone(),
+                    Action::Flee => match flee_exit(session) {
+                        Some(exit) => exit,
+                        None => {
+                            session.notify("SAFETY: no exit to flee by");
+                            continue;
+                        }
+                    },
+                };
+                session.send_command(&command);
+                sent.push(command);
+            }
+            let (points, max) = safeguard.points.of(vitals);
+            session.notify(&format!(
+                "SAFETY: {} {}/{}, sent {} (`#bc safety off` stops this)",
+                safeguard.points,
+                points,
+                max,
+                match sent.is_empty() {
+                    true => "nothing".to_string(),
+                    false => sent.join(", "),
+                }
+            ));
+            session.write_client(b"\x07".to_vec());
+        }
+    }
+}
+
+/// The exit to flee by: back the way the player came into the room the
+/// mapper last reported, or its first exit.
+fn flee_exit(session: &Session) -> Option<String> {
+    let room = session.last_room.as_ref()?;
+    let back = back(&room.direction);
+    room.exits
+        .iter()
+        .find(|exit| Some(exit.as_str()) == back)
+        .or_else(|| room.exits.first())
+        .cloned()
+}
+
+/// The direction leading back from a move towards `direction`.
+fn back(direction: &str) -> Option<&'static str> {
+    Some(match direction {
+        "n" => "s",
+        "s" => "n",
+        "e" => "w",
+        "w" => "e",
+        "ne" => "sw",
+        "sw" => "ne",
+        "nw" => "se",
+        "se" => "nw",
+        "u" => "d",
+        "d" => "u",
+        _ => return None,
+    })
+}
@@ -0,0 +1,119 @@
+//! Offers on the sales channel, such as `Bob [sales]: wts long sword 5k,
+//! shield 800`, read into items and prices and kept in the database so
+//! `;;price <item>` can tell what an item usually goes for.
+
+use crate::{
+    bc::Frame,
+    channel,
+    db::{Db, Event},
+};
+
+pub use crate::db::Side;
+
+/// The channel offers are read from.
+const SALES: &str = "sales";
+
+/// An item with a price in an offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub side: Side,
+    pub item: String,
+    /// In gold.
+    pub price: u64,
+}
+
+/// The priced items of a sales message such as `wts sword 5k, shield 800`.
+/// Items without a price are left out.
+pub fn parse(text: &str) -> Vec<Offer> {
+    let text = text.trim();
+    let lower = text.to_ascii_lowercase();
+    let side = [
+        ("wts ", Side::Sell),
+        ("selling ", Side::Sell),
+        ("wtb ", Side::Buy),
+        ("buying ", Side::Buy),
+    ]
+    .into_iter()
+    .find(|(prefix, _)| lower.starts_with(prefix));
+    let (rest, side) = match side {
+        Some((prefix, side)) => (&text[prefix.len()..], side),
+        None => return Vec::new(),
+    };
+    rest.split([',', ';'])
+        .filter_map(|part| parse_item(part, side))
+        .collect()
+}
+
+/// An item followed by its price, as in `long sword for 5k`.
+fn parse_item(part: &str, side: Side) -> Option<Offer> {
+    let mut words: Vec<&str> = part.split_whitespace().collect();
+    if matches!(words.last(), Some(&("gold" | "gp" | "g"))) {
+        words.pop();
+    }
+    let price = parse_price(words.pop()?)?;
+    if matches!(words.last(), Some(&("for" | "@" | "at"))) {
+        words.pop();
+    }
+    let item = words.join(" ").to_lowercase();
+    if item.is_empty() {
+        return None;
+    }
+    Some(Offer { side, item, price })
+}
+
+/// A price such as `800`, `5k`, `1.5k`, `2m` or `300gp`.
+pub fn parse_price(word: &str) -> Option<u64> {
+    let word = word.to_ascii_lowercase();
+    let word = word
+        .trim_start_matches('@')
+        .trim_end_matches("gp")
+        .trim_end_matches('g');
+    let (number, scale) = match word.strip_suffix('k') {
+        Some(number) => (number, 1_000.0),
+        None => match word.strip_suffix('m') {
+            Some(number) => (number, 1_000_000.0),
+            None => (word, 1.0),
+        },
+    };
+    if number.is_empty() || !number.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let number: f64 = number.parse().ok()?;
+    Some((number * scale).round() as u64)
+}
+
+/// `price` written short, as in `5k` or `1.5m`.
+pub fn format_price(price: u64) -> String {
+    match price {
+        p if p >= 1_000_000 && p % 100_000 == 0 => short(p as f64 / 1_000_000.0, "m"),
+        p if p >= 1_000 && p % 100 == 0 => short(p as f64 / 1_000.0, "k"),
+        p => p.to_string(),
+    }
+}
+
+fn short(n: f64, suffix: &str) -> String {
+    let n = format!("{:.1}", n);
+    format!("{}{}", n.trim_end_matches(".0"), suffix)
+}
+
+/// Keep the offers of `frame` in `db` if it is a sales channel message.
+pub fn record(frame: &Frame, db: &Db) {
+    match frame.channel() {
+        Some(channel) if channel.name == SALES => {}
+        _ => return,
+    }
+    let text = channel::message_text(frame);
+    let (seller, said) = channel::split_speaker(&text);
+    let seller = match seller {
+        Some(seller) => seller,
+        None => return,
+    };
+    for offer in parse(said) {
+        db.send(Event::Offer {
+            side: offer.side,
+            item: offer.item,
+            price: offer.price,
+            seller: seller.to_string(),
+        });
+    }
+}
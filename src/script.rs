@@ -0,0 +1,287 @@
+use std::path::Path;
+
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, Table, Value};
+
+use crate::{
+    bc::{ControlCode, Frame},
+    mapper::{self, Payload},
+    session::Session,
+};
+
+/// Lua instructions a hook, or the script as it loads, may run before it
+/// is aborted, so a script stuck in a loop does not stall the session.
+const MAX_INSTRUCTIONS: u32 = 10_000_000;
+
+/// What a hook decided to do with a frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Render the frame as usual.
+    Default,
+    /// Write these bytes instead of the frame.
+    Replace(Vec<u8>),
+    /// The hook took care of the frame, write nothing.
+    Handled,
+}
+
+/// A user Lua script loaded for one session.
+///
+/// The script may define any of these hooks:
+///
+/// - `on_text(text)` for plain text between control codes,
+/// - `on_prompt(text)` for `spec_prompt` messages,
+/// - `on_mapper(fields, payload)` for `BAT_MAPPER` messages, with the
+///   payload split on `;;` as [`Payload`] reads it,
+/// - `on_map(text, rows, cols)` for the map around the player, with its
+///   size,
+/// - `on_control_code(code)` for every other top level control code, as a
+///   table `{ id = 10, attr = "chan_sales", children = { "text", { id = 20,
+///   ... } } }`.
+///
+/// Returning a string replaces the frame's output, returning `true`
+/// suppresses it and returning nothing keeps it. Hooks can send commands to
+/// the server with `bcproxy.send(command)`. A hook running more than
+/// [`MAX_INSTRUCTIONS`] fails and the frame is rendered as usual.
+pub struct Script {
+    lua: Lua,
+}
+
+struct Outbox(Vec<String>);
+
+impl Script {
+    pub fn load(path: &Path) -> mlua::Result<Self> {
+        let source = std::fs::read(path).map_err(mlua::Error::external)?;
+        let lua = Lua::new();
+
+        lua.set_app_data(Outbox(Vec::new()));
+        let api = lua.create_table()?;
+        api.set(
+            "send",
+            lua.create_function(|lua, command: String| {
+                if let Some(mut outbox) = lua.app_data_mut::<Outbox>() {
+                    outbox.0.push(command);
+                }
+                Ok(())
+            })?,
+        )?;
+        lua.globals().set("bcproxy", api)?;
+
+        limit(&lua);
+        lua.load(source).set_name(path.to_string_lossy()).exec()?;
+        Ok(Self { lua })
+    }
+
+    pub fn on_frame(&self, frame: &Frame, session: &mut Session) -> Outcome {
+        let outcome = match frame {
+            Frame::Text(text) => self.call("on_text", |lua| lua.create_string(text)),
+            Frame::Prompt(code) => {
+                self.call_or_code("on_prompt", code, |lua| lua.create_string(code.text()))
+            }
+            Frame::Code(code) if code.id == 99 && code.text().starts_with(b"BAT_MAPPER;;") => self
+                .call_or_code("on_mapper", code, |lua| {
+                    let payload = code.text();
+                    let fields = lua.create_table()?;
+                    // The tags are kept as the first and last field.
+                    if let Some(parsed) = Payload::parse(&payload) {
+                        fields.push(lua.create_string(mapper::TAG)?)?;
+                        for field in parsed.fields() {
+                            fields.push(lua.create_string(field)?)?;
+                        }
+                        if parsed.is_closed() {
+                            fields.push(lua.create_string(mapper::TAG)?)?;
+                        }
+                    }
+                    Ok((fields, lua.create_string(&payload)?))
+                }),
+            Frame::Map(map) => self.call_or_code("on_map", &map.code, |lua| {
+                Ok((lua.create_string(map.code.text())?, map.rows, map.cols))
+            }),
+            Frame::Code(code) | Frame::LoginResult(code) => {
+                self.call("on_control_code", |lua| code_table(lua, code))
+            }
+        };
+
+        if let Some(mut outbox) = self.lua.app_data_mut::<Outbox>() {
+            for command in outbox.0.drain(..) {
+                session.send_command(&command);
+            }
+        }
+
+        outcome
+    }
+
+    /// Call the specific hook `name` if the script defines it, otherwise
+    /// `on_control_code`.
+    fn call_or_code<'lua, A, F>(&'lua self, name: &str, code: &ControlCode, args: F) -> Outcome
+    where
+        A: IntoLuaMulti<'lua>,
+        F: FnOnce(&'lua Lua) -> mlua::Result<A>,
+    {
+        if self.hook(name).is_some() {
+            self.call(name, args)
+        } else {
+            self.call("on_control_code", |lua| code_table(lua, code))
+        }
+    }
+
+    fn call<'lua, A, F>(&'lua self, name: &str, args: F) -> Outcome
+    where
+        A: IntoLuaMulti<'lua>,
+        F: FnOnce(&'lua Lua) -> mlua::Result<A>,
+    {
+        let hook = match self.hook(name) {
+            Some(hook) => hook,
+            None => return Outcome::Default,
+        };
+
+        limit(&self.lua);
+        match args(&self.lua).and_then(|args| hook.call::<_, Value>(args)) {
+            Ok(Value::String(s)) => Outcome::Replace(s.as_bytes().to_vec()),
+            Ok(Value::Boolean(true)) => Outcome::Handled,
+            Ok(_) => Outcome::Default,
+            Err(e) => {
+                tracing::warn!("{} failed: {}", name, e);
+                Outcome::Default
+            }
+        }
+    }
+
+    fn hook(&self, name: &str) -> Option<Function<'_>> {
+        self.lua.globals().get(name).ok()
+    }
+}
+
+/// Give the next call [`MAX_INSTRUCTIONS`] to run, setting the hook again
+/// restarting its count.
+fn limit(lua: &Lua) {
+    let triggers = HookTriggers::new().every_nth_instruction(MAX_INSTRUCTIONS);
+    lua.set_hook(triggers, |_, _| {
+        Err(mlua::Error::runtime(format!(
+            "ran more than {} instructions",
+            MAX_INSTRUCTIONS
+        )))
+    });
+}
+
+fn code_table<'lua>(lua: &'lua Lua, code: &ControlCode) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("id", code.id)?;
+    if let Some(attr) = &code.attr {
+        table.set("attr", lua.create_string(attr)?)?;
+    }
+
+    let children = lua.create_table()?;
+    for (i, frame) in code.body.iter().enumerate() {
+        match frame {
+            Frame::Text(text) => children.set(i + 1, lua.create_string(text)?)?,
+            Frame::Code(child) | Frame::Prompt(child) | Frame::LoginResult(child) => {
+                children.set(i + 1, code_table(lua, child)?)?
+            }
+            Frame::Map(map) => children.set(i + 1, code_table(lua, &map.code)?)?,
+        }
+    }
+    table.set("children", children)?;
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn script(name: &str, source: &str) -> Script {
+        let path = std::env::temp_dir().join(format!(
+            "bcproxy-script-{}-{}.lua",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, source).unwrap();
+        let script = Script::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        script.unwrap()
+    }
+
+    fn code(id: u8, attr: &'static str, body: Vec<Frame>) -> ControlCode {
+        ControlCode::new(id, Some(attr), body)
+    }
+
+    #[test]
+    fn control_codes_are_given_as_a_tree() {
+        let script = script(
+            "tree",
+            r#"
+            function on_control_code(code)
+              local color = code.children[2]
+              return table.concat({ code.id, code.attr, code.children[1],
+                color.id, color.attr, color.children[1] }, " ")
+            end
+            "#,
+        );
+        let frame = Frame::Code(code(
+            10,
+            "chan_sales",
+            vec![
+                Frame::text("Bob:"),
+                Frame::Code(code(20, "ff0000", vec![Frame::text("sword")])),
+            ],
+        ));
+        let mut session = Session::new(&Config::default(), None);
+        assert_eq!(
+            script.on_frame(&frame, &mut session),
+            Outcome::Replace(b"10 chan_sales Bob: 20 ff0000 sword".to_vec())
+        );
+    }
+
+    #[test]
+    fn hooks_replace_suppress_or_keep_codes() {
+        let script = script(
+            "outcome",
+            r#"
+            function on_control_code(code)
+              if code.id == 10 then return true end
+              if code.id == 11 then return "cleared" end
+            end
+            "#,
+        );
+        let mut session = Session::new(&Config::default(), None);
+        let mut outcome = |frame: Frame| script.on_frame(&frame, &mut session);
+        assert_eq!(
+            outcome(Frame::Code(code(10, "chan_sales", vec![]))),
+            Outcome::Handled
+        );
+        assert_eq!(
+            outcome(Frame::Code(code(11, "", vec![]))),
+            Outcome::Replace(b"cleared".to_vec())
+        );
+        assert_eq!(
+            outcome(Frame::LoginResult(code(5, "", vec![]))),
+            Outcome::Default
+        );
+        // Prompts go to it too without an `on_prompt`.
+        assert_eq!(
+            outcome(Frame::Prompt(code(10, "spec_prompt", vec![]))),
+            Outcome::Handled
+        );
+        assert_eq!(outcome(Frame::text("text")), Outcome::Default);
+    }
+
+    #[test]
+    fn hooks_send_commands_and_survive_errors() {
+        let script = script(
+            "send",
+            r#"
+            function on_control_code(code)
+              bcproxy.send("look")
+              if code.id == 11 then error("no") end
+              return true
+            end
+            "#,
+        );
+        let mut session = Session::new(&Config::default(), None);
+        assert_eq!(
+            script.on_frame(&Frame::Code(code(11, "", vec![])), &mut session),
+            Outcome::Default
+        );
+        assert_eq!(session.to_server, [b"look\n".to_vec()]);
+    }
+}
@@ -0,0 +1,829 @@
+use std::{
+    future::Future,
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{info, info_span, warn, Instrument};
+
+#[cfg(unix)]
+use crate::upgrade::{self, Sockets};
+use crate::{
+    auth::{self, AuthConfig, Lockouts},
+    bc::Frame,
+    chain,
+    channel::ChannelPort,
+    config::{Config, Listen},
+    db::Db,
+    io::{Connect, FrameHook},
+    listener::{self, Listener, Stream, ACCEPT_RETRY},
+    logging,
+    login::{self, Login},
+    map::MapPort,
+    middleware::{Middleware, MiddlewareFactory},
+    mirror,
+    party::PartyPort,
+    session::{Session, Sessions},
+    state,
+    style::Profile,
+    tap::Tap,
+    telnet::{self, Segment},
+    websocket,
+};
+
+/// Longest line read when a client picks a profile.
+const MAX_PROFILE_NAME: usize = 256;
+/// How long the sessions get to save their state on shutdown.
+const SAVE_WAIT: Duration = Duration::from_secs(5);
+
+/// Accepts clients and proxies each of them to the remote server.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// batproxy_rs::ProxyServer::builder()
+///     .listen("127.0.0.1:7788")
+///     .remote("batmud.bat.org:2023")
+///     .frame_hook(|frame, _session| Some(frame))
+///     .build()?
+///     .run()
+///     .await
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ProxyServer {
+    /// The config as last loaded. Listeners stay on the addresses of the
+    /// first one.
+    config: Arc<RwLock<Arc<Config>>>,
+    config_path: Option<Arc<PathBuf>>,
+    db: Option<Db>,
+    hooks: Arc<[FrameHook]>,
+    middleware: Arc<[MiddlewareFactory]>,
+    sessions: Sessions,
+    channel_port: Option<ChannelPort>,
+    map_port: Option<MapPort>,
+    party_port: Option<PartyPort>,
+    taps: Arc<[Tap]>,
+    profiles: Arc<[ProfileServer]>,
+    lockouts: Lockouts,
+    serving: Arc<watch::Sender<Serving>>,
+    #[cfg(unix)]
+    sockets: Sockets,
+}
+
+/// Whether the listeners accept clients, see [`ProxyServer::take_over`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Serving {
+    Yes,
+    /// Not while a handover is under way.
+    Paused,
+    /// Never again, a new proxy has the listeners.
+    HandedOver,
+}
+
+/// A server connection for a session, with the state of the session it
+/// was handed over with, if it was.
+struct Outbound {
+    stream: TcpStream,
+    adopted: Option<String>,
+}
+
+/// The database of a config profile, its config is in the main one.
+struct ProfileServer {
+    name: String,
+    db: Option<Db>,
+}
+
+#[derive(Default)]
+pub struct ProxyServerBuilder {
+    config: Config,
+    config_path: Option<PathBuf>,
+    db: Option<Db>,
+    hooks: Vec<FrameHook>,
+    middleware: Vec<MiddlewareFactory>,
+}
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder {
+        ProxyServerBuilder::default()
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let config = self.config();
+        let mut listeners = Vec::new();
+        for listen in &config.listen {
+            listeners.push((self.bind(&listen.addr).await?, listen.clone()));
+        }
+        if let Some(addr) = &config.api_listen {
+            let api = listener::local_only("api_listen", addr, self.bind(addr).await?)?;
+            tokio::spawn(crate::api::serve(
+                api,
+                self.db.clone(),
+                self.sessions.clone(),
+            ));
+        }
+
+        if let Some(addr) = &config.admin_listen {
+            let admin = listener::local_only("admin_listen", addr, self.bind(addr).await?)?;
+            tokio::spawn(crate::admin::serve(admin, self.clone()));
+        }
+
+        if let (Some(addr), Some(port)) = (&config.channel_listen, &self.channel_port) {
+            let channels = self.bind_tcp(addr).await?;
+            tokio::spawn(port.clone().serve(channels));
+        }
+
+        for tap in self.taps.iter() {
+            tokio::spawn(tap.clone().serve());
+        }
+
+        if let (Some(addr), Some(port)) = (&config.map_listen, &self.map_port) {
+            let maps = self.bind_tcp(addr).await?;
+            tokio::spawn(port.clone().serve(maps));
+        }
+
+        if let (Some(addr), Some(port)) = (&config.party_listen, &self.party_port) {
+            let party = listener::local_only("party_listen", addr, self.bind(addr).await?)?;
+            tokio::spawn(port.clone().serve(party));
+        }
+
+        if let Some(addr) = &config.websocket_listen {
+            let websockets = self.bind_tcp(addr).await?;
+            tokio::spawn(self.clone().run_websockets(websockets));
+        }
+
+        for (i, profile) in config.profiles.iter().enumerate() {
+            for listen in &profile.config.listen {
+                let profiles = self.bind(&listen.addr).await?;
+                tokio::spawn(self.clone().accept(profiles, listen.clone(), Some(i)));
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &config.upgrade_socket {
+            // A path even without a directory in it.
+            let addr = std::path::Path::new(".").join(path);
+            let upgrades = self.bind(&addr.to_string_lossy()).await?;
+            tokio::spawn(self.clone().serve_upgrades(upgrades));
+        }
+        #[cfg(unix)]
+        self.sockets.close_unused();
+
+        let (listener, listen) = listeners.remove(0);
+        for (other, listen) in listeners {
+            tokio::spawn(self.clone().accept(other, listen, None));
+        }
+        // Returns once the listeners were handed over to a new proxy.
+        self.accept(listener, listen, None).await;
+        Ok(())
+    }
+
+    /// Take over the listeners and the server connections of the proxy
+    /// running on the config's `upgrade_socket`, before [`run`](Self::run).
+    /// Clients then carry on with its sessions on the same connections.
+    #[cfg(unix)]
+    pub async fn take_over(&self) -> io::Result<()> {
+        let path = self.config().upgrade_socket.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upgrade_socket is not set in the config",
+            )
+        })?;
+        self.sockets.take_over(&path).await
+    }
+
+    /// Listen on `addr`, on the listener the previous proxy handed over if
+    /// there is one.
+    async fn bind(&self, addr: &str) -> io::Result<Listener> {
+        #[cfg(unix)]
+        if let Some(listener) = self.sockets.listener(addr)? {
+            return Ok(listener);
+        }
+        let listener = listener::bind(addr).await?;
+        #[cfg(unix)]
+        self.sockets.keep(addr, &listener)?;
+        Ok(listener)
+    }
+
+    /// Like [`bind`](Self::bind), for a TCP address.
+    async fn bind_tcp(&self, addr: &str) -> io::Result<TcpListener> {
+        #[cfg(unix)]
+        if let Some(listener) = self.sockets.tcp_listener(addr)? {
+            return Ok(listener);
+        }
+        let listener = TcpListener::bind(addr).await?;
+        #[cfg(unix)]
+        self.sockets.keep(addr, &listener)?;
+        Ok(listener)
+    }
+
+    /// Hand over to the new proxies connecting to `listener`, until one
+    /// got everything.
+    #[cfg(unix)]
+    async fn serve_upgrades(self, listener: Listener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("accepting a new proxy failed: {}", e);
+                    sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+            let stream = match stream {
+                Stream::Unix(stream) => stream,
+                Stream::Tcp(_) => continue,
+            };
+            match self.hand_over(stream).await {
+                Ok(()) => return,
+                Err(e) => warn!("handing over to the new proxy failed: {}", e),
+            }
+        }
+    }
+
+    /// Stop accepting clients and stop the sessions, and hand the
+    /// listeners and server connections over to the new proxy on `stream`.
+    /// Once it has them the sessions end, otherwise they go on.
+    #[cfg(unix)]
+    async fn hand_over(&self, stream: tokio::net::UnixStream) -> io::Result<()> {
+        upgrade::same_user(&stream)?;
+        self.serving.send_replace(Serving::Paused);
+        let sent = async {
+            let connections = timeout(SAVE_WAIT, self.sessions.hand_over())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "sessions did not stop"))?;
+            self.sockets.hand_over(stream, &connections).await?;
+            Ok(connections.len())
+        };
+        match sent.await {
+            Ok(sessions) => {
+                info!(sessions, "handed over to the new proxy");
+                self.serving.send_replace(Serving::HandedOver);
+                self.sessions.kick_all();
+                Ok(())
+            }
+            Err(e) => {
+                self.sessions.resume();
+                self.serving.send_replace(Serving::Yes);
+                Err(e)
+            }
+        }
+    }
+
+    /// The next result of `accept` while clients are accepted, `None` once
+    /// they no longer are.
+    async fn next_client<T, F>(&self, mut accept: impl FnMut() -> F) -> Option<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        let mut serving = self.serving.subscribe();
+        loop {
+            let state = *serving.borrow_and_update();
+            match state {
+                Serving::HandedOver => return None,
+                Serving::Paused => {}
+                Serving::Yes => tokio::select! {
+                    accepted = accept() => match accepted {
+                        Ok(accepted) => return Some(accepted),
+                        Err(e) => {
+                            warn!("accepting a client failed: {}", e);
+                            sleep(ACCEPT_RETRY).await;
+                            continue;
+                        }
+                    },
+                    _ = serving.changed() => continue,
+                },
+            }
+            serving.changed().await.ok()?;
+        }
+    }
+
+    /// The server connection for a new session of `profile`: one the
+    /// previous proxy handed over, or else a new one.
+    async fn outbound(&self, profile: Option<&str>, config: &Config) -> io::Result<Outbound> {
+        #[cfg(unix)]
+        if let Some((stream, state)) = self.sockets.adopt(profile)? {
+            return Ok(Outbound {
+                stream,
+                adopted: Some(state),
+            });
+        }
+        let stream = crate::io::connect(&config.remote, config.connect_timeout).await?;
+        Ok(Outbound {
+            stream,
+            adopted: None,
+        })
+    }
+
+    /// Have the sessions save their state for the next ones of their
+    /// profiles, before the process exits, giving them a few seconds.
+    pub async fn save_sessions(&self) {
+        if timeout(SAVE_WAIT, self.sessions.save()).await.is_err() {
+            warn!("sessions did not save their state in time");
+        }
+    }
+
+    /// Commit what was sent to the databases, before the process exits.
+    pub fn flush(&self) {
+        let profiles = self
+            .profiles
+            .iter()
+            .filter_map(|profile| profile.db.as_ref());
+        for db in self.db.iter().chain(profiles) {
+            db.flush();
+        }
+    }
+
+    /// The config as last loaded.
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub(crate) fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// Read the config file again. New sessions start with all of it,
+    /// running ones take its triggers, aliases, highlights and channel
+    /// settings, and the log level changes. A config that does not parse is
+    /// rejected and the old one kept. Returns the settings that only change
+    /// on a restart, such as listen addresses, if they were changed.
+    pub fn reload(&self) -> io::Result<Vec<&'static str>> {
+        let path = self.config_path.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the config was not read from a file",
+            )
+        })?;
+        let config = Config::load(path)?;
+        let old = self.config();
+        let names = |config: &Config| -> Vec<String> {
+            config.profiles.iter().map(|p| p.name.clone()).collect()
+        };
+        if names(&config) != names(&old) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "profiles were added, removed or renamed, which takes a restart",
+            ));
+        }
+        if logging::level().is_some() {
+            logging::set_level(&config.log.level)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let needs_restart = restart_settings(&old, &config);
+        for setting in &needs_restart {
+            warn!(setting, "changed in the config, takes a restart");
+        }
+        let config = Arc::new(config);
+        *self.config.write().unwrap() = config.clone();
+        self.sessions.reload(|summary| match &summary.profile {
+            Some(name) => match config.profiles.iter().find(|p| &p.name == name) {
+                Some(profile) => Arc::new(profile.config.clone()),
+                None => config.clone(),
+            },
+            None => config.clone(),
+        });
+        info!(path = %path.display(), "config reloaded");
+        Ok(needs_restart)
+    }
+
+    /// Proxy the clients of `listener` with `profile`, or the one they pick
+    /// if it is not given, and the settings of `listen`.
+    async fn accept(self, listener: Listener, listen: Listen, profile: Option<usize>) {
+        let ask_auth = listen
+            .auth
+            .unwrap_or_else(|| !listener.is_local().unwrap_or(false));
+        if ask_auth && !self.auth_config(profile).is_enabled() {
+            warn!(
+                addr = listen.addr,
+                "other machines can connect without a password"
+            );
+        }
+        while let Some((mut inbound, peer)) = self.next_client(|| listener.accept()).await {
+            let listen = listen.clone();
+            let server = self.clone();
+            tokio::spawn(async move {
+                if ask_auth {
+                    let auth = server.auth_config(profile);
+                    let lockouts = &server.lockouts;
+                    match auth::authenticate(&mut inbound, &peer, &auth, lockouts, true).await {
+                        Ok(true) => {}
+                        Ok(false) => return,
+                        Err(e) => return warn!(%peer, "authentication failed: {}", e),
+                    }
+                }
+                if let Some(mode) = listen.attach {
+                    if let Err(e) = mirror::serve(inbound, &peer, &server.sessions, mode).await {
+                        warn!(%peer, "attached client failed: {}", e);
+                    }
+                    return;
+                }
+                let profile = match profile {
+                    Some(i) => Some(i),
+                    None if server.profiles.is_empty() => None,
+                    None => match server.pick_profile(&mut inbound).await {
+                        Ok(profile) => profile,
+                        Err(e) => return warn!(%peer, "picking a profile failed: {}", e),
+                    },
+                };
+                let main = server.config();
+                let (config, db, name) = match profile {
+                    Some(i) => {
+                        let p = &server.profiles[i];
+                        (&main.profiles[i].config, p.db.clone(), Some(p.name.clone()))
+                    }
+                    None => (&*main, server.db.clone(), None),
+                };
+                let config = listen.apply(config);
+                match server.outbound(name.as_deref(), &config).await {
+                    Ok(outbound) => {
+                        server
+                            .proxy(inbound, outbound, peer.clone(), name, &config, db)
+                            .await
+                    }
+                    Err(e) => warn!(%peer, "failed to connect to {}: {}", config.remote, e),
+                }
+            });
+        }
+    }
+
+    /// The client authentication settings of `profile`, or of the main
+    /// config.
+    fn auth_config(&self, profile: Option<usize>) -> AuthConfig {
+        let config = self.config();
+        match profile {
+            Some(i) => config.profiles[i].config.auth.clone(),
+            None => config.auth.clone(),
+        }
+    }
+
+    /// Ask a client of the main listener which profile to use, `None` for
+    /// the main config.
+    async fn pick_profile(&self, inbound: &mut Stream) -> io::Result<Option<usize>> {
+        let names: Vec<&str> = self.profiles.iter().map(|p| p.name.as_str()).collect();
+        let question = format!(
+            "[bcproxy] profile ({}), enter for default: ",
+            names.join(", ")
+        );
+        let mut parser = telnet::Parser::new();
+        let mut line = Vec::new();
+        loop {
+            inbound.write_all(question.as_bytes()).await?;
+            line.clear();
+            // Telnet commands the client sends on its own are dropped, the
+            // session negotiates again once it starts.
+            while !line.ends_with(b"\n") {
+                let mut buf = [0; 256];
+                let n = inbound.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let mut segments = Vec::new();
+                parser.parse(&buf[..n], &mut segments);
+                for segment in segments {
+                    if let Segment::Data(data) = segment {
+                        line.extend_from_slice(&data);
+                    }
+                }
+                if line.len() > MAX_PROFILE_NAME {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+                }
+            }
+            let name = String::from_utf8_lossy(&line);
+            let name = name.trim();
+            if name.is_empty() {
+                return Ok(None);
+            }
+            if let Some(i) = self.profiles.iter().position(|p| p.name == name) {
+                return Ok(Some(i));
+            }
+            let reply = format!("[bcproxy] no profile `{}`\r\n", name);
+            inbound.write_all(reply.as_bytes()).await?;
+        }
+    }
+
+    /// Accept browser clients.
+    async fn run_websockets(self, listener: TcpListener) {
+        let ask_auth = !listener
+            .local_addr()
+            .is_ok_and(|addr| addr.ip().is_loopback());
+        while let Some((stream, peer)) = self.next_client(|| listener.accept()).await {
+            let server = self.clone();
+            let config = websocket_config(&self.config());
+            tokio::spawn(async move {
+                let origins = &config.websocket_origins;
+                let password = config.auth.is_enabled();
+                let (mut inbound, untrusted) =
+                    match websocket::accept(stream, origins, password).await {
+                        Ok(accepted) => accepted,
+                        Err(e) => return warn!(%peer, "websocket handshake failed: {}", e),
+                    };
+                if (ask_auth || untrusted) && password {
+                    let peer = peer.to_string();
+                    let lockouts = &server.lockouts;
+                    match auth::authenticate(&mut inbound, &peer, &config.auth, lockouts, false)
+                        .await
+                    {
+                        Ok(true) => {}
+                        Ok(false) => return,
+                        Err(e) => return warn!(%peer, "authentication failed: {}", e),
+                    }
+                }
+                match server.outbound(None, &config).await {
+                    Ok(outbound) => {
+                        let db = server.db.clone();
+                        server
+                            .proxy(inbound, outbound, peer.to_string(), None, &config, db)
+                            .await
+                    }
+                    Err(e) => warn!(%peer, "failed to connect to {}: {}", config.remote, e),
+                }
+            });
+        }
+    }
+
+    async fn proxy<C>(
+        &self,
+        inbound: C,
+        outbound: Outbound,
+        peer: String,
+        profile: Option<String>,
+        config: &Config,
+        db: Option<Db>,
+    ) where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut session = Session::new(config, db);
+        let listing = self.sessions.register(peer.clone(), profile.clone());
+        let span = info_span!(
+            "session",
+            id = listing.id(),
+            peer = %peer,
+            profile = profile.as_deref().unwrap_or("-"),
+        );
+        let kicked = listing.kicked();
+        session.listing = Some(listing);
+        session.profile = profile;
+        match &outbound.adopted {
+            Some(adopted) => state::adopt(adopted, &mut session),
+            None => state::restore(&mut session),
+        }
+        let run = self.run_session(inbound, outbound, config, session);
+        async {
+            tokio::select! {
+                () = run => {}
+                () = kicked.notified() => info!("session kicked"),
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn run_session<C>(
+        &self,
+        mut inbound: C,
+        outbound: Outbound,
+        config: &Config,
+        mut session: Session,
+    ) where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        info!("client connected");
+        let Outbound {
+            stream: mut outbound,
+            adopted,
+        } = outbound;
+        chain::start(&mut session);
+        if config.login.is_enabled() {
+            match Login::new(&config.login, config.bc_mode).await {
+                Ok(login) => {
+                    session.login = Some(login);
+                    // A connection handed over is logged in already.
+                    if adopted.is_none() {
+                        login::start(&mut session);
+                    }
+                }
+                Err(e) => session.notify(&format!("auto-login: {}", e)),
+            }
+        }
+        // The server connection is only kept to hand over if there is a
+        // socket for upgrades, a copy of it keeps it open.
+        #[cfg(unix)]
+        let slot = match (&self.config().upgrade_socket, &session.listing) {
+            (Some(_), Some(listing)) => Some(listing.server()),
+            _ => None,
+        };
+        #[cfg(unix)]
+        if let Some(slot) = &slot {
+            slot.set(&outbound);
+        }
+        session.channels.port = self.channel_port.clone();
+        session.map_port = self.map_port.clone();
+        session.party_port = self.party_port.clone();
+        session.taps = self.taps.clone();
+        let result = if config.reconnect.enabled {
+            let (remote, timeout) = (config.remote.clone(), config.connect_timeout);
+            let connect: Connect<TcpStream> = Box::new(move || {
+                let remote = remote.clone();
+                #[cfg(unix)]
+                let slot = slot.clone();
+                Box::pin(async move {
+                    let stream = crate::io::connect(&remote, timeout).await?;
+                    #[cfg(unix)]
+                    if let Some(slot) = &slot {
+                        slot.set(&stream);
+                    }
+                    Ok(stream)
+                })
+            });
+            crate::io::proxy_reconnecting(
+                outbound,
+                connect,
+                &mut inbound,
+                config,
+                session,
+                &self.hooks,
+                &self.middleware,
+            )
+            .await
+        } else {
+            crate::io::proxy_bidirection(
+                &mut outbound,
+                &mut inbound,
+                config,
+                session,
+                &self.hooks,
+                &self.middleware,
+            )
+            .await
+        };
+        match result {
+            Err(e) => warn!("session failed: {}", e),
+            Ok((x, y)) => info!(to_client = x, to_server = y, "session ended"),
+        }
+    }
+}
+
+/// The config of browser clients. They get JSON output and no telnet.
+fn websocket_config(config: &Config) -> Arc<Config> {
+    let mut config = config.clone();
+    config.output_style = Profile::Json;
+    config.client_negotiation = false;
+    config.plain_output = false;
+    Arc::new(config)
+}
+
+/// The settings that differ between `old` and `new` but only change on a
+/// restart.
+fn restart_settings(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    let mut check = |name, differs| {
+        if differs {
+            changed.push(name);
+        }
+    };
+    check("listen", old.listen != new.listen);
+    check("api_listen", old.api_listen != new.api_listen);
+    check("admin_listen", old.admin_listen != new.admin_listen);
+    check("upgrade_socket", old.upgrade_socket != new.upgrade_socket);
+    check(
+        "websocket_listen",
+        old.websocket_listen != new.websocket_listen,
+    );
+    check("channel_listen", old.channel_listen != new.channel_listen);
+    check("map_listen", old.map_listen != new.map_listen);
+    check("party_listen", old.party_listen != new.party_listen);
+    check("tap", old.taps != new.taps);
+    check("database", old.database != new.database);
+    check("log_format", old.log.format != new.log.format);
+    let profile_listen = old
+        .profiles
+        .iter()
+        .zip(&new.profiles)
+        .any(|(old, new)| old.config.listen != new.config.listen);
+    check("profile listen", profile_listen);
+    let profile_database = old
+        .profiles
+        .iter()
+        .zip(&new.profiles)
+        .any(|(old, new)| old.config.database != new.config.database);
+    check("profile database", profile_database);
+    changed
+}
+
+impl ProxyServerBuilder {
+    /// Start from `config` instead of the defaults. Settings made before
+    /// are replaced.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The file `config` was read from, read again by
+    /// [`ProxyServer::reload`].
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Listen on `addr` only, replacing the addresses of the config.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.config.listen = vec![Listen::new(addr)];
+        self
+    }
+
+    pub fn remote(mut self, addr: impl Into<String>) -> Self {
+        self.config.remote = addr.into();
+        self
+    }
+
+    /// Store mapper data in `db` rather than the config's database.
+    pub fn database(mut self, db: Db) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Run `hook` on every frame of server output after the Lua script.
+    /// Returning `None` drops the frame.
+    pub fn frame_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(Frame, &mut Session) -> Option<Frame> + Send + Sync + 'static,
+    {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Add a middleware layer after the configured ones. `factory` creates
+    /// it for each session.
+    pub fn middleware<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(&Config) -> Box<dyn Middleware> + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(factory));
+        self
+    }
+
+    /// Open the config's database unless one was given, and those of
+    /// profiles that use another one.
+    pub fn build(self) -> io::Result<ProxyServer> {
+        let db = match (self.db, &self.config.database) {
+            (Some(db), _) => Some(db),
+            (None, Some(path)) => {
+                Some(Db::open_batched(path, self.config.db_batch).map_err(io::Error::other)?)
+            }
+            (None, None) => None,
+        };
+        let profiles = self
+            .config
+            .profiles
+            .iter()
+            .map(|profile| {
+                let db = match &profile.config.database {
+                    Some(path) if profile.config.database != self.config.database => Some(
+                        Db::open_batched(path, profile.config.db_batch)
+                            .map_err(io::Error::other)?,
+                    ),
+                    Some(_) => db.clone(),
+                    None => None,
+                };
+                Ok(ProfileServer {
+                    name: profile.name.clone(),
+                    db,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let channel_port = self
+            .config
+            .channel_listen
+            .as_ref()
+            .map(|_| ChannelPort::new());
+        let map_port = self.config.map_listen.as_ref().map(|_| MapPort::new());
+        let party_port = self.config.party_listen.as_ref().map(|_| PartyPort::new());
+        let taps = self.config.taps.iter().cloned().map(Tap::new).collect();
+        Ok(ProxyServer {
+            config: Arc::new(RwLock::new(Arc::new(self.config))),
+            config_path: self.config_path.map(Arc::new),
+            db,
+            hooks: self.hooks.into(),
+            middleware: self.middleware.into(),
+            sessions: Sessions::default(),
+            channel_port,
+            map_port,
+            party_port,
+            taps,
+            profiles,
+            lockouts: Lockouts::default(),
+            serving: Arc::new(watch::Sender::new(Serving::Yes)),
+            #[cfg(unix)]
+            sockets: Sockets::default(),
+        })
+    }
+}
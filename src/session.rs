@@ -0,0 +1,736 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+
+#[cfg(unix)]
+use crate::upgrade::{Connection, ServerSlot};
+use crate::{
+    action::{ActionStatus, Countdown},
+    capability::Capabilities,
+    catalog::{CodeStats, UnknownCodes},
+    channel::Channels,
+    color::ColorMode,
+    config::Config,
+    db::Db,
+    death::Corpse,
+    effect::Effects,
+    exp::ExpTracker,
+    history::History,
+    inventory::Inventory,
+    link::{GameLinks, LinkStyle},
+    login::{Login, LoginState},
+    map::{MapPort, MapRender},
+    mapper::{Location, Room},
+    mirror::{self, Attach},
+    notifier::Notifier,
+    party::{PartyPort, PartyStatus},
+    queue::CommandQueue,
+    style::Profile,
+    tap::Tap,
+    target::Target,
+    telnet::{self, ClientInfo},
+    tick::Tick,
+    vitals::Vitals,
+};
+
+/// What to do in a session with the result of work done off it.
+type Done = Box<dyn FnOnce(&mut Session) + Send>;
+
+/// Output the proxy sends to the client on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToClient {
+    /// A line shown in the output style of the session.
+    Message(String),
+    /// Bytes written as they are, such as telnet negotiation.
+    Raw(Vec<u8>),
+}
+
+/// State shared by both directions of a proxied connection.
+pub struct Session {
+    /// Commands the proxy sends to the server on its own, one per entry.
+    pub to_server: VecDeque<Vec<u8>>,
+    /// Output from the proxy itself to the client.
+    pub to_client: VecDeque<ToClient>,
+    pub db: Option<Db>,
+    /// The config profile of the session, if not the main one.
+    pub profile: Option<String>,
+    /// The room the mapper last reported.
+    pub last_room: Option<Room>,
+    /// Where the player last died, see `;;corpse`.
+    pub corpse: Option<Corpse>,
+    /// What the player carries and wears, see `;;inv`.
+    pub inventory: Inventory,
+    /// Where the player last was on the outworld map.
+    pub location: Option<Location>,
+    /// Whether the keepalive command is sent when the client is idle.
+    pub keepalive: bool,
+    /// BC features the server has used so far.
+    pub capabilities: Capabilities,
+    /// Control codes the proxy does not know that the server sent.
+    pub unknown_codes: UnknownCodes,
+    /// Every control code the server sent, counted by id.
+    pub code_stats: CodeStats,
+    /// The colors the client can show.
+    pub color_mode: ColorMode,
+    /// Whether output to the client is turned into plain text.
+    pub plain: bool,
+    /// Whether output is wrapped at the client's window width.
+    pub wrap: bool,
+    /// Whether lines to the client start with a timestamp.
+    pub timestamps: bool,
+    /// The server echoes what is typed (telnet `WILL ECHO`), as it does at
+    /// password prompts. Lines typed meanwhile are secret: they go to the
+    /// server as they are, never taken for proxy commands or aliases.
+    pub server_echo: bool,
+    /// The telnet options the server sent `DO` or `DONT` for.
+    pub server_options: Vec<u8>,
+    /// The telnet options the proxy asked the client about itself. The
+    /// client's answers are for the server only if it asked too.
+    pub client_options: Vec<u8>,
+    /// How control codes are written to the client.
+    pub output_style: Profile,
+    /// How hyperlinks are written to the client.
+    pub links: LinkStyle,
+    /// The in-game links of the current room, for `;;link`.
+    pub game_links: GameLinks,
+    /// How the map around the player is drawn.
+    pub map_render: MapRender,
+    /// Where maps go instead of the client, if `map_listen` is set.
+    pub map_port: Option<MapPort>,
+    /// What party members are told of the player.
+    pub party: PartyStatus,
+    /// Where the party status goes, if `party_listen` is set.
+    pub party_port: Option<PartyPort>,
+    /// The taps server output is written to.
+    pub taps: Arc<[Tap]>,
+    /// What the client told about its terminal.
+    pub client: ClientInfo,
+    /// The last prompt as sent to the client, shown again after the proxy's
+    /// own lines.
+    pub prompt: Option<Vec<u8>>,
+    /// The spell or skill under way.
+    pub action: Option<ActionStatus>,
+    /// How the rounds left of the action are shown.
+    pub countdown: Countdown,
+    /// Experience gained and how fast.
+    pub exp: ExpTracker,
+    /// The monster the player last targeted.
+    pub target: Option<Target>,
+    /// Effects on the player and when they run out.
+    pub effects: Effects,
+    /// Whether safeguards may fire, see `#bc safety`.
+    pub safety: bool,
+    /// When the next tick is due.
+    pub tick: Tick,
+    /// How the time to the next tick is shown.
+    pub tick_countdown: Countdown,
+    /// How the session clock is shown.
+    pub clock: Countdown,
+    /// Steps of a walk still to be sent to the server.
+    pub walk: VecDeque<String>,
+    /// Time between the steps of a walk.
+    pub walk_delay: Duration,
+    /// Lines from the player waiting to go to the server.
+    pub commands: CommandQueue,
+    /// The lines the player typed, for `;;history`.
+    pub history: History,
+    /// Lines from clients attached read-write and lines recalled from the
+    /// history, taken between the lines of the session's own client.
+    pub attached_input: VecDeque<Vec<u8>>,
+    /// Posts webhooks for the configured events.
+    pub notifier: Notifier,
+    /// Where channel messages go and how they look.
+    pub channels: Channels,
+    /// Server output waiting for the client.
+    pub output_queue: QueueStats,
+    /// Traffic both ways and how fast the server answers.
+    pub stats: SessionStats,
+    /// The player sent `quit`, the server closing the connection is not
+    /// a reason to reconnect.
+    pub quit: bool,
+    /// A line turning BC mode on went to the server since its output last
+    /// looked.
+    pub bc_requested: bool,
+    /// A new proxy took over the server connection, the session stays
+    /// where it is until it ends.
+    pub handed_over: bool,
+    /// The server may be another bcproxy, asked on every connection, see
+    /// `chain`.
+    pub chain: bool,
+    /// Server output broke the protocol in `strict-disconnect` mode, the
+    /// session is closed.
+    pub protocol_error: Option<String>,
+    /// The auto-login, kept to log in again.
+    pub login: Option<Login>,
+    /// Whether the player is logged in on the current server connection.
+    pub login_state: LoginState,
+    /// The session's entry in the list of connected sessions, if it is in one.
+    pub listing: Option<Listing>,
+    /// Directory `#bc map export` writes to, off if not set.
+    pub export_dir: Option<PathBuf>,
+    // Work done off the session hands back here what to do with its result.
+    done: mpsc::UnboundedSender<Done>,
+    done_rx: mpsc::UnboundedReceiver<Done>,
+    queued: bool,
+    // The stats as last shown in the listing.
+    published: SessionStats,
+}
+
+impl Session {
+    pub fn new(config: &Config, db: Option<Db>) -> Self {
+        let (done, done_rx) = mpsc::unbounded_channel();
+        let mut session = Self {
+            to_server: VecDeque::new(),
+            to_client: VecDeque::new(),
+            db,
+            profile: None,
+            last_room: None,
+            corpse: None,
+            inventory: Inventory::new(config.inventory_headers.clone()),
+            location: None,
+            keepalive: true,
+            capabilities: Capabilities::default(),
+            unknown_codes: UnknownCodes::default(),
+            code_stats: CodeStats::default(),
+            color_mode: config.color_mode,
+            plain: config.plain_output,
+            wrap: config.wrap,
+            timestamps: config.timestamps,
+            server_echo: false,
+            server_options: Vec::new(),
+            client_options: Vec::new(),
+            output_style: config.output_style,
+            links: config.hyperlinks,
+            game_links: GameLinks::default(),
+            map_render: config.map_render,
+            map_port: None,
+            party: PartyStatus::new(config.party_name.as_ref().unwrap_or(&config.login.name)),
+            party_port: None,
+            taps: Arc::new([]),
+            client: ClientInfo::default(),
+            prompt: None,
+            action: None,
+            countdown: config.countdown,
+            exp: ExpTracker::new(config.exp_window),
+            target: None,
+            effects: Effects::default(),
+            safety: true,
+            tick: Tick::default(),
+            tick_countdown: config.tick_countdown,
+            clock: config.clock,
+            walk: VecDeque::new(),
+            walk_delay: config.walk_delay,
+            commands: CommandQueue::new(config.command_queue),
+            history: History::new(config.history_size),
+            attached_input: VecDeque::new(),
+            notifier: Notifier::new(config.notifiers.clone(), config.notifier_interval),
+            channels: Channels::new(config),
+            output_queue: QueueStats::default(),
+            stats: SessionStats::new(),
+            quit: false,
+            bc_requested: false,
+            handed_over: false,
+            chain: config.chain,
+            protocol_error: None,
+            login: None,
+            login_state: LoginState::default(),
+            listing: None,
+            export_dir: config.export_dir.clone(),
+            done,
+            done_rx,
+            queued: false,
+            published: SessionStats::new(),
+        };
+        // Telnet negotiation would get in the way of JSON output.
+        if config.client_negotiation && config.output_style.style().is_terminal() {
+            session.write_client(telnet::client_negotiation());
+            session.client_options = vec![telnet::NAWS, telnet::TTYPE];
+        }
+        session
+    }
+
+    pub fn send_command(&mut self, command: &str) {
+        let mut line = command.as_bytes().to_vec();
+        line.push(b'\n');
+        self.to_server.push_back(line);
+        self.queued = true;
+    }
+
+    /// Send raw bytes to the server, such as telnet negotiation.
+    pub fn write_server(&mut self, bytes: Vec<u8>) {
+        self.to_server.push_back(bytes);
+        self.queued = true;
+    }
+
+    /// Send `steps` to the server one by one, replacing any walk under way.
+    pub fn start_walk(&mut self, steps: Vec<String>) {
+        self.walk = steps.into();
+        self.queued = true;
+    }
+
+    /// Have both directions polled again, for lines queued in the session
+    /// or the server prompting for the next one.
+    pub fn wake(&mut self) {
+        self.queued = true;
+    }
+
+    /// Run `work`, such as a database query or a file write, on a thread
+    /// of its own rather than in the session, then `then` with its result
+    /// in the session.
+    pub fn in_background<R, W, T>(&self, work: W, then: T)
+    where
+        R: Send + 'static,
+        W: FnOnce() -> R + Send + 'static,
+        T: FnOnce(R, &mut Session) + Send + 'static,
+    {
+        let done = self.done.clone();
+        let work = move || {
+            let result = work();
+            let _ = done.send(Box::new(move |session: &mut Session| then(result, session)));
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(work)),
+            Err(_) => work(),
+        }
+    }
+
+    /// Take the results of work done in the background. The waker of `cx`
+    /// is woken when more are done.
+    pub fn poll_background(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some(done)) = self.done_rx.poll_recv(cx) {
+            done(self);
+        }
+    }
+
+    /// Show a line from the proxy to the client.
+    pub fn notify(&mut self, message: &str) {
+        self.to_client
+            .push_back(ToClient::Message(message.to_string()));
+        self.queued = true;
+    }
+
+    /// Send raw bytes to the client.
+    pub fn write_client(&mut self, bytes: Vec<u8>) {
+        self.to_client.push_back(ToClient::Raw(bytes));
+        self.queued = true;
+    }
+
+    /// Forget what only held on the server connection that was lost, so
+    /// the mapper does not link the first room after it to the last one.
+    pub fn reconnected(&mut self) {
+        self.last_room = None;
+        self.location = None;
+        self.action = None;
+        self.target = None;
+        self.walk.clear();
+        self.stats.reconnected();
+        self.set_login_state(LoginState::Unauthenticated);
+        if let Some(listing) = &self.listing {
+            listing.update(|summary| summary.room = None);
+        }
+    }
+
+    pub fn set_output_queue(&mut self, stats: QueueStats) {
+        if stats == self.output_queue {
+            return;
+        }
+        self.output_queue = stats;
+        if let Some(listing) = &self.listing {
+            listing.update(|summary| summary.output_queue = stats);
+        }
+    }
+
+    pub fn set_login_state(&mut self, state: LoginState) {
+        if state == self.login_state {
+            return;
+        }
+        tracing::info!(from = %self.login_state, to = %state, "login state");
+        self.login_state = state;
+        if let Some(listing) = &self.listing {
+            listing.update(|summary| summary.login_state = state);
+        }
+    }
+
+    /// Show the stats in the session's listing if they changed.
+    pub fn publish_stats(&mut self) {
+        if self.stats == self.published {
+            return;
+        }
+        self.published = self.stats;
+        if let Some(listing) = &self.listing {
+            let stats = self.stats;
+            listing.update(|summary| summary.stats = stats);
+        }
+    }
+
+    /// Whether anything was queued since the last call. The other direction
+    /// must be polled again to pick it up.
+    pub fn take_queued(&mut self) -> bool {
+        std::mem::take(&mut self.queued)
+    }
+}
+
+/// Server output waiting for the client, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    /// The most that was waiting at once.
+    pub peak: usize,
+    /// Dropped because the client fell behind.
+    pub dropped: u64,
+}
+
+/// Traffic of one direction of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// Bytes read from the sending side.
+    pub read: u64,
+    /// Bytes written to the receiving side, after the proxy's changes.
+    pub written: u64,
+    /// Frames decoded from server output, or lines sent to the server.
+    pub frames: u64,
+}
+
+/// Traffic both ways and how fast the server answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Server output on its way to the client.
+    pub server: Traffic,
+    /// Client input and the proxy's own commands on their way to the
+    /// server.
+    pub client: Traffic,
+    /// Time from a command to the prompt after it, smoothed over the last
+    /// few. Servers that send no prompts leave it unknown.
+    pub latency: Option<Duration>,
+    pub connected_at: Instant,
+    /// When the server connection was opened, again after a reconnect.
+    pub server_connected_at: Instant,
+    /// When the player last typed something.
+    pub typed_at: Instant,
+    // When the first command the server has not answered yet was sent.
+    waiting_since: Option<Instant>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            server: Traffic::default(),
+            client: Traffic::default(),
+            latency: None,
+            connected_at: now,
+            server_connected_at: now,
+            typed_at: now,
+            waiting_since: None,
+        }
+    }
+
+    /// A command went to the server.
+    pub fn command_sent(&mut self) {
+        self.waiting_since.get_or_insert_with(Instant::now);
+    }
+
+    /// The server sent a prompt, which answers the commands before it.
+    pub fn prompt_received(&mut self) {
+        if let Some(sent) = self.waiting_since.take() {
+            let sample = sent.elapsed();
+            self.latency = Some(match self.latency {
+                Some(latency) => (latency * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+    }
+
+    fn reconnected(&mut self) {
+        self.server_connected_at = Instant::now();
+        self.waiting_since = None;
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the rest of the proxy sees of a connected session.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub id: u64,
+    /// Address of the client.
+    pub peer: String,
+    /// The config profile the client connected with, if not the main one.
+    pub profile: Option<String>,
+    pub connected_at: SystemTime,
+    /// The room the mapper last reported.
+    pub room: Option<Room>,
+    pub output_queue: QueueStats,
+    pub stats: SessionStats,
+    pub login_state: LoginState,
+    /// The points the server last reported, and when.
+    pub vitals: Option<(Vitals, SystemTime)>,
+}
+
+/// Something asked of a session from outside of it.
+#[derive(Debug)]
+pub enum Request {
+    /// Run a `#bc` command, given without the prefix, answering with the
+    /// lines it shows instead of showing them to the client.
+    Command(String, oneshot::Sender<Vec<String>>),
+    /// Take the triggers, aliases, highlights and channel settings of a
+    /// reloaded config.
+    Reload(Arc<Config>),
+    /// A line from a client attached read-write, with its line ending.
+    Input(Vec<u8>),
+    /// Save the session's state, as the proxy is shutting down.
+    Save(oneshot::Sender<()>),
+    /// Stop reading and writing, answering with the session's state, as a
+    /// new proxy takes over the server connection.
+    Handover(oneshot::Sender<String>),
+    /// Go on after a handover failed.
+    Resume,
+}
+
+/// The sessions connected to a proxy server.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<SessionList>>);
+
+#[derive(Default)]
+struct SessionList {
+    next_id: u64,
+    sessions: BTreeMap<u64, Entry>,
+}
+
+struct Entry {
+    summary: Summary,
+    requests: mpsc::UnboundedSender<Request>,
+    kick: Arc<Notify>,
+    mirror: broadcast::Sender<Bytes>,
+    #[cfg(unix)]
+    server: ServerSlot,
+}
+
+impl Sessions {
+    /// Add a session for a client connected from `peer` with `profile`.
+    /// It is taken off the list again when the returned listing is dropped.
+    pub fn register(&self, peer: String, profile: Option<String>) -> Listing {
+        let mut list = self.0.lock().unwrap();
+        list.next_id += 1;
+        let id = list.next_id;
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let kick = Arc::new(Notify::new());
+        let (mirror, _) = broadcast::channel(mirror::BACKLOG);
+        #[cfg(unix)]
+        let server = ServerSlot::default();
+        list.sessions.insert(
+            id,
+            Entry {
+                summary: Summary {
+                    id,
+                    peer,
+                    profile,
+                    connected_at: SystemTime::now(),
+                    room: None,
+                    output_queue: QueueStats::default(),
+                    stats: SessionStats::new(),
+                    login_state: LoginState::default(),
+                    vitals: None,
+                },
+                requests,
+                kick: kick.clone(),
+                mirror: mirror.clone(),
+                #[cfg(unix)]
+                server: server.clone(),
+            },
+        );
+        Listing {
+            id,
+            sessions: self.clone(),
+            requests: receiver,
+            kick,
+            mirror,
+            #[cfg(unix)]
+            server,
+        }
+    }
+
+    pub fn summaries(&self) -> Vec<Summary> {
+        let list = self.0.lock().unwrap();
+        list.sessions.values().map(|e| e.summary.clone()).collect()
+    }
+
+    /// How many sessions were registered so far, including those that ended.
+    pub fn served(&self) -> u64 {
+        self.0.lock().unwrap().next_id
+    }
+
+    /// Send each session the config it now runs with.
+    pub fn reload(&self, config_of: impl Fn(&Summary) -> Arc<Config>) {
+        for entry in self.0.lock().unwrap().sessions.values() {
+            let config = config_of(&entry.summary);
+            let _ = entry.requests.send(Request::Reload(config));
+        }
+    }
+
+    /// Have every session save its state, and wait until they did.
+    pub async fn save(&self) {
+        let answers: Vec<_> = {
+            let list = self.0.lock().unwrap();
+            list.sessions
+                .values()
+                .filter_map(|entry| {
+                    let (reply, answer) = oneshot::channel();
+                    entry.requests.send(Request::Save(reply)).ok()?;
+                    Some(answer)
+                })
+                .collect()
+        };
+        for answer in answers {
+            let _ = answer.await;
+        }
+    }
+
+    /// Stop the sessions for a new proxy that takes over their server
+    /// connections, and gather those with the state of their sessions.
+    #[cfg(unix)]
+    pub(crate) async fn hand_over(&self) -> Vec<Connection> {
+        let answers: Vec<_> = {
+            let list = self.0.lock().unwrap();
+            list.sessions
+                .values()
+                .filter_map(|entry| {
+                    let (reply, answer) = oneshot::channel();
+                    entry.requests.send(Request::Handover(reply)).ok()?;
+                    Some((entry.summary.profile.clone(), entry.server.clone(), answer))
+                })
+                .collect()
+        };
+        let mut connections = Vec::new();
+        for (profile, server, answer) in answers {
+            if let (Ok(state), Some(fd)) = (answer.await, server.get()) {
+                connections.push(Connection { profile, state, fd });
+            }
+        }
+        connections
+    }
+
+    /// Have the sessions go on after a handover failed.
+    pub fn resume(&self) {
+        for entry in self.0.lock().unwrap().sessions.values() {
+            let _ = entry.requests.send(Request::Resume);
+        }
+    }
+
+    /// End every session.
+    pub fn kick_all(&self) {
+        for entry in self.0.lock().unwrap().sessions.values() {
+            entry.kick.notify_one();
+        }
+    }
+
+    /// End session `id`. Returns false if there is no such session.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.0.lock().unwrap().sessions.get(&id) {
+            Some(entry) => {
+                entry.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Attach a client to session `id` as `mode` says, returning the id,
+    /// what the session's client gets from now on and, if it may write,
+    /// where its lines go. `None` if there is no such session.
+    pub fn attach(
+        &self,
+        id: u64,
+        mode: Attach,
+    ) -> Option<(
+        u64,
+        broadcast::Receiver<Bytes>,
+        Option<mpsc::UnboundedSender<Request>>,
+    )> {
+        let list = self.0.lock().unwrap();
+        let entry = list.sessions.get(&id)?;
+        let input = (mode == Attach::ReadWrite).then(|| entry.requests.clone());
+        Some((id, entry.mirror.subscribe(), input))
+    }
+
+    /// Run `command` in session `id` as if its client had sent
+    /// `#bc <command>`, returning the lines it shows. `None` if there is no
+    /// such session or it ended before answering.
+    pub async fn command(&self, id: u64, command: &str) -> Option<Vec<String>> {
+        let (reply, answer) = oneshot::channel();
+        {
+            let list = self.0.lock().unwrap();
+            let entry = list.sessions.get(&id)?;
+            entry
+                .requests
+                .send(Request::Command(command.to_string(), reply))
+                .ok()?;
+        }
+        answer.await.ok()
+    }
+}
+
+/// A session's entry in [`Sessions`].
+pub struct Listing {
+    id: u64,
+    sessions: Sessions,
+    requests: mpsc::UnboundedReceiver<Request>,
+    kick: Arc<Notify>,
+    mirror: broadcast::Sender<Bytes>,
+    #[cfg(unix)]
+    server: ServerSlot,
+}
+
+impl Listing {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut Summary)) {
+        if let Some(entry) = self.sessions.0.lock().unwrap().sessions.get_mut(&self.id) {
+            f(&mut entry.summary);
+        }
+    }
+
+    /// Where what the client gets is sent for the clients attached.
+    pub fn mirror(&self) -> broadcast::Sender<Bytes> {
+        self.mirror.clone()
+    }
+
+    /// Notified when the session is kicked with [`Sessions::kick`].
+    pub fn kicked(&self) -> Arc<Notify> {
+        self.kick.clone()
+    }
+
+    /// Where the session's server connection is kept to hand over.
+    #[cfg(unix)]
+    pub(crate) fn server(&self) -> ServerSlot {
+        self.server.clone()
+    }
+
+    /// The next request for the session, if one is waiting. The waker of
+    /// `cx` is woken when one arrives.
+    pub fn poll_request(&mut self, cx: &mut Context<'_>) -> Option<Request> {
+        match self.requests.poll_recv(cx) {
+            Poll::Ready(request) => request,
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl Drop for Listing {
+    fn drop(&mut self) {
+        self.sessions.0.lock().unwrap().sessions.remove(&self.id);
+    }
+}
@@ -0,0 +1,118 @@
+//! Speedwalks let the player type a whole route on one line, e.g. `3n2e u`
+//! for `n`, `n`, `n`, `e`, `e`, `u`. Steps are a direction with an optional
+//! count in front, and counted groups like `2(n e)` repeat everything in
+//! them. Tokens are separated by spaces; a token like `news` is not read as
+//! `ne`, `w`, `s`, so ordinary commands are never taken for speedwalks.
+
+const DIRECTIONS: &[&str] = &["ne", "nw", "se", "sw", "n", "s", "e", "w", "u", "d"];
+
+/// More steps than this are not expanded.
+pub const MAX_STEPS: usize = 100;
+const MAX_DEPTH: usize = 8;
+
+/// The steps `line` walks, or `None` if it is not a speedwalk of more than
+/// one step.
+pub fn expand(line: &str) -> Option<Vec<String>> {
+    let mut parser = Parser {
+        input: line.trim().as_bytes(),
+        pos: 0,
+    };
+    let steps = parser.sequence(0)?;
+    (parser.pos == parser.input.len() && steps.len() > 1).then_some(steps)
+}
+
+/// Whether `partial`, the start of a line, could still be a speedwalk.
+pub fn may_be_speedwalk(partial: &[u8]) -> bool {
+    partial
+        .iter()
+        .all(|&b| b.is_ascii_digit() || b"nsewud() \t\r\n".contains(&b))
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// Tokens up to the end of the line or the `)` closing a group.
+    fn sequence(&mut self, depth: usize) -> Option<Vec<String>> {
+        let mut steps = Vec::new();
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                None => return (depth == 0).then_some(steps),
+                Some(b')') if depth > 0 => return Some(steps),
+                Some(_) => self.token(depth, &mut steps)?,
+            }
+        }
+    }
+
+    /// A run of counted steps and groups not separated by spaces.
+    fn token(&mut self, depth: usize, steps: &mut Vec<String>) -> Option<()> {
+        loop {
+            let count = self.count()?;
+            let is_group = self.peek() == Some(b'(');
+            if is_group {
+                if depth == MAX_DEPTH {
+                    return None;
+                }
+                self.pos += 1;
+                let group = self.sequence(depth + 1)?;
+                self.pos += 1;
+                for _ in 0..count {
+                    if steps.len() + group.len() > MAX_STEPS {
+                        return None;
+                    }
+                    steps.extend(group.iter().cloned());
+                }
+            } else {
+                let direction = self.direction()?;
+                if steps.len() + count > MAX_STEPS {
+                    return None;
+                }
+                steps.extend(std::iter::repeat_n(direction.to_string(), count));
+            }
+
+            match self.peek() {
+                None | Some(b' ' | b'\t' | b')') => return Some(()),
+                Some(b) if is_group || b.is_ascii_digit() || b == b'(' => {}
+                // Letters straight after a direction make a word.
+                Some(_) => return None,
+            }
+        }
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Some(1);
+        }
+        let count: usize = std::str::from_utf8(&self.input[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()?;
+        (1..=MAX_STEPS).contains(&count).then_some(count)
+    }
+
+    fn direction(&mut self) -> Option<&'static str> {
+        let rest = &self.input[self.pos..];
+        let direction = DIRECTIONS
+            .iter()
+            .find(|direction| rest.starts_with(direction.as_bytes()))?;
+        self.pos += direction.len();
+        Some(direction)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.pos += 1;
+        }
+    }
+}
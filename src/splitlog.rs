@@ -0,0 +1,234 @@
+//! Split logs: server output written to a file per kind, in the directory
+//! `split_log_dir` sets.
+//!
+//! - `channels` gets channel messages as `[sales] text`
+//! - `tells` gets tells
+//! - `battle` gets `spec_battle` messages
+//! - `mapper` gets the rooms the mapper reports as `<area> <id> <short desc>`
+//! - `raw` gets everything, as the server sent it
+//!
+//! Lines but those of `raw` start with the time. Files are named with the
+//! strftime format `split_log_name`, `{file}` standing for the names above,
+//! so a name with the date in it starts a new file every day.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use chrono::{format::StrftimeItems, Local};
+
+use crate::{battle::BATTLE_ATTR, bc::Frame, channel, mapper::Mapper, session::Session};
+
+/// Message type of a tell.
+const TELL_ATTR: &[u8] = b"tell";
+
+/// The files of the split logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFile {
+    Channels,
+    Tells,
+    Battle,
+    Mapper,
+    Raw,
+}
+
+impl LogFile {
+    const ALL: [LogFile; 5] = [
+        LogFile::Channels,
+        LogFile::Tells,
+        LogFile::Battle,
+        LogFile::Mapper,
+        LogFile::Raw,
+    ];
+
+    /// The line for `frame` in this file, if it goes there.
+    fn line(self, frame: &Frame) -> Option<Vec<u8>> {
+        if self == LogFile::Raw {
+            let mut raw = Vec::new();
+            frame.encode(&mut raw);
+            return Some(raw);
+        }
+        let code = match frame {
+            Frame::Code(code) => code,
+            _ => return None,
+        };
+        let text = match self {
+            LogFile::Channels => {
+                let channel = frame.channel()?;
+                format!("[{}] {}", channel.name, channel::message_text(frame))
+            }
+            LogFile::Tells if code.id == 10 && code.attr_is(TELL_ATTR) => {
+                channel::message_text(frame)
+            }
+            LogFile::Battle if code.id == 10 && code.attr_is(BATTLE_ATTR) => {
+                channel::message_text(frame)
+            }
+            LogFile::Mapper => match Mapper::from_code(code)? {
+                Mapper::Room(room) => format!("{} {} {}", room.area, room.id, room.short_desc),
+                Mapper::RealmMap => return None,
+            },
+            _ => return None,
+        };
+        let line = format!("{} {}\n", Local::now().format("%H:%M:%S"), text);
+        Some(line.into_bytes())
+    }
+}
+
+impl FromStr for LogFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogFile::ALL
+            .into_iter()
+            .find(|file| file.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown split log `{}`, expected channels, tells, battle, mapper or raw",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for LogFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFile::Channels => "channels",
+            LogFile::Tells => "tells",
+            LogFile::Battle => "battle",
+            LogFile::Mapper => "mapper",
+            LogFile::Raw => "raw",
+        })
+    }
+}
+
+/// The `split_log_*` settings of the config. The logs are off while `dir`
+/// is not set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitLogConfig {
+    pub dir: Option<PathBuf>,
+    pub files: Vec<LogFile>,
+    name: String,
+}
+
+impl Default for SplitLogConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            files: vec![
+                LogFile::Channels,
+                LogFile::Tells,
+                LogFile::Battle,
+                LogFile::Mapper,
+            ],
+            name: "{file}-%Y-%m-%d.log".to_string(),
+        }
+    }
+}
+
+impl SplitLogConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some() && !self.files.is_empty()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name the files with the strftime format `name`.
+    pub fn set_name(&mut self, name: &str) -> Result<(), String> {
+        let invalid = || format!("invalid split log name `{}`", name);
+        if !name.contains("{file}") {
+            return Err(format!("{}, it needs {{file}}", invalid()));
+        }
+        StrftimeItems::new(name).parse().map_err(|_| invalid())?;
+        self.name = name.to_string();
+        Ok(())
+    }
+
+    /// The path of `file` as of now.
+    fn path(&self, file: LogFile) -> Option<PathBuf> {
+        let name = self.name.replace("{file}", &file.to_string());
+        let name = Local::now().format(&name).to_string();
+        Some(self.dir.as_ref()?.join(name))
+    }
+}
+
+/// An open log file and the path it was opened at.
+struct Open {
+    path: PathBuf,
+    file: File,
+}
+
+/// Writes server output to the split logs.
+pub struct SplitLogs {
+    config: SplitLogConfig,
+    open: Vec<(LogFile, Option<Open>)>,
+}
+
+impl SplitLogs {
+    pub fn new(config: SplitLogConfig) -> Self {
+        Self {
+            open: config.files.iter().map(|&file| (file, None)).collect(),
+            config,
+        }
+    }
+
+    /// Write to the logs of `config` from now on.
+    pub fn set_config(&mut self, config: SplitLogConfig) {
+        if config != self.config {
+            *self = Self::new(config);
+        }
+    }
+
+    /// Write `frame` to the logs it goes to. A log that fails is left out
+    /// from then on, and the player told why.
+    pub fn write(&mut self, frame: &Frame, session: &mut Session) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        let config = &self.config;
+        self.open.retain_mut(|(file, open)| {
+            let line = match file.line(frame) {
+                Some(line) => line,
+                None => return true,
+            };
+            match write(config, *file, open, &line) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(%file, "failed to write the split log: {}", e);
+                    session.notify(&format!("failed to write the {} log: {}", file, e));
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Write `line` to `file`, opening it again if its name changed since.
+fn write(
+    config: &SplitLogConfig,
+    file: LogFile,
+    open: &mut Option<Open>,
+    line: &[u8],
+) -> io::Result<()> {
+    let path = match config.path(file) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let log = match open {
+        Some(log) if log.path == path => log,
+        _ => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            open.insert(Open { path, file })
+        }
+    };
+    log.file.write_all(line)
+}
@@ -0,0 +1,132 @@
+//! The state of a session kept across restarts of the proxy: the room the
+//! mapper last reported, the last prompt, the effects on the player and the
+//! experience gained. Each session saves it to its database when the proxy
+//! shuts down, and the next session of the same profile takes it back, so
+//! `#bc exprate` goes on where it was and the mapper links the first room
+//! after an upgrade to the last one before. Sessions without a database
+//! keep nothing.
+//!
+//! On a live upgrade the state goes to the new proxy with the server
+//! connection instead, see `upgrade_socket`.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{db::Event, exp::SavedExp, login::LoginState, mapper::Room, session::Session};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    room: Option<Room>,
+    prompt: Option<Vec<u8>>,
+    /// The effects and the seconds they had left.
+    effects: Vec<(String, u64)>,
+    exp: SavedExp,
+    /// Only set on a handover, a restart logs in again.
+    #[serde(default)]
+    logged_in: bool,
+}
+
+impl SavedState {
+    fn of(session: &mut Session) -> Self {
+        let now = Instant::now();
+        SavedState {
+            room: session.last_room.clone(),
+            prompt: session.prompt.clone(),
+            effects: session.effects.save(now),
+            exp: session.exp.save(now),
+            logged_in: false,
+        }
+    }
+
+    /// Put the state back into `session`, `since` after it was saved.
+    fn apply(self, since: Duration, session: &mut Session) {
+        let now = Instant::now();
+        if let Some(listing) = &session.listing {
+            listing.update(|summary| summary.room = self.room.clone());
+        }
+        session.last_room = self.room;
+        session.prompt = self.prompt;
+        session.effects.restore(self.effects, since, now);
+        session.exp.restore(self.exp, now);
+    }
+}
+
+/// Save the state of `session` for the next session of its profile.
+pub fn save(session: &mut Session) {
+    let db = match session.db.clone() {
+        Some(db) => db,
+        None => return,
+    };
+    match serde_json::to_string(&SavedState::of(session)) {
+        Ok(state) => db.send(Event::SessionState {
+            profile: profile(session),
+            state: Some(state),
+        }),
+        Err(e) => tracing::warn!("failed to save the session state: {}", e),
+    }
+}
+
+/// Take back the state a session of the same profile saved, if one did
+/// since the last restore.
+pub fn restore(session: &mut Session) {
+    let db = match session.db.clone() {
+        Some(db) => db,
+        None => return,
+    };
+    let profile = profile(session);
+    let (state, saved_at) = match db.session_state(&profile) {
+        Ok(Some(saved)) => saved,
+        Ok(None) => return,
+        Err(e) => return tracing::warn!("failed to read the session state: {}", e),
+    };
+    // Taken once, a later session starts afresh.
+    db.send(Event::SessionState {
+        profile,
+        state: None,
+    });
+    let state: SavedState = match serde_json::from_str(&state) {
+        Ok(state) => state,
+        Err(e) => return tracing::warn!("the saved session state is invalid: {}", e),
+    };
+
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let since = Duration::from_secs(unix_now.saturating_sub(saved_at).max(0) as u64);
+    state.apply(since, session);
+    session.notify(&format!(
+        "restored the state of the session saved {}s ago",
+        since.as_secs()
+    ));
+}
+
+/// The state of `session` to hand over to a new proxy with its server
+/// connection.
+pub fn snapshot(session: &mut Session) -> String {
+    let state = SavedState {
+        logged_in: session.login_state.is_authenticated(),
+        ..SavedState::of(session)
+    };
+    serde_json::to_string(&state).unwrap_or_default()
+}
+
+/// Take over the state of a session of the previous proxy, whose server
+/// connection `session` continues on.
+pub fn adopt(state: &str, session: &mut Session) {
+    let state: SavedState = match serde_json::from_str(state) {
+        Ok(state) => state,
+        Err(e) => return tracing::warn!("the handed over session state is invalid: {}", e),
+    };
+    if state.logged_in {
+        session.set_login_state(LoginState::Authenticated);
+    }
+    state.apply(Duration::ZERO, session);
+    session.notify("took over the server connection of the previous proxy");
+}
+
+/// The profile a session's state is kept under, empty for the main config.
+fn profile(session: &Session) -> String {
+    session.profile.clone().unwrap_or_default()
+}
@@ -0,0 +1,397 @@
+use std::{fmt, str::FromStr};
+
+use serde_json::json;
+
+use crate::{
+    bc::{ControlCode, Frame},
+    color,
+    login::LoginFailure,
+    mapper::Mapper,
+    vitals::{Vitals, PLAYER_STATUS},
+};
+
+/// How frames are written out to the client.
+pub trait OutputStyle: Send + Sync {
+    fn render(&self, frame: &Frame, out: &mut Vec<u8>);
+
+    /// Write a message from the proxy itself.
+    fn message(&self, message: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("[bcproxy] {}\r\n", message).as_bytes());
+    }
+
+    /// Whether output is shown on a terminal: prompts end in telnet GA and
+    /// are shown again after the proxy's messages, and plain output applies.
+    fn is_terminal(&self) -> bool {
+        true
+    }
+}
+
+/// Control codes are passed on as the server sent them.
+pub struct Raw;
+
+impl OutputStyle for Raw {
+    fn render(&self, frame: &Frame, out: &mut Vec<u8>) {
+        frame.encode(out);
+    }
+}
+
+/// Control codes become plain text, each line of their body starting with
+/// a tag naming the code, e.g. `[chan_sales] Bob: selling a sword`.
+pub struct Tagged {
+    pub open: &'static str,
+    pub close: &'static str,
+}
+
+impl OutputStyle for Tagged {
+    fn render(&self, frame: &Frame, out: &mut Vec<u8>) {
+        if let Frame::Map(map) = frame {
+            if let Some(clear) = &map.clear {
+                self.render(&Frame::Code(clear.clone()), out);
+            }
+            return self.render(&Frame::Code(map.code.clone()), out);
+        }
+        let code = match frame.code() {
+            Some(code) => code,
+            None => return frame.encode(out),
+        };
+
+        let tag = format!("{}{}{} ", self.open, tag(code), self.close);
+        let text = code.text();
+        if text.is_empty() {
+            out.extend_from_slice(tag.trim_end().as_bytes());
+            out.extend_from_slice(b"\r\n");
+            return;
+        }
+
+        for line in text.split_inclusive(|&b| b == b'\n') {
+            out.extend_from_slice(tag.as_bytes());
+            out.extend_from_slice(line);
+        }
+        // Keep tagged lines apart, except the prompt the player types after.
+        if !text.ends_with(b"\n") && !matches!(frame, Frame::Prompt(_)) {
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+/// Every frame becomes a JSON object on a line of its own, e.g.
+/// `{"type":"chan","channel":"sales","text":"Bob: selling a sword"}`, and
+/// text outside control codes an object per line.
+///
+/// `text` is the text without ANSI codes and line ending, `ansi` the text
+/// as it would have been shown if it has any. A `text` object for the start
+/// of a line whose end has not arrived yet has `"partial": true`.
+///
+/// Types are `text`, `chan`, `message` (other messages, with their `kind`),
+/// `prompt`, `room` (mapper rooms), `map` (the map around the player, with
+/// its `rows` and `cols` and whether the screen was `clear`ed before it),
+/// `login`, `login_failed` (with the `reason` the server gave, such as
+/// `wrong_password`), `vitals` (the player's points), `clear_screen`,
+/// `proxy` (the proxy's own messages) and `code` for the rest, with their
+/// `id` and `attr`.
+pub struct Json;
+
+impl OutputStyle for Json {
+    fn render(&self, frame: &Frame, out: &mut Vec<u8>) {
+        let mut object = match frame {
+            Frame::Text(text) => {
+                for line in text.split_inclusive(|&b| b == b'\n') {
+                    let mut object = json!({ "type": "text" });
+                    add_text(&mut object, line);
+                    if !line.ends_with(b"\n") {
+                        object["partial"] = true.into();
+                    }
+                    push_line(&object, out);
+                }
+                return;
+            }
+            Frame::Prompt(_) => json!({ "type": "prompt" }),
+            Frame::Code(code) | Frame::LoginResult(code) => code_json(code),
+            Frame::Map(map) => json!({
+                "type": "map",
+                "rows": map.rows,
+                "cols": map.cols,
+                "clear": map.clear.is_some(),
+            }),
+        };
+        let mut raw = Vec::new();
+        frame.push_text(&mut raw);
+        add_text(&mut object, &raw);
+        push_line(&object, out);
+    }
+
+    fn message(&self, message: &str, out: &mut Vec<u8>) {
+        push_line(&json!({ "type": "proxy", "text": message }), out);
+    }
+
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+fn code_json(code: &ControlCode) -> serde_json::Value {
+    let attr = code
+        .attr
+        .as_deref()
+        .map(|attr| String::from_utf8_lossy(attr).into_owned());
+    match code.id {
+        10 => match attr.as_deref().and_then(|attr| attr.strip_prefix("chan_")) {
+            Some(channel) => json!({ "type": "chan", "channel": channel }),
+            None => json!({ "type": "message", "kind": attr }),
+        },
+        5 => json!({ "type": "login" }),
+        6 => json!({ "type": "login_failed", "reason": LoginFailure::from_code(code).name() }),
+        11 => json!({ "type": "clear_screen" }),
+        PLAYER_STATUS => match Vitals::from_code(code) {
+            Some(v) => json!({
+                "type": "vitals",
+                "hp": v.hp,
+                "max_hp": v.max_hp,
+                "sp": v.sp,
+                "max_sp": v.max_sp,
+                "ep": v.ep,
+                "max_ep": v.max_ep,
+            }),
+            None => json!({ "type": "code", "id": code.id, "attr": attr }),
+        },
+        99 => match Mapper::from_code(code) {
+            Some(Mapper::Room(room)) => json!({
+                "type": "room",
+                "area": room.area,
+                "id": room.id,
+                "direction": room.direction,
+                "indoors": room.indoors,
+                "short_desc": room.short_desc,
+                "long_desc": room.long_desc,
+                "exits": room.exits,
+            }),
+            Some(Mapper::RealmMap) => json!({ "type": "realm_map" }),
+            None => json!({ "type": "code", "id": code.id, "attr": attr }),
+        },
+        id => json!({ "type": "code", "id": id, "attr": attr }),
+    }
+}
+
+fn add_text(object: &mut serde_json::Value, raw: &[u8]) {
+    if object["type"] == "room" || object["type"] == "vitals" {
+        return;
+    }
+    let raw = raw.strip_suffix(b"\n").unwrap_or(raw);
+    let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+    let text = color::strip_ansi(raw);
+    let ansi = String::from_utf8_lossy(raw);
+    if ansi != text {
+        object["ansi"] = ansi.into();
+    }
+    object["text"] = text.into();
+}
+
+fn push_line(object: &serde_json::Value, out: &mut Vec<u8>) {
+    out.extend_from_slice(object.to_string().as_bytes());
+    out.push(b'\n');
+}
+
+static RAW: Raw = Raw;
+static JSON: Json = Json;
+#[cfg(feature = "legacy-format")]
+static LEGACY_BC: Tagged = Tagged {
+    open: "[",
+    close: "]",
+};
+static BAT_EMOJI: Tagged = Tagged {
+    open: "🦇",
+    close: "",
+};
+static PI_PREFIX: Tagged = Tagged {
+    open: "π",
+    close: "",
+};
+
+/// The output styles clients can choose from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    #[default]
+    Raw,
+    /// `[chan_sales] text`, the format of the original bcproxy. Built with
+    /// the `legacy-format` feature, on by default.
+    #[cfg(feature = "legacy-format")]
+    LegacyBc,
+    /// `🦇chan_sales text`
+    BatEmoji,
+    /// `πchan_sales text`
+    PiPrefix,
+    /// A JSON object per frame.
+    Json,
+}
+
+impl Profile {
+    pub fn style(self) -> &'static dyn OutputStyle {
+        match self {
+            Profile::Raw => &RAW,
+            #[cfg(feature = "legacy-format")]
+            Profile::LegacyBc => &LEGACY_BC,
+            Profile::BatEmoji => &BAT_EMOJI,
+            Profile::PiPrefix => &PI_PREFIX,
+            Profile::Json => &JSON,
+        }
+    }
+}
+
+#[cfg(feature = "legacy-format")]
+const EXPECTED: &str = "raw, legacy-bc, bat-emoji, pi-prefix or json";
+#[cfg(not(feature = "legacy-format"))]
+const EXPECTED: &str = "raw, bat-emoji, pi-prefix or json";
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Profile::Raw),
+            #[cfg(feature = "legacy-format")]
+            "legacy-bc" => Ok(Profile::LegacyBc),
+            "bat-emoji" => Ok(Profile::BatEmoji),
+            "pi-prefix" => Ok(Profile::PiPrefix),
+            "json" => Ok(Profile::Json),
+            _ => Err(format!(
+                "invalid output style `{}`, expected {}",
+                s, EXPECTED
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Profile::Raw => "raw",
+            #[cfg(feature = "legacy-format")]
+            Profile::LegacyBc => "legacy-bc",
+            Profile::BatEmoji => "bat-emoji",
+            Profile::PiPrefix => "pi-prefix",
+            Profile::Json => "json",
+        })
+    }
+}
+
+/// The name a code is tagged with: the message type for messages, a name
+/// for other known codes and the code id for the rest.
+fn tag(code: &ControlCode) -> String {
+    match code.id {
+        10 => code
+            .attr
+            .as_deref()
+            .map(|attr| String::from_utf8_lossy(attr).into_owned())
+            .unwrap_or_else(|| "message".to_string()),
+        5 => "login".to_string(),
+        6 => "login_failed".to_string(),
+        11 => "clear_screen".to_string(),
+        99 if Mapper::from_code(code).is_some() => "bat_mapper".to_string(),
+        id => format!("{:02}", id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::bc::Decoder;
+
+    /// The JSON lines `bytes` of server output become.
+    fn json(bytes: &[u8]) -> Vec<Value> {
+        let mut frames = Vec::new();
+        let mut decoder = Decoder::new();
+        decoder.decode(bytes, &mut frames);
+        decoder.finish(&mut frames);
+        let mut out = Vec::new();
+        for frame in &frames {
+            Json.render(frame, &mut out);
+        }
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn text_is_an_object_per_line() {
+        assert_eq!(
+            json(b"one\r\ntwo\r\nthr"),
+            [
+                json!({ "type": "text", "text": "one" }),
+                json!({ "type": "text", "text": "two" }),
+                json!({ "type": "text", "text": "thr", "partial": true }),
+            ]
+        );
+    }
+
+    #[test]
+    fn ansi_codes_are_kept_apart_from_the_text() {
+        assert_eq!(
+            json(b"\x1b[31mred\x1b[0m\r\n"),
+            [json!({ "type": "text", "text": "red", "ansi": "\u{1b}[31mred\u{1b}[0m" })]
+        );
+    }
+
+    #[test]
+    fn messages_are_channels_or_kinds() {
+        assert_eq!(
+            json(b"\x1b<10chan_sales\x1b|Bob: a sword\r\n\x1b>10\x1b<10tell\x1b|hi\x1b>10"),
+            [
+                json!({ "type": "chan", "channel": "sales", "text": "Bob: a sword" }),
+                json!({ "type": "message", "kind": "tell", "text": "hi" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn known_codes_have_types_of_their_own() {
+        assert_eq!(
+            json(b"\x1b<05\x1b>05\x1b<06Wrong password.\x1b>06\x1b<11\x1b>11"),
+            [
+                json!({ "type": "login", "text": "" }),
+                json!({ "type": "login_failed", "reason": "wrong_password", "text": "Wrong password." }),
+                json!({ "type": "clear_screen", "text": "" }),
+            ]
+        );
+        assert_eq!(
+            json(b"\x1b<10spec_prompt\x1b|Hp:1 >\x1b>10"),
+            [json!({ "type": "prompt", "text": "Hp:1 >" })]
+        );
+        assert_eq!(
+            json(b"\x1b<31ff\x1b|x\x1b>31"),
+            [json!({ "type": "code", "id": 31, "attr": "ff", "text": "x" })]
+        );
+    }
+
+    #[test]
+    fn mapper_rooms_are_their_fields_without_text() {
+        assert_eq!(
+            json(b"\x1b<99BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99"),
+            [json!({
+                "type": "room",
+                "area": "arelium",
+                "id": "1",
+                "direction": "n",
+                "indoors": false,
+                "short_desc": "Square",
+                "long_desc": "A square.",
+                "exits": ["n", "s"],
+            })]
+        );
+    }
+
+    #[test]
+    fn proxy_messages_are_objects_too() {
+        let mut out = Vec::new();
+        Json.message("reloaded", &mut out);
+        assert!(out.ends_with(b"\n"));
+        assert_eq!(
+            serde_json::from_slice::<Value>(&out).unwrap(),
+            json!({ "type": "proxy", "text": "reloaded" })
+        );
+        assert!(!Json.is_terminal());
+    }
+}
@@ -0,0 +1,202 @@
+//! Taps write some of the server output, as JSON lines like those of the
+//! `json` output style, to a named pipe or Unix socket for other programs,
+//! such as a notification daemon or a stream overlay. Each
+//! `tap = <category> <path>` line of the config sets one up: `channels`
+//! for channel messages, `vitals` for the player's points and `rooms` for
+//! the rooms the mapper reports.
+//!
+//! The reader of a named pipe, or the program listening on a socket, can
+//! come and go. Lines nobody is there for, or that a slow reader falls
+//! behind on, are dropped rather than holding up the sessions.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, warn};
+
+use crate::{bc::Frame, mapper::Mapper, style::Profile, vitals::Vitals};
+
+/// Lines kept for a tap whose reader is slow.
+const BACKLOG: usize = 256;
+
+/// The frames a tap writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Channels,
+    Vitals,
+    Rooms,
+}
+
+impl Category {
+    fn matches(self, frame: &Frame) -> bool {
+        let code = match frame {
+            Frame::Code(code) => code,
+            _ => return false,
+        };
+        match self {
+            Category::Channels => {
+                code.id == 10 && code.attr.as_ref().is_some_and(|a| a.starts_with(b"chan_"))
+            }
+            Category::Vitals => Vitals::from_code(code).is_some(),
+            Category::Rooms => matches!(Mapper::from_code(code), Some(Mapper::Room(_))),
+        }
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channels" => Ok(Category::Channels),
+            "vitals" => Ok(Category::Vitals),
+            "rooms" => Ok(Category::Rooms),
+            _ => Err(format!(
+                "invalid tap `{}`, expected channels, vitals or rooms",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::Channels => "channels",
+            Category::Vitals => "vitals",
+            Category::Rooms => "rooms",
+        })
+    }
+}
+
+/// A `tap = <category> <path>` line of the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapRule {
+    pub category: Category,
+    pub path: PathBuf,
+}
+
+impl FromStr for TapRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (category, path) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("tap `{}` has no path", s))?;
+        Ok(Self {
+            category: category.parse()?,
+            path: PathBuf::from(path.trim()),
+        })
+    }
+}
+
+impl fmt::Display for TapRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.category, self.path.display())
+    }
+}
+
+/// A tap the sessions of a proxy write to.
+#[derive(Debug, Clone)]
+pub struct Tap {
+    rule: TapRule,
+    lines: broadcast::Sender<Bytes>,
+}
+
+impl Tap {
+    pub fn new(rule: TapRule) -> Self {
+        Self {
+            rule,
+            lines: broadcast::channel(BACKLOG).0,
+        }
+    }
+
+    /// Write `frame` if it is one for the tap.
+    pub fn send(&self, frame: &Frame) {
+        if !self.rule.category.matches(frame) {
+            return;
+        }
+        let mut line = Vec::new();
+        Profile::Json.style().render(frame, &mut line);
+        // Nobody may be listening, which is fine.
+        let _ = self.lines.send(line.into());
+    }
+
+    /// Write the lines of the tap to its path, opened again whenever the
+    /// reader is gone.
+    pub async fn serve(self) {
+        let mut lines = self.lines.subscribe();
+        let mut out: Option<Box<dyn AsyncWrite + Unpin + Send>> = None;
+        loop {
+            let line = match lines.recv().await {
+                Ok(line) => line,
+                Err(RecvError::Lagged(n)) => {
+                    debug!(path = %self.rule.path.display(), "tap reader behind, {} lines dropped", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if out.is_none() {
+                match open(&self.rule.path).await {
+                    Ok(opened) => out = Some(opened),
+                    // No reader yet.
+                    Err(e) if is_missing_reader(&e) => continue,
+                    Err(e) => {
+                        return warn!(path = %self.rule.path.display(), "tap failed: {}", e);
+                    }
+                }
+            }
+            if let Some(writer) = out.as_mut() {
+                if writer.write_all(&line).await.is_err() {
+                    out = None;
+                }
+            }
+        }
+    }
+}
+
+fn is_missing_reader(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+    ) || e.raw_os_error() == Some(ENXIO)
+}
+
+/// What opening a named pipe that has no reader fails with.
+const ENXIO: i32 = 6;
+
+/// Open the named pipe or connect to the Unix socket at `path`.
+#[cfg(unix)]
+async fn open(path: &Path) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = tokio::fs::metadata(path).await?.file_type();
+    if file_type.is_fifo() {
+        let pipe = tokio::net::unix::pipe::OpenOptions::new().open_sender(path)?;
+        Ok(Box::new(pipe))
+    } else if file_type.is_socket() {
+        Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a named pipe or Unix socket",
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+async fn open(_path: &Path) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "taps need named pipes or Unix sockets",
+    ))
+}
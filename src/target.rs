@@ -0,0 +1,185 @@
+//! The monster the player is fighting, from code 70 `player_target`:
+//! `<name> <hp percent>`. Shown as a health bar such as
+//! `[target] evilmonster [█████░░░░░] 45%`.
+
+use std::{fmt, str::FromStr};
+
+use crate::bc::{ControlCode, Frame};
+
+pub const PLAYER_TARGET: u8 = 70;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub name: String,
+    pub hp_percent: u8,
+}
+
+impl Target {
+    pub fn from_code(code: &ControlCode) -> Option<Self> {
+        if code.id != PLAYER_TARGET {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&code.text()).into_owned();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        match fields.as_slice() {
+            [name @ .., hp] if !name.is_empty() => Some(Self {
+                name: name.join(" "),
+                hp_percent: hp.trim_end_matches('%').parse::<u8>().ok()?.min(100),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// How the target is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BarStyle {
+    /// `[█████░░░░░]`
+    #[default]
+    Blocks,
+    /// `[#####-----]`
+    Ascii,
+    /// Just the percentage.
+    Percent,
+    /// The code is passed on as it is.
+    Off,
+}
+
+impl FromStr for BarStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(BarStyle::Blocks),
+            "ascii" => Ok(BarStyle::Ascii),
+            "percent" => Ok(BarStyle::Percent),
+            "off" => Ok(BarStyle::Off),
+            _ => Err(format!(
+                "invalid target bar `{}`, expected blocks, ascii, percent or off",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BarStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BarStyle::Blocks => "blocks",
+            BarStyle::Ascii => "ascii",
+            BarStyle::Percent => "percent",
+            BarStyle::Off => "off",
+        })
+    }
+}
+
+/// The line showing `target`, the filled part of the bar in a color code
+/// going from green to red as the target weakens.
+pub fn render(target: &Target, style: BarStyle, width: usize) -> Vec<Frame> {
+    let (full, empty) = match style {
+        BarStyle::Blocks => ("█", "░"),
+        BarStyle::Ascii => ("#", "-"),
+        BarStyle::Percent => {
+            let line = format!("[target] {} {}%\r\n", target.name, target.hp_percent);
+            return vec![Frame::text(line.into_bytes())];
+        }
+        BarStyle::Off => return Vec::new(),
+    };
+
+    let filled = (usize::from(target.hp_percent) * width).div_ceil(100);
+    let color = match target.hp_percent {
+        51.. => "00cc00",
+        21..=50 => "cccc00",
+        _ => "cc0000",
+    };
+    vec![
+        Frame::text(format!("[target] {} [", target.name).into_bytes()),
+        Frame::Code(ControlCode::new(
+            20,
+            Some(color.as_bytes().to_vec()),
+            vec![Frame::text(full.repeat(filled).into_bytes())],
+        )),
+        Frame::text(
+            format!(
+                "{}] {}%\r\n",
+                empty.repeat(width - filled),
+                target.hp_percent
+            )
+            .into_bytes(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(text: &'static str) -> Option<Target> {
+        Target::from_code(&ControlCode::new(
+            PLAYER_TARGET,
+            None::<&[u8]>,
+            vec![Frame::text(text)],
+        ))
+    }
+
+    fn bytes(frames: Vec<Frame>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for frame in frames {
+            frame.encode(&mut out);
+        }
+        out
+    }
+
+    fn orc(hp_percent: u8) -> Target {
+        Target {
+            name: "big orc".to_string(),
+            hp_percent,
+        }
+    }
+
+    #[test]
+    fn targets_are_the_name_and_hp_percent() {
+        assert_eq!(target("big orc 45"), Some(orc(45)));
+        assert_eq!(target("big orc 45%"), Some(orc(45)));
+        assert_eq!(target("big orc 250"), Some(orc(100)));
+        assert_eq!(target("45"), None);
+        assert_eq!(target("big orc"), None);
+        assert_eq!(target("big orc -5"), None);
+        let other = ControlCode::new(71, None::<&[u8]>, vec![Frame::text("orc 45")]);
+        assert_eq!(Target::from_code(&other), None);
+    }
+
+    #[test]
+    fn bars_fill_by_the_hp_left_in_its_color() {
+        assert_eq!(
+            bytes(render(&orc(45), BarStyle::Ascii, 10)),
+            b"[target] big orc [\x1b<20cccc00\x1b|#####\x1b>20-----] 45%\r\n"
+        );
+        assert_eq!(
+            bytes(render(&orc(100), BarStyle::Blocks, 4)),
+            "[target] big orc [\x1b<2000cc00\x1b|████\x1b>20] 100%\r\n".as_bytes()
+        );
+        // Any hp left shows.
+        assert_eq!(
+            bytes(render(&orc(1), BarStyle::Ascii, 10)),
+            b"[target] big orc [\x1b<20cc0000\x1b|#\x1b>20---------] 1%\r\n"
+        );
+        assert_eq!(
+            bytes(render(&orc(0), BarStyle::Ascii, 3)),
+            b"[target] big orc [\x1b<20cc0000\x1b|\x1b>20---] 0%\r\n"
+        );
+    }
+
+    #[test]
+    fn percent_and_off_need_no_bar() {
+        assert_eq!(
+            bytes(render(&orc(45), BarStyle::Percent, 10)),
+            b"[target] big orc 45%\r\n"
+        );
+        assert!(render(&orc(45), BarStyle::Off, 10).is_empty());
+        for style in ["blocks", "ascii", "percent", "off"] {
+            assert_eq!(style.parse::<BarStyle>().unwrap().to_string(), style);
+        }
+        assert!("bar".parse::<BarStyle>().is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Control codes the server sends over and over, such as the status codes
+//! 50, 51 and 54, thinned out before they reach the frame log and the
+//! client. `throttle = <code> dedup` drops a code identical to the last one
+//! of its kind, `throttle = <code> <n>/s` lets at most n of them through
+//! each second.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{bc::Frame, io::CodeMatch};
+
+/// How often a throttled code gets through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Unless it is the same as the last one.
+    Dedup,
+    /// At most this many times a second.
+    PerSecond(u32),
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "dedup" {
+            return Ok(Limit::Dedup);
+        }
+        s.strip_suffix("/s")
+            .and_then(|n| n.parse().ok())
+            .filter(|&n| n > 0)
+            .map(Limit::PerSecond)
+            .ok_or_else(|| format!("invalid throttle `{}`, expected dedup or <n>/s", s))
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Dedup => f.write_str("dedup"),
+            Limit::PerSecond(n) => write!(f, "{}/s", n),
+        }
+    }
+}
+
+/// A `throttle = <code> <limit>` line of the config.
+#[derive(Debug, Clone)]
+pub struct ThrottleRule {
+    pub code: CodeMatch,
+    pub limit: Limit,
+}
+
+impl FromStr for ThrottleRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (code, limit) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("throttle `{}` has no limit", s))?;
+        Ok(Self {
+            code: code.parse()?,
+            limit: limit.trim().parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ThrottleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.limit)
+    }
+}
+
+/// The throttled codes of a session. A code takes the first rule matching
+/// it.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    rules: Vec<ThrottleRule>,
+    seen: Vec<Seen>,
+}
+
+/// What a rule has let through.
+#[derive(Debug, Default)]
+struct Seen {
+    last: Option<Vec<u8>>,
+    since: Option<Instant>,
+    count: u32,
+}
+
+impl Throttle {
+    pub fn new(rules: Vec<ThrottleRule>) -> Self {
+        let mut throttle = Self::default();
+        throttle.set_rules(rules);
+        throttle
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<ThrottleRule>) {
+        self.seen = rules.iter().map(|_| Seen::default()).collect();
+        self.rules = rules;
+    }
+
+    /// Whether `frame`, arriving at `now`, gets through.
+    pub fn allow(&mut self, frame: &Frame, now: Instant) -> bool {
+        let code = match frame {
+            Frame::Code(code) => code,
+            _ => return true,
+        };
+        let i = match self.rules.iter().position(|rule| rule.code.matches(code)) {
+            Some(i) => i,
+            None => return true,
+        };
+        let seen = &mut self.seen[i];
+        match self.rules[i].limit {
+            Limit::Dedup => {
+                let mut encoded = Vec::new();
+                frame.encode(&mut encoded);
+                if seen.last.as_ref() == Some(&encoded) {
+                    return false;
+                }
+                seen.last = Some(encoded);
+            }
+            Limit::PerSecond(n) => {
+                if seen
+                    .since
+                    .is_none_or(|since| now.duration_since(since) >= Duration::from_secs(1))
+                {
+                    seen.since = Some(now);
+                    seen.count = 0;
+                }
+                if seen.count >= n {
+                    return false;
+                }
+                seen.count += 1;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,113 @@
+//! The game's tick, when the player regains hit, spell and endurance
+//! points. It is learned from the player's [`Vitals`]: the points going up
+//! mark a tick, and the time between ticks is the median of the last gaps.
+//! Once known, points going up far from when a tick was due, such as from a
+//! heal, are not taken for one, and the tick is learned again after too
+//! many of those in a row.
+
+use std::time::{Duration, Instant};
+
+use crate::{bc::ControlCode, vitals::Vitals};
+
+/// Gaps between ticks kept to learn the tick from.
+const MAX_GAPS: usize = 8;
+/// Gaps needed before the tick is known.
+const MIN_GAPS: usize = 3;
+/// Gaps shorter or longer than these are not between two ticks.
+const MIN_GAP: Duration = Duration::from_secs(2);
+const MAX_GAP: Duration = Duration::from_secs(120);
+/// How far from when it was due a tick may come.
+const TOLERANCE: Duration = Duration::from_secs(2);
+
+/// What the player's points and the ticks seen so far tell about the next
+/// tick.
+#[derive(Debug, Default)]
+pub struct Tick {
+    points: Option<[i64; 3]>,
+    last: Option<Instant>,
+    gaps: Vec<Duration>,
+    /// Points going up in a row that were not on a tick.
+    missed: usize,
+}
+
+impl Tick {
+    /// Update the points if `code` is a code 50, returning whether they
+    /// went up on a tick.
+    pub fn observe(&mut self, code: &ControlCode, now: Instant) -> bool {
+        let points = match Vitals::from_code(code) {
+            Some(vitals) => [vitals.hp, vitals.sp, vitals.ep],
+            None => return false,
+        };
+        let rose = self
+            .points
+            .replace(points)
+            .is_some_and(|old| old.iter().zip(&points).any(|(old, new)| new > old));
+        if !rose {
+            return false;
+        }
+
+        let period = self.period();
+        if period.is_some_and(|period| self.off_by(now, period) > TOLERANCE) {
+            // Ticks that never come when due mean the tick was learned
+            // wrong, or has changed.
+            self.missed += 1;
+            if self.missed >= MAX_GAPS {
+                *self = Self {
+                    points: self.points,
+                    last: Some(now),
+                    ..Self::default()
+                };
+            }
+            return false;
+        }
+        if let Some(last) = self.last {
+            // No points are sent on ticks the player is already full on.
+            let mut gap = now.duration_since(last);
+            if let Some(period) = period {
+                gap = gap.div_f64((gap.as_secs_f64() / period.as_secs_f64()).round().max(1.0));
+            }
+            if (MIN_GAP..=MAX_GAP).contains(&gap) {
+                if self.gaps.len() == MAX_GAPS {
+                    self.gaps.remove(0);
+                }
+                self.gaps.push(gap);
+            }
+        }
+        self.last = Some(now);
+        self.missed = 0;
+        true
+    }
+
+    /// The time between ticks, once enough of them were seen.
+    pub fn period(&self) -> Option<Duration> {
+        if self.gaps.len() < MIN_GAPS {
+            return None;
+        }
+        let mut gaps = self.gaps.clone();
+        gaps.sort();
+        Some(gaps[gaps.len() / 2])
+    }
+
+    /// When the next tick after `now` is due.
+    pub fn next(&self, now: Instant) -> Option<Instant> {
+        let (period, last) = (self.period()?, self.last?);
+        let passed = now.saturating_duration_since(last).as_millis() / period.as_millis();
+        Some(last + period * (passed as u32 + 1))
+    }
+
+    /// How long until the next tick.
+    pub fn time_left(&self, now: Instant) -> Option<Duration> {
+        self.next(now).map(|next| next - now)
+    }
+
+    /// How far `now` is from the closest tick due every `period`.
+    fn off_by(&self, now: Instant, period: Duration) -> Duration {
+        let last = match self.last {
+            Some(last) => last,
+            None => return Duration::ZERO,
+        };
+        let since = now.saturating_duration_since(last).as_millis() % period.as_millis();
+        let since = Duration::from_millis(since as u64);
+        since.min(period - since)
+    }
+}
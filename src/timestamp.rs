@@ -0,0 +1,199 @@
+//! Timestamps at the start of each line sent to the client, see
+//! `#bc timestamps`. The time is written with a strftime format such as
+//! `[%H:%M:%S]`, optionally followed by the time since the previous line,
+//! as in `[21:04:17] +0.512s You are hungry.`
+//!
+//! Timestamps go in after the output is rendered, so they come before the
+//! tags of the tagged output styles and are not colored by a color the
+//! line before left on: the color is reset for the timestamp and set again
+//! after it.
+
+use std::{fmt, str::FromStr, time::Instant};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local,
+};
+
+use crate::{
+    bc::ESC,
+    telnet::{DO, DONT, IAC, SB, SE, WILL, WONT},
+};
+
+/// Most bytes of color codes set again after a timestamp.
+const MAX_SGR: usize = 256;
+
+/// A `timestamp_format` of the config.
+#[derive(Debug, Clone)]
+pub struct TimestampFormat {
+    source: String,
+    items: Vec<Item<'static>>,
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        "[%H:%M:%S]".parse().expect("default timestamp format")
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items = StrftimeItems::new(s)
+            .parse_to_owned()
+            .map_err(|_| format!("invalid timestamp format `{}`", s))?;
+        Ok(Self {
+            source: s.to_string(),
+            items,
+        })
+    }
+}
+
+impl fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Debug, Default)]
+enum Escape {
+    #[default]
+    None,
+    Esc,
+    Csi(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+enum Telnet {
+    #[default]
+    None,
+    Iac,
+    Option,
+    Sub,
+    SubIac,
+}
+
+/// Puts a timestamp before each line of the output. Lines are followed
+/// across calls, along with the colors left on.
+#[derive(Debug)]
+pub struct Stamper {
+    format: TimestampFormat,
+    elapsed: bool,
+    line_start: bool,
+    // When the last timestamp was written.
+    last: Option<Instant>,
+    escape: Escape,
+    telnet: Telnet,
+    // The color codes in effect since the last reset.
+    sgr: Vec<u8>,
+}
+
+impl Stamper {
+    pub fn new(format: TimestampFormat, elapsed: bool) -> Self {
+        Self {
+            format,
+            elapsed,
+            line_start: true,
+            last: None,
+            escape: Escape::None,
+            telnet: Telnet::None,
+            sgr: Vec::new(),
+        }
+    }
+
+    /// Take the format of a reloaded config.
+    pub fn set_format(&mut self, format: TimestampFormat, elapsed: bool) {
+        self.format = format;
+        self.elapsed = elapsed;
+    }
+
+    /// Start over, as the output written meanwhile was not seen.
+    pub fn reset(&mut self) {
+        self.line_start = true;
+        self.escape = Escape::None;
+        self.telnet = Telnet::None;
+        self.sgr.clear();
+    }
+
+    pub fn convert(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if !matches!(self.telnet, Telnet::None) || b == IAC {
+                self.telnet(b);
+            } else if !matches!(self.escape, Escape::None) {
+                self.escape(b);
+            } else {
+                // Blank lines and telnet commands get no timestamp.
+                if self.line_start && b != b'\r' && b != b'\n' {
+                    self.line_start = false;
+                    self.stamp(out);
+                }
+                match b {
+                    ESC => self.escape = Escape::Esc,
+                    b'\n' => self.line_start = true,
+                    _ => {}
+                }
+            }
+            out.push(b);
+        }
+    }
+
+    fn stamp(&mut self, out: &mut Vec<u8>) {
+        if !self.sgr.is_empty() {
+            out.extend_from_slice(b"\x1b[0m");
+        }
+        let now = Instant::now();
+        let time = Local::now().format_with_items(self.format.items.iter());
+        out.extend_from_slice(time.to_string().as_bytes());
+        if self.elapsed {
+            let since = self.last.map_or(0.0, |last| (now - last).as_secs_f64());
+            out.extend_from_slice(format!(" +{:.3}s", since).as_bytes());
+        }
+        out.push(b' ');
+        out.extend_from_slice(&self.sgr);
+        self.last = Some(now);
+    }
+
+    fn telnet(&mut self, b: u8) {
+        self.telnet = match (&self.telnet, b) {
+            (Telnet::None, _) => Telnet::Iac,
+            (Telnet::Iac, SB) => Telnet::Sub,
+            (Telnet::Iac, WILL | WONT | DO | DONT) => Telnet::Option,
+            (Telnet::Sub, IAC) => Telnet::SubIac,
+            (Telnet::Sub, _) => Telnet::Sub,
+            (Telnet::SubIac, SE) => Telnet::None,
+            (Telnet::SubIac, _) => Telnet::Sub,
+            (Telnet::Iac | Telnet::Option, _) => Telnet::None,
+        };
+    }
+
+    fn escape(&mut self, b: u8) {
+        match &mut self.escape {
+            Escape::Esc if b == b'[' => self.escape = Escape::Csi(Vec::new()),
+            Escape::Csi(params) if !(0x40..=0x7e).contains(&b) => params.push(b),
+            Escape::Csi(params) if b == b'm' => {
+                let params = std::mem::take(params);
+                self.escape = Escape::None;
+                self.sgr(&params);
+            }
+            _ => self.escape = Escape::None,
+        }
+    }
+
+    /// Keep the colors set by `ESC [ params m`.
+    fn sgr(&mut self, params: &[u8]) {
+        let first = params.split(|&b| b == b';').next().unwrap_or_default();
+        if first.is_empty() || first == b"0" {
+            self.sgr.clear();
+        }
+        if params.is_empty() || params == b"0" {
+            return;
+        }
+        if self.sgr.len() + params.len() + 3 > MAX_SGR {
+            self.sgr.clear();
+        }
+        self.sgr.extend_from_slice(b"\x1b[");
+        self.sgr.extend_from_slice(params);
+        self.sgr.push(b'm');
+    }
+}
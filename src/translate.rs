@@ -0,0 +1,425 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Sleep},
+};
+
+use crate::{bc::Frame, color, http};
+
+/// Lines are collected this long before being sent off in one request.
+const BATCH_WINDOW: Duration = Duration::from_millis(300);
+const MAX_BATCH: usize = 16;
+const MAX_CACHE: usize = 1024;
+/// Requests a translator has open at once. Batches due meanwhile wait.
+const MAX_REQUESTS: usize = 2;
+/// Batches waiting for a request, beyond which the oldest is dropped.
+const MAX_QUEUED: usize = 8;
+
+/// Lines of a batch with their translations, `None` if the request failed.
+type Translated = Vec<(String, Option<String>)>;
+
+/// A LibreTranslate compatible API to translate channel messages with.
+/// Translation is off while `url` or `channels` is empty.
+#[derive(Debug, Clone)]
+pub struct TranslateConfig {
+    pub url: String,
+    pub source: String,
+    pub target: String,
+    /// Message types to translate, e.g. `chan_suomi`.
+    pub channels: Vec<String>,
+}
+
+impl Default for TranslateConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            source: "auto".to_string(),
+            target: "en".to_string(),
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl TranslateConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.url.is_empty() && !self.channels.is_empty()
+    }
+}
+
+/// Appends a translation below each message on the configured channels.
+///
+/// Messages are passed on right away. Their translation follows as an
+/// indented line once the API answers, or immediately if it is cached. A
+/// line already waiting for its translation is not asked for again, it gets
+/// the same translation when it arrives.
+pub struct Translator {
+    config: TranslateConfig,
+    cache: HashMap<String, String>,
+    /// Lines batched or asked for, with how many messages wait for each.
+    waiting: HashMap<String, usize>,
+    batch: Vec<String>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// Batches due while [`MAX_REQUESTS`] were open.
+    queued: VecDeque<Vec<String>>,
+    requests: usize,
+    tx: mpsc::UnboundedSender<Translated>,
+    rx: mpsc::UnboundedReceiver<Translated>,
+}
+
+impl Translator {
+    pub fn new(config: TranslateConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            config,
+            cache: HashMap::new(),
+            waiting: HashMap::new(),
+            batch: Vec::new(),
+            deadline: None,
+            queued: VecDeque::new(),
+            requests: 0,
+            tx,
+            rx,
+        }
+    }
+
+    pub fn observe(&mut self, frame: &Frame, out: &mut Vec<Frame>) {
+        let code = match frame {
+            Frame::Code(code) if code.id == 10 => code,
+            _ => return,
+        };
+        let enabled = code.attr.as_deref().is_some_and(|attr| {
+            self.config
+                .channels
+                .iter()
+                .any(|channel| channel.as_bytes() == attr)
+        });
+        if !enabled {
+            return;
+        }
+
+        let line = color::strip_ansi(&code.text());
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+
+        if let Some(translated) = self.cache.get(line) {
+            out.push(Frame::text(render(translated)));
+            return;
+        }
+        match self.waiting.get_mut(line) {
+            Some(messages) => *messages += 1,
+            None => {
+                self.waiting.insert(line.to_string(), 1);
+                self.batch.push(line.to_string());
+                if self.batch.len() >= MAX_BATCH {
+                    self.send_batch();
+                } else if self.deadline.is_none() {
+                    self.deadline = Some(Box::pin(sleep(BATCH_WINDOW)));
+                }
+            }
+        }
+    }
+
+    /// Send off a due batch and pass on translations that have arrived.
+    pub fn poll_translated(&mut self, cx: &mut Context<'_>, out: &mut Vec<Frame>) -> Poll<()> {
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                self.send_batch();
+            }
+        }
+
+        let mut translated = false;
+        while let Poll::Ready(Some(lines)) = self.rx.poll_recv(cx) {
+            self.requests -= 1;
+            for (line, translation) in lines {
+                let messages = self.waiting.remove(&line).unwrap_or(0);
+                let Some(translation) = translation else {
+                    continue;
+                };
+                for _ in 0..messages {
+                    out.push(Frame::text(render(&translation)));
+                }
+                if self.cache.len() >= MAX_CACHE {
+                    self.cache.clear();
+                }
+                self.cache.insert(line, translation);
+                translated = true;
+            }
+        }
+        self.dispatch();
+
+        if translated {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn send_batch(&mut self) {
+        self.deadline = None;
+        let lines = std::mem::take(&mut self.batch);
+        if lines.is_empty() {
+            return;
+        }
+        if self.queued.len() >= MAX_QUEUED {
+            let dropped = self.queued.pop_front().unwrap_or_default();
+            tracing::warn!("translate is behind, dropping {} lines", dropped.len());
+            for line in dropped {
+                self.waiting.remove(&line);
+            }
+        }
+        self.queued.push_back(lines);
+        self.dispatch();
+    }
+
+    /// Ask for the queued batches while fewer than [`MAX_REQUESTS`] are
+    /// open.
+    fn dispatch(&mut self) {
+        while self.requests < MAX_REQUESTS {
+            match self.queued.pop_front() {
+                Some(lines) => self.request(lines),
+                None => return,
+            }
+        }
+    }
+
+    fn request(&mut self, lines: Vec<String>) {
+        self.requests += 1;
+        let url = self.config.url.clone();
+        let body = serde_json::json!({
+            "q": lines,
+            "source": self.config.source,
+            "target": self.config.target,
+            "format": "text",
+        })
+        .to_string();
+        let tx = self.tx.clone();
+
+        tokio::spawn(async move {
+            let mut translations = match translate(&url, &body).await {
+                Ok(translations) => translations,
+                Err(e) => {
+                    tracing::warn!("translate {} failed: {}", url, e);
+                    Vec::new()
+                }
+            }
+            .into_iter();
+            let lines = lines
+                .into_iter()
+                .map(|line| (line, translations.next()))
+                .collect();
+            let _ = tx.send(lines);
+        });
+    }
+}
+
+async fn translate(url: &str, body: &str) -> std::io::Result<Vec<String>> {
+    let response = http::post_json(url, body).await?;
+    let json: serde_json::Value = serde_json::from_slice(&response)?;
+    json["translatedText"]
+        .as_array()
+        .map(|lines| {
+            lines
+                .iter()
+                .map(|line| line.as_str().unwrap_or_default().to_string())
+                .collect()
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "response has no translatedText list",
+            )
+        })
+}
+
+fn render(translation: &str) -> Vec<u8> {
+    format!("    {}\r\n", translation).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::bc::Decoder;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn message(text: &str) -> Frame {
+        let mut frames = Vec::new();
+        Decoder::new().decode(
+            format!("\x1b<10chan_suomi\x1b|{}\x1b>10", text).as_bytes(),
+            &mut frames,
+        );
+        frames.remove(0)
+    }
+
+    fn translator(listener: &TcpListener) -> Translator {
+        Translator::new(TranslateConfig {
+            url: format!("http://{}/translate", listener.local_addr().unwrap()),
+            channels: vec!["chan_suomi".to_string()],
+            ..TranslateConfig::default()
+        })
+    }
+
+    fn observe(translator: &mut Translator, text: &str) -> Vec<Frame> {
+        let mut out = Vec::new();
+        translator.observe(&message(text), &mut out);
+        out
+    }
+
+    /// The translations the translator passes on next.
+    async fn translated(translator: &mut Translator) -> Vec<Frame> {
+        let mut out = Vec::new();
+        timeout(
+            TIMEOUT,
+            poll_fn(|cx| translator.poll_translated(cx, &mut out)),
+        )
+        .await
+        .unwrap();
+        out
+    }
+
+    /// Answer the request on `stream` with its lines in upper case, and
+    /// return the lines.
+    async fn answer(mut stream: TcpStream) -> Vec<String> {
+        let mut request = Vec::new();
+        let body = loop {
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if body.len() == length {
+                    break body.to_string();
+                }
+            }
+        };
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let lines: Vec<String> = body["q"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|line| line.as_str().unwrap().to_string())
+            .collect();
+        let translated: Vec<String> = lines.iter().map(|line| line.to_uppercase()).collect();
+        let response = serde_json::json!({ "translatedText": translated });
+        stream
+            .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{}", response).as_bytes())
+            .await
+            .unwrap();
+        lines
+    }
+
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        timeout(TIMEOUT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn lines_waiting_for_a_translation_are_not_asked_for_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut translator = translator(&listener);
+
+        assert!(observe(&mut translator, "hei").is_empty());
+        assert!(observe(&mut translator, "moi").is_empty());
+        assert!(observe(&mut translator, "hei").is_empty());
+        let (asked, out) = tokio::join!(
+            async { answer(accept(&listener).await).await },
+            translated(&mut translator)
+        );
+        assert_eq!(asked, ["hei", "moi"]);
+        assert_eq!(
+            out,
+            [
+                Frame::text("    HEI\r\n"),
+                Frame::text("    HEI\r\n"),
+                Frame::text("    MOI\r\n"),
+            ]
+        );
+
+        // Asked again once cached.
+        assert_eq!(
+            observe(&mut translator, "hei"),
+            [Frame::text("    HEI\r\n")]
+        );
+        assert!(translator.waiting.is_empty());
+        assert!(timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn batches_wait_while_requests_are_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut translator = translator(&listener);
+
+        for i in 0..(MAX_REQUESTS + 1) * MAX_BATCH {
+            observe(&mut translator, &format!("line {}", i));
+        }
+        let mut open = Vec::new();
+        for _ in 0..MAX_REQUESTS {
+            open.push(accept(&listener).await);
+        }
+        assert!(timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .is_err());
+        assert_eq!(translator.queued.len(), 1);
+
+        // The queued batch goes once a request is answered.
+        tokio::spawn(answer(open.remove(0)));
+        let out = translated(&mut translator).await;
+        assert_eq!(out.len(), MAX_BATCH);
+        let asked = answer(accept(&listener).await).await;
+        assert_eq!(asked[0], format!("line {}", MAX_REQUESTS * MAX_BATCH));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_asked_again_later() {
+        // Nothing listens there.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut translator = translator(&listener);
+        drop(listener);
+
+        observe(&mut translator, "hei");
+        let mut out = Vec::new();
+        timeout(TIMEOUT, async {
+            while !translator.waiting.is_empty() {
+                poll_fn(|cx| {
+                    let _ = translator.poll_translated(cx, &mut out);
+                    Poll::Ready(())
+                })
+                .await;
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(out.is_empty());
+        assert_eq!(translator.requests, 0);
+        assert!(translator.cache.is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use tokio::time::{sleep, Sleep};
+
+use crate::{bc::Frame, color, session::Session, webhook};
+
+const HIGHLIGHT_ON: &[u8] = b"\x1b[7m";
+const HIGHLIGHT_OFF: &[u8] = b"\x1b[27m";
+/// How long the start of a line cut off by the end of a read is held, so
+/// a highlight covers all of it once the rest arrives.
+const LINE_WAIT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Send(String),
+    Highlight,
+    Bell,
+    Webhook(String),
+}
+
+/// A pattern and the actions to run when a line of server output matches it.
+///
+/// Written as `[@<message type>] <pattern> => <action> [| <action>...]` where
+/// the pattern is a glob, or a regex if prefixed with `re:`. A trigger scoped
+/// with `@chan_sales` only looks at messages of that type.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    source: String,
+    scope: Option<String>,
+    pattern: Regex,
+    actions: Vec<Action>,
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, actions) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("trigger `{}` has no `=>`", s))?;
+
+        let mut pattern = pattern.trim();
+        let mut scope = None;
+        if let Some(scoped) = pattern.strip_prefix('@') {
+            let (name, rest) = scoped.split_once(' ').unwrap_or((scoped, ""));
+            scope = Some(name.to_string());
+            pattern = rest.trim_start();
+        }
+
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => re.to_string(),
+            None => glob_to_regex(pattern),
+        };
+        let regex = Regex::new(&regex).map_err(|e| e.to_string())?;
+
+        let actions = actions
+            .split('|')
+            .map(|action| {
+                let action = action.trim();
+                let (name, arg) = action.split_once(' ').unwrap_or((action, ""));
+                let arg = arg.trim();
+                match (name, arg.is_empty()) {
+                    ("send", false) => Ok(Action::Send(arg.to_string())),
+                    ("webhook", false) => Ok(Action::Webhook(arg.to_string())),
+                    ("highlight", true) => Ok(Action::Highlight),
+                    ("bell", true) => Ok(Action::Bell),
+                    _ => Err(format!("invalid trigger action `{}`", action)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            scope,
+            pattern: regex,
+            actions,
+        })
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Trigger {
+    fn has_side_effects(&self) -> bool {
+        self.actions.iter().any(|a| *a != Action::Highlight)
+    }
+}
+
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Runs triggers over server output, one line at a time.
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    cooldown: Duration,
+    last_fired: Vec<Option<Instant>>,
+    // Text of the line seen so far and its frames not yet passed on.
+    line: Vec<u8>,
+    pending: Vec<Frame>,
+    // When the frames of an unfinished line held for a highlight are
+    // passed on anyway.
+    held_until: Option<Pin<Box<Sleep>>>,
+}
+
+impl Triggers {
+    pub fn new(triggers: Vec<Trigger>, cooldown: Duration) -> Self {
+        Self {
+            last_fired: vec![None; triggers.len()],
+            triggers,
+            cooldown,
+            line: Vec::new(),
+            pending: Vec::new(),
+            held_until: None,
+        }
+    }
+
+    /// Match `triggers` from now on, none of them having fired yet.
+    pub fn set_triggers(&mut self, triggers: Vec<Trigger>, cooldown: Duration) {
+        self.last_fired = vec![None; triggers.len()];
+        self.triggers = triggers;
+        self.cooldown = cooldown;
+    }
+
+    pub fn push(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        if self.triggers.is_empty() {
+            out.push(frame);
+            return;
+        }
+
+        match frame {
+            Frame::Code(ref code) if code.id == 10 => self.push_message(frame, out, session),
+            Frame::Prompt(_) => self.push_message(frame, out, session),
+            Frame::Code(_) | Frame::LoginResult(_) | Frame::Map(_) => {
+                frame.push_text(&mut self.line);
+                self.pending.push(frame);
+            }
+            Frame::Text(mut text) => {
+                while let Some(i) = text.iter().position(|&b| b == b'\n') {
+                    let rest = text.split_off(i + 1);
+                    self.line.extend_from_slice(&text);
+                    self.pending.push(Frame::Text(text));
+                    text = rest;
+
+                    let line = color::strip_ansi(&std::mem::take(&mut self.line));
+                    let frames = std::mem::take(&mut self.pending);
+                    self.held_until = None;
+                    self.run(None, &line, frames, out, session);
+                }
+                if !text.is_empty() {
+                    self.line.extend_from_slice(&text);
+                    self.pending.push(Frame::Text(text));
+                }
+            }
+        }
+    }
+
+    /// Pass on the frames of an unfinished line so output is not delayed.
+    /// The line's text is kept for matching once the rest arrives. With
+    /// triggers that highlight, the frames are held for [`LINE_WAIT`]
+    /// first, so the whole line is highlighted if it matches.
+    pub fn flush(&mut self, out: &mut Vec<Frame>) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let highlights = self
+            .triggers
+            .iter()
+            .any(|trigger| trigger.actions.contains(&Action::Highlight));
+        if !highlights {
+            return self.release(out);
+        }
+        if self.held_until.is_none() {
+            self.held_until = Some(Box::pin(sleep(LINE_WAIT)));
+        }
+    }
+
+    /// Pass on the frames of an unfinished line held for too long.
+    pub fn poll_held(&mut self, cx: &mut Context<'_>, out: &mut Vec<Frame>) -> Poll<()> {
+        let Some(held) = self.held_until.as_mut() else {
+            return Poll::Pending;
+        };
+        if held.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.release(out);
+        Poll::Ready(())
+    }
+
+    /// Pass on the frames of an unfinished line.
+    pub fn release(&mut self, out: &mut Vec<Frame>) {
+        self.held_until = None;
+        out.append(&mut self.pending);
+    }
+
+    fn push_message(&mut self, frame: Frame, out: &mut Vec<Frame>, session: &mut Session) {
+        self.release(out);
+
+        let scope = frame
+            .code()
+            .and_then(|code| code.attr.as_deref())
+            .map(|attr| String::from_utf8_lossy(attr).into_owned());
+        let mut text = Vec::new();
+        frame.push_text(&mut text);
+        let line = color::strip_ansi(&text);
+        self.run(scope.as_deref(), &line, vec![frame], out, session);
+    }
+
+    fn run(
+        &mut self,
+        scope: Option<&str>,
+        line: &str,
+        frames: Vec<Frame>,
+        out: &mut Vec<Frame>,
+        session: &mut Session,
+    ) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut highlight = false;
+        let mut bell = false;
+        let now = Instant::now();
+
+        for (trigger, last_fired) in self.triggers.iter().zip(self.last_fired.iter_mut()) {
+            if trigger.scope.is_some() && trigger.scope.as_deref() != scope {
+                continue;
+            }
+            if !trigger.pattern.is_match(line) {
+                continue;
+            }
+
+            highlight |= trigger.actions.contains(&Action::Highlight);
+
+            if trigger.has_side_effects() {
+                if matches!(last_fired, Some(t) if now.duration_since(*t) < self.cooldown) {
+                    continue;
+                }
+                *last_fired = Some(now);
+            }
+
+            for action in &trigger.actions {
+                match action {
+                    Action::Send(command) => session.send_command(command),
+                    Action::Bell => bell = true,
+                    Action::Webhook(url) => webhook::post(
+                        url,
+                        serde_json::json!({ "trigger": trigger.source, "text": line }).to_string(),
+                    ),
+                    Action::Highlight => {}
+                }
+            }
+        }
+
+        if highlight {
+            out.push(Frame::text(HIGHLIGHT_ON.to_vec()));
+            out.extend(frames);
+            out.push(Frame::text(HIGHLIGHT_OFF.to_vec()));
+        } else {
+            out.extend(frames);
+        }
+        if bell {
+            out.push(Frame::text(b"\x07".to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{bc::ControlCode, config::Config};
+
+    fn triggers(triggers: &[&str], cooldown: Duration) -> Triggers {
+        let triggers = triggers.iter().map(|t| t.parse().unwrap()).collect();
+        Triggers::new(triggers, cooldown)
+    }
+
+    fn push(triggers: &mut Triggers, frame: Frame, session: &mut Session) -> Vec<u8> {
+        let mut out = Vec::new();
+        triggers.push(frame, &mut out, session);
+        let mut written = Vec::new();
+        for frame in &out {
+            frame.encode(&mut written);
+        }
+        written
+    }
+
+    fn line(text: &str) -> Frame {
+        Frame::text(format!("{}\r\n", text))
+    }
+
+    fn sent(session: &mut Session) -> Vec<String> {
+        session
+            .to_server
+            .drain(..)
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn triggers_are_read_with_their_scope_and_actions() {
+        let trigger: Trigger = "@chan_sales *sword* => send buy sword | bell"
+            .parse()
+            .unwrap();
+        assert_eq!(trigger.scope.as_deref(), Some("chan_sales"));
+        assert!(trigger.pattern.is_match("Bob: a sword"));
+        assert_eq!(
+            trigger.actions,
+            [Action::Send("buy sword".to_string()), Action::Bell]
+        );
+
+        assert_eq!(
+            "x => dance".parse::<Trigger>().unwrap_err(),
+            "invalid trigger action `dance`"
+        );
+        assert!("x => send".parse::<Trigger>().is_err());
+        assert!("x send y".parse::<Trigger>().is_err());
+        assert!("re:( => bell".parse::<Trigger>().is_err());
+    }
+
+    #[test]
+    fn matching_lines_send_commands_and_ring_the_bell() {
+        let mut session = Session::new(&Config::default(), None);
+        let mut triggers = triggers(
+            &["You are hungry* => send eat bread | bell"],
+            Duration::ZERO,
+        );
+
+        // Colors are not part of the line matched.
+        assert_eq!(
+            push(
+                &mut triggers,
+                line("\x1b[31mYou are hungry.\x1b[0m"),
+                &mut session
+            ),
+            b"\x1b[31mYou are hungry.\x1b[0m\r\n\x07"
+        );
+        assert_eq!(sent(&mut session), ["eat bread\n"]);
+
+        assert_eq!(
+            push(&mut triggers, line("You eat."), &mut session),
+            b"You eat.\r\n"
+        );
+        assert!(sent(&mut session).is_empty());
+    }
+
+    #[test]
+    fn lines_split_between_frames_are_matched_whole() {
+        let mut session = Session::new(&Config::default(), None);
+        let mut triggers = triggers(&["You are hungry* => send eat"], Duration::ZERO);
+        let mut out = Vec::new();
+        triggers.push(Frame::text("You are "), &mut out, &mut session);
+        triggers.flush(&mut out);
+        assert_eq!(out, [Frame::text("You are ")]);
+
+        push(&mut triggers, line("hungry."), &mut session);
+        assert_eq!(sent(&mut session), ["eat\n"]);
+    }
+
+    #[test]
+    fn scoped_triggers_only_see_their_messages() {
+        let mut session = Session::new(&Config::default(), None);
+        let mut triggers = triggers(&["@chan_sales *sword* => send buy"], Duration::ZERO);
+        push(&mut triggers, line("a sword"), &mut session);
+        assert!(sent(&mut session).is_empty());
+
+        let sales = Frame::Code(ControlCode::new(
+            10,
+            Some("chan_sales"),
+            vec![line("Bob: a sword")],
+        ));
+        push(&mut triggers, sales, &mut session);
+        assert_eq!(sent(&mut session), ["buy\n"]);
+    }
+
+    #[test]
+    fn triggers_fire_at_most_once_a_cooldown() {
+        let mut session = Session::new(&Config::default(), None);
+        let mut triggers = triggers(
+            &["*hungry* => send eat", "*hungry* => highlight"],
+            Duration::from_secs(60),
+        );
+        for _ in 0..3 {
+            // Highlights do nothing else, so they are not limited.
+            assert_eq!(
+                push(&mut triggers, line("hungry"), &mut session),
+                b"\x1b[7mhungry\r\n\x1b[27m"
+            );
+        }
+        assert_eq!(sent(&mut session), ["eat\n"]);
+
+        // Each trigger has a cooldown of its own, and new triggers start
+        // without one.
+        triggers.set_triggers(
+            vec![
+                "*hungry* => send eat".parse().unwrap(),
+                "*hungry* => send drink".parse().unwrap(),
+            ],
+            Duration::from_secs(60),
+        );
+        push(&mut triggers, line("hungry"), &mut session);
+        push(&mut triggers, line("hungry"), &mut session);
+        assert_eq!(sent(&mut session), ["eat\n", "drink\n"]);
+    }
+
+    #[tokio::test]
+    async fn webhooks_get_the_trigger_and_the_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trigger = format!(
+            "*dragon* => webhook http://{}/hook",
+            listener.local_addr().unwrap()
+        );
+        let mut session = Session::new(&Config::default(), None);
+        let mut triggers = triggers(&[&trigger], Duration::ZERO);
+        push(&mut triggers, line("A dragon arrives."), &mut session);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let body = loop {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&request));
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                assert!(head.starts_with("POST /hook "), "{}", head);
+                if body.ends_with('}') {
+                    break body.to_string();
+                }
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "trigger": trigger, "text": "A dragon arrives." })
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Live upgrades: a new proxy takes over the listeners and the server
+//! connections of a running one, so a new binary is deployed without the
+//! player dropping out of the game.
+//!
+//! The running proxy listens on `upgrade_socket`. The new one, started with
+//! `--takeover`, connects there before it binds anything. The old one stops
+//! accepting clients and stops its sessions where they are, then sends the
+//! descriptors of its listeners and server connections over the socket,
+//! with the state of each session. Once the new one answered that it has
+//! them, the old one ends its sessions, which closes its clients but not
+//! the server connections, and exits. The clients connect again, to the
+//! same listeners now served by the new proxy, and each session there takes
+//! over a server connection of its profile, still logged in, before it
+//! would open one.
+//!
+//! What the server sends meanwhile waits in the kernel for the new proxy.
+//! Both ends check the other runs as the same user, as anyone who can
+//! reach the socket could otherwise take over the player's connections.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    mem,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    net::{TcpListener, TcpStream, UnixStream},
+};
+use tracing::{info, warn};
+
+use crate::listener::{self, Listener};
+
+/// Most descriptors Linux passes in one message.
+const MAX_FDS: usize = 253;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// A server connection handed over with its session.
+pub struct Connection {
+    /// The config profile of the session, if not the main one.
+    pub profile: Option<String>,
+    /// The state of the session, as [`crate::state::snapshot`] gives it.
+    pub state: String,
+    pub fd: OwnedFd,
+}
+
+/// What is sent ahead of the descriptors, which come in its order:
+/// the listeners first, then the server connections.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    listeners: Vec<String>,
+    sessions: Vec<Handover>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Handover {
+    profile: Option<String>,
+    state: String,
+}
+
+/// The sockets of a proxy as far as upgrades go: copies of those it bound,
+/// to hand over to the next one, and those the previous one handed over
+/// and were not taken yet.
+#[derive(Clone, Default)]
+pub struct Sockets(Arc<Mutex<SocketList>>);
+
+#[derive(Default)]
+struct SocketList {
+    bound: Vec<(String, OwnedFd)>,
+    listeners: HashMap<String, OwnedFd>,
+    connections: Vec<Connection>,
+}
+
+impl Sockets {
+    /// Keep a copy of `listener` on `addr` to hand over.
+    pub fn keep(&self, addr: &str, listener: &impl AsFd) -> io::Result<()> {
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        self.0.lock().unwrap().bound.push((addr.to_string(), fd));
+        Ok(())
+    }
+
+    /// The listener on `addr` the previous proxy handed over, if it did.
+    /// It is kept to hand over again.
+    pub fn listener(&self, addr: &str) -> io::Result<Option<Listener>> {
+        let fd = match self.take(addr)? {
+            Some(fd) => fd,
+            None => return Ok(None),
+        };
+        let listener = if listener::is_path(addr) {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            Listener::Unix(tokio::net::UnixListener::from_std(listener)?)
+        } else {
+            Listener::Tcp(tcp_listener(fd)?)
+        };
+        Ok(Some(listener))
+    }
+
+    /// Like [`listener`](Self::listener), for a TCP address.
+    pub fn tcp_listener(&self, addr: &str) -> io::Result<Option<TcpListener>> {
+        self.take(addr)?.map(tcp_listener).transpose()
+    }
+
+    fn take(&self, addr: &str) -> io::Result<Option<OwnedFd>> {
+        let mut list = self.0.lock().unwrap();
+        let fd = match list.listeners.remove(addr) {
+            Some(fd) => fd,
+            None => return Ok(None),
+        };
+        list.bound.push((addr.to_string(), fd.try_clone()?));
+        Ok(Some(fd))
+    }
+
+    /// Close the listeners handed over that the config no longer has.
+    pub fn close_unused(&self) {
+        for (addr, _) in self.0.lock().unwrap().listeners.drain() {
+            info!(addr, "closed the listener the old proxy had");
+        }
+    }
+
+    /// A server connection of `profile` the previous proxy handed over,
+    /// with the state of its session.
+    pub fn adopt(&self, profile: Option<&str>) -> io::Result<Option<(TcpStream, String)>> {
+        let connection = {
+            let mut list = self.0.lock().unwrap();
+            match list
+                .connections
+                .iter()
+                .position(|c| c.profile.as_deref() == profile)
+            {
+                Some(i) => list.connections.remove(i),
+                None => return Ok(None),
+            }
+        };
+        let stream = std::net::TcpStream::from(connection.fd);
+        stream.set_nonblocking(true)?;
+        Ok(Some((TcpStream::from_std(stream)?, connection.state)))
+    }
+
+    /// Take over the listeners and server connections of the proxy
+    /// listening on `path`.
+    pub async fn take_over(&self, path: &Path) -> io::Result<()> {
+        let mut stream = UnixStream::connect(path).await?;
+        same_user(&stream)?;
+        let mut header = [0; 4];
+        let (n, mut fds) = stream
+            .async_io(Interest::READABLE, || recv_fds(&stream, &mut header))
+            .await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        stream.read_exact(&mut header[n..]).await?;
+        let mut manifest = vec![0; u32::from_be_bytes(header) as usize];
+        stream.read_exact(&mut manifest).await?;
+        let manifest: Manifest = serde_json::from_slice(&manifest)?;
+        if fds.len() != manifest.listeners.len() + manifest.sessions.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "got {} sockets for {} listeners and {} sessions",
+                    fds.len(),
+                    manifest.listeners.len(),
+                    manifest.sessions.len()
+                ),
+            ));
+        }
+        stream.write_all(&[1]).await?;
+
+        let connections = fds.split_off(manifest.listeners.len());
+        let mut list = self.0.lock().unwrap();
+        list.listeners = manifest.listeners.into_iter().zip(fds).collect();
+        list.connections = manifest
+            .sessions
+            .into_iter()
+            .zip(connections)
+            .map(|(session, fd)| Connection {
+                profile: session.profile,
+                state: session.state,
+                fd,
+            })
+            .collect();
+        info!(
+            listeners = list.listeners.len(),
+            sessions = list.connections.len(),
+            "took over from the old proxy"
+        );
+        Ok(())
+    }
+
+    /// Hand the listeners kept and `connections` over to the proxy on
+    /// `stream`, waiting until it has them. Whoever stops the sessions
+    /// checks the proxy with [`same_user`] first.
+    pub async fn hand_over(
+        &self,
+        mut stream: UnixStream,
+        connections: &[Connection],
+    ) -> io::Result<()> {
+        let listeners = {
+            let list = self.0.lock().unwrap();
+            list.bound
+                .iter()
+                .map(|(addr, fd)| Ok((addr.clone(), fd.try_clone()?)))
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        let manifest = Manifest {
+            listeners: listeners.iter().map(|(addr, _)| addr.clone()).collect(),
+            sessions: connections
+                .iter()
+                .map(|c| Handover {
+                    profile: c.profile.clone(),
+                    state: c.state.clone(),
+                })
+                .collect(),
+        };
+        let fds: Vec<RawFd> = listeners
+            .iter()
+            .map(|(_, fd)| fd.as_raw_fd())
+            .chain(connections.iter().map(|c| c.fd.as_raw_fd()))
+            .collect();
+        if fds.len() > MAX_FDS {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} sockets are more than can be handed over", fds.len()),
+            ));
+        }
+
+        let manifest = serde_json::to_vec(&manifest)?;
+        let header = (manifest.len() as u32).to_be_bytes();
+        let sent = stream
+            .async_io(Interest::WRITABLE, || send_fds(&stream, &header, &fds))
+            .await?;
+        stream.write_all(&header[sent..]).await?;
+        stream.write_all(&manifest).await?;
+        stream.read_exact(&mut [0]).await?;
+        Ok(())
+    }
+}
+
+/// A copy of the descriptor of a session's server connection, kept to hand
+/// it over.
+#[derive(Clone, Default)]
+pub struct ServerSlot(Arc<Mutex<Option<OwnedFd>>>);
+
+impl ServerSlot {
+    /// Keep a copy of `stream`, the server connection from now on.
+    pub fn set(&self, stream: &TcpStream) {
+        match stream.as_fd().try_clone_to_owned() {
+            Ok(fd) => *self.0.lock().unwrap() = Some(fd),
+            Err(e) => warn!("cannot keep the server connection to hand over: {}", e),
+        }
+    }
+
+    /// A copy of the server connection, if there is one.
+    pub fn get(&self) -> Option<OwnedFd> {
+        let slot = self.0.lock().unwrap();
+        slot.as_ref().and_then(|fd| fd.try_clone().ok())
+    }
+}
+
+/// Fail unless the proxy on the other end of `stream` runs as the same
+/// user.
+pub fn same_user(stream: &UnixStream) -> io::Result<()> {
+    let peer = stream.peer_cred()?.uid();
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    if peer != uid {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "the proxy on the upgrade socket runs as uid {}, not {}",
+                peer, uid
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn tcp_listener(fd: OwnedFd) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Send `bytes` on `socket` with `fds` attached, returning how many of
+/// the bytes went.
+fn send_fds(socket: &impl AsRawFd, bytes: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let len = mem::size_of_val(fds) as u32;
+    // u64 for the alignment of the control message header.
+    let mut control = vec![0u64; unsafe { libc::CMSG_SPACE(len) } as usize / 8 + 1];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    // SAFETY: an all-zero msghdr is a valid empty one.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(len) } as _;
+        // SAFETY: the control buffer has room for a header and `fds`.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        }
+    }
+    // SAFETY: `msg` points at buffers that outlive the call.
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Read into `buf` from `socket` with the descriptors attached, returning
+/// how many bytes were read and the descriptors.
+fn recv_fds(socket: &impl AsRawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let len = (MAX_FDS * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u64; unsafe { libc::CMSG_SPACE(len) } as usize / 8 + 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: an all-zero msghdr is a valid empty one.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = (control.len() * 8) as _;
+    // SAFETY: `msg` points at buffers that outlive the call.
+    let read = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled in the control messages of `msg`, and the
+    // descriptors in them are new ones of this process.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "more sockets than can be taken over",
+        ));
+    }
+    Ok((read as usize, fds))
+}
@@ -0,0 +1,59 @@
+//! The player's hit, spell and endurance points, from code 50
+//! `player_status`: `<hp> <max hp> <sp> <max sp> <ep> <max ep>`.
+
+use std::time::SystemTime;
+
+use crate::{
+    bc::{ControlCode, Frame},
+    session::Session,
+};
+
+pub const PLAYER_STATUS: u8 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vitals {
+    pub hp: i64,
+    pub max_hp: i64,
+    pub sp: i64,
+    pub max_sp: i64,
+    pub ep: i64,
+    pub max_ep: i64,
+}
+
+impl Vitals {
+    /// The points in `code`, if it is a code 50.
+    pub fn from_code(code: &ControlCode) -> Option<Self> {
+        if code.id != PLAYER_STATUS {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&code.text()).into_owned();
+        let fields: Vec<i64> = text
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        match fields[..] {
+            [hp, max_hp, sp, max_sp, ep, max_ep] => Some(Self {
+                hp,
+                max_hp,
+                sp,
+                max_sp,
+                ep,
+                max_ep,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Show the points `frame` reports in the session's listing.
+pub fn observe(frame: &Frame, session: &mut Session) {
+    let vitals = match frame {
+        Frame::Code(code) => Vitals::from_code(code),
+        _ => None,
+    };
+    if let (Some(vitals), Some(listing)) = (vitals, &session.listing) {
+        let now = SystemTime::now();
+        listing.update(|summary| summary.vitals = Some((vitals, now)));
+    }
+}
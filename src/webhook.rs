@@ -0,0 +1,11 @@
+use crate::http;
+
+/// POST `body` as JSON to an `http://` or `https://` url in the background.
+pub fn post(url: &str, body: String) {
+    let url = url.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = http::post_json(&url, &body).await {
+            tracing::warn!("webhook {} failed: {}", url, e);
+        }
+    });
+}
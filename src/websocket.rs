@@ -0,0 +1,587 @@
+//! The server side of the WebSocket protocol (RFC 6455), as much as browser
+//! clients need.
+//!
+//! A [`WebSocket`] is read and written as a byte stream like a TCP client:
+//! every message received reads as a line of input, and every line written
+//! is sent as a message of its own.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE: usize = 8 * 1024;
+const MAX_MESSAGE: u64 = 64 * 1024;
+/// Encoded output held before writes wait for the socket.
+const MAX_PENDING: usize = 256 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+const FORBIDDEN: &str = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+const UPGRADE_REQUIRED: &str =
+    "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n";
+
+pub struct WebSocket<S> {
+    inner: S,
+    // Bytes read from `inner` that do not make a whole frame yet.
+    received: Vec<u8>,
+    // Message data not read yet.
+    input: Vec<u8>,
+    input_pos: usize,
+    // Start of a line written but not sent yet.
+    line: Vec<u8>,
+    // Frames waiting to be written to `inner`.
+    pending: Vec<u8>,
+    pending_pos: usize,
+    closed: bool,
+    close_sent: bool,
+}
+
+/// Answer the opening handshake of a client on `stream`. Browsers send the
+/// origin of the page, which may be any page the player has open: unless it
+/// is one of `origins` the client is only let in if it can be asked for
+/// `password`. Returns whether it has to be, which clients other than
+/// browsers do not.
+pub async fn accept<S>(
+    mut stream: S,
+    origins: &[String],
+    password: bool,
+) -> io::Result<(WebSocket<S>, bool)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let end = loop {
+        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_HANDSHAKE {
+            return Err(invalid("incomplete handshake"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..end]);
+    let handshake = parse_handshake(&head).and_then(|handshake| {
+        let untrusted = handshake
+            .origin
+            .is_some_and(|origin| !origins.iter().any(|o| same_origin(o, origin)));
+        match untrusted && !password {
+            true => Err((FORBIDDEN, "the origin of the page is not allowed")),
+            false => Ok((handshake.key, untrusted)),
+        }
+    });
+    let (key, untrusted) = match handshake {
+        Ok(handshake) => handshake,
+        Err((response, reason)) => {
+            stream.write_all(response.as_bytes()).await?;
+            return Err(invalid(reason));
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    let socket = WebSocket {
+        inner: stream,
+        received: request[end..].to_vec(),
+        input: Vec::new(),
+        input_pos: 0,
+        line: Vec::new(),
+        pending: Vec::new(),
+        pending_pos: 0,
+        closed: false,
+        close_sent: false,
+    };
+    Ok((socket, untrusted))
+}
+
+/// What the proxy needs of a valid opening handshake.
+#[derive(Debug, PartialEq, Eq)]
+struct Handshake<'a> {
+    key: &'a str,
+    origin: Option<&'a str>,
+}
+
+/// Check `head` is an opening handshake as RFC 6455 section 4.2.1 has it,
+/// or give the response it gets and why.
+fn parse_handshake(head: &str) -> Result<Handshake<'_>, (&'static str, &'static str)> {
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    if (request.next(), request.nth(1)) != (Some("GET"), Some("HTTP/1.1")) {
+        return Err((BAD_REQUEST, "not a GET request over HTTP/1.1"));
+    }
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, value)| value)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err((BAD_REQUEST, "not a websocket handshake"));
+    }
+    if header("sec-websocket-version") != Some("13") {
+        return Err((UPGRADE_REQUIRED, "unsupported websocket version"));
+    }
+    let key = match header("sec-websocket-key") {
+        Some(key) if is_nonce(key) => key,
+        _ => return Err((BAD_REQUEST, "invalid websocket key")),
+    };
+    Ok(Handshake {
+        key,
+        origin: header("origin"),
+    })
+}
+
+/// Whether `key` is 16 bytes in base64.
+fn is_nonce(key: &str) -> bool {
+    key.len() == 24
+        && key.ends_with("==")
+        && key[..22]
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+fn same_origin(allowed: &str, origin: &str) -> bool {
+    allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+}
+
+/// The `Sec-WebSocket-Accept` answering `key`.
+fn accept_key(key: &str) -> String {
+    let mut hash = sha1_smol::Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(GUID.as_bytes());
+    base64(&hash.digest().bytes())
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// Take the next whole frame out of `received`.
+    fn next_frame(&mut self) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+        let b = &self.received;
+        if b.len() < 2 {
+            return Ok(None);
+        }
+        let fin = b[0] & 0x80 != 0;
+        let opcode = b[0] & 0x0f;
+        let masked = b[1] & 0x80 != 0;
+        let (len, mut pos) = match b[1] & 0x7f {
+            126 if b.len() >= 4 => (u64::from(u16::from_be_bytes([b[2], b[3]])), 4),
+            127 if b.len() >= 10 => (u64::from_be_bytes(b[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_MESSAGE {
+            return Err(invalid("message too long"));
+        }
+        if !masked {
+            return Err(invalid("frame from the client not masked"));
+        }
+        if b.len() < pos + 4 {
+            return Ok(None);
+        }
+        let mask = [b[pos], b[pos + 1], b[pos + 2], b[pos + 3]];
+        pos += 4;
+        let end = pos + len as usize;
+        if b.len() < end {
+            return Ok(None);
+        }
+
+        let mut payload = b[pos..end].to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        self.received.drain(..end);
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.pending.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => self.pending.push(len as u8),
+            len @ 126..=0xffff => {
+                self.pending.push(126);
+                self.pending.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.pending.push(127);
+                self.pending.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.pending.extend_from_slice(payload);
+    }
+
+    /// Send each whole line written so far as a message.
+    fn queue_lines(&mut self) {
+        while let Some(i) = self.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=i).collect();
+            self.queue_message(&line);
+        }
+    }
+
+    fn queue_message(&mut self, line: &[u8]) {
+        let message = line.strip_suffix(b"\n").unwrap_or(line);
+        let message = message.strip_suffix(b"\r").unwrap_or(message);
+        let opcode = match std::str::from_utf8(message) {
+            Ok(_) => TEXT,
+            Err(_) => BINARY,
+        };
+        self.queue_frame(opcode, message);
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.input_pos < this.input.len() {
+                let n = buf.remaining().min(this.input.len() - this.input_pos);
+                buf.put_slice(&this.input[this.input_pos..this.input_pos + n]);
+                this.input_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            this.input.clear();
+            this.input_pos = 0;
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            match this.next_frame()? {
+                Some((fin, opcode, payload)) => match opcode {
+                    CONTINUATION | TEXT | BINARY => {
+                        this.input.extend_from_slice(&payload);
+                        if fin {
+                            this.input.push(b'\n');
+                        }
+                    }
+                    CLOSE => {
+                        this.closed = true;
+                        if !this.close_sent {
+                            this.close_sent = true;
+                            this.queue_frame(CLOSE, &payload[..payload.len().min(2)]);
+                        }
+                        let _ = this.poll_send(cx)?;
+                    }
+                    PING => {
+                        this.queue_frame(PONG, &payload);
+                        let _ = this.poll_send(cx)?;
+                    }
+                    PONG => {}
+                    _ => return Poll::Ready(Err(invalid("unknown opcode"))),
+                },
+                None => {
+                    let mut chunk = [0; 4096];
+                    let mut chunk = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+                    if chunk.filled().is_empty() {
+                        this.closed = true;
+                    }
+                    this.received.extend_from_slice(chunk.filled());
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending.len() - this.pending_pos > MAX_PENDING {
+            ready!(this.poll_send(cx))?;
+        }
+        this.line.extend_from_slice(buf);
+        this.queue_lines();
+        // Sent in full by poll_flush, which comes after the writes.
+        let _ = this.poll_send(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.close_sent = true;
+            if !this.line.is_empty() {
+                let line = std::mem::take(&mut this.line);
+                this.queue_message(&line);
+            }
+            this.queue_frame(CLOSE, &[]);
+        }
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn request(origin: Option<&str>) -> String {
+        let mut request = format!(
+            "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n",
+            KEY
+        );
+        if let Some(origin) = origin {
+            request.push_str(&format!("Origin: {}\r\n", origin));
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    /// A frame from a client, its length in `extended` bytes after the
+    /// first two if not 0.
+    fn client_frame(opcode: u8, payload: &[u8], extended: usize) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode];
+        match extended {
+            0 => frame.push(0x80 | payload.len() as u8),
+            2 => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            _ => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn socket(received: Vec<u8>) -> WebSocket<DuplexStream> {
+        WebSocket {
+            inner: duplex(64).0,
+            received,
+            input: Vec::new(),
+            input_pos: 0,
+            line: Vec::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    /// Complete a handshake of `request` with the proxy's side, returning
+    /// the result and the response.
+    async fn handshake(
+        request: &str,
+        origins: &[String],
+        password: bool,
+    ) -> (io::Result<bool>, String) {
+        let (mut client, server) = duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let accepted = accept(server, origins, password).await;
+        let accepted = accepted.map(|(_, untrusted)| untrusted);
+        let mut response = vec![0; 4096];
+        let n = client.read(&mut response).await.unwrap();
+        (
+            accepted,
+            String::from_utf8_lossy(&response[..n]).into_owned(),
+        )
+    }
+
+    #[test]
+    fn the_accept_key_is_the_one_of_the_rfc() {
+        assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn a_valid_handshake_gives_the_key_and_the_origin() {
+        let request = request(Some("http://localhost:8080"));
+        let handshake = parse_handshake(&request).unwrap();
+        assert_eq!(
+            handshake,
+            Handshake {
+                key: KEY,
+                origin: Some("http://localhost:8080"),
+            }
+        );
+    }
+
+    #[test]
+    fn handshakes_that_are_not_rfc_6455_are_refused() {
+        let valid = request(None);
+        let cases = [
+            (valid.replace("GET", "POST"), BAD_REQUEST),
+            (valid.replace("HTTP/1.1", "HTTP/1.0"), BAD_REQUEST),
+            (
+                valid.replace("Upgrade: websocket", "Upgrade: h2c"),
+                BAD_REQUEST,
+            ),
+            (
+                valid.replace("keep-alive, Upgrade", "keep-alive"),
+                BAD_REQUEST,
+            ),
+            (valid.replace("Version: 13", "Version: 8"), UPGRADE_REQUIRED),
+            (valid.replace(KEY, "short=="), BAD_REQUEST),
+            (valid.replace(KEY, "dGhlIHNhbXBsZSBub25jZQ!!"), BAD_REQUEST),
+        ];
+        for (request, response) in cases {
+            let refused = parse_handshake(&request).unwrap_err();
+            assert_eq!(refused.0, response, "{}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_of_other_origins_need_the_password() {
+        let origins = vec!["http://localhost:8080/".to_string()];
+
+        let (accepted, response) =
+            handshake(&request(Some("http://localhost:8080")), &origins, false).await;
+        assert!(!accepted.unwrap());
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let (accepted, _) = handshake(&request(Some("http://evil.example")), &origins, true).await;
+        assert!(accepted.unwrap(), "asked for the password");
+
+        let (accepted, response) =
+            handshake(&request(Some("http://evil.example")), &origins, false).await;
+        assert!(accepted.is_err());
+        assert_eq!(response, FORBIDDEN);
+
+        // Not a browser.
+        let (accepted, _) = handshake(&request(None), &[], false).await;
+        assert!(!accepted.unwrap());
+    }
+
+    #[test]
+    fn frames_are_read_with_each_length_encoding() {
+        let long = vec![b'x'; 300];
+        for (payload, extended) in [(&b"look"[..], 0), (&long[..], 2), (&b"north"[..], 8)] {
+            let mut socket = socket(client_frame(TEXT, payload, extended));
+            let (fin, opcode, read) = socket.next_frame().unwrap().unwrap();
+            assert!(fin);
+            assert_eq!(opcode, TEXT);
+            assert_eq!(read, payload, "unmasked");
+            assert!(socket.received.is_empty());
+        }
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let frame = client_frame(TEXT, &[b'x'; 300], 2);
+        for end in [1, 3, 7, frame.len() - 1] {
+            assert!(socket(frame[..end].to_vec())
+                .next_frame()
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn unmasked_and_oversized_frames_are_errors() {
+        let mut unmasked = client_frame(TEXT, b"look", 0);
+        unmasked[1] &= 0x7f;
+        assert!(socket(unmasked).next_frame().is_err());
+
+        let mut oversized = vec![0x80 | TEXT, 0x80 | 127];
+        oversized.extend_from_slice(&(MAX_MESSAGE + 1).to_be_bytes());
+        assert!(socket(oversized).next_frame().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_read_as_lines() {
+        let mut received = client_frame(TEXT, b"lo", 0);
+        received[0] &= 0x7f;
+        received.extend(client_frame(CONTINUATION, b"ok", 0));
+        received.extend(client_frame(TEXT, b"north", 0));
+        let mut socket = socket(received);
+        let mut lines = [0; 11];
+        socket.read_exact(&mut lines).await.unwrap();
+        assert_eq!(&lines, b"look\nnorth\n");
+    }
+}
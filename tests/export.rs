@@ -0,0 +1,68 @@
+//! Area maps exported from the rooms and links the mapper stored.
+
+use batproxy_rs::{db::Link, export, mapper::Room};
+
+fn room(id: &str, short_desc: &str) -> Room {
+    Room {
+        area: "arelium".to_string(),
+        id: id.to_string(),
+        direction: String::new(),
+        indoors: false,
+        short_desc: short_desc.to_string(),
+        long_desc: String::new(),
+        exits: Vec::new(),
+    }
+}
+
+fn link(from: &str, to: &str, direction: &str) -> Link {
+    Link {
+        from: from.to_string(),
+        to: to.to_string(),
+        direction: direction.to_string(),
+    }
+}
+
+/// The column and row of the box labeled `label` in `svg`.
+fn place(svg: &str, label: &str) -> (i32, i32) {
+    let line = svg
+        .lines()
+        .find(|line| line.contains(&format!(">{}</text>", label)))
+        .unwrap_or_else(|| panic!("no room {} in {}", label, svg));
+    let attr = |name: &str| -> i32 {
+        let start = line.find(&format!(" {}=\"", name)).unwrap() + name.len() + 3;
+        let end = start + line[start..].find('"').unwrap();
+        line[start..end].parse().unwrap()
+    };
+    ((attr("x") - 12) / 160, (attr("y") - 22) / 80)
+}
+
+#[test]
+fn svg_maps_place_rooms_the_way_their_exits_lead() {
+    let rooms = [
+        room("1", "Square"),
+        room("2", "Gate"),
+        room("3", "Market"),
+        room("4", "Inn & <Tavern>"),
+        room("5", "Cellar"),
+    ];
+    let links = [
+        link("1", "2", "n"),
+        link("2", "1", "s"),
+        link("1", "3", "e"),
+        link("1", "4", "enter"),
+        link("4", "outside", "w"),
+    ];
+    let svg = export::to_svg("arelium", &rooms, &links);
+    assert!(
+        svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"320\" height=\"320\"")
+    );
+    assert_eq!(place(&svg, "Gate"), (0, 0));
+    assert_eq!(place(&svg, "Square"), (0, 1));
+    assert_eq!(place(&svg, "Market"), (1, 1));
+    // East of the square is taken by the market.
+    assert_eq!(place(&svg, "Inn &amp; &lt;Tavern&gt;"), (1, 2));
+    // Nothing leads to the cellar.
+    assert_eq!(place(&svg, "Cellar"), (0, 3));
+    assert_eq!(svg.matches("<line ").count(), 3);
+    assert!(svg.contains(">enter</text>"));
+}
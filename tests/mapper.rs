@@ -0,0 +1,142 @@
+//! Reading `BAT_MAPPER` payloads: the fields between the tags, and the rooms
+//! made of them however the payload is cut short or padded.
+
+use batproxy_rs::mapper::{Mapper, Payload, Room};
+use proptest::prelude::*;
+
+fn room(payload: &[u8]) -> Option<Room> {
+    match Mapper::parse(payload)? {
+        Mapper::Room(room) => Some(room),
+        Mapper::RealmMap => panic!("not a room: {}", payload.escape_ascii()),
+    }
+}
+
+fn square() -> Room {
+    Room {
+        area: "arelium".to_string(),
+        id: "1".to_string(),
+        direction: "n".to_string(),
+        indoors: false,
+        short_desc: "Square".to_string(),
+        long_desc: "A square.".to_string(),
+        exits: vec!["n".to_string(), "s".to_string()],
+    }
+}
+
+#[test]
+fn a_room_is_read_from_its_fields() {
+    assert_eq!(
+        room(b"BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER"),
+        Some(square())
+    );
+}
+
+#[test]
+fn the_realm_map_is_no_room() {
+    assert_eq!(
+        Mapper::parse(b"BAT_MAPPER;;REALM_MAP;;BAT_MAPPER"),
+        Some(Mapper::RealmMap)
+    );
+    assert_eq!(
+        Mapper::parse(b"BAT_MAPPER;;REALM_MAP"),
+        Some(Mapper::RealmMap)
+    );
+}
+
+#[test]
+fn payloads_without_the_tag_are_not_read() {
+    assert_eq!(Payload::parse(b""), None);
+    assert_eq!(Payload::parse(b"arelium;;1;;BAT_MAPPER"), None);
+    assert_eq!(Mapper::parse(b"BAT_MAPPERS;;arelium;;1"), None);
+}
+
+#[test]
+fn escaped_semicolons_stay_in_their_field() {
+    let payload = Payload::parse(b"BAT_MAPPER;;a\\;b;;c\\;\\;d;;BAT_MAPPER").unwrap();
+    assert_eq!(payload.fields(), [b"a;b".to_vec(), b"c;;d".to_vec()]);
+    assert!(payload.is_closed());
+}
+
+#[test]
+fn data_after_the_closing_tag_is_left_out() {
+    let payload = b"BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER;;junk;;more";
+    assert_eq!(room(payload), Some(square()));
+    assert_eq!(Payload::parse(payload).unwrap().fields().len(), 7);
+}
+
+#[test]
+fn missing_fields_are_left_empty() {
+    let payload = Payload::parse(b"BAT_MAPPER;;arelium;;1;;n").unwrap();
+    assert!(!payload.is_closed());
+    assert_eq!(
+        room(b"BAT_MAPPER;;arelium;;1;;n"),
+        Some(Room {
+            short_desc: String::new(),
+            long_desc: String::new(),
+            exits: Vec::new(),
+            ..square()
+        })
+    );
+}
+
+#[test]
+fn rooms_need_an_area_and_an_id() {
+    assert_eq!(room(b"BAT_MAPPER"), None);
+    assert_eq!(room(b"BAT_MAPPER;;arelium"), None);
+    assert_eq!(room(b"BAT_MAPPER;;arelium;;;;n;;BAT_MAPPER"), None);
+    assert_eq!(room(b"BAT_MAPPER;;;;1;;n;;BAT_MAPPER"), None);
+}
+
+#[test]
+fn extra_fields_belong_to_the_long_description() {
+    assert_eq!(
+        room(b"BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square;;with more.;;n,s;;BAT_MAPPER"),
+        Some(Room {
+            long_desc: "A square;;with more.".to_string(),
+            ..square()
+        })
+    );
+}
+
+#[test]
+fn rooms_survive_a_round_trip_through_json() {
+    let mapper = Mapper::Room(square());
+    let json = serde_json::to_value(&mapper).unwrap();
+    assert_eq!(json["type"], "room");
+    assert_eq!(json["short_desc"], "Square");
+    assert_eq!(serde_json::from_value::<Mapper>(json).unwrap(), mapper);
+    let json = serde_json::to_string(&Mapper::RealmMap).unwrap();
+    assert_eq!(json, r#"{"type":"realm_map"}"#);
+}
+
+fn field() -> impl Strategy<Value = String> {
+    "[a-z ;,.\\\\]{0,12}".prop_filter("ends in a backslash", |f| !f.ends_with('\\'))
+}
+
+fn escape(field: &str) -> String {
+    field.replace(";", "\\;")
+}
+
+proptest! {
+    #[test]
+    fn any_payload_is_read_without_panicking(payload in prop::collection::vec(any::<u8>(), 0..64)) {
+        let mut tagged = b"BAT_MAPPER;;".to_vec();
+        tagged.extend_from_slice(&payload);
+        let _ = Mapper::parse(&payload);
+        let _ = Mapper::parse(&tagged);
+    }
+
+    #[test]
+    fn escaped_fields_are_read_back(fields in prop::collection::vec(field(), 1..8)) {
+        let escaped: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+        let payload = format!("BAT_MAPPER;;{};;BAT_MAPPER", escaped.join(";;"));
+        let parsed = Payload::parse(payload.as_bytes()).unwrap();
+        let read: Vec<String> = parsed
+            .fields()
+            .iter()
+            .map(|f| String::from_utf8(f.clone()).unwrap())
+            .collect();
+        prop_assert_eq!(read, fields);
+        prop_assert!(parsed.is_closed());
+    }
+}
@@ -0,0 +1,2188 @@
+//! Runs the proxy in process between a scripted fake BatMUD server and a
+//! fake client, and checks the exact bytes each side receives. Output piped
+//! through without sockets is checked the same way.
+
+use std::{path::Path, time::Duration};
+
+use batproxy_rs::{config::Listen, Config, ProxyServer};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A proxy with a client connected to it and the server connection it
+/// opened for the client.
+struct Harness {
+    client: TcpStream,
+    server: TcpStream,
+}
+
+impl Harness {
+    async fn start(config: &str) -> Self {
+        Self::start_with(config, None).await
+    }
+
+    /// Start with the config in `path`, read again on reload. Its listen
+    /// and remote addresses are replaced.
+    async fn start_file(path: &Path) -> Self {
+        let config = std::fs::read_to_string(path).unwrap();
+        Self::start_with(&config, Some(path)).await
+    }
+
+    async fn start_with(config: &str, path: Option<&Path>) -> Self {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = free_port().await;
+        let mut config = Config::parse(config).unwrap();
+        config.listen = vec![Listen::new(listen.as_str())];
+        config.remote = server.local_addr().unwrap().to_string();
+        let mut builder = ProxyServer::builder().config(config);
+        if let Some(path) = path {
+            builder = builder.config_path(path);
+        }
+        tokio::spawn(builder.build().unwrap().run());
+
+        let client = connect(&listen).await;
+        let (server, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+        Self { client, server }
+    }
+
+    /// Send `reads` from the server one by one, so that the proxy reads
+    /// them separately, then close the connection and return everything
+    /// the client got.
+    async fn serve(mut self, reads: &[&[u8]]) -> Vec<u8> {
+        for read in reads {
+            self.server.write_all(read).await.unwrap();
+            self.server.flush().await.unwrap();
+            sleep(Duration::from_millis(20)).await;
+        }
+        drop(self.server);
+        let mut received = Vec::new();
+        timeout(TIMEOUT, self.client.read_to_end(&mut received))
+            .await
+            .expect("the proxy did not close the client connection")
+            .unwrap();
+        received
+    }
+}
+
+async fn free_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("proxy did not start listening on {}", addr);
+}
+
+const CONFIG: &str = "client_negotiation = off\n";
+
+#[tokio::test]
+async fn plain_text_passes_through() {
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"Hello there.\r\n", b"You are in a dark room.\r\n"])
+        .await;
+    assert_eq!(received, b"Hello there.\r\nYou are in a dark room.\r\n");
+}
+
+#[tokio::test]
+async fn output_without_bc_mode_passes_through_untouched() {
+    const RED: &[u8] = b"\x1b<20ff0000\x1b|red\x1b>20 plain\r\n";
+    let mut harness = Harness::start("client_negotiation = off\nbc_mode = off\n").await;
+    harness.server.write_all(RED).await.unwrap();
+    assert_eq!(read_until(&mut harness.client, b"plain\r\n").await, RED);
+
+    // Until the player turns BC mode on.
+    harness.client.write_all(b"\x1bbc 1\r\n").await.unwrap();
+    let mut received = [0; 7];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received, b"\x1bbc 1\r\n");
+    assert_eq!(
+        harness.serve(&[RED]).await,
+        b"\x1b[38;5;196mred\x1b[39m plain\r\n"
+    );
+
+    // A server that ignores BC mode is given up on.
+    let text = b"Welcome.\r\n".repeat(2000);
+    let received = Harness::start(CONFIG).await.serve(&[&text, RED]).await;
+    let expected = [
+        &text[..],
+        b"[bcproxy] no control codes from the server, BC mode is off, passing its output through\r\n",
+        RED,
+    ]
+    .concat();
+    assert_eq!(
+        String::from_utf8_lossy(&received),
+        String::from_utf8_lossy(&expected)
+    );
+}
+
+#[tokio::test]
+async fn color_codes_become_sgr() {
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"\x1b<20ff0000\x1b|red\x1b>20 plain\r\n"])
+        .await;
+    assert_eq!(received, b"\x1b[38;5;196mred\x1b[39m plain\r\n");
+
+    let received = Harness::start("client_negotiation = off\ncolor_mode = truecolor\n")
+        .await
+        .serve(&[b"\x1b<20ff8800\x1b|orange\x1b>20\r\n"])
+        .await;
+    assert_eq!(received, b"\x1b[38;2;255;136;0morange\x1b[39m\r\n");
+}
+
+#[tokio::test]
+async fn nested_colors_are_restored() {
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"\x1b<21000080\x1b|a \x1b<20ff0000\x1b|red\x1b>20 b\x1b>21\r\n"])
+        .await;
+    assert_eq!(
+        received,
+        b"\x1b[48;5;18ma \x1b[38;5;196mred\x1b[39m b\x1b[49m\r\n"
+    );
+}
+
+#[tokio::test]
+async fn codes_split_across_reads() {
+    let output = b"\x1b<21000080\x1b|a \x1b<20ff0000\x1b|red\x1b>20 b\x1b>21\r\n";
+    let reads: Vec<&[u8]> = output.chunks(1).collect();
+    let received = Harness::start(CONFIG).await.serve(&reads).await;
+    assert_eq!(
+        received,
+        b"\x1b[48;5;18ma \x1b[38;5;196mred\x1b[39m b\x1b[49m\r\n"
+    );
+}
+
+#[tokio::test]
+async fn highlights_cover_lines_split_across_reads() {
+    let config = "client_negotiation = off\ntrigger = *sword* => highlight\n";
+    let received = Harness::start(config)
+        .await
+        .serve(&[b"a sw", b"ord here\r\n"])
+        .await;
+    assert_eq!(received, b"\x1b[7ma sword here\r\n\x1b[27m");
+
+    // The start of a line that never ends is still passed on.
+    let received = Harness::start(config).await.serve(&[b"a sw"]).await;
+    assert_eq!(received, b"a sw");
+}
+
+#[tokio::test]
+async fn prompts_end_with_go_ahead() {
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10"])
+        .await;
+    assert_eq!(
+        received,
+        b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10\xff\xf9"
+    );
+}
+
+#[tokio::test]
+async fn telnet_commands_pass_through() {
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"\xff\xfb\x01Password: \xff\xf9"])
+        .await;
+    assert_eq!(received, b"\xff\xfb\x01Password: \xff\xf9");
+}
+
+#[tokio::test]
+async fn messages_and_mapper_codes_reach_bc_clients() {
+    let output: &[u8] = b"\x1b<10chan_sales\x1b|Bob [sales]: wtb sword\r\n\x1b>10\
+        \x1b<99BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99\
+        You are here.\r\n";
+    let received = Harness::start(CONFIG).await.serve(&[output]).await;
+    assert_eq!(received, output);
+}
+
+#[tokio::test]
+async fn plain_output_drops_colors() {
+    let received = Harness::start("client_negotiation = off\nplain_output = on\n")
+        .await
+        .serve(&[
+            b"\x1b<10chan_sales\x1b|Bob [sales]: wtb \x1b<20ff0000\x1b|sword\x1b>20\r\n\x1b>10",
+            b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10",
+        ])
+        .await;
+    assert_eq!(
+        received,
+        b"\x1b<10chan_sales\x1b|Bob [sales]: wtb sword\r\n\x1b>10\
+          \x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10\xff\xf9"
+    );
+}
+
+#[tokio::test]
+async fn lines_start_with_a_timestamp() {
+    let received = Harness::start(
+        "client_negotiation = off\ntimestamps = on\ntimestamp_format = <%H:%M:%S>\n",
+    )
+    .await
+    .serve(&[b"\x1b[31mred\r\nstill red\x1b[0m\r\n\r\nplain\r\n"])
+    .await;
+    // The color left on is reset for the timestamp and set again after it,
+    // blank lines have none.
+    let expected = regex::bytes::Regex::new(
+        r"^<\d\d:\d\d:\d\d> \x1b\[31mred\r\n\x1b\[0m<\d\d:\d\d:\d\d> \x1b\[31mstill red\x1b\[0m\r\n\r\n<\d\d:\d\d:\d\d> plain\r\n$",
+    )
+    .unwrap();
+    assert!(expected.is_match(&received), "{}", received.escape_ascii());
+}
+
+#[tokio::test]
+async fn the_session_clock_comes_with_each_prompt() {
+    let received = Harness::start("client_negotiation = off\nclock = line\n")
+        .await
+        .serve(&[b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10"])
+        .await;
+    let expected = regex::bytes::Regex::new(
+        r"^\[since \d\d:\d\d \(\ds\), idle \ds, exp \+0\]\r\n\x1b<10spec_prompt\x1b\|Hp:100/100 >\x1b>10(?-u:\xff\xf9)$",
+    )
+    .unwrap();
+    assert!(expected.is_match(&received), "{}", received.escape_ascii());
+}
+
+#[tokio::test]
+async fn script_hooks_stuck_in_a_loop_are_aborted() {
+    let path = std::env::temp_dir().join(format!("bcproxy-loop-{}.lua", std::process::id()));
+    std::fs::write(
+        &path,
+        "function on_text(text)\n\
+         if text:find('spin') then while true do end end\n\
+         return text:upper()\n\
+         end\n",
+    )
+    .unwrap();
+    let config = format!("client_negotiation = off\nscript = {}\n", path.display());
+    let received = Harness::start(&config)
+        .await
+        .serve(&[b"spin\r\n", b"done\r\n"])
+        .await;
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(received, b"spin\r\nDONE\r\n");
+}
+
+#[tokio::test]
+async fn codes_lists_the_control_codes_seen() {
+    let mut harness = Harness::start(CONFIG).await;
+    const SALES: &[u8] = b"\x1b<10chan_sales\x1b|Bob: \x1b<20ff0000\x1b|sword\x1b>20\r\n\x1b>10";
+    const PROMPT: &[u8] = b"\x1b<10spec_prompt\x1b|> \x1b>10";
+    harness
+        .server
+        .write_all(&[SALES, PROMPT].concat())
+        .await
+        .unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+    harness.client.write_all(b";;codes\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    let expected = regex::bytes::Regex::new(&format!(
+        concat!(
+            r"^\[bcproxy\] code 10: 2 seen, {} bytes, last \d+\.\ds ago, attr spec_prompt\r\n",
+            r"\[bcproxy\] code 20: 1 seen, 21 bytes, last \d+\.\ds ago, attr ff0000\r\n",
+        ),
+        SALES.len() + PROMPT.len()
+    ))
+    .unwrap();
+    assert!(expected.is_match(&received), "{}", received.escape_ascii());
+}
+
+#[tokio::test]
+async fn client_lines_reach_the_server() {
+    let mut harness = Harness::start("client_negotiation = off\nalias = k => kill $*\n").await;
+    harness
+        .client
+        .write_all(b"look\r\nk orc\r\n")
+        .await
+        .unwrap();
+    // Expanded aliases end in a plain newline.
+    let expected = b"look\r\nkill orc\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn lines_typed_while_the_server_echoes_go_as_they_are() {
+    let config = "client_negotiation = off\nalias = k => kill $*\n";
+    let mut harness = Harness::start(config).await;
+    let mut received = vec![0; 13];
+    harness
+        .server
+        .write_all(b"\xff\xfb\x01Password: ")
+        .await
+        .unwrap();
+    timeout(TIMEOUT, harness.client.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"\xff\xfb\x01Password: ");
+    harness.client.write_all(b"k orc\r\n").await.unwrap();
+    let mut line = vec![0; 7];
+    timeout(TIMEOUT, harness.server.read_exact(&mut line))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(line, b"k orc\r\n");
+
+    harness.server.write_all(b"\xff\xfc\x01\r\n").await.unwrap();
+    let mut received = vec![0; 5];
+    timeout(TIMEOUT, harness.client.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"\xff\xfc\x01\r\n");
+    harness.client.write_all(b"k orc\r\n").await.unwrap();
+    let mut line = vec![0; 9];
+    timeout(TIMEOUT, harness.server.read_exact(&mut line))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(line, b"kill orc\n");
+
+    let received = Harness::start("client_negotiation = off\necho_negotiation = off\n")
+        .await
+        .serve(&[b"\xff\xfb\x01Password: \xff\xf9"])
+        .await;
+    assert_eq!(received, b"Password: \xff\xf9");
+}
+
+/// Send `command` to the admin console and return its answer, up to the
+/// next prompt.
+async fn admin(console: &mut TcpStream, command: &str) -> String {
+    console
+        .write_all(format!("{}\n", command).as_bytes())
+        .await
+        .unwrap();
+    read_prompt(console).await
+}
+
+async fn read_prompt(console: &mut TcpStream) -> String {
+    let mut answer = Vec::new();
+    while !answer.ends_with(b"> ") {
+        let mut buf = [0; 1024];
+        let n = timeout(TIMEOUT, console.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(n > 0, "the admin console closed the connection");
+        answer.extend_from_slice(&buf[..n]);
+    }
+    answer.truncate(answer.len() - 2);
+    String::from_utf8(answer).unwrap()
+}
+
+#[tokio::test]
+async fn admin_console_runs_commands_and_kicks_sessions() {
+    let addr = free_port().await;
+    let config = format!("client_negotiation = off\nadmin_listen = {}\n", addr);
+    let mut harness = Harness::start(&config).await;
+    let mut console = connect(&addr).await;
+    read_prompt(&mut console).await;
+
+    let sessions = admin(&mut console, "sessions").await;
+    assert!(sessions.starts_with("1 peer 127.0.0.1:"), "{}", sessions);
+    assert_eq!(admin(&mut console, "bc 1 wrap off").await, "wrap off\n");
+    assert_eq!(admin(&mut console, "kick 2").await, "error: no session 2\n");
+    assert_eq!(admin(&mut console, "kick 1").await, "session 1 kicked\n");
+
+    let mut received = Vec::new();
+    timeout(TIMEOUT, harness.client.read_to_end(&mut received))
+        .await
+        .expect("the kicked client is still connected")
+        .unwrap();
+    // The command's answer went to the console, not the client.
+    assert_eq!(received, b"");
+    assert_eq!(admin(&mut console, "sessions").await, "");
+}
+
+#[tokio::test]
+async fn reload_takes_new_aliases_and_rejects_bad_configs() {
+    let path = std::env::temp_dir().join(format!("bcproxy-reload-{}.conf", std::process::id()));
+    let addr = free_port().await;
+    let config = format!(
+        "client_negotiation = off\nadmin_listen = {}\nalias = k => kill $*\n",
+        addr
+    );
+    std::fs::write(&path, &config).unwrap();
+    let mut harness = Harness::start_file(&path).await;
+    let mut console = connect(&addr).await;
+    read_prompt(&mut console).await;
+
+    std::fs::write(&path, config.replace("kill", "backstab")).unwrap();
+    // The harness replaced the listen address of the file.
+    assert_eq!(
+        admin(&mut console, "reload").await,
+        "config reloaded\nlisten changed, takes a restart\n"
+    );
+    std::fs::write(&path, "trigger = no arrow\n").unwrap();
+    let answer = admin(&mut console, "reload").await;
+    assert!(answer.starts_with("error: config line 1:"), "{}", answer);
+    std::fs::remove_file(&path).unwrap();
+
+    harness.client.write_all(b"k orc\r\n").await.unwrap();
+    let expected = b"backstab orc\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn a_chained_proxy_passes_the_output_of_another_through() {
+    let spawn = |config: &str, listen: &str, remote: String| {
+        let mut config = Config::parse(config).unwrap();
+        config.listen = vec![Listen::new(listen)];
+        config.remote = remote;
+        tokio::spawn(ProxyServer::builder().config(config).build().unwrap().run());
+    };
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = free_port().await;
+    spawn(CONFIG, &upstream, server.local_addr().unwrap().to_string());
+    // Once it listens.
+    drop(connect(&upstream).await);
+    drop(timeout(TIMEOUT, server.accept()).await.unwrap().unwrap());
+
+    let downstream = free_port().await;
+    spawn(
+        "client_negotiation = off\nchain = on\n",
+        &downstream,
+        upstream,
+    );
+    let mut client = connect(&downstream).await;
+    let (mut server, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    assert_eq!(
+        read_until(&mut client, b"through\r\n").await,
+        b"[bcproxy] the server is a bcproxy, passing its output through\r\n"
+    );
+
+    // Decoded once, by the proxy next to the server.
+    server
+        .write_all(b"\x1b<20ff0000\x1b|red\x1b>20\r\n\x1b<10spec_prompt\x1b|Hp:1 >\x1b>10")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_until(&mut client, b"\xff\xf9").await,
+        b"\x1b[38;5;196mred\x1b[39m\r\n\x1b<10spec_prompt\x1b|Hp:1 >\x1b>10\xff\xf9"
+    );
+
+    // The question is not passed on to the server.
+    client.write_all(b"look\r\n").await.unwrap();
+    let mut received = [0; 6];
+    timeout(TIMEOUT, server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received, b"look\r\n");
+}
+
+#[tokio::test]
+async fn pipe_transforms_like_a_session() {
+    let config =
+        Config::parse("client_negotiation = off\nhighlight = sword => fg=ff0000\n").unwrap();
+    let input: &[u8] =
+        b"\x1b<20ff0000\x1b|red\x1b>20 a sword\r\n\x1b<10spec_prompt\x1b|Hp:1 >\x1b>10";
+    let mut output = Vec::new();
+    let written = batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        output,
+        b"\x1b[38;5;196mred\x1b[39m a \x1b[38;5;196msword\x1b[39m\r\n\
+          \x1b<10spec_prompt\x1b|Hp:1 >\x1b>10\xff\xf9"
+    );
+    assert_eq!(written, output.len() as u64);
+}
+
+#[cfg(feature = "legacy-format")]
+#[tokio::test]
+async fn the_legacy_style_tags_codes_in_brackets() {
+    let config = Config::parse("client_negotiation = off\noutput_style = legacy-bc\n").unwrap();
+    let input: &[u8] = b"\x1b<10chan_sales\x1b|Bob [sales]: wtb sword\r\n\x1b>10You are here.\r\n";
+    let mut output = Vec::new();
+    batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output),
+        "[chan_sales] Bob [sales]: wtb sword\r\nYou are here.\r\n"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn each_listen_address_has_its_own_settings() {
+    use tokio::net::UnixStream;
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp = free_port().await;
+    let path = std::env::temp_dir().join(format!("bcproxy-listen-{}.sock", std::process::id()));
+    let config = format!(
+        "client_negotiation = off\nlisten = {}\nlisten = {} color_mode=truecolor\nremote = {}\n",
+        tcp,
+        path.display(),
+        server.local_addr().unwrap()
+    );
+    let proxy = ProxyServer::builder()
+        .config(Config::parse(&config).unwrap())
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.run());
+
+    let output = b"\x1b<20ff8800\x1b|orange\x1b>20\r\n";
+    let mut client = connect(&tcp).await;
+    let (mut upstream, _) = server.accept().await.unwrap();
+    upstream.write_all(output).await.unwrap();
+    drop(upstream);
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"\x1b[38;5;208morange\x1b[39m\r\n");
+
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    let (mut upstream, _) = server.accept().await.unwrap();
+    upstream.write_all(output).await.unwrap();
+    drop(upstream);
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"\x1b[38;2;255;136;0morange\x1b[39m\r\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Read from `client` until it has sent `end`.
+async fn read_until(client: &mut TcpStream, end: &[u8]) -> Vec<u8> {
+    let mut received = Vec::new();
+    while !received.ends_with(end) {
+        let mut buf = [0; 1024];
+        let n = timeout(TIMEOUT, client.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(n > 0, "the proxy closed the connection");
+        received.extend_from_slice(&buf[..n]);
+    }
+    received
+}
+
+#[tokio::test]
+async fn clients_give_the_password_first_and_get_locked_out() {
+    const PROMPT: &[u8] = b"[bcproxy] password: ";
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_port().await;
+    let config = format!(
+        "client_negotiation = off\nlisten = {} auth=on\nremote = {}\n\
+         auth_password_command = echo sesame\nauth_max_failures = 2\n",
+        listen,
+        server.local_addr().unwrap()
+    );
+    let proxy = ProxyServer::builder()
+        .config(Config::parse(&config).unwrap())
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.run());
+
+    // Telnet clients are asked for the password in NEW-ENVIRON too, and
+    // not to echo it.
+    let mut client = connect(&listen).await;
+    let received = read_until(&mut client, PROMPT).await;
+    assert_eq!(&received[..6], &[255, 253, 39, 255, 251, 1]);
+    client.write_all(b"wrong\r\n").await.unwrap();
+    read_until(&mut client, PROMPT).await;
+    client.write_all(b"sesame\r\n").await.unwrap();
+    let (mut upstream, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    upstream.write_all(b"Welcome\r\n").await.unwrap();
+    drop(upstream);
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"\xff\xfc\x01\r\nWelcome\r\n");
+
+    // The earlier failure was forgotten when the password was right.
+    let mut client = connect(&listen).await;
+    read_until(&mut client, PROMPT).await;
+    client.write_all(b"wrong\r\n").await.unwrap();
+    read_until(&mut client, b"wrong password\r\n[bcproxy] password: ").await;
+    client.write_all(b"still wrong\r\n").await.unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"\r\n[bcproxy] too many wrong passwords\r\n");
+
+    let mut client = connect(&listen).await;
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(
+        received,
+        b"[bcproxy] too many wrong passwords, try again in 10 minutes\r\n"
+    );
+    assert!(timeout(Duration::from_millis(100), server.accept())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn clients_asked_before_a_lockout_are_refused_with_the_right_password() {
+    const PROMPT: &[u8] = b"[bcproxy] password: ";
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_port().await;
+    let config = format!(
+        "client_negotiation = off\nlisten = {} auth=on\nremote = {}\n\
+         auth_password_command = echo sesame\nauth_max_failures = 1\n",
+        listen,
+        server.local_addr().unwrap()
+    );
+    let proxy = ProxyServer::builder()
+        .config(Config::parse(&config).unwrap())
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.run());
+
+    let mut waiting = connect(&listen).await;
+    read_until(&mut waiting, PROMPT).await;
+    let mut other = connect(&listen).await;
+    read_until(&mut other, PROMPT).await;
+    other.write_all(b"wrong\r\n").await.unwrap();
+    read_until(&mut other, b"too many wrong passwords\r\n").await;
+
+    waiting.write_all(b"sesame\r\n").await.unwrap();
+    let mut received = Vec::new();
+    waiting.read_to_end(&mut received).await.unwrap();
+    assert_eq!(
+        received,
+        b"\r\n[bcproxy] too many wrong passwords, try again in 10 minutes\r\n"
+    );
+    assert!(timeout(Duration::from_millis(100), server.accept())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn the_client_password_command_runs_once() {
+    const PROMPT: &[u8] = b"[bcproxy] password: ";
+
+    let runs = std::env::temp_dir().join(format!("bcproxy-auth-runs-{}", std::process::id()));
+    let _ = std::fs::remove_file(&runs);
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_port().await;
+    let config = format!(
+        "client_negotiation = off\nlisten = {} auth=on\nremote = {}\n\
+         auth_password_command = echo run >> {}; echo sesame\n",
+        listen,
+        server.local_addr().unwrap(),
+        runs.display()
+    );
+    let proxy = ProxyServer::builder()
+        .config(Config::parse(&config).unwrap())
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.run());
+
+    // Clients that never answer do not run it at all.
+    let idle = connect(&listen).await;
+    for _ in 0..2 {
+        let mut client = connect(&listen).await;
+        read_until(&mut client, PROMPT).await;
+        client.write_all(b"sesame\r\n").await.unwrap();
+        read_until(&mut client, b"\xff\xfc\x01\r\n").await;
+        timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    }
+    drop(idle);
+    assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+    std::fs::remove_file(&runs).unwrap();
+}
+
+#[tokio::test]
+async fn stats_count_traffic_and_latency() {
+    let mut harness = Harness::start(CONFIG).await;
+    harness.client.write_all(b"look\r\n").await.unwrap();
+    let mut received = vec![0; 6];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    let output: &[u8] = b"Room.\r\n\x1b<10spec_prompt\x1b|Hp:1 >\x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    harness.client.write_all(b";;stats\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    let received = String::from_utf8_lossy(&received);
+    assert!(
+        received.contains(&format!("server: {} bytes in 2 frames", output.len())),
+        "{}",
+        received
+    );
+    // The stats line itself was read but not sent.
+    assert!(received.contains("client: 15 bytes in 1 lines, 6 bytes to the server"));
+    assert!(!received.contains("latency: unknown"), "{}", received);
+}
+
+#[tokio::test]
+async fn unknown_codes_are_counted_and_stored() {
+    let path = std::env::temp_dir().join(format!("bcproxy-codes-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] = b"\x1b<77new\x1b|a\x1b>77\x1b<10\x1b<77new\x1b|b\x1b>77\r\n\x1b>10\
+        \x1b<78\x1b>78\x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    harness.client.write_all(b"#bc codes\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.contains("code 77 new: seen 2"), "{}", received);
+    assert!(received.contains("code 78: seen 1"), "{}", received);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let mut rows = Vec::new();
+    // The db task writes in the background.
+    for _ in 0..50 {
+        rows = conn
+            .prepare("SELECT id, attr, count, sample FROM unknown_codes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<(u8, String, i64, String)>, _>>()
+            .unwrap();
+        if rows.iter().map(|row| row.2).sum::<i64>() == 3 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        rows,
+        [
+            (77, "new".to_string(), 2, "a".to_string()),
+            (78, String::new(), 1, String::new())
+        ]
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn strict_protocol_mode_closes_sessions_on_bad_output() {
+    let input: &[u8] = b"one\x1b>10two\r\n";
+    let config = Config::parse("client_negotiation = off\n").unwrap();
+    let mut output = Vec::new();
+    batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+        .await
+        .unwrap();
+    assert_eq!(output, input);
+
+    let config = Config::parse("client_negotiation = off\nprotocol = strict-disconnect\n").unwrap();
+    let error = batproxy_rs::io::pipe(&mut &input[..], &mut Vec::new(), &config, &[], &[])
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut harness =
+        Harness::start("client_negotiation = off\nprotocol = strict-disconnect\n").await;
+    harness.server.write_all(input).await.unwrap();
+    let mut received = Vec::new();
+    timeout(TIMEOUT, harness.client.read_to_end(&mut received))
+        .await
+        .expect("the proxy did not close the client connection")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn login_results_move_the_login_state() {
+    let mut harness = Harness::start(CONFIG).await;
+    harness
+        .server
+        .write_all(b"\x1b<06Wrong password.\r\n\x1b>06")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"password\r\n").await;
+    let received = String::from_utf8_lossy(&received);
+    assert!(
+        received.contains("login failed: wrong password"),
+        "{}",
+        received
+    );
+
+    harness.client.write_all(b"#bc stats\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"password\r\n").await;
+    assert!(String::from_utf8_lossy(&received).contains("login failed: wrong password"));
+
+    harness.server.write_all(b"\x1b<05\x1b>05").await.unwrap();
+    harness.client.write_all(b"#bc stats\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"logged in\r\n").await;
+    assert!(!String::from_utf8_lossy(&received).contains("not logged in"));
+}
+
+#[tokio::test]
+async fn hyperlinks_become_osc8_or_text() {
+    let output: &[u8] =
+        b"see \x1b<30https://bat.org\x1b|\x1b<20ff0000\x1b|the site\x1b>20\x1b>30\r\n";
+    let received = Harness::start(CONFIG).await.serve(&[output]).await;
+    assert_eq!(
+        received,
+        b"see [\x1b[38;5;196mthe site\x1b[39m](https://bat.org)\r\n"
+    );
+
+    let received = Harness::start("client_negotiation = off\ncolor_mode = truecolor\n")
+        .await
+        .serve(&[output])
+        .await;
+    assert_eq!(
+        received,
+        b"see \x1b]8;;https://bat.org\x1b\\\x1b[38;2;255;0;0mthe site\x1b[39m\x1b]8;;\x1b\\\r\n"
+    );
+
+    let received = Harness::start("client_negotiation = off\nhyperlinks = osc8\n")
+        .await
+        .serve(&[b"\x1b<30https://bat.org\x1b>30\r\n"])
+        .await;
+    assert_eq!(
+        received,
+        b"\x1b]8;;https://bat.org\x1b\\https://bat.org\x1b]8;;\x1b\\\r\n"
+    );
+}
+
+#[tokio::test]
+async fn in_game_links_are_numbered_and_followed() {
+    let mut harness = Harness::start(CONFIG).await;
+    harness
+        .server
+        .write_all(b"Exits: \x1b<31enter gate\x1b|gate\x1b>31 and \x1b<31climb\x1b>31\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\r\n").await;
+    assert_eq!(
+        received,
+        b"Exits: \x1b[4mgate\x1b[24m[1] and \x1b[4mclimb\x1b[24m[2]\r\n"
+    );
+
+    harness
+        .client
+        .write_all(b";;link 1\r\n;;link;;2\r\n")
+        .await
+        .unwrap();
+    let expected = b"enter gate\nclimb\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn maps_are_drawn_as_configured() {
+    let input: &[u8] = b"\x1b<11\x1b>11\x1b<10spec_map\x1b|  \x1b<20ff0000\x1b|#\x1b>20.  \r\n      \r\n .#\r\n\x1b>10after\r\n";
+    let draw = |render: &str| {
+        let config = Config::parse(&format!(
+            "client_negotiation = off\nmap_render = {}\n",
+            render
+        ))
+        .unwrap();
+        async move {
+            let mut output = Vec::new();
+            batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+                .await
+                .unwrap();
+            output
+        }
+    };
+    assert_eq!(
+        draw("full").await,
+        b"\x1b<11\x1b>11\x1b<10spec_map\x1b|  \x1b[38;5;196m#\x1b[39m.  \r\n      \r\n .#\r\n\x1b>10after\r\n"
+    );
+    assert_eq!(
+        draw("compact").await,
+        b"\x1b<11\x1b>11\x1b<10spec_map\x1b|  \x1b[38;5;196m#\x1b[39m.\r\n .#\r\n\x1b>10after\r\n"
+    );
+    assert_eq!(
+        draw("coordinates").await,
+        b"\x1b<11\x1b>11\x1b<10spec_map\x1b|  012345\r\n0   \x1b[38;5;196m#\x1b[39m.  \r\n1       \r\n2  .#\r\n\x1b>10after\r\n"
+    );
+    assert_eq!(draw("off").await, b"after\r\n");
+}
+
+#[tokio::test]
+async fn the_window_size_reaches_the_server_and_cuts_maps() {
+    let mut harness = Harness::start("client_negotiation = off\nmap_render = full\n").await;
+    let naws = b"\xff\xfa\x1f\x00\x14\x00\x28\xff\xf0";
+    harness.client.write_all(b"\xff\xfb\x1f").await.unwrap();
+    harness.client.write_all(naws).await.unwrap();
+    let mut received = vec![0; 12];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received[..3], b"\xff\xfb\x1f");
+    assert_eq!(&received[3..], naws);
+
+    // A server asking again, as after a reconnect, is answered by the
+    // proxy with the size it knows.
+    harness.server.write_all(b"\xff\xfd\x1f").await.unwrap();
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received[..3], b"\xff\xfb\x1f");
+    assert_eq!(&received[3..], naws);
+
+    let map = format!("\x1b<10spec_map\x1b|{}\r\n\x1b>10", "0123456789".repeat(3));
+    let received = harness.serve(&[map.as_bytes()]).await;
+    assert_eq!(
+        received,
+        b"\x1b<10spec_map\x1b|01234567890123456789\r\n\x1b>10"
+    );
+}
+
+#[tokio::test]
+async fn answers_to_the_proxy_negotiation_stay_with_it() {
+    let mut harness = Harness::start("").await;
+    let mut asked = vec![0; 6];
+    timeout(TIMEOUT, harness.client.read_exact(&mut asked))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(asked, b"\xff\xfd\x1f\xff\xfd\x18");
+
+    let naws = b"\xff\xfa\x1f\x00\x14\x00\x28\xff\xf0";
+    harness.client.write_all(b"\xff\xfb\x1f").await.unwrap();
+    harness.client.write_all(naws).await.unwrap();
+    harness.client.write_all(b"\xff\xfb\x18").await.unwrap();
+    harness
+        .client
+        .write_all(b"\xff\xfa\x18\x00xterm-256color\xff\xf0look\n")
+        .await
+        .unwrap();
+    let mut received = vec![0; 5];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"look\n");
+
+    // Once the server asks as well, it is answered with the size, and the
+    // client's later sizes go to it.
+    harness.server.write_all(b"\xff\xfd\x1f").await.unwrap();
+    let mut received = vec![0; 12];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received[..3], b"\xff\xfb\x1f");
+    assert_eq!(&received[3..], naws);
+    let resized = b"\xff\xfa\x1f\x00\x50\x00\x28\xff\xf0";
+    harness.client.write_all(resized).await.unwrap();
+    let mut received = vec![0; 9];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, resized);
+}
+
+#[tokio::test]
+async fn maps_go_to_the_map_port() {
+    let maps = free_port().await;
+    let mut harness = Harness::start(&format!("{}map_listen = {}\n", CONFIG, maps)).await;
+    let mut map_client = connect(&maps).await;
+    harness
+        .server
+        .write_all(
+            b"\x1b<11\x1b>11\x1b<10spec_map\x1b| \x1b<20ff0000\x1b|#\x1b>20.\r\n\x1b>10after\r\n",
+        )
+        .await
+        .unwrap();
+
+    let expected = b"\x1b[H\x1b[2J \x1b[38;5;196m#\x1b[39m.\r\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, map_client.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+    assert_eq!(harness.serve(&[]).await, b"after\r\n");
+}
+
+#[tokio::test]
+async fn the_party_port_tells_points_room_and_target() {
+    async fn reads(stream: &mut TcpStream, expected: &str) {
+        let mut received = vec![0; expected.len()];
+        timeout(TIMEOUT, stream.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&received), expected);
+    }
+
+    let party = free_port().await;
+    let config = format!("{}party_listen = {}\nparty_name = Bob\n", CONFIG, party);
+    let mut harness = Harness::start(&config).await;
+    let mut first = connect(&party).await;
+    harness
+        .server
+        .write_all(b"\x1b<50310 320 95 100 200 210\x1b>50")
+        .await
+        .unwrap();
+    reads(&mut first, "Bob hp=310/320 sp=95/100 ep=200/210\n").await;
+
+    // Points that did not change send no line.
+    harness
+        .server
+        .write_all(
+            b"\x1b<50310 320 95 100 200 210\x1b>50\
+            \x1b<99BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99\
+            \x1b<70giant orc 80%\x1b>70",
+        )
+        .await
+        .unwrap();
+    reads(
+        &mut first,
+        "Bob hp=310/320 sp=95/100 ep=200/210 room=arelium:1\n\
+        Bob hp=310/320 sp=95/100 ep=200/210 room=arelium:1 target=giant_orc:80\n",
+    )
+    .await;
+
+    // A client connecting later gets the last line.
+    let mut second = connect(&party).await;
+    reads(
+        &mut second,
+        "Bob hp=310/320 sp=95/100 ep=200/210 room=arelium:1 target=giant_orc:80\n",
+    )
+    .await;
+    harness.serve(&[]).await;
+}
+
+#[tokio::test]
+async fn map_coordinates_number_rows_past_99() {
+    let mut input = b"\x1b<10spec_map\x1b|".to_vec();
+    input.extend(b".\r\n".repeat(101));
+    input.extend(b"\x1b>10");
+    let config = Config::parse("client_negotiation = off\nmap_render = coordinates\n").unwrap();
+    let mut output = Vec::new();
+    batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+        .await
+        .unwrap();
+
+    let mut expected = b"\x1b<10spec_map\x1b|    0\r\n".to_vec();
+    for row in 0..=100 {
+        expected.extend(format!("{:>3} .\r\n", row).as_bytes());
+    }
+    expected.extend(b"\x1b>10");
+    assert_eq!(
+        String::from_utf8_lossy(&output),
+        String::from_utf8_lossy(&expected)
+    );
+}
+
+#[tokio::test]
+async fn repeated_status_codes_are_throttled() {
+    let input: &[u8] = b"\x1b<50a\x1b>50\x1b<50a\x1b>50\x1b<50b\x1b>50\x1b<50a\x1b>50\
+        \x1b<541\x1b>54\x1b<542\x1b>54\x1b<543\x1b>54\x1b<511\x1b>51\x1b<511\x1b>51";
+    let config =
+        Config::parse("client_negotiation = off\nthrottle = 50 dedup\nthrottle = 54 2/s\n")
+            .unwrap();
+    let mut output = Vec::new();
+    batproxy_rs::io::pipe(&mut &input[..], &mut output, &config, &[], &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        output,
+        b"\x1b<50a\x1b>50\x1b<50b\x1b>50\x1b<50a\x1b>50\x1b<541\x1b>54\x1b<542\x1b>54\x1b<511\x1b>51\x1b<511\x1b>51"
+    );
+    assert!(Config::parse("throttle = 54 fast\n").is_err());
+}
+
+#[test]
+fn minutes_too_long_for_a_duration_are_config_errors() {
+    let config = Config::parse("keepalive_idle_minutes = 15\n").unwrap();
+    assert_eq!(config.keepalive.idle, Duration::from_secs(15 * 60));
+    for key in ["keepalive_idle_minutes", "exp_window_minutes"] {
+        let error = Config::parse(&format!("{} = {}\n", key, u64::MAX)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("config line 1: {} must be a number of minutes", key)
+        );
+    }
+}
+
+#[tokio::test]
+async fn safeguards_fire_on_low_points_until_turned_off() {
+    let config = format!(
+        "{}safeguard = hp < 20% => send quaff heal | flee\nsafeguard_cooldown_secs = 0\n",
+        CONFIG
+    );
+    let mut harness = Harness::start(&config).await;
+    harness
+        .server
+        .write_all(
+            b"\x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;e,s;;BAT_MAPPER\x1b>99\
+            \x1b<5040 150 30 80 90 100\x1b>50\x1b<5029 150 30 80 90 100\x1b>50",
+        )
+        .await
+        .unwrap();
+    let expected = b"quaff heal\ns\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+    let received = read_until(&mut harness.client, b"\x07").await;
+    assert!(String::from_utf8_lossy(&received).contains(
+        "[bcproxy] SAFETY: hp 29/150, sent quaff heal, s (`#bc safety off` stops this)\r\n"
+    ));
+
+    harness
+        .client
+        .write_all(b"#bc safety off\r\n")
+        .await
+        .unwrap();
+    read_until(&mut harness.client, b"safety off\r\n").await;
+    let received = harness
+        .serve(&[b"\x1b<5010 150 30 80 90 100\x1b>50done\r\n"])
+        .await;
+    assert!(!String::from_utf8_lossy(&received).contains("SAFETY"));
+    assert!(received.ends_with(b"done\r\n"));
+}
+
+#[tokio::test]
+async fn typed_lines_are_recalled_from_the_history() {
+    let mut harness = Harness::start(CONFIG).await;
+    async fn server_reads(harness: &mut Harness, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        timeout(TIMEOUT, harness.server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, expected);
+    }
+
+    // The name typed before the login is not kept.
+    harness.client.write_all(b"bob\r\n").await.unwrap();
+    server_reads(&mut harness, b"bob\r\n").await;
+    harness
+        .server
+        .write_all(b"\x1b<05\x1b>05Welcome.\r\n")
+        .await
+        .unwrap();
+    read_until(&mut harness.client, b"Welcome.\r\n").await;
+
+    harness
+        .client
+        .write_all(b"kill orc\r\nlook\r\nlook\r\n")
+        .await
+        .unwrap();
+    server_reads(&mut harness, b"kill orc\r\nlook\r\nlook\r\n").await;
+    harness.client.write_all(b";;history\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"look\r\n").await;
+    assert_eq!(
+        String::from_utf8_lossy(&received),
+        "[bcproxy]    1  kill orc\r\n[bcproxy]    2  look\r\n"
+    );
+
+    harness.client.write_all(b";;!ki\r\n").await.unwrap();
+    server_reads(&mut harness, b"kill orc\n").await;
+    let received = read_until(&mut harness.client, b"\r\n").await;
+    assert_eq!(received, b"[bcproxy] !ki: kill orc\r\n");
+}
+
+#[tokio::test]
+async fn split_logs_get_their_kind_of_output() {
+    let dir = std::env::temp_dir().join(format!("bcproxy-split-{}", std::process::id()));
+    let config = format!(
+        "{}split_log_dir = {}\nsplit_log_files = channels tells mapper raw\nsplit_log_name = %Y/{{file}}.log\n",
+        CONFIG,
+        dir.display()
+    );
+    let output: &[u8] = b"\x1b<10chan_sales\x1b|Bob [sales]: wts sword\r\n\x1b>10\
+        \x1b<10tell\x1b|Alice tells you 'hi'\r\n\x1b>10\
+        \x1b<99BAT_MAPPER;;arelium;;1;;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99\
+        You are hungry.\r\n";
+    Harness::start(&config).await.serve(&[output]).await;
+
+    let dir = dir.join(chrono::Local::now().format("%Y").to_string());
+    let read = |file: &str| -> Vec<String> {
+        std::fs::read_to_string(dir.join(file))
+            .unwrap()
+            .lines()
+            // Past the time.
+            .map(|line| line[9..].to_string())
+            .collect()
+    };
+    assert_eq!(read("channels.log"), ["[sales] Bob [sales]: wts sword"]);
+    assert_eq!(read("tells.log"), ["Alice tells you 'hi'"]);
+    assert_eq!(read("mapper.log"), ["arelium 1 Square"]);
+    assert_eq!(std::fs::read(dir.join("raw.log")).unwrap(), output);
+    assert!(!dir.join("battle.log").exists());
+    std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn the_self_test_checks_bc_mode_and_the_database() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut line = [0; 6];
+        server.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"\x1bbc 1\n");
+        server
+            .write_all(b"\xff\xfb\x01Welcome!\r\n\x1b<10spec_prompt\x1b|Name: \x1b>10")
+            .await
+            .unwrap();
+        // The connection is left open, the prompt ends the check.
+        sleep(TIMEOUT).await;
+    });
+    let path = std::env::temp_dir().join(format!("bcproxy-check-{}.db", std::process::id()));
+    let unreachable = free_port().await;
+    let config = format!(
+        "remote = {}\ndatabase = {}\n[profile other]\nremote = {}\nlisten = 127.0.0.1:0\n",
+        remote,
+        path.display(),
+        unreachable
+    );
+    let checks = batproxy_rs::check::run(&Config::parse(&config).unwrap()).await;
+    std::fs::remove_file(&path).unwrap();
+
+    let lines: Vec<String> = checks.iter().map(ToString::to_string).collect();
+    assert_eq!(lines.len(), 4, "{:#?}", lines);
+    assert!(lines[0].starts_with(&format!("ok    server {}: connected in", remote)));
+    assert_eq!(
+        lines[1],
+        format!(
+            "ok    bc mode 1 on {}: login screen with control-codes,messages,prompt",
+            remote
+        )
+    );
+    assert!(lines[2].starts_with(&format!("FAIL  server {}: ", unreachable)));
+    assert_eq!(
+        lines[3],
+        format!(
+            "ok    database {}: schema up to date, 0 rooms",
+            path.display()
+        )
+    );
+}
+
+#[tokio::test]
+async fn queued_lines_wait_for_the_prompt() {
+    let mut harness = Harness::start("client_negotiation = off\ncommand_queue = prompt\n").await;
+    async fn server_reads(harness: &mut Harness, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        timeout(TIMEOUT, harness.server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, expected);
+    }
+
+    harness.client.write_all(b"a\r\nb\r\nc\r\n").await.unwrap();
+    server_reads(&mut harness, b"a\r\n").await;
+    harness
+        .server
+        .write_all(b"\x1b<10spec_prompt\x1b|>\x1b>10")
+        .await
+        .unwrap();
+    server_reads(&mut harness, b"b\r\n").await;
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    harness
+        .client
+        .write_all(b";;queue clear\r\nd\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] 1 queued lines dropped\r\n\x1b<10spec_prompt\x1b|>\x1b>10\xff\xf9"
+    );
+    harness
+        .server
+        .write_all(b"\x1b<10spec_prompt\x1b|>\x1b>10")
+        .await
+        .unwrap();
+    server_reads(&mut harness, b"d\r\n").await;
+}
+
+#[tokio::test]
+async fn attached_clients_watch_and_write_to_a_session() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (listen, attach) = (free_port().await, free_port().await);
+    let mut config = Config::parse(CONFIG).unwrap();
+    config.listen = vec![
+        Listen::new(listen.as_str()),
+        format!("{} attach=rw", attach).parse().unwrap(),
+    ];
+    config.remote = server.local_addr().unwrap().to_string();
+    tokio::spawn(ProxyServer::builder().config(config).build().unwrap().run());
+    let mut client = connect(&listen).await;
+    let (mut server, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    server.write_all(b"hello\r\n").await.unwrap();
+    read_until(&mut client, b"hello\r\n").await;
+
+    let mut attached = connect(&attach).await;
+    read_until(&mut attached, b"[bcproxy] attached to session 1\r\n").await;
+    server.write_all(b"You see an orc.\r\n").await.unwrap();
+    assert_eq!(
+        read_until(&mut attached, b"\r\n").await,
+        b"You see an orc.\r\n"
+    );
+    read_until(&mut client, b"You see an orc.\r\n").await;
+
+    attached.write_all(b"kill orc\r\n").await.unwrap();
+    let expected = b"kill orc\r\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn taps_write_json_lines_to_unix_sockets() {
+    let dir = std::env::temp_dir();
+    let channels = dir.join(format!("bcproxy-tap-chan-{}.sock", std::process::id()));
+    let vitals = dir.join(format!("bcproxy-tap-vitals-{}.sock", std::process::id()));
+    let channel_reader = tokio::net::UnixListener::bind(&channels).unwrap();
+    let vitals_reader = tokio::net::UnixListener::bind(&vitals).unwrap();
+    let config = format!(
+        "{}tap = channels {}\ntap = vitals {}\n",
+        CONFIG,
+        channels.display(),
+        vitals.display()
+    );
+    let received = Harness::start(&config)
+        .await
+        .serve(&[b"\x1b<10chan_sales\x1b|Bob [sales]: wtb sword\r\n\x1b>10\
+            \x1b<50310 320 95 100 200 210\x1b>50You see an orc.\r\n"])
+        .await;
+    assert!(received.ends_with(b"You see an orc.\r\n"));
+
+    for (reader, expected) in [
+        (
+            channel_reader,
+            "{\"channel\":\"sales\",\"text\":\"Bob [sales]: wtb sword\",\"type\":\"chan\"}\n",
+        ),
+        (
+            vitals_reader,
+            "{\"ep\":200,\"hp\":310,\"max_ep\":210,\"max_hp\":320,\"max_sp\":100,\"sp\":95,\"type\":\"vitals\"}\n",
+        ),
+    ] {
+        let (mut stream, _) = timeout(TIMEOUT, reader.accept()).await.unwrap().unwrap();
+        let mut line = vec![0; expected.len()];
+        timeout(TIMEOUT, stream.read_exact(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&line), expected);
+    }
+    std::fs::remove_file(&channels).unwrap();
+    std::fs::remove_file(&vitals).unwrap();
+}
+
+#[tokio::test]
+async fn remote_hostnames_are_resolved_to_each_address() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let remote = format!("localhost:{}", port);
+    batproxy_rs::io::connect(&remote, TIMEOUT).await.unwrap();
+    timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+
+    // Nobody listens there any more, each address is refused in turn.
+    drop(server);
+    let failed = batproxy_rs::io::connect(&remote, TIMEOUT).await;
+    assert!(failed.is_err());
+}
+
+#[tokio::test]
+async fn a_silent_server_is_probed_then_reported() {
+    let config = "client_negotiation = off\nwatchdog_secs = 1\nwatchdog_probe = time\n";
+    let mut harness = Harness::start(config).await;
+    harness.client.write_all(b"look\r\n").await.unwrap();
+    let expected = b"look\r\ntime\n";
+    let mut received = vec![0; expected.len()];
+    timeout(TIMEOUT, harness.server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+
+    let received = read_until(&mut harness.client, b"\r\n").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] the server has not answered for 2s\r\n"
+    );
+}
+
+#[tokio::test]
+async fn area_stats_count_rooms_and_explored_exits() {
+    let path = std::env::temp_dir().join(format!("bcproxy-areas-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99\
+        \x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;s;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let explored: Option<i64> = conn
+            .query_row("SELECT explored FROM areas", [], |row| row.get(0))
+            .ok();
+        if explored == Some(1) {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    harness
+        .client
+        .write_all(b";;area stats arelium\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] area arelium: 2 rooms, 1 of 3 exits explored (33%), \
+          first seen just now, last seen just now\r\n\x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn visits_count_every_report_of_a_room() {
+    let path = std::env::temp_dir().join(format!("bcproxy-visits-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let square: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;s;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99";
+    let street: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;s;;BAT_MAPPER\x1b>99";
+    let output = [
+        square,
+        street,
+        square,
+        street,
+        square,
+        b"\x1b<10spec_prompt\x1b|> \x1b>10",
+    ];
+    harness.server.write_all(&output.concat()).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let visits: Option<i64> = conn
+            .query_row(
+                "SELECT visited_count FROM rooms WHERE id = '1'",
+                [],
+                |row| row.get(0),
+            )
+            .ok();
+        if visits == Some(3) {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    let rooms: i64 = conn
+        .query_row("SELECT rooms FROM areas", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(rooms, 2);
+    harness.client.write_all(b";;visits\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] area arelium: 5 visits to 2 rooms\r\n\
+          [bcproxy]   Square (1): 3 visits, last just now\r\n\
+          [bcproxy]   Street (2): 2 visits, last just now\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn explore_shows_the_way_to_the_closest_unexplored_exit() {
+    let path = std::env::temp_dir().join(format!("bcproxy-explore-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;n;;BAT_MAPPER\x1b>99\
+        \x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;s,e;;BAT_MAPPER\x1b>99\
+        \x1b<99BAT_MAPPER;;arelium;;1;;s;;0;;Square;;A square.;;n;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let links: i64 = conn
+            .query_row("SELECT count(*) FROM room_links", [], |row| row.get(0))
+            .unwrap_or(0);
+        if links == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    harness.client.write_all(b";;explore\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] unexplored exits of Street: e, path: n\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn rooms_are_found_by_their_descriptions() {
+    let path = std::env::temp_dir().join(format!("bcproxy-find-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A fountain splashes.;;n;;BAT_MAPPER\x1b>99\
+        \x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;s;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let rooms: i64 = conn
+            .query_row("SELECT count(*) FROM rooms", [], |row| row.get(0))
+            .unwrap_or(0);
+        if rooms == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    harness
+        .client
+        .write_all(b";;room find FOUNTAIN\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] 1 rooms in arelium match `FOUNTAIN`:\r\n\
+          [bcproxy] Square (arelium, 1)\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn rooms_are_stored_without_colors() {
+    let path = std::env::temp_dir().join(format!("bcproxy-ansi-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] = b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;\x1b[1mSquare\x1b[0m;;\
+        A \x1b[34mblue\x1b[0m fountain.;;n;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    // The client still gets the colors.
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(received, [output, b"\xff\xf9"].concat());
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let mut room = None;
+    for _ in 0..50 {
+        room = conn
+            .query_row("SELECT short_desc, long_desc FROM rooms", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .ok();
+        if room.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        room,
+        Some(("Square".to_string(), "A blue fountain.".to_string()))
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn database_writes_are_committed_in_batches() {
+    use batproxy_rs::{
+        db::{Batch, Db, Event},
+        mapper::Room,
+    };
+
+    let path = std::env::temp_dir().join(format!("bcproxy-batch-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let batch = Batch {
+        interval: Duration::from_secs(3600),
+        max_events: 3,
+    };
+    let db = Db::open_batched(&path, batch).unwrap();
+    let room = |id: &str| Room {
+        area: "arelium".to_string(),
+        id: id.to_string(),
+        direction: String::new(),
+        indoors: false,
+        short_desc: "Square".to_string(),
+        long_desc: "A square.".to_string(),
+        exits: Vec::new(),
+    };
+    db.send(Event::Room(room("1")));
+    db.send(Event::Room(room("2")));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(db.status().pending, 2);
+    assert_eq!(db.room("1").unwrap(), None);
+
+    // A full batch is written at once.
+    db.send(Event::Room(room("3")));
+    for _ in 0..50 {
+        if db.status().pending == 0 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(db.status().committed, 3);
+    assert!(db.room("3").unwrap().is_some());
+
+    // As is what is left on a flush.
+    db.send(Event::Room(room("4")));
+    db.flush();
+    assert_eq!(db.status().pending, 0);
+    assert!(db.room("4").unwrap().is_some());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn mapped_rooms_reach_the_storage() {
+    use batproxy_rs::db::{Batch, Db, Event, Link, Memory};
+
+    let memory = Memory::new();
+    let db = Db::with_storage(memory.clone(), Batch::default());
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_port().await;
+    let mut config = Config::parse(CONFIG).unwrap();
+    config.listen = vec![Listen::new(listen.as_str())];
+    config.remote = server.local_addr().unwrap().to_string();
+    let proxy = ProxyServer::builder()
+        .config(config)
+        .database(db.clone())
+        .build()
+        .unwrap();
+    tokio::spawn(proxy.run());
+    let client = connect(&listen).await;
+    let (server, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+
+    Harness { client, server }
+        .serve(&[
+            b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;n;;BAT_MAPPER\x1b>99",
+            b"\x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Gate;;A gate.;;s;;BAT_MAPPER\x1b>99",
+        ])
+        .await;
+    db.flush();
+    let stored: Vec<String> = memory
+        .events()
+        .iter()
+        .filter_map(|event| match event {
+            Event::Room(room) => Some(format!("room {}", room.id)),
+            Event::Link(link) => Some(format!("link {} {} {}", link.from, link.direction, link.to)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(stored, ["room 1", "room 2", "link 1 n 2"]);
+    assert_eq!(db.status().failed, 0);
+    // Rooms and links are read back from the storage, other reads fail.
+    let square = db.room("1").unwrap().unwrap();
+    assert_eq!(
+        (square.short_desc.as_str(), square.exits),
+        ("Square", vec!["n".to_string()])
+    );
+    assert!(db.room("3").unwrap().is_none());
+    assert_eq!(
+        db.links().unwrap(),
+        [Link {
+            from: "1".to_string(),
+            to: "2".to_string(),
+            direction: "n".to_string(),
+        }]
+    );
+    assert!(db.find_rooms("Square").is_err());
+}
+
+#[tokio::test]
+async fn aggressive_monsters_are_warned_of_and_found() {
+    let path = std::env::temp_dir().join(format!("bcproxy-aggro-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    const SQUARE: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;n;;BAT_MAPPER\x1b>99";
+    harness.server.write_all(SQUARE).await.unwrap();
+    harness
+        .server
+        .write_all(b"\x1b<10spec_prompt\x1b|> \x1b>10")
+        .await
+        .unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+    harness
+        .client
+        .write_all(b";;monster:aggro;;big orc\r\n")
+        .await
+        .unwrap();
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let aggro: i64 = conn
+            .query_row("SELECT count(*) FROM aggro", [], |row| row.get(0))
+            .unwrap_or(0);
+        if aggro == 1 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    harness.server.write_all(SQUARE).await.unwrap();
+    let received = read_until(&mut harness.client, b"BAT_MAPPER\x1b>99").await;
+    assert_eq!(
+        received,
+        [b"[warning] known aggro here: big orc\r\n", SQUARE].concat()
+    );
+
+    harness
+        .client
+        .write_all(b";;mob find ORC\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] big orc (arelium): 0 kills, aggro\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn corpses_are_left_where_the_player_died() {
+    let mut harness = Harness::start(CONFIG).await;
+    let output: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;n;;BAT_MAPPER\x1b>99\
+        \x1b<99BAT_MAPPER;;arelium;;2;;n;;0;;Street;;A street.;;s;;BAT_MAPPER\x1b>99\
+        You die.\r\n\
+        \x1b<99BAT_MAPPER;;nunnery;;9;;;;1;;Chapel;;A chapel.;;out;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    let received = String::from_utf8_lossy(&received);
+    assert_eq!(
+        received
+            .matches("[bcproxy] corpse left in Street\r\n")
+            .count(),
+        1
+    );
+
+    harness.client.write_all(b";;corpse\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] corpse in Street (arelium, 2), died just now, no known way there\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+}
+
+#[tokio::test]
+async fn inventory_listings_are_compared() {
+    let mut harness = Harness::start(CONFIG).await;
+    let output: &[u8] =
+        b"You are carrying:\r\na sword\r\nbread\r\n\x1b<10spec_prompt\x1b|> \x1b>10\
+        You are carrying:\r\na sword\r\na shield\r\n\x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(
+        &mut harness.client,
+        b"a shield\r\n\x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9",
+    )
+    .await;
+
+    harness.client.write_all(b";;inv diff\r\n").await.unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] inventory since just now:\r\n\
+          [bcproxy] + a shield\r\n\
+          [bcproxy] - bread\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+}
+
+#[tokio::test]
+async fn sales_offers_make_a_price_index() {
+    let path = std::env::temp_dir().join(format!("bcproxy-sales-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!("client_negotiation = off\ndatabase = {}\n", path.display());
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] =
+        b"\x1b<10chan_sales\x1b|Bob [sales]: wts long sword 5k, shield 800\r\n\x1b>10\
+        \x1b<10chan_sales\x1b|Ann [sales]: selling long sword for 4.5k\r\n\x1b>10\
+        \x1b<10chan_sales\x1b|Cid [sales]: wtb long sword 3000 gold\r\n\x1b>10\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    // The db task writes in the background.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for _ in 0..50 {
+        let offers: i64 = conn
+            .query_row("SELECT count(*) FROM offers", [], |row| row.get(0))
+            .unwrap_or(0);
+        if offers == 4 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    harness
+        .client
+        .write_all(b";;price sword\r\n")
+        .await
+        .unwrap();
+    let received = read_until(&mut harness.client, b"\xff\xf9").await;
+    assert_eq!(
+        received,
+        b"[bcproxy] asked 2 times for `sword`: 4.5k to 5k, median 5k, \
+          last 4.5k for long sword by Ann just now\r\n\
+          [bcproxy] bid 1 times for `sword`: 3k to 3k, median 3k, \
+          last 3k for long sword by Cid just now\r\n\
+          \x1b<10spec_prompt\x1b|> \x1b>10\xff\xf9"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn the_session_state_is_restored_after_a_restart() {
+    let path = std::env::temp_dir().join(format!("bcproxy-state-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let start = |listen: &str| {
+        let mut config = Config::parse(&format!(
+            "client_negotiation = off\ndatabase = {}\n",
+            path.display()
+        ))
+        .unwrap();
+        config.listen = vec![Listen::new(listen)];
+        config.remote = server.local_addr().unwrap().to_string();
+        let proxy = ProxyServer::builder().config(config).build().unwrap();
+        tokio::spawn(proxy.clone().run());
+        proxy
+    };
+    const PROMPT: &[u8] = b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10";
+
+    let listen = free_port().await;
+    let proxy = start(&listen);
+    let mut client = connect(&listen).await;
+    let (mut upstream, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    let output = [
+        &b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;e;;BAT_MAPPER\x1b>99"[..],
+        b"\x1b<53100\x1b>53\x1b<53150\x1b>53",
+        PROMPT,
+    ]
+    .concat();
+    upstream.write_all(&output).await.unwrap();
+    read_until(&mut client, b"\xff\xf9").await;
+    proxy.save_sessions().await;
+    proxy.flush();
+    drop((client, upstream));
+
+    let listen = free_port().await;
+    let proxy = start(&listen);
+    let mut client = connect(&listen).await;
+    let (mut upstream, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    let restored = read_until(&mut client, b"\xff\xf9").await;
+    let restored = String::from_utf8_lossy(&restored);
+    assert!(
+        restored.starts_with("[bcproxy] restored the state of the session saved "),
+        "{}",
+        restored
+    );
+    // The prompt is shown again after the line.
+    assert!(
+        restored.ends_with("s ago\r\n\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10\u{fffd}\u{fffd}"),
+        "{}",
+        restored
+    );
+
+    client.write_all(b"#bc exprate\r\n").await.unwrap();
+    let rate = read_until(&mut client, b"\xff\xf9").await;
+    let rate = String::from_utf8_lossy(&rate);
+    assert!(rate.contains(", 50 gained in 0h 0m\r\n"), "{}", rate);
+
+    // The first room after the restart is linked to the last one before.
+    upstream
+        .write_all(b"\x1b<99BAT_MAPPER;;arelium;;2;;e;;0;;Road;;A road.;;w;;BAT_MAPPER\x1b>99")
+        .await
+        .unwrap();
+    upstream.write_all(PROMPT).await.unwrap();
+    read_until(&mut client, b"\xff\xf9").await;
+    proxy.flush();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let link: (String, String, String) = conn
+        .query_row(
+            "SELECT from_id, to_id, direction FROM room_links",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(link, ("1".into(), "2".into(), "e".into()));
+    let saved: i64 = conn
+        .query_row("SELECT count(*) FROM session_state", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(saved, 0, "the state is taken only once");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_new_proxy_takes_over_without_dropping_the_server() {
+    let socket = std::env::temp_dir().join(format!("bcproxy-upgrade-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_port().await;
+    let mut config = Config::parse(&format!(
+        "client_negotiation = off\nupgrade_socket = {}\n",
+        socket.display()
+    ))
+    .unwrap();
+    config.listen = vec![Listen::new(listen.as_str())];
+    config.remote = server.local_addr().unwrap().to_string();
+    const PROMPT: &[u8] = b"\x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10";
+
+    let old = ProxyServer::builder()
+        .config(config.clone())
+        .build()
+        .unwrap();
+    let old = tokio::spawn(old.run());
+    let mut client = connect(&listen).await;
+    let (mut upstream, _) = timeout(TIMEOUT, server.accept()).await.unwrap().unwrap();
+    upstream.write_all(PROMPT).await.unwrap();
+    read_until(&mut client, b"\xff\xf9").await;
+
+    let new = ProxyServer::builder().config(config).build().unwrap();
+    timeout(TIMEOUT, new.take_over()).await.unwrap().unwrap();
+    tokio::spawn(new.run());
+    timeout(TIMEOUT, old).await.unwrap().unwrap().unwrap();
+    let mut rest = Vec::new();
+    timeout(TIMEOUT, client.read_to_end(&mut rest))
+        .await
+        .expect("the old proxy did not let its client go")
+        .unwrap();
+
+    let mut client = connect(&listen).await;
+    let took = read_until(&mut client, b"\xff\xf9").await;
+    assert_eq!(
+        String::from_utf8_lossy(&took),
+        "[bcproxy] took over the server connection of the previous proxy\r\n\
+         \x1b<10spec_prompt\x1b|Hp:100/100 >\x1b>10\u{fffd}\u{fffd}"
+    );
+    let reconnected = timeout(Duration::from_millis(100), server.accept()).await;
+    assert!(reconnected.is_err(), "the new proxy opened a connection");
+    upstream
+        .write_all(b"You are still here.\r\n")
+        .await
+        .unwrap();
+    read_until(&mut client, b"You are still here.\r\n").await;
+
+    // The server got nothing in between, no login and no end.
+    client.write_all(b"look\r\n").await.unwrap();
+    let mut line = [0; 6];
+    timeout(TIMEOUT, upstream.read_exact(&mut line))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&line, b"look\r\n");
+    let _ = std::fs::remove_file(&socket);
+}
+
+#[tokio::test]
+async fn maps_are_exported_into_the_export_dir_only() {
+    let dir = std::env::temp_dir().join(format!("bcproxy-export-{}", std::process::id()));
+    let path = std::env::temp_dir().join(format!("bcproxy-export-{}.db", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::remove_file(&path);
+    let config = format!(
+        "client_negotiation = off\ndatabase = {}\nexport_dir = {}\n",
+        path.display(),
+        dir.display()
+    );
+    let mut harness = Harness::start(&config).await;
+    let output: &[u8] =
+        b"\x1b<99BAT_MAPPER;;arelium;;1;;;;0;;Square;;A square.;;e;;BAT_MAPPER\x1b>99\
+        \x1b<10spec_prompt\x1b|> \x1b>10";
+    harness.server.write_all(output).await.unwrap();
+    read_until(&mut harness.client, b"\xff\xf9").await;
+
+    harness
+        .client
+        .write_all(b"#bc map export arelium to ../escape.dot\r\n")
+        .await
+        .unwrap();
+    let refused = read_until(&mut harness.client, b"\xff\xf9").await;
+    let refused = String::from_utf8_lossy(&refused);
+    assert!(
+        refused.contains("`../escape.dot` is not a plain file name"),
+        "{}",
+        refused
+    );
+
+    // The db task stores the room in the background.
+    let mut exported = String::new();
+    for _ in 0..50 {
+        harness
+            .client
+            .write_all(b"#bc map export arelium to arelium.dot\r\n")
+            .await
+            .unwrap();
+        exported = String::from_utf8_lossy(&read_until(&mut harness.client, b"\xff\xf9").await)
+            .into_owned();
+        if exported.contains("exported 1 rooms") {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(
+        exported.contains("exported 1 rooms and 0 links of arelium to arelium.dot"),
+        "{}",
+        exported
+    );
+    let dot = std::fs::read_to_string(dir.join("arelium.dot")).unwrap();
+    assert!(dot.starts_with("digraph \"arelium\""), "{}", dot);
+    assert!(!std::env::temp_dir().join("escape.dot").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn reported_vitals_are_served_by_the_api() {
+    let api = free_port().await;
+    let mut harness = Harness::start(&format!("{}api_listen = {}\n", CONFIG, api)).await;
+    harness
+        .server
+        .write_all(b"\x1b<5080 100 20 50 30 60\x1b>50ready\r\n")
+        .await
+        .unwrap();
+    read_until(&mut harness.client, b"ready\r\n").await;
+
+    let mut stream = connect(&api).await;
+    stream
+        .write_all(b"GET /api/vitals/latest HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(
+        response.contains(r#""hp":80,"max_ep":60,"max_hp":100,"max_sp":50"#),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn the_api_is_only_served_on_the_loopback_interface() {
+    let mut config = Config::parse(&format!("{}api_listen = 0.0.0.0:0\n", CONFIG)).unwrap();
+    config.listen = vec![Listen::new(free_port().await)];
+    let error = ProxyServer::builder()
+        .config(config)
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+        error.to_string(),
+        "api_listen 0.0.0.0:0 is not on the loopback interface"
+    );
+}
+
+#[tokio::test]
+async fn the_party_port_is_only_served_on_the_loopback_interface() {
+    let mut config = Config::parse(&format!("{}party_listen = 0.0.0.0:0\n", CONFIG)).unwrap();
+    config.listen = vec![Listen::new(free_port().await)];
+    let error = ProxyServer::builder()
+        .config(config)
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "party_listen 0.0.0.0:0 is not on the loopback interface"
+    );
+}
+
+#[tokio::test]
+async fn server_output_cut_off_by_a_disconnect_is_passed_on() {
+    // A control code the server never closed comes out as text.
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"before\r\n\x1b<10spec_map\x1b|half a m"])
+        .await;
+    assert_eq!(received, b"before\r\n\x1b<10spec_map\x1b|half a m");
+
+    // As does the start of a telnet command.
+    let received = Harness::start(CONFIG)
+        .await
+        .serve(&[b"before\r\n", b"\xff\xfb"])
+        .await;
+    assert_eq!(received, b"before\r\n\xff\xfb");
+}
+
+#[tokio::test]
+async fn client_input_cut_off_by_a_disconnect_is_passed_on() {
+    // A line that may still have become a proxy command is sent, not run.
+    for (input, expected) in [
+        (&b"look\n#bc sta"[..], &b"look\n#bc sta"[..]),
+        (b"look\n#bc status", b"look\n#bc status"),
+        (b"say hi\n\xff\xfa\x1f\x00", b"say hi\n\xff\xfa\x1f\x00"),
+        (b"#b\xff", b"#b\xff"),
+    ] {
+        let mut harness = Harness::start(CONFIG).await;
+        harness.client.write_all(input).await.unwrap();
+        harness.client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        timeout(TIMEOUT, harness.server.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, expected, "{}", input.escape_ascii());
+    }
+}
@@ -0,0 +1,188 @@
+//! Drives the proxy with a long stream of BC output and checks that its
+//! memory use and thread count level off.
+//!
+//! Run with `cargo test --release --features soak --test soak`. The number
+//! of frames can be changed with `SOAK_FRAMES`.
+#![cfg(all(feature = "soak", target_os = "linux"))]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+const DEFAULT_FRAMES: u64 = 10_000_000;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Growth in RSS allowed between the first and the second half of the run.
+const RSS_SLACK_KB: u64 = 8 * 1024;
+
+struct Sample {
+    rss_kb: u64,
+    threads: u64,
+}
+
+struct Proxy(Child);
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn memory_and_threads_plateau() {
+    let frames = std::env::var("SOAK_FRAMES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_FRAMES);
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = server.local_addr().unwrap();
+    let listen = free_port();
+
+    let config = std::env::temp_dir().join(format!("bcproxy-soak-{}.conf", std::process::id()));
+    std::fs::write(
+        &config,
+        format!(
+            "listen = {}\nremote = {}\nclient_negotiation = off\n\
+             trigger = @chan_sales *sword* => highlight\n\
+             highlight = Bob => fg=ff8800 bold\n",
+            listen, remote
+        ),
+    )
+    .unwrap();
+
+    let proxy = Proxy(
+        Command::new(env!("CARGO_BIN_EXE_batproxy-rs"))
+            .arg("--config")
+            .arg(&config)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let pid = proxy.0.id();
+
+    let mut client = connect(&listen);
+    let (mut upstream, _) = server.accept().unwrap();
+    let writer = thread::spawn(move || {
+        for i in 0..frames {
+            upstream.write_all(&frame(i)).unwrap();
+        }
+    });
+
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut samples = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                samples.push(sample(pid));
+                thread::sleep(SAMPLE_INTERVAL);
+            }
+            samples
+        })
+    };
+
+    let reader = thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        let mut total = 0u64;
+        while let Ok(n) = client.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+        }
+        total
+    });
+
+    writer.join().unwrap();
+    // The server closing its side ends the session.
+    let received = reader.join().unwrap();
+    done.store(true, Ordering::Relaxed);
+    let samples = sampler.join().unwrap();
+    let _ = std::fs::remove_file(&config);
+
+    assert!(received > 0, "client received nothing");
+    assert!(
+        samples.len() >= 4,
+        "run too short to sample, raise SOAK_FRAMES"
+    );
+
+    // Skip the warm-up before comparing the two halves.
+    let samples = &samples[samples.len() / 10..];
+    let (first, second) = samples.split_at(samples.len() / 2);
+    let peak = |s: &[Sample], f: fn(&Sample) -> u64| s.iter().map(f).max().unwrap_or(0);
+
+    let (rss_first, rss_second) = (peak(first, |s| s.rss_kb), peak(second, |s| s.rss_kb));
+    assert!(
+        rss_second <= rss_first + RSS_SLACK_KB,
+        "RSS kept growing: {} kB, then {} kB",
+        rss_first,
+        rss_second
+    );
+
+    let (threads_first, threads_second) = (peak(first, |s| s.threads), peak(second, |s| s.threads));
+    assert!(
+        threads_second <= threads_first,
+        "thread count kept growing: {}, then {}",
+        threads_first,
+        threads_second
+    );
+}
+
+/// One frame of server output, cycling through the kinds the proxy handles.
+fn frame(i: u64) -> Vec<u8> {
+    match i % 6 {
+        0 => format!(
+            "\x1b<10chan_sales\x1b|Bob: selling a sword {}\r\n\x1b>10",
+            i
+        ),
+        1 => format!(
+            "\x1b<99BAT_MAPPER;;arelium;;{};;n;;0;;Square;;A square.;;n,s;;BAT_MAPPER\x1b>99",
+            i
+        ),
+        2 => "\x1b<10spec_prompt\x1b|Hp:100/100 Sp:50/50 Ep:80/80 >\x1b>10".to_string(),
+        3 => format!("\x1b<20ff0000\x1b|A red line {}\x1b>20\r\n", i),
+        4 => "\x1b<11\x1b>11\x1b<10spec_map\x1b|  ###\r\n  #@#\r\n  ###\r\n\x1b>10".to_string(),
+        _ => format!("Plain text line number {}.\r\n", i),
+    }
+    .into_bytes()
+}
+
+fn free_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("proxy did not start listening on {}", addr);
+}
+
+fn sample(pid: u32) -> Sample {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    };
+    Sample {
+        rss_kb: field("VmRSS:"),
+        threads: field("Threads:"),
+    }
+}
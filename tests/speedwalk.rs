@@ -0,0 +1,50 @@
+use batproxy_rs::speedwalk::{expand, MAX_STEPS};
+
+fn steps(line: &str) -> Option<String> {
+    expand(line).map(|steps| steps.join(" "))
+}
+
+#[test]
+fn counts_and_tokens() {
+    assert_eq!(steps("3n2e u").as_deref(), Some("n n n e e u"));
+    assert_eq!(steps("  2ne sw d ").as_deref(), Some("ne ne sw d"));
+    assert_eq!(steps("n2e").as_deref(), Some("n e e"));
+}
+
+#[test]
+fn nested_counts() {
+    assert_eq!(steps("2(n e)").as_deref(), Some("n e n e"));
+    assert_eq!(steps("2(n 2(e))w").as_deref(), Some("n e e n e e w"));
+    assert_eq!(steps("3()n2s").as_deref(), Some("n s s"));
+}
+
+#[test]
+fn single_steps_are_left_alone() {
+    assert_eq!(steps("n"), None);
+    assert_eq!(steps("1nw"), None);
+    assert_eq!(steps(""), None);
+}
+
+#[test]
+fn invalid_tokens() {
+    for line in [
+        "news", "used", "say 3n", "3x", "n 2", "2(n e", "n e)", "0n e", "2(n)(e)x", "3n-2e",
+    ] {
+        assert_eq!(steps(line), None, "{:?}", line);
+    }
+}
+
+#[test]
+fn walks_are_bounded() {
+    assert_eq!(
+        expand(&format!("{}n", MAX_STEPS)).map(|s| s.len()),
+        Some(MAX_STEPS)
+    );
+    assert_eq!(steps(&format!("{}n e", MAX_STEPS)), None);
+    assert_eq!(steps("99999999999999999999999n"), None);
+    assert_eq!(steps("10(10(10(n)))"), None);
+    assert_eq!(
+        steps(&format!("{}n{}", "2(".repeat(20), ")".repeat(20))),
+        None
+    );
+}
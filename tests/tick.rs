@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use batproxy_rs::{
+    bc::{ControlCode, Frame},
+    tick::Tick,
+    vitals::PLAYER_STATUS,
+};
+
+fn status(hp: i64, sp: i64) -> ControlCode {
+    let text = format!("{} 100 {} 100 50 50", hp, sp);
+    ControlCode::new(
+        PLAYER_STATUS,
+        None::<Vec<u8>>,
+        vec![Frame::text(text.into_bytes())],
+    )
+}
+
+fn secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs)
+}
+
+#[test]
+fn learns_the_tick_from_points_going_up() {
+    let start = Instant::now();
+    let mut tick = Tick::default();
+    tick.observe(&status(10, 10), start);
+    for i in 1..=4 {
+        assert!(tick.observe(&status(10 + i, 10), start + secs(15.0 * i as f64)));
+    }
+    assert_eq!(tick.period(), Some(secs(15.0)));
+    assert_eq!(tick.time_left(start + secs(65.0)), Some(secs(10.0)));
+}
+
+#[test]
+fn points_up_between_ticks_are_not_a_tick() {
+    let start = Instant::now();
+    let mut tick = Tick::default();
+    tick.observe(&status(10, 10), start);
+    for i in 1..=4 {
+        tick.observe(&status(10 + i, 10), start + secs(15.0 * i as f64));
+    }
+    // A heal between ticks, then a tick after one the player was full on.
+    assert!(!tick.observe(&status(50, 10), start + secs(67.0)));
+    assert!(tick.observe(&status(50, 11), start + secs(90.5)));
+    assert_eq!(tick.period(), Some(secs(15.0)));
+    assert_eq!(tick.time_left(start + secs(95.0)), Some(secs(10.5)));
+}
+
+#[test]
+fn points_going_down_or_staying_are_not_a_tick() {
+    let start = Instant::now();
+    let mut tick = Tick::default();
+    tick.observe(&status(10, 10), start);
+    assert!(!tick.observe(&status(10, 10), start + secs(15.0)));
+    assert!(!tick.observe(&status(5, 10), start + secs(30.0)));
+    assert!(!tick.observe(
+        &ControlCode::new(PLAYER_STATUS, None::<Vec<u8>>, vec![]),
+        start
+    ));
+    assert_eq!(tick.period(), None);
+}